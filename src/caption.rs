@@ -0,0 +1,347 @@
+use std::fmt;
+
+use crate::converter::TrimRange;
+use crate::language::normalize_language;
+
+/// Ключевое слово в подписи, включающее режим кружка.
+const VIDEO_NOTE_KEYWORD: &str = "#round";
+/// Префикс параметра с языком звуковой дорожки: `lang=jpn`.
+const AUDIO_LANGUAGE_PREFIX: &str = "lang=";
+/// Команды обрезки: за ними идёт диапазон, `trim 0:10-0:42`.
+const TRIM_KEYWORDS: &[&str] = &["trim", "cut"];
+
+/// Параметры конвертации, заданные в подписи к видео.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CaptionOptions {
+    /// Отправить результат кружком (`send_video_note`).
+    pub video_note: bool,
+    /// Язык звуковой дорожки вместо настроек чата, в нижнем регистре.
+    pub audio_language: Option<String>,
+    /// Оставить только этот фрагмент видео.
+    pub trim: Option<TrimRange>,
+}
+
+/// Диапазон после `trim`/`cut`, который не удалось разобрать. Текст
+/// ошибки уходит пользователю как есть.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TrimError {
+    pub range: String,
+    pub reason: &'static str,
+}
+
+impl fmt::Display for TrimError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Invalid trim range \"{}\": {}. Use for example \"trim 0:10-0:42\", \"cut 1:00-1:30\" or \"trim 1:05:00-\".",
+            self.range, self.reason
+        )
+    }
+}
+
+/// Код языка из `lang=xxx` в виде ISO 639-2, как в тегах ffprobe:
+/// `lang=ja` превращается в `jpn`.
+fn parse_audio_language(word: &str) -> Option<String> {
+    let prefix = word.get(..AUDIO_LANGUAGE_PREFIX.len())?;
+    if !prefix.eq_ignore_ascii_case(AUDIO_LANGUAGE_PREFIX) {
+        return None;
+    }
+    normalize_language(&word[AUDIO_LANGUAGE_PREFIX.len()..])
+}
+
+/// Похоже ли слово на диапазон. Только такие слова после `trim`/`cut`
+/// считаются командой, иначе «cut» в обычном тексте ломал бы подпись.
+fn looks_like_range(word: &str) -> bool {
+    word.contains('-')
+        && word.chars().any(|c| c.is_ascii_digit())
+        && word
+            .chars()
+            .all(|c| c.is_ascii_digit() || matches!(c, ':' | '.' | '-'))
+}
+
+/// Целое число из одних цифр, без знака и пробелов.
+fn parse_digits(part: &str) -> Option<u64> {
+    if part.is_empty() || !part.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    part.parse().ok()
+}
+
+/// Метка времени `SS`, `M:SS` или `H:MM:SS`, у секунд может быть дробная
+/// часть. Старший компонент не ограничен: `90` и `90:00` допустимы.
+pub fn parse_timestamp(value: &str) -> Option<f64> {
+    let parts: Vec<&str> = value.split(':').collect();
+    if parts.len() > 3 {
+        return None;
+    }
+    let (seconds_part, leading) = parts.split_last()?;
+
+    let seconds = match seconds_part.split_once('.') {
+        Some((whole, fraction)) => {
+            parse_digits(whole)?;
+            parse_digits(fraction)?;
+            seconds_part.parse::<f64>().ok()?
+        }
+        None => parse_digits(seconds_part)? as f64,
+    };
+    if !leading.is_empty() && seconds >= 60.0 {
+        return None;
+    }
+
+    let mut total = seconds;
+    let mut multiplier = 60.0;
+    for (position, part) in leading.iter().rev().enumerate() {
+        let value = parse_digits(part)?;
+        // Минуты перед секундами ограничены, только если есть ещё и часы.
+        if position + 1 < leading.len() && value >= 60 {
+            return None;
+        }
+        total += value as f64 * multiplier;
+        multiplier *= 60.0;
+    }
+    Some(total)
+}
+
+/// Диапазон `начало-конец`; любой конец можно опустить: `-0:30` — первые
+/// 30 секунд, `1:00-` — с минуты до конца.
+pub fn parse_trim_range(range: &str) -> Result<TrimRange, TrimError> {
+    let error = |reason| TrimError {
+        range: range.to_string(),
+        reason,
+    };
+    let (start, end) = range
+        .split_once('-')
+        .ok_or_else(|| error("expected start-end"))?;
+    if start.is_empty() && end.is_empty() {
+        return Err(error("both ends are missing"));
+    }
+
+    let start_secs = if start.is_empty() {
+        0.0
+    } else {
+        parse_timestamp(start).ok_or_else(|| error("the start is not a timestamp"))?
+    };
+    let end_secs = if end.is_empty() {
+        None
+    } else {
+        Some(parse_timestamp(end).ok_or_else(|| error("the end is not a timestamp"))?)
+    };
+    if end_secs.is_some_and(|end_secs| end_secs <= start_secs) {
+        return Err(error("the end must be after the start"));
+    }
+    Ok(TrimRange {
+        start_secs,
+        end_secs,
+    })
+}
+
+/// Разбирает подпись: возвращает найденные параметры и текст подписи без
+/// них. Строки без параметров остаются как есть, строки, от которых ничего
+/// не осталось, удаляются. Пустой остаток превращается в `None`. Ошибка —
+/// только если диапазон обрезки записан с ошибкой.
+pub fn parse_caption(caption: Option<&str>) -> Result<(CaptionOptions, Option<String>), TrimError> {
+    let mut options = CaptionOptions::default();
+    let Some(caption) = caption else {
+        return Ok((options, None));
+    };
+
+    let mut kept_lines = Vec::new();
+    let mut found_any = false;
+    for line in caption.lines() {
+        let mut kept_words = Vec::new();
+        let mut found = false;
+        let mut words = line.split(' ').peekable();
+        while let Some(word) = words.next() {
+            if word.eq_ignore_ascii_case(VIDEO_NOTE_KEYWORD) {
+                options.video_note = true;
+                found = true;
+            } else if let Some(language) = parse_audio_language(word) {
+                options.audio_language = Some(language);
+                found = true;
+            } else if let Some(range) = words.next_if(|next| {
+                looks_like_range(next)
+                    && TRIM_KEYWORDS
+                        .iter()
+                        .any(|keyword| word.eq_ignore_ascii_case(keyword))
+            }) {
+                options.trim = Some(parse_trim_range(range)?);
+                found = true;
+            } else {
+                kept_words.push(word);
+            }
+        }
+
+        if !found {
+            kept_lines.push(line.to_string());
+            continue;
+        }
+        found_any = true;
+        let kept = kept_words.join(" ").trim().to_string();
+        if !kept.is_empty() {
+            kept_lines.push(kept);
+        }
+    }
+
+    // Без параметров подпись возвращаем байт в байт, с исходными переводами строк.
+    let remaining = if found_any {
+        kept_lines.join("\n")
+    } else {
+        caption.to_string()
+    };
+    Ok((options, (!remaining.trim().is_empty()).then_some(remaining)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_caption, parse_timestamp, parse_trim_range, CaptionOptions};
+    use crate::converter::TrimRange;
+
+    fn range(start_secs: f64, end_secs: Option<f64>) -> TrimRange {
+        TrimRange {
+            start_secs,
+            end_secs,
+        }
+    }
+
+    #[test]
+    fn leaves_plain_captions_alone() {
+        assert_eq!(
+            parse_caption(Some("look at this\nsecond line")).unwrap(),
+            (
+                CaptionOptions::default(),
+                Some("look at this\nsecond line".to_string())
+            )
+        );
+        assert_eq!(
+            parse_caption(None).unwrap(),
+            (CaptionOptions::default(), None)
+        );
+    }
+
+    #[test]
+    fn extracts_video_note_keyword() {
+        let (options, caption) = parse_caption(Some("cat #ROUND video")).unwrap();
+        assert!(options.video_note);
+        assert_eq!(caption.as_deref(), Some("cat video"));
+
+        let (options, caption) = parse_caption(Some("#round")).unwrap();
+        assert!(options.video_note);
+        assert_eq!(caption, None);
+    }
+
+    #[test]
+    fn keeps_lines_without_options_verbatim() {
+        let text = "  indented  \r\n\n  code block\n";
+        assert_eq!(
+            parse_caption(Some(text)).unwrap(),
+            (CaptionOptions::default(), Some(text.to_string()))
+        );
+
+        let (options, caption) = parse_caption(Some("#round\n  indented  \n\nend")).unwrap();
+        assert!(options.video_note);
+        assert_eq!(caption.as_deref(), Some("  indented  \n\nend"));
+
+        let (_, caption) = parse_caption(Some("   \n")).unwrap();
+        assert_eq!(caption, None);
+    }
+
+    #[test]
+    fn ignores_keyword_inside_words() {
+        let (options, caption) = parse_caption(Some("#rounds of applause")).unwrap();
+        assert!(!options.video_note);
+        assert_eq!(caption.as_deref(), Some("#rounds of applause"));
+    }
+
+    #[test]
+    fn extracts_audio_language() {
+        let (options, caption) = parse_caption(Some("episode 3 LANG=JPN #round")).unwrap();
+        assert_eq!(options.audio_language.as_deref(), Some("jpn"));
+        assert!(options.video_note);
+        assert_eq!(caption.as_deref(), Some("episode 3"));
+
+        let (options, caption) = parse_caption(Some("lang=ja")).unwrap();
+        assert_eq!(options.audio_language.as_deref(), Some("jpn"));
+        assert_eq!(caption, None);
+
+        let (options, caption) = parse_caption(Some("lang=klingon lang= lang=ру lang=xx")).unwrap();
+        assert_eq!(options.audio_language, None);
+        assert_eq!(
+            caption.as_deref(),
+            Some("lang=klingon lang= lang=ру lang=xx")
+        );
+    }
+
+    #[test]
+    fn parses_timestamp_formats() {
+        assert_eq!(parse_timestamp("0"), Some(0.0));
+        assert_eq!(parse_timestamp("42"), Some(42.0));
+        assert_eq!(parse_timestamp("90"), Some(90.0));
+        assert_eq!(parse_timestamp("42.5"), Some(42.5));
+        assert_eq!(parse_timestamp("1:05"), Some(65.0));
+        assert_eq!(parse_timestamp("01:05.250"), Some(65.25));
+        assert_eq!(parse_timestamp("90:00"), Some(5400.0));
+        assert_eq!(parse_timestamp("1:02:03"), Some(3723.0));
+        assert_eq!(parse_timestamp("0:00:07.5"), Some(7.5));
+        assert_eq!(parse_timestamp("12:00:00"), Some(43200.0));
+    }
+
+    #[test]
+    fn rejects_malformed_timestamps() {
+        for value in [
+            "", ":", "1:", ":30", "1::30", "1:60", "1:2:60", "1:60:00", "1:2:3:4", ".5", "5.",
+            "1.2.3", "1:5.", "a", "1:xx", "-1", "+5", " 5", "1,5", "１",
+        ] {
+            assert_eq!(parse_timestamp(value), None, "accepted: {:?}", value);
+        }
+    }
+
+    #[test]
+    fn parses_trim_ranges() {
+        assert_eq!(parse_trim_range("0:10-0:42"), Ok(range(10.0, Some(42.0))));
+        assert_eq!(parse_trim_range("5-7.5"), Ok(range(5.0, Some(7.5))));
+        assert_eq!(parse_trim_range("1:00-"), Ok(range(60.0, None)));
+        assert_eq!(parse_trim_range("-0:30"), Ok(range(0.0, Some(30.0))));
+        assert_eq!(range(10.0, Some(42.0)).duration_secs(), Some(32.0));
+        assert_eq!(range(60.0, None).duration_secs(), None);
+    }
+
+    #[test]
+    fn explains_invalid_trim_ranges() {
+        let reason = |value: &str| parse_trim_range(value).unwrap_err().reason;
+        assert_eq!(reason("0:42-0:10"), "the end must be after the start");
+        assert_eq!(reason("0:10-0:10"), "the end must be after the start");
+        assert_eq!(reason("-"), "both ends are missing");
+        assert_eq!(reason("0:70-1:30"), "the start is not a timestamp");
+        assert_eq!(reason("0:10-0:10-0:20"), "the end is not a timestamp");
+        assert_eq!(reason("42"), "expected start-end");
+
+        let error = parse_trim_range("1:30-1:00").unwrap_err();
+        assert!(error
+            .to_string()
+            .starts_with("Invalid trim range \"1:30-1:00\""));
+    }
+
+    #[test]
+    fn extracts_trim_command_from_caption() {
+        let (options, caption) = parse_caption(Some("the goal TRIM 0:10-0:42 #round")).unwrap();
+        assert_eq!(options.trim, Some(range(10.0, Some(42.0))));
+        assert!(options.video_note);
+        assert_eq!(caption.as_deref(), Some("the goal"));
+
+        let (options, caption) = parse_caption(Some("cut 1:00-1:30")).unwrap();
+        assert_eq!(options.trim, Some(range(60.0, Some(90.0))));
+        assert_eq!(caption, None);
+
+        let error = parse_caption(Some("funny part\ntrim 0:42-0:10")).unwrap_err();
+        assert_eq!(error.range, "0:42-0:10");
+    }
+
+    #[test]
+    fn keeps_trim_words_in_plain_text() {
+        for text in ["cut the cake", "trim", "budget cut 20%", "cut to 1:00"] {
+            let (options, caption) = parse_caption(Some(text)).unwrap();
+            assert_eq!(options.trim, None);
+            assert_eq!(caption.as_deref(), Some(text));
+        }
+    }
+}
@@ -0,0 +1,605 @@
+use anyhow::{anyhow, bail, Context, Result as AnyResult};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::Path;
+
+use crate::converter::OutputTarget;
+
+const DEFAULT_PROFILE_NAME: &str = "balanced";
+const DEFAULT_ANIMATION_MAX_DURATION_SECS: f64 = 30.0;
+const DEFAULT_VOICE_MAX_DURATION_SECS: f64 = 120.0;
+const DEFAULT_MAX_FRAME_RATE: f64 = 60.0;
+const SUPPORTED_VIDEO_CODECS: &[&str] = &["libx264", "libx265"];
+/// 10-битный `yuv420p10le` допустим только для кодеков, чьё 10-битное видео
+/// проигрывают клиенты Telegram; 10-битный H.264 они не показывают.
+const TEN_BIT_VIDEO_CODECS: &[&str] = &["libx265"];
+const SUPPORTED_AUDIO_CODECS: &[&str] = &["aac"];
+/// Пресеты x264 от самого быстрого к самому медленному.
+pub const X264_PRESETS: &[&str] = &[
+    "ultrafast",
+    "superfast",
+    "veryfast",
+    "faster",
+    "fast",
+    "medium",
+    "slow",
+    "slower",
+    "veryslow",
+    "placebo",
+];
+
+/// Набор параметров кодирования, из которого собираются аргументы ffmpeg.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct EncodingProfile {
+    pub video_codec: String,
+    pub preset: String,
+    pub crf: u8,
+    pub pixel_format: String,
+    pub audio_codec: String,
+    /// Битрейт звука в синтаксисе ffmpeg, например `192k`.
+    pub audio_bitrate: String,
+    /// Целевая громкость по EBU R128 в LUFS; без неё звук не выравнивается.
+    pub loudness_target: Option<f64>,
+}
+
+impl EncodingProfile {
+    fn new(preset: &str, crf: u8, audio_bitrate: &str) -> Self {
+        Self {
+            video_codec: "libx264".to_string(),
+            preset: preset.to_string(),
+            crf,
+            pixel_format: "yuv420p".to_string(),
+            audio_codec: "aac".to_string(),
+            audio_bitrate: audio_bitrate.to_string(),
+            loudness_target: None,
+        }
+    }
+
+    fn validate(&self) -> AnyResult<()> {
+        if !SUPPORTED_VIDEO_CODECS.contains(&self.video_codec.as_str()) {
+            bail!(
+                "unsupported video_codec '{}', expected one of {:?}",
+                self.video_codec,
+                SUPPORTED_VIDEO_CODECS
+            );
+        }
+        if !X264_PRESETS.contains(&self.preset.as_str()) {
+            bail!("unknown preset '{}'", self.preset);
+        }
+        if self.crf > 51 {
+            bail!("crf {} is out of range 0..=51", self.crf);
+        }
+        let pixel_formats: &[&str] = if TEN_BIT_VIDEO_CODECS.contains(&self.video_codec.as_str()) {
+            &["yuv420p", "yuv420p10le"]
+        } else {
+            &["yuv420p"]
+        };
+        if !pixel_formats.contains(&self.pixel_format.as_str()) {
+            bail!(
+                "unsupported pixel_format '{}' for {}, expected one of {:?}",
+                self.pixel_format,
+                self.video_codec,
+                pixel_formats
+            );
+        }
+        if !SUPPORTED_AUDIO_CODECS.contains(&self.audio_codec.as_str()) {
+            bail!(
+                "unsupported audio_codec '{}', expected one of {:?}",
+                self.audio_codec,
+                SUPPORTED_AUDIO_CODECS
+            );
+        }
+        if parse_bitrate(&self.audio_bitrate).is_none() {
+            bail!("invalid audio_bitrate '{}'", self.audio_bitrate);
+        }
+        if let Some(target) = self.loudness_target {
+            // Диапазон, который принимает фильтр `loudnorm`.
+            if !(-70.0..=-5.0).contains(&target) {
+                bail!("loudness_target {} is out of range -70..=-5 LUFS", target);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Разбирает битрейт вида `192k`, `1M` или `128000` в биты в секунду.
+pub fn parse_bitrate(value: &str) -> Option<u64> {
+    let value = value.trim();
+    let (digits, multiplier) = match value.chars().last()? {
+        'k' | 'K' => (&value[..value.len() - 1], 1_000),
+        'm' | 'M' => (&value[..value.len() - 1], 1_000_000),
+        _ => (value, 1),
+    };
+    digits
+        .parse::<u64>()
+        .ok()
+        .filter(|bitrate| *bitrate > 0)
+        .map(|bitrate| bitrate * multiplier)
+}
+
+fn validate_duration(key: &str, secs: f64) -> AnyResult<f64> {
+    if !(secs >= 0.0 && secs.is_finite()) {
+        bail!("{} must be a non-negative number, got {}", key, secs);
+    }
+    Ok(secs)
+}
+
+fn validate_frame_rate(fps: f64) -> AnyResult<f64> {
+    if !(fps > 0.0 && fps.is_finite()) {
+        bail!("max_frame_rate must be a positive number, got {}", fps);
+    }
+    Ok(fps)
+}
+
+fn builtin_profiles() -> HashMap<String, EncodingProfile> {
+    HashMap::from([
+        (
+            "fast".to_string(),
+            EncodingProfile::new("ultrafast", 26, "128k"),
+        ),
+        (
+            "balanced".to_string(),
+            EncodingProfile::new("veryfast", 23, "192k"),
+        ),
+        ("small".to_string(), EncodingProfile::new("slow", 28, "96k")),
+        (
+            "archive".to_string(),
+            EncodingProfile::new("slower", 18, "256k"),
+        ),
+    ])
+}
+
+/// Что делать с дорожками субтитров при конвертации видео.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SubtitleMode {
+    /// Не переносить субтитры.
+    #[default]
+    Drop,
+    /// Вжечь одну дорожку в кадр.
+    Burn,
+    /// Переложить текстовые дорожки в MP4 как `mov_text`.
+    Embed,
+}
+
+/// Настройки отдельного чата.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ChatSettings {
+    /// Профиль по умолчанию для этого чата.
+    pub profile: Option<String>,
+    /// Отправлять все видео кружками.
+    #[serde(default)]
+    pub video_note: bool,
+    /// Владелец набора стикеров чата: Bot API создаёт наборы только от имени
+    /// пользователя, который хоть раз писал боту.
+    pub sticker_set_owner: Option<u64>,
+    #[serde(default)]
+    pub subtitles: SubtitleMode,
+    /// Предпочитаемый язык субтитров (тег `language`, например `eng`).
+    pub subtitle_language: Option<String>,
+    /// Языки звуковой дорожки в порядке предпочтения.
+    #[serde(default)]
+    pub audio_languages: Vec<String>,
+    /// Оставлять HDR-видео в 10 битах вместо тонмаппинга в SDR.
+    #[serde(default)]
+    pub keep_10bit: bool,
+    /// Контейнер и кодеки видео; без него — по `video_codec` профиля.
+    pub output_target: Option<OutputTarget>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawConfig {
+    default_profile: Option<String>,
+    animation_max_duration_secs: Option<f64>,
+    voice_max_duration_secs: Option<f64>,
+    max_frame_rate: Option<f64>,
+    #[serde(default)]
+    profiles: HashMap<String, EncodingProfile>,
+    #[serde(default)]
+    chats: HashMap<String, ChatSettings>,
+}
+
+/// Проверенная конфигурация бота.
+#[derive(Debug, Clone)]
+pub struct Config {
+    default_profile: String,
+    animation_max_duration_secs: f64,
+    voice_max_duration_secs: f64,
+    max_frame_rate: f64,
+    profiles: HashMap<String, EncodingProfile>,
+    chats: HashMap<i64, ChatSettings>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            default_profile: DEFAULT_PROFILE_NAME.to_string(),
+            animation_max_duration_secs: DEFAULT_ANIMATION_MAX_DURATION_SECS,
+            voice_max_duration_secs: DEFAULT_VOICE_MAX_DURATION_SECS,
+            max_frame_rate: DEFAULT_MAX_FRAME_RATE,
+            profiles: builtin_profiles(),
+            chats: HashMap::new(),
+        }
+    }
+}
+
+impl Config {
+    /// Читает TOML-файл; если его нет, используются встроенные профили.
+    pub fn load(path: &Path) -> AnyResult<Self> {
+        if !path.exists() {
+            log::info!(
+                "Config file {} not found, using built-in profiles",
+                path.display()
+            );
+            return Ok(Self::default());
+        }
+
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read config file {}", path.display()))?;
+        Self::parse(&text).with_context(|| format!("Invalid config file {}", path.display()))
+    }
+
+    /// Разбирает и проверяет конфигурацию. Профили из файла дополняют
+    /// встроенные и переопределяют их при совпадении имён.
+    pub fn parse(text: &str) -> AnyResult<Self> {
+        let raw: RawConfig = toml::from_str(text)?;
+
+        let mut profiles = builtin_profiles();
+        profiles.extend(raw.profiles);
+        for (name, profile) in &profiles {
+            profile
+                .validate()
+                .with_context(|| format!("profile '{}'", name))?;
+        }
+
+        let default_profile = raw
+            .default_profile
+            .unwrap_or_else(|| DEFAULT_PROFILE_NAME.to_string());
+        if !profiles.contains_key(&default_profile) {
+            bail!("default_profile '{}' is not defined", default_profile);
+        }
+
+        let animation_max_duration_secs = validate_duration(
+            "animation_max_duration_secs",
+            raw.animation_max_duration_secs
+                .unwrap_or(DEFAULT_ANIMATION_MAX_DURATION_SECS),
+        )?;
+        let voice_max_duration_secs = validate_duration(
+            "voice_max_duration_secs",
+            raw.voice_max_duration_secs
+                .unwrap_or(DEFAULT_VOICE_MAX_DURATION_SECS),
+        )?;
+        let max_frame_rate =
+            validate_frame_rate(raw.max_frame_rate.unwrap_or(DEFAULT_MAX_FRAME_RATE))?;
+
+        let mut chats = HashMap::new();
+        for (chat_id, settings) in raw.chats {
+            let parsed_chat_id = chat_id
+                .parse::<i64>()
+                .map_err(|_| anyhow!("chat id '{}' is not an integer", chat_id))?;
+            if let Some(profile) = &settings.profile {
+                if !profiles.contains_key(profile) {
+                    bail!("chat {} uses undefined profile '{}'", chat_id, profile);
+                }
+            }
+            chats.insert(parsed_chat_id, settings);
+        }
+
+        let config = Self {
+            default_profile,
+            animation_max_duration_secs,
+            voice_max_duration_secs,
+            max_frame_rate,
+            profiles,
+            chats,
+        };
+        for (chat_id, settings) in &config.chats {
+            let target = config.output_target_for_chat(*chat_id);
+            if settings.keep_10bit && !target.supports_10bit() {
+                bail!(
+                    "chat {} sets keep_10bit, but its output target {:?} cannot carry 10-bit video; use mp4-hevc, mp4-av1 or webm-vp9",
+                    chat_id,
+                    target
+                );
+            }
+        }
+        Ok(config)
+    }
+
+    pub fn default_profile(&self) -> &str {
+        &self.default_profile
+    }
+
+    /// Беззвучные ролики не длиннее этого отправляются анимацией; 0 отключает.
+    pub fn animation_max_duration_secs(&self) -> f64 {
+        self.animation_max_duration_secs
+    }
+
+    /// Моно-записи не длиннее этого отправляются голосовыми; 0 отключает.
+    pub fn voice_max_duration_secs(&self) -> f64 {
+        self.voice_max_duration_secs
+    }
+
+    /// Видео с частотой кадров выше этой перекодируется с понижением.
+    pub fn max_frame_rate(&self) -> f64 {
+        self.max_frame_rate
+    }
+
+    /// Профиль, выбранный чатом, или профиль по умолчанию.
+    pub fn profile_for_chat(&self, chat_id: i64) -> (&str, &EncodingProfile) {
+        let name = self
+            .chats
+            .get(&chat_id)
+            .and_then(|settings| settings.profile.as_deref())
+            .unwrap_or(&self.default_profile);
+        let (name, profile) = self
+            .profiles
+            .get_key_value(name)
+            .expect("profiles are validated at load time");
+        (name.as_str(), profile)
+    }
+
+    /// Контейнер и кодеки видео чата: выбранные им или по кодеку профиля.
+    pub fn output_target_for_chat(&self, chat_id: i64) -> OutputTarget {
+        self.chats
+            .get(&chat_id)
+            .and_then(|settings| settings.output_target)
+            .unwrap_or_else(|| {
+                OutputTarget::for_codec(&self.profile_for_chat(chat_id).1.video_codec)
+            })
+    }
+
+    /// Все цели, которые могут понадобиться: по умолчанию и выбранные
+    /// чатами. По ним при запуске проверяются кодеры ffmpeg.
+    pub fn output_targets(&self) -> Vec<OutputTarget> {
+        let mut used: Vec<OutputTarget> = self
+            .chats
+            .keys()
+            .map(|chat_id| self.output_target_for_chat(*chat_id))
+            .collect();
+        // Чат без своих настроек получает цель профиля по умолчанию.
+        used.push(OutputTarget::for_codec(
+            &self.profiles[&self.default_profile].video_codec,
+        ));
+        OutputTarget::ALL
+            .into_iter()
+            .filter(|target| used.contains(target))
+            .collect()
+    }
+
+    /// Включён ли в чате режим кружков.
+    pub fn video_note_for_chat(&self, chat_id: i64) -> bool {
+        self.chats
+            .get(&chat_id)
+            .map(|settings| settings.video_note)
+            .unwrap_or(false)
+    }
+
+    /// Оставлять ли в чате HDR-видео в 10 битах.
+    pub fn keep_10bit_for_chat(&self, chat_id: i64) -> bool {
+        self.chats
+            .get(&chat_id)
+            .map(|settings| settings.keep_10bit)
+            .unwrap_or(false)
+    }
+
+    /// Владелец набора стикеров чата, если `/sticker add` в нём разрешён.
+    pub fn sticker_set_owner_for_chat(&self, chat_id: i64) -> Option<u64> {
+        self.chats
+            .get(&chat_id)
+            .and_then(|settings| settings.sticker_set_owner)
+    }
+
+    /// Режим субтитров чата и предпочитаемый язык дорожки.
+    pub fn subtitles_for_chat(&self, chat_id: i64) -> (SubtitleMode, Option<&str>) {
+        self.chats
+            .get(&chat_id)
+            .map_or((SubtitleMode::Drop, None), |settings| {
+                (settings.subtitles, settings.subtitle_language.as_deref())
+            })
+    }
+
+    /// Предпочитаемые языки звука чата; пусто, если чат их не задал.
+    pub fn audio_languages_for_chat(&self, chat_id: i64) -> &[String] {
+        self.chats
+            .get(&chat_id)
+            .map_or(&[], |settings| settings.audio_languages.as_slice())
+    }
+
+    pub fn profile_names(&self) -> Vec<&str> {
+        let mut names: Vec<&str> = self.profiles.keys().map(String::as_str).collect();
+        names.sort_unstable();
+        names
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_bitrate, Config, SubtitleMode};
+    use crate::converter::OutputTarget;
+
+    #[test]
+    fn defaults_to_builtin_profiles() {
+        let config = Config::parse("").unwrap();
+
+        assert_eq!(
+            config.profile_names(),
+            vec!["archive", "balanced", "fast", "small"]
+        );
+        let (name, profile) = config.profile_for_chat(42);
+        assert_eq!(name, "balanced");
+        assert_eq!(profile.crf, 23);
+        assert_eq!(config.animation_max_duration_secs(), 30.0);
+        assert_eq!(config.voice_max_duration_secs(), 120.0);
+        assert_eq!(config.max_frame_rate(), 60.0);
+        assert_eq!(config.output_target_for_chat(42), OutputTarget::Mp4H264);
+        assert_eq!(config.output_targets(), [OutputTarget::Mp4H264]);
+        assert_eq!(profile.preset, "veryfast");
+    }
+
+    #[test]
+    fn resolves_chat_profile_and_overrides() {
+        let config = Config::parse(
+            r#"
+            default_profile = "fast"
+
+            [profiles.small]
+            video_codec = "libx265"
+            preset = "medium"
+            crf = 30
+            pixel_format = "yuv420p10le"
+            audio_codec = "aac"
+            audio_bitrate = "64k"
+
+            [chats."-1001234567890"]
+            profile = "small"
+            "#,
+        )
+        .unwrap();
+
+        let (name, profile) = config.profile_for_chat(-1001234567890);
+        assert_eq!(name, "small");
+        assert_eq!(profile.crf, 30);
+        assert_eq!(profile.pixel_format, "yuv420p10le");
+        assert_eq!(config.profile_for_chat(1).0, "fast");
+        assert!(!config.video_note_for_chat(-1001234567890));
+        assert_eq!(
+            config.output_target_for_chat(-1001234567890),
+            OutputTarget::Mp4Hevc
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_configs() {
+        let cases = [
+            r#"default_profile = "missing""#,
+            r#"[chats."-100"]
+               profile = "missing""#,
+            r#"[chats.general]
+               profile = "fast""#,
+            r#"[profiles.bad]
+               video_codec = "libx264"
+               preset = "warp"
+               crf = 23
+               pixel_format = "yuv420p"
+               audio_codec = "aac"
+               audio_bitrate = "128k""#,
+            r#"[profiles.bad]
+               video_codec = "libx264"
+               preset = "fast"
+               crf = 60
+               pixel_format = "yuv420p"
+               audio_codec = "aac"
+               audio_bitrate = "128k""#,
+            r#"[profiles.bad]
+               video_codec = "libx264"
+               preset = "fast"
+               crf = 23
+               pixel_format = "yuv420p"
+               audio_codec = "aac"
+               audio_bitrate = "loud""#,
+            r#"[profiles.bad]
+               video_codec = "libx264"
+               preset = "fast"
+               crf = 23
+               pixel_format = "yuv420p"
+               audio_codec = "aac"
+               audio_bitrate = "128k"
+               loudness_target = 3.0"#,
+            r#"[profiles.bad]
+               video_codec = "libx264"
+               preset = "fast"
+               crf = 23
+               pixel_format = "yuv444p"
+               audio_codec = "aac"
+               audio_bitrate = "128k""#,
+            r#"[profiles.bad]
+               video_codec = "libx264"
+               preset = "fast"
+               crf = 23
+               pixel_format = "yuv420p10le"
+               audio_codec = "aac"
+               audio_bitrate = "128k""#,
+            r#"unknown_key = 1"#,
+            r#"animation_max_duration_secs = -5.0"#,
+            r#"voice_max_duration_secs = nan"#,
+            r#"max_frame_rate = 0.0"#,
+            r#"[chats."-100"]
+               subtitles = "karaoke""#,
+            r#"[chats."-100"]
+               output_target = "mkv-h264""#,
+            r#"[chats."-100"]
+               keep_10bit = true"#,
+            r#"[chats."-100"]
+               keep_10bit = true
+               output_target = "mp4-h264""#,
+        ];
+
+        for case in cases {
+            assert!(Config::parse(case).is_err(), "accepted: {}", case);
+        }
+    }
+
+    #[test]
+    fn accepts_example_config() {
+        let config = Config::parse(include_str!("../config.example.toml")).unwrap();
+
+        assert_eq!(config.profile_for_chat(-1001234567890).0, "small");
+        assert!(config.profile_names().contains(&"tiny"));
+        assert_eq!(
+            config.profile_for_chat(-1009876543210).1.loudness_target,
+            Some(-16.0)
+        );
+        assert_eq!(
+            config.profile_for_chat(-1001234567890).1.loudness_target,
+            None
+        );
+        assert!(config.video_note_for_chat(-1009876543210));
+        assert!(!config.video_note_for_chat(-1001234567890));
+        assert!(config.keep_10bit_for_chat(-1001234567890));
+        assert!(!config.keep_10bit_for_chat(-1009876543210));
+        assert_eq!(
+            config.sticker_set_owner_for_chat(-1001234567890),
+            Some(123456789)
+        );
+        assert_eq!(config.sticker_set_owner_for_chat(-1009876543210), None);
+        assert_eq!(
+            config.subtitles_for_chat(-1001234567890),
+            (SubtitleMode::Burn, Some("eng"))
+        );
+        assert_eq!(
+            config.subtitles_for_chat(-1009876543210),
+            (SubtitleMode::Drop, None)
+        );
+        assert_eq!(
+            config.audio_languages_for_chat(-1001234567890),
+            ["jpn", "eng"]
+        );
+        assert!(config.audio_languages_for_chat(-1009876543210).is_empty());
+        assert_eq!(
+            config.output_target_for_chat(-1001234567890),
+            OutputTarget::WebmVp9
+        );
+        assert_eq!(
+            config.output_target_for_chat(-1009876543210),
+            OutputTarget::Mp4H264
+        );
+        assert_eq!(
+            config.output_targets(),
+            [OutputTarget::Mp4H264, OutputTarget::WebmVp9]
+        );
+    }
+
+    #[test]
+    fn parses_bitrates() {
+        assert_eq!(parse_bitrate("192k"), Some(192_000));
+        assert_eq!(parse_bitrate("1M"), Some(1_000_000));
+        assert_eq!(parse_bitrate("128000"), Some(128_000));
+        assert_eq!(parse_bitrate("0k"), None);
+        assert_eq!(parse_bitrate("k"), None);
+        assert_eq!(parse_bitrate(""), None);
+    }
+}
@@ -1,77 +1,2947 @@
-use anyhow::{anyhow, Context, Result as AnyResult};
+use anyhow::{anyhow, Context};
+use serde::Deserialize;
+use std::collections::HashSet;
+use std::fmt;
 use std::path::{Path, PathBuf};
-use std::process::Command;
+use std::process::Stdio;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::process::Command;
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+use tokio_util::sync::CancellationToken;
 
-fn build_output_path(file_path: &str) -> String {
+use crate::config::{parse_bitrate, EncodingProfile, X264_PRESETS};
+use crate::lottie::{Animation, UnsupportedFeatures};
+use crate::loudness::{measure_filter, LoudnessCorrection, LoudnessMeasurement};
+use crate::media::{probe_media, MediaInfo};
+use crate::progress::{ProgressParser, ProgressStage, ProgressUpdate};
+
+/// Аудиокодеки, которые можно без перекодирования положить в MP4.
+pub const MP4_COMPATIBLE_AUDIO_CODECS: &[&str] = &["aac", "mp3"];
+/// Форматы пикселей, которые мы отдаём как есть при ремуксе.
+const REMUX_PIXEL_FORMATS: &[&str] = &["yuv420p", "yuvj420p"];
+/// Битрейт звука при кодировании под лимит размера.
+const BUDGET_AUDIO_BITRATE: u64 = 128_000;
+/// Ниже этого битрейта видео превращается в кашу, такие попытки не делаем.
+const MIN_BUDGET_VIDEO_BITRATE: u64 = 100_000;
+/// Высоты кадра, до которых уменьшаем видео, если оно всё ещё не влезает.
+const BUDGET_FALLBACK_HEIGHTS: &[u32] = &[720, 480, 360];
+/// Сторона квадратного кадра кружка в пикселях.
+pub const VIDEO_NOTE_SIZE: u32 = 384;
+/// Предельная длительность кружка в Telegram, секунды.
+pub const VIDEO_NOTE_MAX_SECS: u32 = 60;
+/// Битрейт Opus для голосовых сообщений: речи хватает с запасом.
+const VOICE_BITRATE: &str = "64k";
+/// Большая сторона фото: `send_photo` не принимает слишком крупные кадры.
+const MAX_PHOTO_SIDE: u32 = 4096;
+/// Большая сторона стикера Telegram.
+const STICKER_SIDE: u32 = 512;
+/// Ограничения Telegram для видеостикеров.
+const STICKER_MAX_SECS: u32 = 3;
+pub const STICKER_MAX_FPS: f64 = 30.0;
+const STICKER_MAX_BYTES: u64 = 256 * 1024;
+/// CRF VP9 для попыток уложиться в лимит размера, от лучшего качества к худшему.
+const STICKER_CRF_LADDER: &[u32] = &[32, 40, 48, 56, 63];
+/// Задержки кадров GIF кратны 10 мс, чаще 30 кадров в секунду не бывает смысла.
+const GIF_MAX_FPS: f64 = 30.0;
+/// Сколько отрисованных кадров анимации ждут записи в ffmpeg.
+const FRAME_QUEUE_LEN: usize = 8;
+/// Делает стороны кадра чётными: libx264 не кодирует yuv420p с нечётными.
+const EVEN_FRAME_FILTER: &str = "scale=trunc(iw/2)*2:trunc(ih/2)*2";
+/// HDR (PQ, HLG) в BT.709 SDR: через линейный свет и тонмаппинг Hable.
+const TONEMAP_FILTER: &str = "zscale=t=linear:npl=100,format=gbrpf32le,zscale=p=bt709,\
+tonemap=tonemap=hable:desat=0,zscale=t=bt709:m=bt709:r=tv,format=yuv420p";
+/// Фильтры из `TONEMAP_FILTER`, которых нет в сборках ffmpeg без libzimg.
+pub const TONEMAP_FILTERS: [&str; 2] = ["zscale", "tonemap"];
+/// Формат пикселей для 10-битного результата.
+const TEN_BIT_PIXEL_FORMAT: &str = "yuv420p10le";
+/// Предельная сторона превью видео в Telegram.
+const THUMBNAIL_SIDE: u32 = 320;
+/// С какой доли длительности искать кадр для превью: начало часто тёмное.
+const THUMBNAIL_SEEK_FRACTION: f64 = 0.1;
+
+/// Параметры конвертации, общие для всех файлов.
+#[derive(Debug, Clone)]
+pub struct ConvertOptions {
+    /// Максимальный размер результата в байтах (лимит загрузки Bot API).
+    pub max_output_bytes: u64,
+    /// Предельное время всей конвертации, включая повторные попытки.
+    pub timeout: Duration,
+}
+
+/// Ошибка конвертации. Таймаут и отмену обработчик показывает пользователю
+/// отдельно от обычных сбоев ffmpeg.
+#[derive(Debug)]
+pub enum ConversionError {
+    /// Конвертация не уложилась в `ConvertOptions::timeout`.
+    TimedOut(Duration),
+    /// Конвертацию отменили через `CancellationToken`.
+    Cancelled,
+    Failed(anyhow::Error),
+}
+
+impl fmt::Display for ConversionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConversionError::TimedOut(timeout) => {
+                write!(f, "conversion timed out after {}s", timeout.as_secs())
+            }
+            ConversionError::Cancelled => write!(f, "conversion cancelled"),
+            ConversionError::Failed(error) => write!(f, "{:#}", error),
+        }
+    }
+}
+
+impl std::error::Error for ConversionError {}
+
+impl From<anyhow::Error> for ConversionError {
+    fn from(error: anyhow::Error) -> Self {
+        ConversionError::Failed(error)
+    }
+}
+
+/// Во что превращается результат в чате.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputKind {
+    /// Обычное видео со звуком (`send_video`).
+    Video,
+    /// Беззвучный зацикленный ролик (`send_animation`).
+    Animation,
+    /// Квадратный кружок (`send_video_note`).
+    VideoNote,
+}
+
+impl OutputKind {
+    fn keeps_audio(self) -> bool {
+        matches!(self, OutputKind::Video | OutputKind::VideoNote)
+    }
+
+    /// Кружок требует обрезки кадра, поэтому ремукс для него невозможен.
+    fn allows_remux(self) -> bool {
+        !matches!(self, OutputKind::VideoNote)
+    }
+
+    fn max_duration_secs(self) -> Option<u32> {
+        match self {
+            OutputKind::VideoNote => Some(VIDEO_NOTE_MAX_SECS),
+            OutputKind::Video | OutputKind::Animation => None,
+        }
+    }
+}
+
+/// Как субтитры попадают в результат. Номера дорожек — `N` в `0:s:N`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum SubtitlePlan {
+    #[default]
+    None,
+    /// Текстовая дорожка вжигается фильтром `subtitles`.
+    BurnText(usize),
+    /// Дорожка-картинка (PGS, DVD) накладывается фильтром `overlay`.
+    BurnBitmap(usize),
+    /// Текстовые дорожки перекладываются в MP4 как `mov_text`.
+    Embed(Vec<usize>),
+}
+
+impl SubtitlePlan {
+    fn requires_transcode(&self) -> bool {
+        matches!(
+            self,
+            SubtitlePlan::BurnText(_) | SubtitlePlan::BurnBitmap(_)
+        )
+    }
+}
+
+/// Что делать с HDR-видео (PQ или HLG).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum HdrPlan {
+    /// Исходник SDR, ничего особенного не нужно.
+    #[default]
+    Sdr,
+    /// Тонмаппинг в BT.709 SDR: без него HDR в yuv420p выглядит серым.
+    ToneMap,
+    /// Оставить HDR в 10 битах для клиентов, которые умеют его показывать.
+    Keep10Bit,
+}
+
+impl HdrPlan {
+    fn filter(self) -> Option<&'static str> {
+        match self {
+            HdrPlan::ToneMap => Some(TONEMAP_FILTER),
+            HdrPlan::Sdr | HdrPlan::Keep10Bit => None,
+        }
+    }
+}
+
+/// Контейнер и кодеки результата конвертации видео.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum OutputTarget {
+    /// MP4 с H.264 и AAC: проигрывается везде.
+    #[default]
+    Mp4H264,
+    /// MP4 с HEVC и AAC.
+    Mp4Hevc,
+    /// WebM с VP9 и Opus.
+    WebmVp9,
+    /// MP4 с AV1 (SVT-AV1) и AAC: меньше всего, но кодируется дольше всего.
+    Mp4Av1,
+}
+
+impl OutputTarget {
+    pub const ALL: [OutputTarget; 4] = [
+        OutputTarget::Mp4H264,
+        OutputTarget::Mp4Hevc,
+        OutputTarget::WebmVp9,
+        OutputTarget::Mp4Av1,
+    ];
+
+    /// Цель по `video_codec` профиля, если чат не выбрал свою.
+    pub fn for_codec(video_codec: &str) -> Self {
+        match video_codec {
+            "libx265" => OutputTarget::Mp4Hevc,
+            _ => OutputTarget::Mp4H264,
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            OutputTarget::WebmVp9 => "webm",
+            OutputTarget::Mp4H264 | OutputTarget::Mp4Hevc | OutputTarget::Mp4Av1 => "mp4",
+        }
+    }
+
+    /// `send_video` проигрывает только MP4; WebM Telegram показывает файлом.
+    pub fn is_mp4(self) -> bool {
+        self.extension() == "mp4"
+    }
+
+    /// Кодеры ffmpeg, без которых цель недоступна: видео и звук.
+    pub fn encoders(self) -> [&'static str; 2] {
+        match self {
+            OutputTarget::Mp4H264 => ["libx264", "aac"],
+            OutputTarget::Mp4Hevc => ["libx265", "aac"],
+            OutputTarget::WebmVp9 => ["libvpx-vp9", "libopus"],
+            OutputTarget::Mp4Av1 => ["libsvtav1", "aac"],
+        }
+    }
+
+    /// Кодеры цели, которых нет в сборке ffmpeg.
+    pub fn missing_encoders(self, available: &HashSet<String>) -> Vec<&'static str> {
+        self.encoders()
+            .into_iter()
+            .filter(|encoder| !available.contains(*encoder))
+            .collect()
+    }
+
+    /// 10-битное видео клиенты Telegram показывают только в HEVC с тегом
+    /// `hvc1`, AV1 и VP9; H.264 High 10 они не играют.
+    pub fn supports_10bit(self) -> bool {
+        !matches!(self, OutputTarget::Mp4H264)
+    }
+
+    /// Ремукс перекладывает H.264 из исходника, поэтому он возможен только
+    /// для цели H.264; остальные цели выбирают ради размера, и там видео
+    /// всегда перекодируется.
+    pub fn allows_remux(self) -> bool {
+        self == OutputTarget::Mp4H264
+    }
+
+    fn audio_codec(self, profile: &EncodingProfile) -> &str {
+        match self {
+            OutputTarget::WebmVp9 => "libopus",
+            OutputTarget::Mp4H264 | OutputTarget::Mp4Hevc | OutputTarget::Mp4Av1 => {
+                &profile.audio_codec
+            }
+        }
+    }
+
+    fn subtitle_codec(self) -> &'static str {
+        match self {
+            OutputTarget::WebmVp9 => "webvtt",
+            OutputTarget::Mp4H264 | OutputTarget::Mp4Hevc | OutputTarget::Mp4Av1 => "mov_text",
+        }
+    }
+}
+
+/// Как выровнять частоту кадров результата.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct FrameRatePlan {
+    /// Частота кадров результата, если её задаёт фильтр `fps`.
+    pub target_fps: Option<f64>,
+    /// Исходник быстрее `max_frame_rate`, частота снижена.
+    pub capped: bool,
+    /// Исходник с переменной частотой приведён к постоянной.
+    pub constant: bool,
+}
+
+/// Фрагмент исходника, который нужно оставить.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TrimRange {
+    pub start_secs: f64,
+    /// `None` — до конца файла.
+    pub end_secs: Option<f64>,
+}
+
+impl TrimRange {
+    pub fn duration_secs(&self) -> Option<f64> {
+        self.end_secs.map(|end_secs| end_secs - self.start_secs)
+    }
+}
+
+/// Параметры одной конвертации, зависящие от чата.
+#[derive(Debug, Clone)]
+pub struct JobSettings {
+    pub profile: EncodingProfile,
+    pub output: OutputKind,
+    pub target: OutputTarget,
+    pub subtitles: SubtitlePlan,
+    /// Единственная звуковая дорожка результата: `N` из `0:a:N`.
+    pub audio_track: usize,
+    /// Обрезка из подписи; точная, поэтому исключает ремукс.
+    pub trim: Option<TrimRange>,
+    /// Поворот исходника по часовой стрелке, `MediaInfo::video_rotation`.
+    pub rotation: u16,
+    pub hdr: HdrPlan,
+    pub frame_rate: FrameRatePlan,
+    /// Выравнивание громкости; заполняет конвертер после замера.
+    pub loudness: Option<LoudnessCorrection>,
+}
+
+impl JobSettings {
+    /// Длительность результата: остаток исходника после начала обрезки,
+    /// ограниченный длиной фрагмента и пределом формата. Без
+    /// `source_secs` — только ограничения, если они есть.
+    pub fn output_duration_secs(&self, source_secs: Option<f64>) -> Option<f64> {
+        let start_secs = self.trim.map_or(0.0, |trim| trim.start_secs);
+        [
+            source_secs.map(|source_secs| (source_secs - start_secs).max(0.0)),
+            self.trim.and_then(|trim| trim.duration_secs()),
+            self.output.max_duration_secs().map(f64::from),
+        ]
+        .into_iter()
+        .flatten()
+        .reduce(f64::min)
+    }
+
+    /// Формат пикселей результата: из профиля или 10-битный для HDR.
+    fn pixel_format(&self) -> &str {
+        match self.hdr {
+            HdrPlan::Keep10Bit => TEN_BIT_PIXEL_FORMAT,
+            HdrPlan::Sdr | HdrPlan::ToneMap => &self.profile.pixel_format,
+        }
+    }
+
+    /// Нужны ли результату фильтры, которых нет в исходнике: смена частоты
+    /// кадров, тонмаппинг HDR, вжигание субтитров или выравнивание громкости
+    /// (если в файле есть звук). С ними исходник нельзя оставить как есть.
+    pub fn requires_filters(&self, has_audio: bool) -> bool {
+        self.frame_rate.target_fps.is_some()
+            || self.hdr == HdrPlan::ToneMap
+            || self.subtitles.requires_transcode()
+            || (has_audio && self.output.keeps_audio() && self.profile.loudness_target.is_some())
+    }
+
+    fn allows_remux(&self) -> bool {
+        self.output.allows_remux()
+            && self.target.allows_remux()
+            && !self.subtitles.requires_transcode()
+            && self.trim.is_none()
+            && self.frame_rate.target_fps.is_none()
+    }
+}
+
+/// Готовое видео и то, что Telegram показывает до его загрузки.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ConvertedMedia {
+    pub path: String,
+    /// JPEG-превью не больше 320×320.
+    pub thumbnail: Option<String>,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub duration_secs: Option<f64>,
+    /// Что сделано с частотой кадров, для логов.
+    pub frame_rate: FrameRatePlan,
+}
+
+/// Формат звуковой дорожки, извлечённой из видео.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AudioFormat {
+    /// MP3 для `send_audio`.
+    Mp3,
+    /// OGG/Opus для `send_voice`.
+    Voice,
+}
+
+impl AudioFormat {
+    fn extension(self) -> &'static str {
+        match self {
+            AudioFormat::Mp3 => "mp3",
+            AudioFormat::Voice => "ogg",
+        }
+    }
+}
+
+/// Формат картинки для `send_photo`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageFormat {
+    Jpeg,
+    /// Для картинок с прозрачностью.
+    Png,
+}
+
+impl ImageFormat {
+    fn extension(self) -> &'static str {
+        match self {
+            ImageFormat::Jpeg => "jpg",
+            ImageFormat::Png => "png",
+        }
+    }
+}
+
+/// Вид стикера Telegram.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StickerSource {
+    /// WebP.
+    Static,
+    /// WebM с VP9 и альфа-каналом.
+    Video,
+    /// TGS: Lottie, сжатый gzip.
+    Animated,
+}
+
+/// Формат ролика, в который превращается подвижный стикер.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClipFormat {
+    Mp4,
+    Gif,
+}
+
+impl ClipFormat {
+    fn extension(self) -> &'static str {
+        match self {
+            ClipFormat::Mp4 => "mp4",
+            ClipFormat::Gif => "gif",
+        }
+    }
+}
+
+/// Параметры `/unsticker`.
+#[derive(Debug, Clone)]
+pub struct UnstickerSettings {
+    pub profile: EncodingProfile,
+    pub format: ClipFormat,
+    /// Цвет, которым заливается прозрачный фон подвижных стикеров.
+    pub background: [u8; 3],
+}
+
+/// Способ получения результата из входного файла.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConversionPlan {
+    /// Потоки уже совместимы, достаточно переупаковать их в MP4 (`-c copy`).
+    Remux,
+    /// Полное перекодирование в кодеки `OutputTarget`.
+    Transcode,
+}
+
+/// Выбирает ремукс, если видео уже H.264 в yuv420p без поворота, а
+/// выбранная звуковая дорожка совместима с MP4.
+pub fn plan_conversion(info: &MediaInfo, audio_track: usize) -> ConversionPlan {
+    let video_compatible = info.video_codec() == Some("h264")
+        && info.video_rotation() == 0
+        && info
+            .pixel_format()
+            .map(|pix_fmt| REMUX_PIXEL_FORMATS.contains(&pix_fmt))
+            .unwrap_or(false);
+    let audio_compatible = info.audio_streams().nth(audio_track).is_none_or(|stream| {
+        stream
+            .codec
+            .as_deref()
+            .map(|codec| MP4_COMPATIBLE_AUDIO_CODECS.contains(&codec))
+            .unwrap_or(false)
+    });
+
+    if video_compatible && audio_compatible {
+        ConversionPlan::Remux
+    } else {
+        ConversionPlan::Transcode
+    }
+}
+
+fn build_output_path(file_path: &str, extension: &str) -> String {
     let path = Path::new(file_path);
     let mut output_path = PathBuf::from(path);
-    output_path.set_extension("mp4");
+    // Не перезаписываем исходник, если у него уже нужное расширение.
+    if path.extension().and_then(|ext| ext.to_str()) == Some(extension) {
+        output_path.set_extension(format!("converted.{}", extension));
+    } else {
+        output_path.set_extension(extension);
+    }
     output_path.to_string_lossy().to_string()
 }
 
-/// Конвертирует любой поддерживаемый FFmpeg видеофайл в формат `.mp4`.
-pub fn convert_video_to_mp4(file_path: &str) -> AnyResult<String> {
-    let output_path = build_output_path(file_path);
-    let output = Command::new("ffmpeg")
-        .args([
-            "-hide_banner",
-            "-nostdin",
-            "-y",
-            "-i",
+/// Аргументы видеокодера цели, без параметров ratecontrol. Пресет
+/// профиля задан в терминах x264; для VP9 и SVT-AV1 он переводится в их
+/// шкалы скорости: чем медленнее пресет, тем медленнее и плотнее кодер.
+fn video_encoder_args(
+    target: OutputTarget,
+    profile: &EncodingProfile,
+    pixel_format: &str,
+) -> Vec<String> {
+    let slowness = X264_PRESETS
+        .iter()
+        .position(|preset| *preset == profile.preset)
+        .unwrap_or(2) as u8;
+    let mut args = vec!["-c:v".to_string(), target.encoders()[0].to_string()];
+    match target {
+        OutputTarget::Mp4H264 | OutputTarget::Mp4Hevc => {
+            args.extend(["-preset".to_string(), profile.preset.clone()]);
+        }
+        OutputTarget::WebmVp9 => args.extend([
+            "-deadline".to_string(),
+            "good".to_string(),
+            "-cpu-used".to_string(),
+            8u8.saturating_sub(slowness).min(5).to_string(),
+            "-row-mt".to_string(),
+            "1".to_string(),
+        ]),
+        OutputTarget::Mp4Av1 => {
+            args.extend(["-preset".to_string(), (12 - slowness).to_string()]);
+        }
+    }
+    args.extend(["-pix_fmt".to_string(), pixel_format.to_string()]);
+    // Без тега hvc1 HEVC в MP4 не проигрывается на устройствах Apple.
+    if target == OutputTarget::Mp4Hevc {
+        args.extend(["-tag:v".to_string(), "hvc1".to_string()]);
+    }
+    args
+}
+
+/// Постоянное качество по CRF профиля. У VP9 и SVT-AV1 шкала 0–63, и
+/// тому же качеству там соответствует больший CRF.
+fn quality_args(target: OutputTarget, crf: u8) -> Vec<String> {
+    match target {
+        OutputTarget::Mp4H264 | OutputTarget::Mp4Hevc => vec!["-crf".to_string(), crf.to_string()],
+        // Без `-b:v 0` libvpx считает CRF потолком качества при битрейте по умолчанию.
+        OutputTarget::WebmVp9 => vec![
+            "-crf".to_string(),
+            (crf + 8).min(63).to_string(),
+            "-b:v".to_string(),
+            "0".to_string(),
+        ],
+        OutputTarget::Mp4Av1 => vec!["-crf".to_string(), (crf + 10).min(63).to_string()],
+    }
+}
+
+/// Опции контейнера: MP4 переносит индекс в начало, чтобы видео
+/// начинало играть до конца загрузки.
+fn container_args(target: OutputTarget) -> Vec<String> {
+    if target.is_mp4() {
+        vec!["-movflags".to_string(), "+faststart".to_string()]
+    } else {
+        Vec::new()
+    }
+}
+
+/// Экранирует значение опции фильтра для `-vf`: сначала для разбора
+/// опций, потом для разбора графа фильтров.
+fn escape_filter_value(value: &str) -> String {
+    let escape = |value: &str, special: &[char]| {
+        let mut escaped = String::with_capacity(value.len());
+        for ch in value.chars() {
+            if special.contains(&ch) {
+                escaped.push('\\');
+            }
+            escaped.push(ch);
+        }
+        escaped
+    };
+    escape(
+        &escape(value, &['\\', '\'', ':']),
+        &['\\', '\'', '[', ']', ',', ';'],
+    )
+}
+
+/// Фильтр, поворачивающий кадр на `rotation` градусов по часовой стрелке.
+fn rotation_filter(rotation: u16) -> Option<&'static str> {
+    match rotation {
+        90 => Some("transpose=clock"),
+        180 => Some("hflip,vflip"),
+        270 => Some("transpose=cclock"),
+        _ => None,
+    }
+}
+
+/// Выбор видеопотока и фильтры: поворот, субтитры, кадрирование кружка,
+/// уменьшение высоты под бюджет и чётные стороны кадра. Субтитры-картинки
+/// накладываются через `-filter_complex`, поэтому поток берётся с выхода
+/// графа.
+fn video_stream_args(
+    settings: &JobSettings,
+    input_path: &str,
+    max_height: Option<u32>,
+) -> Vec<String> {
+    // Порядок фильтров: fps → тонмаппинг → поворот → субтитры → кроп и
+    // масштаб. Частоту снижаем раньше всего, чтобы не обрабатывать лишние
+    // кадры; тонмаппинг и поворот идут до субтитров, иначе те выйдут
+    // тусклыми или лягут боком. Субтитрам-картинкам эти фильтры нужны до
+    // наложения, в отдельной ветке графа ниже.
+    let frame_filters: Vec<String> = [
+        settings
+            .frame_rate
+            .target_fps
+            .map(|fps| format!("fps={:.3}", fps)),
+        settings.hdr.filter().map(str::to_string),
+        rotation_filter(settings.rotation).map(str::to_string),
+    ]
+    .into_iter()
+    .flatten()
+    .collect();
+    let mut filters: Vec<String> = Vec::new();
+    if !matches!(settings.subtitles, SubtitlePlan::BurnBitmap(_)) {
+        filters.extend(frame_filters.iter().cloned());
+    }
+    // Субтитры вжигаются до масштабирования, в исходном разрешении.
+    if let SubtitlePlan::BurnText(track) = settings.subtitles {
+        // Фильтр читает субтитры из файла сам и не знает про `-ss`: на время
+        // наложения возвращаем кадрам исходные метки.
+        let start_secs = settings.trim.map_or(0.0, |trim| trim.start_secs);
+        if start_secs > 0.0 {
+            filters.push(format!("setpts=PTS+{:.3}/TB", start_secs));
+        }
+        filters.push(format!(
+            "subtitles=filename={}:si={}",
+            escape_filter_value(input_path),
+            track
+        ));
+        if start_secs > 0.0 {
+            filters.push("setpts=PTS-STARTPTS".to_string());
+        }
+    }
+    if settings.output == OutputKind::VideoNote {
+        // Центральный квадрат: crop по умолчанию берёт середину кадра.
+        filters.push(format!(
+            "crop='min(iw,ih)':'min(iw,ih)',scale={size}:{size},setsar=1",
+            size = VIDEO_NOTE_SIZE
+        ));
+    }
+    if let Some(height) = max_height {
+        filters.push(format!("scale=-2:{}", height));
+    } else if settings.output != OutputKind::VideoNote {
+        filters.push(EVEN_FRAME_FILTER.to_string());
+    }
+
+    if let SubtitlePlan::BurnBitmap(track) = settings.subtitles {
+        let mut graph = if frame_filters.is_empty() {
+            format!("[0:v:0][0:s:{}]overlay", track)
+        } else {
+            format!(
+                "[0:v:0]{}[base];[base][0:s:{}]overlay",
+                frame_filters.join(","),
+                track
+            )
+        };
+        for filter in &filters {
+            graph.push(',');
+            graph.push_str(filter);
+        }
+        graph.push_str("[v]");
+        return vec![
+            "-filter_complex".to_string(),
+            graph,
+            "-map".to_string(),
+            "[v]".to_string(),
+        ];
+    }
+    vec![
+        "-map".to_string(),
+        "0:v:0".to_string(),
+        "-vf".to_string(),
+        filters.join(","),
+    ]
+}
+
+/// Текстовые дорожки для режима `Embed`: выбор потоков и текстовый кодек
+/// контейнера, `mov_text` или `webvtt`.
+fn subtitle_track_args(settings: &JobSettings) -> Vec<String> {
+    let SubtitlePlan::Embed(tracks) = &settings.subtitles else {
+        return Vec::new();
+    };
+    if tracks.is_empty() {
+        return Vec::new();
+    }
+    let mut args = Vec::new();
+    for track in tracks {
+        args.extend(["-map".to_string(), format!("0:s:{}", track)]);
+    }
+    args.extend([
+        "-c:s".to_string(),
+        settings.target.subtitle_codec().to_string(),
+    ]);
+    args
+}
+
+/// Начало ffmpeg-команды с опциями входа. Начало обрезки задаётся до
+/// `-i`: при перекодировании такой поиск точный и не декодирует
+/// пропущенное.
+fn input_args(settings: &JobSettings, input_path: &str) -> Vec<String> {
+    let mut args: Vec<String> = ["-hide_banner", "-nostdin", "-y"]
+        .into_iter()
+        .map(str::to_string)
+        .collect();
+    if let Some(trim) = settings.trim.filter(|trim| trim.start_secs > 0.0) {
+        args.extend(["-ss".to_string(), format!("{:.3}", trim.start_secs)]);
+    }
+    // Поворот делает наш фильтр, поэтому матрицу отображения (и тег
+    // `rotate`) обнуляем: иначе плеер повернёт кадр второй раз.
+    if rotation_filter(settings.rotation).is_some() {
+        args.extend(["-display_rotation:v:0".to_string(), "0".to_string()]);
+    }
+    args.extend(["-i".to_string(), input_path.to_string()]);
+    args
+}
+
+/// Длительность результата: конец обрезки и предел формата Telegram.
+fn duration_limit_args(settings: &JobSettings) -> Vec<String> {
+    match settings.output_duration_secs(None) {
+        Some(secs) => vec!["-t".to_string(), format!("{:.3}", secs)],
+        None => Vec::new(),
+    }
+}
+
+/// Аргументы звука: кодек из профиля либо `-an` для беззвучных результатов.
+fn audio_encoder_args(settings: &JobSettings, audio_bitrate: String) -> Vec<String> {
+    if !settings.output.keeps_audio() {
+        return vec!["-an".to_string()];
+    }
+    let mut args = vec![
+        "-c:a".to_string(),
+        settings.target.audio_codec(&settings.profile).to_string(),
+        "-b:a".to_string(),
+        audio_bitrate,
+    ];
+    let mut filters = Vec::new();
+    // При переменной частоте кадров звук подтягивается к меткам времени,
+    // иначе после выравнивания видео он уезжает.
+    if settings.frame_rate.constant {
+        filters.push("aresample=async=1".to_string());
+    }
+    filters.extend(settings.loudness.map(|loudness| loudness.filter()));
+    if !filters.is_empty() {
+        args.extend(["-af".to_string(), filters.join(",")]);
+    }
+    args
+}
+
+/// Первый проход `loudnorm`: только замер выбранной дорожки с учётом обрезки.
+fn build_loudness_measure_args(
+    settings: &JobSettings,
+    target: f64,
+    input_path: &str,
+) -> Vec<String> {
+    let mut args = input_args(settings, input_path);
+    args.extend([
+        "-map".to_string(),
+        format!("0:a:{}", settings.audio_track),
+        "-af".to_string(),
+        measure_filter(target),
+    ]);
+    args.extend(duration_limit_args(settings));
+    args.extend(["-f", "null", "-"].map(str::to_string));
+    args
+}
+
+/// Замер громкости первой звуковой дорожки перед её извлечением.
+fn build_audio_loudness_measure_args(target: f64, input_path: &str) -> Vec<String> {
+    let mut args: Vec<String> = ["-hide_banner", "-nostdin", "-y", "-i", input_path]
+        .into_iter()
+        .map(str::to_string)
+        .collect();
+    args.extend(["-map".to_string(), "0:a:0".to_string()]);
+    args.extend(["-af".to_string(), measure_filter(target)]);
+    args.extend(["-f", "null", "-"].map(str::to_string));
+    args
+}
+
+/// Первая звуковая дорожка без видео: MP3 VBR или моно Opus для голосовых,
+/// с выравниванием громкости, если оно нужно.
+fn build_audio_extract_args(
+    format: AudioFormat,
+    loudness: Option<&LoudnessCorrection>,
+    input_path: &str,
+    output_path: &str,
+) -> Vec<String> {
+    let mut args: Vec<String> = ["-hide_banner", "-nostdin", "-y", "-i", input_path]
+        .into_iter()
+        .map(str::to_string)
+        .collect();
+    args.extend(["-map", "0:a:0", "-vn"].map(str::to_string));
+    if let Some(loudness) = loudness {
+        args.extend(["-af".to_string(), loudness.filter()]);
+    }
+    match format {
+        AudioFormat::Mp3 => args.extend(["-c:a", "libmp3lame", "-q:a", "2"].map(str::to_string)),
+        AudioFormat::Voice => args.extend(
+            [
+                "-c:a",
+                "libopus",
+                "-b:a",
+                VOICE_BITRATE,
+                "-ac",
+                "1",
+                "-application",
+                "voip",
+            ]
+            .map(str::to_string),
+        ),
+    }
+    args.push(output_path.to_string());
+    args
+}
+
+/// Фильтр, приводящий кадр с EXIF-ориентацией 2–8 к нормальному виду.
+fn exif_orientation_filter(orientation: u8) -> Option<&'static str> {
+    match orientation {
+        2 => Some("hflip"),
+        3 => Some("hflip,vflip"),
+        4 => Some("vflip"),
+        5 => Some("transpose=0"),
+        6 => Some("transpose=1"),
+        7 => Some("transpose=3"),
+        8 => Some("transpose=2"),
+        _ => None,
+    }
+}
+
+/// Один кадр в JPEG или PNG. Если ориентацию задаёт EXIF, поворачиваем
+/// сами и отключаем автоповорот ffmpeg, чтобы не повернуть дважды.
+fn build_image_args(
+    format: ImageFormat,
+    exif_orientation: Option<u8>,
+    input_path: &str,
+    output_path: &str,
+) -> Vec<String> {
+    let orientation_filter = exif_orientation.and_then(exif_orientation_filter);
+    let mut args: Vec<String> = ["-hide_banner", "-nostdin", "-y"]
+        .into_iter()
+        .map(str::to_string)
+        .collect();
+    if orientation_filter.is_some() {
+        args.push("-noautorotate".to_string());
+    }
+    args.extend(["-i".to_string(), input_path.to_string()]);
+    args.extend(["-map", "0:v:0", "-frames:v", "1"].map(str::to_string));
+
+    let mut filters: Vec<String> = orientation_filter.into_iter().map(str::to_string).collect();
+    filters.push(format!(
+        "scale='min(iw,{side})':'min(ih,{side})':force_original_aspect_ratio=decrease",
+        side = MAX_PHOTO_SIDE
+    ));
+    args.extend(["-vf".to_string(), filters.join(",")]);
+
+    match format {
+        ImageFormat::Jpeg => args.extend(["-q:v", "2", "-pix_fmt", "yuvj420p"].map(str::to_string)),
+        ImageFormat::Png => args.extend(["-pix_fmt", "rgba"].map(str::to_string)),
+    }
+    args.extend([
+        "-update".to_string(),
+        "1".to_string(),
+        output_path.to_string(),
+    ]);
+    args
+}
+
+/// Граф фильтров для ролика из стикера: `input` — начало графа, которое
+/// отдаёт один видеопоток. Для MP4 размеры делаются чётными, для GIF
+/// строится собственная палитра. Выход графа помечен `[out]`.
+fn clip_filter_graph(input: &str, format: ClipFormat, frame_rate: Option<f64>) -> String {
+    match format {
+        ClipFormat::Mp4 => format!("{}scale=trunc(iw/2)*2:trunc(ih/2)*2[out]", input),
+        ClipFormat::Gif => {
+            let fps = match frame_rate {
+                Some(rate) if rate > GIF_MAX_FPS => format!("fps={},", GIF_MAX_FPS),
+                _ => String::new(),
+            };
+            format!(
+                "{}{}split[a][b];[a]palettegen=stats_mode=diff[p];[b][p]paletteuse[out]",
+                input, fps
+            )
+        }
+    }
+}
+
+/// Кодер ролика: видеопараметры профиля для MP4, для GIF хватает расширения.
+fn clip_encoder_args(format: ClipFormat, profile: &EncodingProfile) -> Vec<String> {
+    let mut args = vec!["-map".to_string(), "[out]".to_string()];
+    if format == ClipFormat::Mp4 {
+        let target = OutputTarget::for_codec(&profile.video_codec);
+        args.extend(video_encoder_args(target, profile, &profile.pixel_format));
+        args.extend(quality_args(target, profile.crf));
+        args.extend(["-an", "-movflags", "+faststart"].map(str::to_string));
+    }
+    args
+}
+
+/// Видеостикер поверх сплошного фона. Встроенный декодер VP9 в ffmpeg
+/// теряет альфа-канал, поэтому декодер задаём явно.
+fn build_video_sticker_args(
+    settings: &UnstickerSettings,
+    dimensions: (u32, u32),
+    input_path: &str,
+    output_path: &str,
+) -> Vec<String> {
+    let [red, green, blue] = settings.background;
+    let mut args: Vec<String> = [
+        "-hide_banner",
+        "-nostdin",
+        "-y",
+        "-c:v",
+        "libvpx-vp9",
+        "-i",
+        input_path,
+        "-f",
+        "lavfi",
+        "-i",
+    ]
+    .into_iter()
+    .map(str::to_string)
+    .collect();
+    args.push(format!(
+        "color=c=0x{:02x}{:02x}{:02x}:s={}x{}",
+        red, green, blue, dimensions.0, dimensions.1
+    ));
+    args.extend([
+        "-filter_complex".to_string(),
+        clip_filter_graph("[1:v][0:v]overlay=shortest=1,", settings.format, None),
+    ]);
+    args.extend(clip_encoder_args(settings.format, &settings.profile));
+    args.push(output_path.to_string());
+    args
+}
+
+/// Кадры анимированного стикера приходят в stdin сырыми RGBA.
+fn build_animation_args(
+    settings: &UnstickerSettings,
+    dimensions: (u32, u32),
+    frame_rate: f64,
+    output_path: &str,
+) -> Vec<String> {
+    let mut args: Vec<String> = ["-hide_banner", "-y", "-f", "rawvideo", "-pix_fmt", "rgba"]
+        .into_iter()
+        .map(str::to_string)
+        .collect();
+    args.extend([
+        "-s".to_string(),
+        format!("{}x{}", dimensions.0, dimensions.1),
+        "-framerate".to_string(),
+        frame_rate.to_string(),
+        "-i".to_string(),
+        "pipe:0".to_string(),
+        "-filter_complex".to_string(),
+        clip_filter_graph("[0:v]", settings.format, Some(frame_rate)),
+    ]);
+    args.extend(clip_encoder_args(settings.format, &settings.profile));
+    args.push(output_path.to_string());
+    args
+}
+
+/// Видеостикер: VP9 в WebM без звука, большая сторона 512 px, до 3 секунд.
+/// Фильтр `fps` ставится только по `frame_rate`, то есть для исходников
+/// быстрее 30 кадров в секунду или с переменной частотой. Размер
+/// регулируется только через `crf`.
+fn build_sticker_args(
+    crf: u32,
+    frame_rate: &FrameRatePlan,
+    input_path: &str,
+    output_path: &str,
+) -> Vec<String> {
+    let mut args: Vec<String> = ["-hide_banner", "-nostdin", "-y", "-i", input_path]
+        .into_iter()
+        .map(str::to_string)
+        .collect();
+    args.extend([
+        "-map".to_string(),
+        "0:v:0".to_string(),
+        "-t".to_string(),
+        STICKER_MAX_SECS.to_string(),
+        "-vf".to_string(),
+        format!(
+            "scale='if(gte(iw,ih),{side},-2)':'if(gte(iw,ih),-2,{side})',{fps}format=yuv420p",
+            side = STICKER_SIDE,
+            fps = frame_rate
+                .target_fps
+                .map(|fps| format!("fps={:.3},", fps))
+                .unwrap_or_default()
+        ),
+        "-c:v".to_string(),
+        "libvpx-vp9".to_string(),
+        "-b:v".to_string(),
+        "0".to_string(),
+        "-crf".to_string(),
+        crf.to_string(),
+    ]);
+    args.extend(
+        [
+            "-deadline",
+            "good",
+            "-cpu-used",
+            "4",
+            "-row-mt",
+            "1",
+            "-an",
+            "-f",
+            "webm",
+        ]
+        .map(str::to_string),
+    );
+    args.push(output_path.to_string());
+    args
+}
+
+/// Берёт одну звуковую дорожку; `?` оставляет немые файлы без ошибки.
+fn audio_stream_args(settings: &JobSettings) -> Vec<String> {
+    vec!["-map".to_string(), format!("0:a:{}?", settings.audio_track)]
+}
+
+/// Превью из самого характерного кадра (фильтр `thumbnail`) после
+/// `seek_secs`, вписанное в 320×320.
+fn build_thumbnail_args(input_path: &str, seek_secs: f64, output_path: &str) -> Vec<String> {
+    vec![
+        "-hide_banner".to_string(),
+        "-nostdin".to_string(),
+        "-y".to_string(),
+        "-ss".to_string(),
+        format!("{:.3}", seek_secs),
+        "-i".to_string(),
+        input_path.to_string(),
+        "-map".to_string(),
+        "0:v:0".to_string(),
+        "-vf".to_string(),
+        format!(
+            "thumbnail,scale={side}:{side}:force_original_aspect_ratio=decrease",
+            side = THUMBNAIL_SIDE
+        ),
+        "-frames:v".to_string(),
+        "1".to_string(),
+        "-q:v".to_string(),
+        "4".to_string(),
+        output_path.to_string(),
+    ]
+}
+
+fn build_ffmpeg_args(
+    plan: ConversionPlan,
+    settings: &JobSettings,
+    input_path: &str,
+    output_path: &str,
+) -> Vec<String> {
+    let mut args = input_args(settings, input_path);
+    match plan {
+        ConversionPlan::Remux => args.extend(["-map".to_string(), "0:v:0".to_string()]),
+        ConversionPlan::Transcode => args.extend(video_stream_args(settings, input_path, None)),
+    }
+    if settings.output.keeps_audio() {
+        args.extend(audio_stream_args(settings));
+    }
+
+    match plan {
+        ConversionPlan::Remux => {
+            args.extend(["-c".to_string(), "copy".to_string()]);
+            // Выровненный звук перекодируется, видео по-прежнему копируется.
+            if settings.loudness.is_some() && settings.output.keeps_audio() {
+                args.extend(audio_encoder_args(
+                    settings,
+                    settings.profile.audio_bitrate.clone(),
+                ));
+            }
+        }
+        ConversionPlan::Transcode => {
+            args.extend(video_encoder_args(
+                settings.target,
+                &settings.profile,
+                settings.pixel_format(),
+            ));
+            args.extend(quality_args(settings.target, settings.profile.crf));
+            args.extend(audio_encoder_args(
+                settings,
+                settings.profile.audio_bitrate.clone(),
+            ));
+        }
+    }
+    // После `-c copy`: более точный `-c:s` для субтитров перекрывает его.
+    args.extend(subtitle_track_args(settings));
+
+    args.extend(duration_limit_args(settings));
+    args.extend(container_args(settings.target));
+    args.push(output_path.to_string());
+    args
+}
+
+/// Считает битрейт видео, при котором файл длительностью `duration_secs`
+/// уложится в `budget_bytes` с запасом `safety_factor` на контейнер и
+/// погрешность ratecontrol. `None`, если бюджета не хватает даже на минимум.
+fn target_video_bitrate(
+    duration_secs: f64,
+    budget_bytes: u64,
+    audio_bitrate: u64,
+    safety_factor: f64,
+) -> Option<u64> {
+    if duration_secs <= 0.0 {
+        return None;
+    }
+
+    let total_bitrate = budget_bytes as f64 * 8.0 * safety_factor / duration_secs;
+    let video_bitrate = total_bitrate - audio_bitrate as f64;
+    if video_bitrate < MIN_BUDGET_VIDEO_BITRATE as f64 {
+        return None;
+    }
+    Some(video_bitrate as u64)
+}
+
+/// Битрейт звука в режиме бюджета: не выше профиля и не выше
+/// `BUDGET_AUDIO_BITRATE`, а для беззвучных результатов ноль.
+fn budget_audio_bitrate(settings: &JobSettings) -> u64 {
+    if !settings.output.keeps_audio() {
+        return 0;
+    }
+    parse_bitrate(&settings.profile.audio_bitrate)
+        .unwrap_or(BUDGET_AUDIO_BITRATE)
+        .min(BUDGET_AUDIO_BITRATE)
+}
+
+/// Ограничения высоты кадра для последовательных попыток: сначала исходное
+/// разрешение, затем всё меньшие ступени ниже исходной высоты.
+fn budget_height_ladder(source_height: Option<u32>) -> Vec<Option<u32>> {
+    let mut ladder = vec![None];
+    ladder.extend(
+        BUDGET_FALLBACK_HEIGHTS
+            .iter()
+            .filter(|height| source_height.is_none_or(|source| **height < source))
+            .map(|height| Some(*height)),
+    );
+    ladder
+}
+
+fn build_two_pass_args(
+    pass: u8,
+    settings: &JobSettings,
+    input_path: &str,
+    output_path: &str,
+    passlog_prefix: &str,
+    video_bitrate: u64,
+    max_height: Option<u32>,
+) -> Vec<String> {
+    let mut args = input_args(settings, input_path);
+
+    let target = settings.target;
+    args.extend(video_stream_args(settings, input_path, max_height));
+    if pass == 2 && settings.output.keeps_audio() {
+        args.extend(audio_stream_args(settings));
+    }
+    args.extend(video_encoder_args(
+        target,
+        &settings.profile,
+        settings.pixel_format(),
+    ));
+    args.extend(["-b:v".to_string(), video_bitrate.to_string()]);
+    match target {
+        OutputTarget::Mp4Hevc => args.extend([
+            "-x265-params".to_string(),
+            format!("pass={}:stats={}.log", pass, passlog_prefix),
+        ]),
+        OutputTarget::Mp4H264 | OutputTarget::WebmVp9 => args.extend([
+            "-pass".to_string(),
+            pass.to_string(),
+            "-passlogfile".to_string(),
+            passlog_prefix.to_string(),
+        ]),
+        // SVT-AV1 держит битрейт за один проход, см. `budget_passes`.
+        OutputTarget::Mp4Av1 => {}
+    }
+
+    args.extend(duration_limit_args(settings));
+
+    if pass == 1 {
+        args.extend(["-an", "-f", target.extension(), "/dev/null"].map(str::to_string));
+    } else {
+        args.extend(audio_encoder_args(
+            settings,
+            budget_audio_bitrate(settings).to_string(),
+        ));
+        args.extend(subtitle_track_args(settings));
+        args.extend(container_args(target));
+        args.push(output_path.to_string());
+    }
+    args
+}
+
+/// Проходы кодирования под размер: SVT-AV1 через ffmpeg кодирует в один
+/// проход с переменным битрейтом, остальные кодеры — в два.
+fn budget_passes(target: OutputTarget) -> &'static [u8] {
+    match target {
+        OutputTarget::Mp4Av1 => &[2],
+        OutputTarget::Mp4H264 | OutputTarget::Mp4Hevc | OutputTarget::WebmVp9 => &[1, 2],
+    }
+}
+
+/// Двухпроходное кодирование под заданный размер файла. Если
+/// результат всё равно больше бюджета, повторяет с меньшим разрешением.
+async fn encode_to_budget(
+    runner: &mut FfmpegRunner<'_>,
+    settings: &JobSettings,
+    input_path: &str,
+    output_path: &str,
+    duration_secs: f64,
+    source_height: Option<u32>,
+    budget_bytes: u64,
+) -> Result<u64, ConversionError> {
+    let passlog_prefix = format!("{}.2pass", output_path);
+
+    let result = async {
+        for (attempt, max_height) in budget_height_ladder(source_height).into_iter().enumerate() {
+            let safety_factor = 0.95 - 0.05 * attempt as f64;
+            let video_bitrate = target_video_bitrate(
+                duration_secs,
+                budget_bytes,
+                budget_audio_bitrate(settings),
+                safety_factor,
+            )
+            .ok_or_else(|| {
+                anyhow!(
+                    "Video of {:.1}s cannot fit into {} bytes",
+                    duration_secs,
+                    budget_bytes
+                )
+            })?;
+
+            let passes = budget_passes(settings.target);
+            for (index, &pass) in passes.iter().enumerate() {
+                let stage = ProgressStage::FittingSize {
+                    attempt: attempt as u8 + 1,
+                    pass: index as u8 + 1,
+                    passes: passes.len() as u8,
+                };
+                runner
+                    .run_stage(
+                        stage,
+                        &build_two_pass_args(
+                            pass,
+                            settings,
+                            input_path,
+                            output_path,
+                            &passlog_prefix,
+                            video_bitrate,
+                            max_height,
+                        ),
+                    )
+                    .await?;
+            }
+
+            let output_size = std::fs::metadata(output_path)
+                .with_context(|| format!("Converted file is missing: {}", output_path))?
+                .len();
+            log::info!(
+                "Size-targeted encode attempt {}: max_height={:?}, video_bitrate={}, size={}/{}",
+                attempt + 1,
+                max_height,
+                video_bitrate,
+                output_size,
+                budget_bytes,
+            );
+            if output_size <= budget_bytes {
+                return Ok(output_size);
+            }
+        }
+
+        Err(anyhow!(
+            "Converted file still exceeds {} bytes at the lowest resolution",
+            budget_bytes
+        )
+        .into())
+    }
+    .await;
+
+    for suffix in ["-0.log", "-0.log.mbtree", ".log", ".log.cutree"] {
+        let _ = std::fs::remove_file(format!("{}{}", passlog_prefix, suffix));
+    }
+
+    result
+}
+
+/// Убивает всю группу процессов ffmpeg вместе с возможными потомками.
+#[cfg(unix)]
+fn kill_process_group(pid: u32) {
+    // SAFETY: kill(2) не трогает память процесса; отрицательный pid адресует
+    // группу, созданную через `process_group(0)` при запуске ffmpeg.
+    unsafe {
+        libc::kill(-(pid as libc::pid_t), libc::SIGKILL);
+    }
+}
+
+#[cfg(not(unix))]
+fn kill_process_group(_pid: u32) {}
+
+/// Ограничения, общие для всех запусков ffmpeg в рамках одной конвертации.
+struct FfmpegRunner<'a> {
+    deadline: Instant,
+    timeout: Duration,
+    cancel: &'a CancellationToken,
+    on_progress: &'a mut (dyn FnMut(ProgressUpdate) + Send),
+}
+
+enum RunOutcome {
+    Exited(std::io::Result<std::process::ExitStatus>),
+    TimedOut,
+    Cancelled,
+}
+
+impl FfmpegRunner<'_> {
+    /// Запускает ffmpeg с `-progress pipe:1` и передаёт снимки прогресса в
+    /// `on_progress`. По таймауту или отмене убивает группу процессов ffmpeg.
+    async fn run(&mut self, args: &[String]) -> Result<(), ConversionError> {
+        self.run_with_frames(args, None).await.map(drop)
+    }
+
+    /// То же, что `run`, но возвращает stderr ffmpeg: туда пишут свои
+    /// замеры фильтры вроде `loudnorm`.
+    async fn run_for_stderr(&mut self, args: &[String]) -> Result<String, ConversionError> {
+        self.run_with_frames(args, None).await
+    }
+
+    /// То же, что `run_for_stderr`, но помечает снимки прогресса стадией
+    /// `stage`, чтобы статус не начинал каждый проход с нуля молча.
+    async fn run_stage(
+        &mut self,
+        stage: ProgressStage,
+        args: &[String],
+    ) -> Result<String, ConversionError> {
+        let on_progress = &mut *self.on_progress;
+        let mut staged = FfmpegRunner {
+            deadline: self.deadline,
+            timeout: self.timeout,
+            cancel: self.cancel,
+            on_progress: &mut |update| on_progress(ProgressUpdate { stage, ..update }),
+        };
+        staged.run_for_stderr(args).await
+    }
+
+    /// То же, что `run_for_stderr`, но пишет в stdin ffmpeg кадры из
+    /// `frames`, пока канал не закроется.
+    async fn run_with_frames(
+        &mut self,
+        args: &[String],
+        frames: Option<mpsc::Receiver<Vec<u8>>>,
+    ) -> Result<String, ConversionError> {
+        // Отдельная группа процессов позволяет убить ffmpeg вместе с потомками.
+        let mut std_command = std::process::Command::new("ffmpeg");
+        #[cfg(unix)]
+        std::os::unix::process::CommandExt::process_group(&mut std_command, 0);
+
+        let mut command = Command::from(std_command);
+        command
+            .args(["-progress", "pipe:1", "-nostats"])
+            .args(args)
+            .stdin(if frames.is_some() {
+                Stdio::piped()
+            } else {
+                Stdio::null()
+            })
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true);
+
+        let mut child = command.spawn().context("Failed to start ffmpeg")?;
+        let pid = child.id();
+
+        // stderr читаем в отдельной задаче, иначе ffmpeg может упереться в полный пайп.
+        let stderr = child.stderr.take();
+        let stderr_reader = tokio::spawn(async move {
+            let mut buffer = String::new();
+            if let Some(mut stderr) = stderr {
+                let _ = stderr.read_to_string(&mut buffer).await;
+            }
+            buffer
+        });
+
+        // Закрытый stdin — сигнал ffmpeg, что кадры кончились.
+        let stdin = child.stdin.take();
+        let frame_writer = frames.map(|mut frames| {
+            tokio::spawn(async move {
+                let Some(mut stdin) = stdin else {
+                    return;
+                };
+                while let Some(frame) = frames.recv().await {
+                    if stdin.write_all(&frame).await.is_err() {
+                        break;
+                    }
+                }
+            })
+        });
+
+        let stdout = child.stdout.take();
+        let on_progress = &mut *self.on_progress;
+        let drive = async {
+            if let Some(stdout) = stdout {
+                let mut parser = ProgressParser::new();
+                let mut lines = BufReader::new(stdout).lines();
+                while let Ok(Some(line)) = lines.next_line().await {
+                    if let Some(update) = parser.feed_line(&line) {
+                        on_progress(update);
+                    }
+                }
+            }
+            child.wait().await
+        };
+
+        let outcome = tokio::select! {
+            status = drive => RunOutcome::Exited(status),
+            _ = tokio::time::sleep_until(self.deadline) => RunOutcome::TimedOut,
+            _ = self.cancel.cancelled() => RunOutcome::Cancelled,
+        };
+
+        let status = match outcome {
+            RunOutcome::Exited(status) => status.context("Failed to wait for ffmpeg")?,
+            RunOutcome::TimedOut | RunOutcome::Cancelled => {
+                if let Some(pid) = pid {
+                    kill_process_group(pid);
+                }
+                let _ = child.wait().await;
+                stderr_reader.abort();
+                if let Some(frame_writer) = &frame_writer {
+                    frame_writer.abort();
+                }
+                return Err(match outcome {
+                    RunOutcome::TimedOut => ConversionError::TimedOut(self.timeout),
+                    _ => ConversionError::Cancelled,
+                });
+            }
+        };
+        let stderr = stderr_reader.await.unwrap_or_default();
+        if let Some(frame_writer) = frame_writer {
+            frame_writer.abort();
+        }
+
+        if !status.success() {
+            return Err(anyhow!(
+                "FFmpeg conversion failed (status: {}): stderr='{}'",
+                status.code().map_or_else(
+                    || "terminated by signal".to_string(),
+                    |code| code.to_string()
+                ),
+                stderr.trim(),
+            )
+            .into());
+        }
+
+        Ok(stderr)
+    }
+}
+
+/// Конвертирует любой поддерживаемый FFmpeg видеофайл в контейнер и
+/// кодеки `settings.target`.
+///
+/// Если по данным ffprobe потоки уже совместимы с MP4, файл только
+/// переупаковывается; при неудаче ремукса выполняется полное перекодирование.
+/// Без результатов анализа (`None`) файл всегда перекодируется.
+///
+/// Если результат больше `options.max_output_bytes`, файл перекодируется
+/// в два прохода с битрейтом, рассчитанным по длительности. Прогресс
+/// каждого запуска ffmpeg передаётся в `on_progress`.
+///
+/// Вся конвертация ограничена `options.timeout` и прерывается через
+/// `cancel`; при любой ошибке недописанный результат удаляется. К готовому
+/// файлу прилагаются его размеры, длительность и превью.
+pub async fn convert_video(
+    file_path: &str,
+    info: Option<&MediaInfo>,
+    settings: &JobSettings,
+    options: &ConvertOptions,
+    cancel: &CancellationToken,
+    on_progress: &mut (dyn FnMut(ProgressUpdate) + Send),
+) -> Result<ConvertedMedia, ConversionError> {
+    let output_path = build_output_path(file_path, settings.target.extension());
+    let mut runner = FfmpegRunner {
+        deadline: Instant::now() + options.timeout,
+        timeout: options.timeout,
+        cancel,
+        on_progress,
+    };
+
+    let result = convert_with_runner(
+        &mut runner,
+        file_path,
+        &output_path,
+        info,
+        settings,
+        options,
+    )
+    .await;
+    if let Err(error) = result {
+        remove_partial_output(&output_path).await;
+        return Err(error);
+    }
+
+    let mut converted =
+        describe_output(file_path, output_path, runner.deadline, options, cancel).await;
+    converted.frame_rate = settings.frame_rate;
+    Ok(converted)
+}
+
+/// Читает размеры и длительность готового файла и снимает превью. Ошибки
+/// здесь не роняют конвертацию: Telegram просто покажет меньше.
+async fn describe_output(
+    file_path: &str,
+    output_path: String,
+    deadline: Instant,
+    options: &ConvertOptions,
+    cancel: &CancellationToken,
+) -> ConvertedMedia {
+    let probe_path = output_path.clone();
+    let info = match tokio::task::spawn_blocking(move || probe_media(&probe_path)).await {
+        Ok(Ok(info)) => Some(info),
+        Ok(Err(e)) => {
+            log::warn!("Failed to probe converted file {}: {:?}", output_path, e);
+            None
+        }
+        Err(e) => {
+            log::warn!("Failed to join probe task for {}: {:?}", output_path, e);
+            None
+        }
+    };
+    let duration_secs = info.as_ref().and_then(|info| info.duration_secs);
+
+    let thumbnail_path = build_output_path(file_path, "thumb.jpg");
+    let seek_secs = duration_secs.unwrap_or(0.0) * THUMBNAIL_SEEK_FRACTION;
+    // Свой обработчик прогресса: превью не должно откатывать статус назад.
+    let mut runner = FfmpegRunner {
+        deadline,
+        timeout: options.timeout,
+        cancel,
+        on_progress: &mut |_| {},
+    };
+    let thumbnail = match runner
+        .run(&build_thumbnail_args(
+            &output_path,
+            seek_secs,
+            &thumbnail_path,
+        ))
+        .await
+    {
+        Ok(()) => Some(thumbnail_path),
+        Err(e) => {
+            log::warn!("Failed to make thumbnail for {}: {}", output_path, e);
+            remove_partial_output(&thumbnail_path).await;
+            None
+        }
+    };
+
+    probed_output(output_path, info.as_ref(), thumbnail)
+}
+
+/// Собирает описание готового файла из результата ffprobe: размеры с учётом
+/// поворота и длительность. Без результата (`None`) их просто нет.
+fn probed_output(
+    output_path: String,
+    info: Option<&MediaInfo>,
+    thumbnail: Option<String>,
+) -> ConvertedMedia {
+    let dimensions = info.and_then(MediaInfo::display_dimensions);
+    ConvertedMedia {
+        path: output_path,
+        thumbnail,
+        width: dimensions.map(|(width, _)| width),
+        height: dimensions.map(|(_, height)| height),
+        duration_secs: info.and_then(|info| info.duration_secs),
+        ..Default::default()
+    }
+}
+
+/// Извлекает первую звуковую дорожку файла в `format`. С `loudness_target`
+/// громкость сначала замеряется и выравнивается так же, как в видео.
+/// Таймаут и отмена работают так же, как в `convert_video`.
+pub async fn extract_audio(
+    file_path: &str,
+    format: AudioFormat,
+    loudness_target: Option<f64>,
+    options: &ConvertOptions,
+    cancel: &CancellationToken,
+) -> Result<String, ConversionError> {
+    let output_path = build_output_path(file_path, format.extension());
+    let mut runner = FfmpegRunner {
+        deadline: Instant::now() + options.timeout,
+        timeout: options.timeout,
+        cancel,
+        on_progress: &mut |_| {},
+    };
+
+    let result = async {
+        let loudness = match loudness_target {
+            Some(target) => {
+                let args = build_audio_loudness_measure_args(target, file_path);
+                measure_loudness(&mut runner, &args, target, file_path).await?
+            }
+            None => None,
+        };
+        runner
+            .run(&build_audio_extract_args(
+                format,
+                loudness.as_ref(),
+                file_path,
+                &output_path,
+            ))
+            .await
+    }
+    .await;
+    if result.is_err() {
+        remove_partial_output(&output_path).await;
+    }
+
+    result.map(|()| output_path)
+}
+
+/// Конвертирует картинку в `format` с учётом EXIF-ориентации из `info`.
+pub async fn convert_image(
+    file_path: &str,
+    info: Option<&MediaInfo>,
+    format: ImageFormat,
+    options: &ConvertOptions,
+    cancel: &CancellationToken,
+) -> Result<String, ConversionError> {
+    let output_path = build_output_path(file_path, format.extension());
+    let mut runner = FfmpegRunner {
+        deadline: Instant::now() + options.timeout,
+        timeout: options.timeout,
+        cancel,
+        on_progress: &mut |_| {},
+    };
+
+    let exif_orientation = info.and_then(|info| info.exif_orientation);
+    let result = runner
+        .run(&build_image_args(
+            format,
+            exif_orientation,
             file_path,
-            "-map",
-            "0:v:0",
-            "-map",
-            "0:a?",
-            "-c:v",
-            "libx264",
-            "-preset",
-            "veryfast",
-            "-crf",
-            "23",
-            "-pix_fmt",
-            "yuv420p",
-            "-c:a",
-            "aac",
-            "-b:a",
-            "192k",
-            "-movflags",
-            "+faststart",
             &output_path,
-        ])
-        .output()?;
+        ))
+        .await;
+    if result.is_err() {
+        remove_partial_output(&output_path).await;
+    }
+
+    result.map(|()| output_path)
+}
+
+/// Превращает стикер в PNG (статичный) или в `settings.format` на
+/// сплошном фоне (видео и анимированный). `dimensions` — размер кадра
+/// видеостикера по данным Telegram.
+pub async fn convert_sticker(
+    file_path: &str,
+    source: StickerSource,
+    dimensions: Option<(u32, u32)>,
+    settings: &UnstickerSettings,
+    options: &ConvertOptions,
+    cancel: &CancellationToken,
+) -> Result<String, ConversionError> {
+    let extension = match source {
+        StickerSource::Static => ImageFormat::Png.extension(),
+        StickerSource::Video | StickerSource::Animated => settings.format.extension(),
+    };
+    let output_path = build_output_path(file_path, extension);
+    let mut runner = FfmpegRunner {
+        deadline: Instant::now() + options.timeout,
+        timeout: options.timeout,
+        cancel,
+        on_progress: &mut |_| {},
+    };
+
+    let result = match source {
+        StickerSource::Static => {
+            runner
+                .run(&build_image_args(
+                    ImageFormat::Png,
+                    None,
+                    file_path,
+                    &output_path,
+                ))
+                .await
+        }
+        StickerSource::Video => {
+            let dimensions = dimensions.unwrap_or((STICKER_SIDE, STICKER_SIDE));
+            runner
+                .run(&build_video_sticker_args(
+                    settings,
+                    dimensions,
+                    file_path,
+                    &output_path,
+                ))
+                .await
+        }
+        StickerSource::Animated => {
+            render_animation(&mut runner, file_path, settings, &output_path).await
+        }
+    };
+    let result = result.and_then(|()| {
+        let output_size = std::fs::metadata(&output_path)
+            .with_context(|| format!("Converted file is missing: {}", output_path))?
+            .len();
+        if output_size > options.max_output_bytes {
+            return Err(anyhow!(
+                "Converted sticker is {} bytes, over the {} byte limit",
+                output_size,
+                options.max_output_bytes
+            )
+            .into());
+        }
+        Ok(())
+    });
+    if result.is_err() {
+        remove_partial_output(&output_path).await;
+    }
+
+    result.map(|()| output_path)
+}
+
+/// Рисует кадры TGS в отдельном потоке и отдаёт их ffmpeg через stdin.
+async fn render_animation(
+    runner: &mut FfmpegRunner<'_>,
+    file_path: &str,
+    settings: &UnstickerSettings,
+    output_path: &str,
+) -> Result<(), ConversionError> {
+    let bytes = tokio::fs::read(file_path)
+        .await
+        .with_context(|| format!("Failed to read sticker {}", file_path))?;
+    let animation = Animation::from_tgs(&bytes)?;
+    // Без этих элементов кадр выйдет другим, поэтому честнее отказаться.
+    let unsupported = animation.unsupported_features();
+    if !unsupported.is_empty() {
+        log::warn!(
+            "Animated sticker {} uses unsupported Lottie features: {}",
+            file_path,
+            unsupported.join(", ")
+        );
+        return Err(anyhow::Error::new(UnsupportedFeatures(unsupported)).into());
+    }
+    log::info!(
+        "Rendering animated sticker {}: size={}x{}, frame_rate={}, frames={}",
+        file_path,
+        animation.width(),
+        animation.height(),
+        animation.frame_rate(),
+        animation.frame_count(),
+    );
+    let args = build_animation_args(
+        settings,
+        (animation.width(), animation.height()),
+        animation.frame_rate(),
+        output_path,
+    );
+
+    let (sender, receiver) = mpsc::channel(FRAME_QUEUE_LEN);
+    let background = settings.background;
+    // Поток завершится сам, когда ffmpeg закроет канал.
+    tokio::task::spawn_blocking(move || {
+        for index in 0..animation.frame_count() {
+            if sender
+                .blocking_send(animation.render_frame(index, background))
+                .is_err()
+            {
+                break;
+            }
+        }
+    });
+
+    runner
+        .run_with_frames(&args, Some(receiver))
+        .await
+        .map(drop)
+}
+
+/// Делает из видео стикер по правилам Telegram. Если файл больше 256 КБ,
+/// перекодирует его со всё большим CRF, пока не влезет.
+pub async fn make_video_sticker(
+    file_path: &str,
+    frame_rate: &FrameRatePlan,
+    options: &ConvertOptions,
+    cancel: &CancellationToken,
+) -> Result<String, ConversionError> {
+    let output_path = build_output_path(file_path, "webm");
+    let mut runner = FfmpegRunner {
+        deadline: Instant::now() + options.timeout,
+        timeout: options.timeout,
+        cancel,
+        on_progress: &mut |_| {},
+    };
+
+    let result = encode_sticker(&mut runner, file_path, frame_rate, &output_path).await;
+    if result.is_err() {
+        remove_partial_output(&output_path).await;
+    }
+
+    result.map(|()| output_path)
+}
+
+async fn encode_sticker(
+    runner: &mut FfmpegRunner<'_>,
+    file_path: &str,
+    frame_rate: &FrameRatePlan,
+    output_path: &str,
+) -> Result<(), ConversionError> {
+    let mut output_size = 0;
+    for &crf in STICKER_CRF_LADDER {
+        runner
+            .run(&build_sticker_args(crf, frame_rate, file_path, output_path))
+            .await?;
+        output_size = std::fs::metadata(output_path)
+            .with_context(|| format!("Sticker file is missing: {}", output_path))?
+            .len();
+        if output_size <= STICKER_MAX_BYTES {
+            log::info!(
+                "Sticker {} fits at crf={}: {} bytes",
+                output_path,
+                crf,
+                output_size
+            );
+            return Ok(());
+        }
+        log::warn!(
+            "Sticker {} is {} bytes at crf={}, over the {} byte limit; retrying",
+            output_path,
+            output_size,
+            crf,
+            STICKER_MAX_BYTES,
+        );
+    }
+
+    Err(anyhow!(
+        "Sticker is still {} bytes at the lowest quality, over the {} byte limit",
+        output_size,
+        STICKER_MAX_BYTES
+    )
+    .into())
+}
+
+/// Имена кодеров из `ffmpeg -encoders`: список начинается после строки
+/// из дефисов, имя — второе слово строки.
+fn parse_encoder_list(output: &str) -> HashSet<String> {
+    output
+        .lines()
+        .skip_while(|line| !line.trim_start().starts_with("---"))
+        .skip(1)
+        .filter_map(|line| line.split_whitespace().nth(1))
+        .map(str::to_string)
+        .collect()
+}
 
+/// Имена фильтров из `ffmpeg -filters`: в строке списка за флагами идёт
+/// имя и направление вроде `V->V`.
+fn parse_filter_list(output: &str) -> HashSet<String> {
+    output
+        .lines()
+        .filter_map(|line| {
+            let mut words = line.split_whitespace().skip(1);
+            let name = words.next()?;
+            words
+                .next()
+                .is_some_and(|io| io.contains("->"))
+                .then(|| name.to_string())
+        })
+        .collect()
+}
+
+/// Фильтры, которые есть в установленной сборке ffmpeg. Вызывается один раз
+/// при запуске, поэтому блокирующий.
+pub fn available_filters() -> anyhow::Result<HashSet<String>> {
+    let output = std::process::Command::new("ffmpeg")
+        .args(["-hide_banner", "-filters"])
+        .output()
+        .context("Failed to start ffmpeg")?;
     if !output.status.success() {
-        let stderr = String::from_utf8_lossy(&output.stderr).trim().to_string();
-        let stdout = String::from_utf8_lossy(&output.stdout).trim().to_string();
         return Err(anyhow!(
-            "FFmpeg conversion failed (status: {}): stderr='{}' stdout='{}'",
-            output.status.code().map_or_else(
-                || "terminated by signal".to_string(),
-                |code| code.to_string()
-            ),
-            stderr,
-            stdout,
+            "ffmpeg -filters failed (status: {})",
+            output.status
         ));
     }
+    Ok(parse_filter_list(&String::from_utf8_lossy(&output.stdout)))
+}
 
-    std::fs::metadata(&output_path)
-        .with_context(|| format!("Converted file is missing: {}", output_path))?;
+/// Кодеры, которые есть в установленной сборке ffmpeg. Вызывается один раз
+/// при запуске, поэтому блокирующий.
+pub fn available_encoders() -> anyhow::Result<HashSet<String>> {
+    let output = std::process::Command::new("ffmpeg")
+        .args(["-hide_banner", "-encoders"])
+        .output()
+        .context("Failed to start ffmpeg")?;
+    if !output.status.success() {
+        return Err(anyhow!(
+            "ffmpeg -encoders failed (status: {})",
+            output.status
+        ));
+    }
+    Ok(parse_encoder_list(&String::from_utf8_lossy(&output.stdout)))
+}
 
-    Ok(output_path)
+async fn remove_partial_output(output_path: &str) {
+    if let Err(e) = tokio::fs::remove_file(output_path).await {
+        if e.kind() != std::io::ErrorKind::NotFound {
+            log::error!("Error deleting file {}: {:?}", output_path, e);
+        }
+    }
+}
+
+/// Замеряет громкость, если профиль задаёт цель, и решает, выравнивать ли
+/// звук. Без результатов анализа или без звука замер не запускается.
+async fn plan_loudness(
+    runner: &mut FfmpegRunner<'_>,
+    file_path: &str,
+    info: Option<&MediaInfo>,
+    settings: &JobSettings,
+) -> Result<Option<LoudnessCorrection>, ConversionError> {
+    let Some(target) = settings.profile.loudness_target else {
+        return Ok(None);
+    };
+    if !settings.output.keeps_audio() || !info.is_some_and(MediaInfo::has_audio) {
+        return Ok(None);
+    }
+
+    let args = build_loudness_measure_args(settings, target, file_path);
+    measure_loudness(runner, &args, target, file_path).await
+}
+
+/// Первый проход `loudnorm` с отдельной стадией прогресса. Нечитаемый замер
+/// только пишется в лог, и звук остаётся как есть.
+async fn measure_loudness(
+    runner: &mut FfmpegRunner<'_>,
+    args: &[String],
+    target: f64,
+    file_path: &str,
+) -> Result<Option<LoudnessCorrection>, ConversionError> {
+    let stderr = runner
+        .run_stage(ProgressStage::MeasuringLoudness, args)
+        .await?;
+    let Some(measured) = LoudnessMeasurement::parse(&stderr) else {
+        log::warn!(
+            "Failed to read loudness of {}, keeping audio as is",
+            file_path
+        );
+        return Ok(None);
+    };
+    let correct = measured.needs_correction(target);
+    log::info!(
+        "Measured loudness of {}: input_i={}, input_tp={}, input_lra={}, input_thresh={}, target_i={}, linear_target_i={}, normalize={}, mode={}",
+        file_path,
+        measured.input_i,
+        measured.input_tp,
+        measured.input_lra,
+        measured.input_thresh,
+        target,
+        measured.linear_target(target),
+        correct,
+        if measured.is_linear() { "linear" } else { "dynamic" },
+    );
+    Ok(correct.then_some(LoudnessCorrection { target, measured }))
+}
+
+async fn convert_with_runner(
+    runner: &mut FfmpegRunner<'_>,
+    file_path: &str,
+    output_path: &str,
+    info: Option<&MediaInfo>,
+    settings: &JobSettings,
+    options: &ConvertOptions,
+) -> Result<(), ConversionError> {
+    let settings = &JobSettings {
+        loudness: plan_loudness(runner, file_path, info, settings).await?,
+        ..settings.clone()
+    };
+    let plan = match info {
+        Some(info) => {
+            let plan = if settings.allows_remux() {
+                plan_conversion(info, settings.audio_track)
+            } else {
+                ConversionPlan::Transcode
+            };
+            log::info!(
+                "Probed {}: container={:?}, video={:?}, pix_fmt={:?}, dimensions={:?}, has_audio={}, duration={:?}, plan={:?}",
+                file_path,
+                info.container,
+                info.video_codec(),
+                info.pixel_format(),
+                info.dimensions(),
+                info.has_audio(),
+                info.duration_secs,
+                plan,
+            );
+            plan
+        }
+        None => ConversionPlan::Transcode,
+    };
+
+    let result = runner
+        .run(&build_ffmpeg_args(plan, settings, file_path, output_path))
+        .await;
+    match (plan, result) {
+        (_, Ok(())) => {}
+        (ConversionPlan::Remux, Err(ConversionError::Failed(error))) => {
+            log::warn!("Remux failed for {}, transcoding: {:?}", file_path, error);
+            runner
+                .run_stage(
+                    ProgressStage::Reencoding,
+                    &build_ffmpeg_args(ConversionPlan::Transcode, settings, file_path, output_path),
+                )
+                .await?;
+        }
+        (_, Err(error)) => return Err(error),
+    }
+
+    let output_size = std::fs::metadata(output_path)
+        .with_context(|| format!("Converted file is missing: {}", output_path))?
+        .len();
+    if output_size <= options.max_output_bytes {
+        return Ok(());
+    }
+
+    let duration_secs = info
+        .and_then(|info| info.duration_secs)
+        .and_then(|duration| settings.output_duration_secs(Some(duration)));
+    let duration_secs = duration_secs.ok_or_else(|| {
+        anyhow!(
+            "Converted file is {} bytes, over the {} byte limit, and the duration is unknown",
+            output_size,
+            options.max_output_bytes
+        )
+    })?;
+    log::warn!(
+        "Converted file {} is {} bytes, over the {} byte limit; re-encoding to fit",
+        output_path,
+        output_size,
+        options.max_output_bytes,
+    );
+    let output_height = match settings.output {
+        OutputKind::VideoNote => Some(VIDEO_NOTE_SIZE),
+        OutputKind::Video | OutputKind::Animation => info
+            .and_then(|info| info.display_dimensions())
+            .map(|(_, height)| height),
+    };
+    encode_to_budget(
+        runner,
+        settings,
+        file_path,
+        output_path,
+        duration_secs,
+        output_height,
+        options.max_output_bytes,
+    )
+    .await?;
+
+    Ok(())
 }
 
 #[cfg(test)]
 mod tests {
-    use super::build_output_path;
+    use super::{
+        budget_height_ladder, budget_passes, build_animation_args, build_audio_extract_args,
+        build_ffmpeg_args, build_image_args, build_output_path, build_sticker_args,
+        build_thumbnail_args, build_two_pass_args, build_video_sticker_args, escape_filter_value,
+        plan_conversion, target_video_bitrate, AudioFormat, ClipFormat, ConversionPlan,
+        ImageFormat, JobSettings, OutputKind, SubtitlePlan, TrimRange, UnstickerSettings,
+    };
+    use super::{
+        build_audio_loudness_measure_args, build_loudness_measure_args, convert_video,
+        parse_encoder_list, parse_filter_list, probed_output, ConvertOptions, ConvertedMedia,
+        FrameRatePlan, HdrPlan, OutputTarget, STICKER_MAX_FPS, TONEMAP_FILTER,
+    };
+    use crate::config::{Config, EncodingProfile};
+    use crate::loudness::{LoudnessCorrection, LoudnessMeasurement};
+    use crate::media::{probe_media, Container, MediaInfo, MediaTags, StreamInfo, StreamKind};
+    use std::path::{Path, PathBuf};
+    use std::process::Command;
+    use std::time::Duration;
+    use tokio_util::sync::CancellationToken;
+
+    fn profile() -> EncodingProfile {
+        Config::default().profile_for_chat(0).1.clone()
+    }
+
+    fn settings(profile: EncodingProfile, output: OutputKind) -> JobSettings {
+        JobSettings {
+            target: OutputTarget::for_codec(&profile.video_codec),
+            profile,
+            output,
+            subtitles: SubtitlePlan::None,
+            audio_track: 0,
+            trim: None,
+            rotation: 0,
+            hdr: HdrPlan::Sdr,
+            frame_rate: FrameRatePlan::default(),
+            loudness: None,
+        }
+    }
+
+    fn stream(kind: StreamKind, codec: &str, pixel_format: Option<&str>) -> StreamInfo {
+        StreamInfo {
+            index: 0,
+            kind,
+            codec: Some(codec.to_string()),
+            pixel_format: pixel_format.map(str::to_string),
+            width: None,
+            height: None,
+            ..Default::default()
+        }
+    }
+
+    fn media(streams: Vec<StreamInfo>) -> MediaInfo {
+        MediaInfo {
+            container: Container::Matroska,
+            duration_secs: Some(10.0),
+            bit_rate: None,
+            streams,
+            tags: MediaTags::default(),
+            exif_orientation: None,
+        }
+    }
+
+    #[test]
+    fn describes_output_from_probe() {
+        let mut video = stream(StreamKind::Video, "h264", Some("yuv420p"));
+        video.width = Some(1920);
+        video.height = Some(1080);
+        video.rotation = 90;
+        let mut info = media(vec![video]);
+        info.duration_secs = Some(12.5);
+
+        assert_eq!(
+            probed_output(
+                "out.mp4".to_string(),
+                Some(&info),
+                Some("out.thumb.jpg".to_string())
+            ),
+            ConvertedMedia {
+                path: "out.mp4".to_string(),
+                thumbnail: Some("out.thumb.jpg".to_string()),
+                width: Some(1080),
+                height: Some(1920),
+                duration_secs: Some(12.5),
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            probed_output("out.mp4".to_string(), None, None),
+            ConvertedMedia {
+                path: "out.mp4".to_string(),
+                ..Default::default()
+            }
+        );
+    }
 
     #[test]
     fn replaces_existing_extension_with_mp4() {
-        assert_eq!(build_output_path("/tmp/example.mkv"), "/tmp/example.mp4");
+        assert_eq!(
+            build_output_path("/tmp/example.mkv", "mp4"),
+            "/tmp/example.mp4"
+        );
     }
 
     #[test]
     fn appends_mp4_when_extension_absent() {
-        assert_eq!(build_output_path("/tmp/example"), "/tmp/example.mp4");
+        assert_eq!(build_output_path("/tmp/example", "mp4"), "/tmp/example.mp4");
+    }
+
+    #[test]
+    fn does_not_overwrite_mp4_input() {
+        assert_eq!(
+            build_output_path("/tmp/example.mp4", "mp4"),
+            "/tmp/example.converted.mp4"
+        );
+        assert_eq!(
+            build_output_path("/tmp/example.mp4", "ogg"),
+            "/tmp/example.ogg"
+        );
+    }
+
+    #[test]
+    fn remuxes_h264_with_aac() {
+        let info = media(vec![
+            stream(StreamKind::Video, "h264", Some("yuv420p")),
+            stream(StreamKind::Audio, "aac", None),
+        ]);
+        assert_eq!(plan_conversion(&info, 0), ConversionPlan::Remux);
+    }
+
+    #[test]
+    fn remuxes_silent_h264() {
+        let info = media(vec![stream(StreamKind::Video, "h264", Some("yuv420p"))]);
+        assert_eq!(plan_conversion(&info, 0), ConversionPlan::Remux);
+    }
+
+    #[test]
+    fn transcodes_incompatible_audio() {
+        let info = media(vec![
+            stream(StreamKind::Video, "h264", Some("yuv420p")),
+            stream(StreamKind::Audio, "opus", None),
+        ]);
+        assert_eq!(plan_conversion(&info, 0), ConversionPlan::Transcode);
+    }
+
+    #[test]
+    fn plans_and_maps_only_the_selected_audio_track() {
+        let info = media(vec![
+            stream(StreamKind::Video, "h264", Some("yuv420p")),
+            stream(StreamKind::Audio, "flac", None),
+            stream(StreamKind::Audio, "aac", None),
+        ]);
+        assert_eq!(plan_conversion(&info, 0), ConversionPlan::Transcode);
+        assert_eq!(plan_conversion(&info, 1), ConversionPlan::Remux);
+
+        let second = JobSettings {
+            audio_track: 1,
+            ..settings(profile(), OutputKind::Video)
+        };
+        let args = build_ffmpeg_args(ConversionPlan::Remux, &second, "in.mkv", "out.mp4");
+        assert!(args.windows(2).any(|pair| pair == ["-map", "0:a:1?"]));
+        assert!(!args.iter().any(|arg| arg == "0:a?" || arg == "0:a:0?"));
+    }
+
+    #[test]
+    fn transcodes_other_video_codecs_and_pixel_formats() {
+        let vp9 = media(vec![stream(StreamKind::Video, "vp9", Some("yuv420p"))]);
+        let high10 = media(vec![stream(StreamKind::Video, "h264", Some("yuv420p10le"))]);
+        assert_eq!(plan_conversion(&vp9, 0), ConversionPlan::Transcode);
+        assert_eq!(plan_conversion(&high10, 0), ConversionPlan::Transcode);
+    }
+
+    fn trimmed(output: OutputKind, start_secs: f64, end_secs: Option<f64>) -> JobSettings {
+        JobSettings {
+            trim: Some(TrimRange {
+                start_secs,
+                end_secs,
+            }),
+            ..settings(profile(), output)
+        }
+    }
+
+    #[test]
+    fn trim_seeks_before_input_and_limits_duration() {
+        let clip = trimmed(OutputKind::Video, 10.0, Some(42.0));
+        assert!(!clip.allows_remux());
+
+        let args = build_ffmpeg_args(ConversionPlan::Transcode, &clip, "in.mkv", "out.mp4");
+        let seek = args.iter().position(|arg| arg == "-ss").unwrap();
+        let input = args.iter().position(|arg| arg == "-i").unwrap();
+        assert_eq!(args[seek + 1], "10.000");
+        assert!(seek < input);
+        assert!(args.windows(2).any(|pair| pair == ["-t", "32.000"]));
+
+        let second = build_two_pass_args(2, &clip, "in.mkv", "out.mp4", "log", 500_000, None);
+        assert!(second.windows(2).any(|pair| pair == ["-ss", "10.000"]));
+        assert!(second.windows(2).any(|pair| pair == ["-t", "32.000"]));
+
+        let from_start = trimmed(OutputKind::Video, 0.0, Some(30.0));
+        let args = build_ffmpeg_args(ConversionPlan::Transcode, &from_start, "in.mkv", "out.mp4");
+        assert!(!args.iter().any(|arg| arg == "-ss"));
+        assert!(args.windows(2).any(|pair| pair == ["-t", "30.000"]));
+    }
+
+    #[test]
+    fn output_duration_combines_trim_source_and_format_limits() {
+        let untrimmed = settings(profile(), OutputKind::Video);
+        assert!(untrimmed.allows_remux());
+        assert_eq!(untrimmed.output_duration_secs(Some(95.0)), Some(95.0));
+        assert_eq!(untrimmed.output_duration_secs(None), None);
+
+        let to_end = trimmed(OutputKind::Video, 60.0, None);
+        assert_eq!(to_end.output_duration_secs(Some(95.0)), Some(35.0));
+        assert_eq!(to_end.output_duration_secs(None), None);
+
+        let note = trimmed(OutputKind::VideoNote, 10.0, Some(100.0));
+        assert_eq!(note.output_duration_secs(Some(300.0)), Some(60.0));
+        assert_eq!(note.output_duration_secs(Some(40.0)), Some(30.0));
+    }
+
+    #[test]
+    fn burned_subtitles_keep_source_timing_after_seek() {
+        let clip = JobSettings {
+            subtitles: SubtitlePlan::BurnText(0),
+            ..trimmed(OutputKind::Video, 90.0, None)
+        };
+        let args = build_ffmpeg_args(ConversionPlan::Transcode, &clip, "in.mkv", "out.mp4");
+        assert!(args.windows(2).any(|pair| pair
+            == [
+                "-vf",
+                "setpts=PTS+90.000/TB,subtitles=filename=in.mkv:si=0,setpts=PTS-STARTPTS,scale=trunc(iw/2)*2:trunc(ih/2)*2"
+            ]));
+    }
+
+    #[test]
+    fn rotates_before_other_filters_and_clears_display_matrix() {
+        let portrait = JobSettings {
+            rotation: 90,
+            subtitles: SubtitlePlan::BurnText(0),
+            ..settings(profile(), OutputKind::Video)
+        };
+        let args = build_ffmpeg_args(ConversionPlan::Transcode, &portrait, "in.mov", "out.mp4");
+        let reset = args
+            .iter()
+            .position(|arg| arg == "-display_rotation:v:0")
+            .unwrap();
+        let input = args.iter().position(|arg| arg == "-i").unwrap();
+        assert_eq!(args[reset + 1], "0");
+        assert!(reset < input);
+        assert!(args.windows(2).any(|pair| pair
+            == [
+                "-vf",
+                "transpose=clock,subtitles=filename=in.mov:si=0,scale=trunc(iw/2)*2:trunc(ih/2)*2"
+            ]));
+
+        let bitmap = JobSettings {
+            rotation: 270,
+            subtitles: SubtitlePlan::BurnBitmap(1),
+            ..settings(profile(), OutputKind::Video)
+        };
+        let args = build_two_pass_args(1, &bitmap, "in.mkv", "out.mp4", "log", 500_000, Some(480));
+        assert!(args.windows(2).any(|pair| pair
+            == [
+                "-filter_complex",
+                "[0:v:0]transpose=cclock[base];[base][0:s:1]overlay,scale=-2:480[v]"
+            ]));
+
+        let upright = settings(profile(), OutputKind::Video);
+        let args = build_ffmpeg_args(ConversionPlan::Transcode, &upright, "in.mov", "out.mp4");
+        assert!(!args.iter().any(|arg| arg == "-display_rotation:v:0"));
+    }
+
+    #[test]
+    fn tone_maps_hdr_first_or_keeps_10bit() {
+        let tone_mapped = JobSettings {
+            hdr: HdrPlan::ToneMap,
+            rotation: 90,
+            ..settings(profile(), OutputKind::Video)
+        };
+        let args = build_ffmpeg_args(ConversionPlan::Transcode, &tone_mapped, "in.mov", "out.mp4");
+        let filters = &args[args.iter().position(|arg| arg == "-vf").unwrap() + 1];
+        assert!(filters.starts_with(&format!("{},transpose=clock,", TONEMAP_FILTER)));
+        assert!(filters.contains("tonemap=tonemap=hable"));
+        assert!(args.windows(2).any(|pair| pair == ["-pix_fmt", "yuv420p"]));
+
+        let bitmap = JobSettings {
+            subtitles: SubtitlePlan::BurnBitmap(0),
+            ..tone_mapped.clone()
+        };
+        let args = build_ffmpeg_args(ConversionPlan::Transcode, &bitmap, "in.mkv", "out.mp4");
+        let graph = &args[args
+            .iter()
+            .position(|arg| arg == "-filter_complex")
+            .unwrap()
+            + 1];
+        assert!(graph.starts_with(&format!("[0:v:0]{},transpose=clock[base];", TONEMAP_FILTER)));
+
+        let kept = JobSettings {
+            hdr: HdrPlan::Keep10Bit,
+            ..settings(profile(), OutputKind::Video)
+        };
+        let second = build_two_pass_args(2, &kept, "in.mov", "out.mp4", "log", 500_000, None);
+        assert!(second
+            .windows(2)
+            .any(|pair| pair == ["-pix_fmt", "yuv420p10le"]));
+        assert!(!second.iter().any(|arg| arg.contains("tonemap")));
+    }
+
+    #[test]
+    fn caps_frame_rate_and_resamples_audio_for_vfr() {
+        let vfr = JobSettings {
+            frame_rate: FrameRatePlan {
+                target_fps: Some(29.97),
+                capped: false,
+                constant: true,
+            },
+            hdr: HdrPlan::ToneMap,
+            ..settings(profile(), OutputKind::Video)
+        };
+        assert!(!vfr.allows_remux());
+        let args = build_ffmpeg_args(ConversionPlan::Transcode, &vfr, "in.mp4", "out.mp4");
+        let filters = &args[args.iter().position(|arg| arg == "-vf").unwrap() + 1];
+        assert!(filters.starts_with(&format!("fps=29.970,{},", TONEMAP_FILTER)));
+        assert!(args
+            .windows(2)
+            .any(|pair| pair == ["-af", "aresample=async=1"]));
+
+        let capped = JobSettings {
+            frame_rate: FrameRatePlan {
+                target_fps: Some(60.0),
+                capped: true,
+                constant: false,
+            },
+            ..settings(profile(), OutputKind::Video)
+        };
+        let args = build_ffmpeg_args(ConversionPlan::Transcode, &capped, "in.mp4", "out.mp4");
+        let filters = &args[args.iter().position(|arg| arg == "-vf").unwrap() + 1];
+        assert!(filters.starts_with("fps=60.000,"));
+        assert!(!args.iter().any(|arg| arg == "-af"));
+    }
+
+    #[test]
+    fn transcodes_rotated_h264() {
+        let mut info = media(vec![
+            stream(StreamKind::Video, "h264", Some("yuv420p")),
+            stream(StreamKind::Audio, "aac", None),
+        ]);
+        info.streams[0].rotation = 180;
+        assert_eq!(plan_conversion(&info, 0), ConversionPlan::Transcode);
+    }
+
+    /// Пишет ролик из lavfi-источника `source` во временный файл. Нужен ffmpeg
+    /// в `PATH`, поэтому такие тесты помечены `#[ignore]`.
+    fn lavfi_input(name: &str, input_args: &[&str], source: &str, output_args: &[&str]) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("shitverter-{}-{}", std::process::id(), name));
+        let status = Command::new("ffmpeg")
+            .args(["-hide_banner", "-loglevel", "error", "-nostdin", "-y"])
+            .args(input_args)
+            .args(["-f", "lavfi", "-i", source])
+            .args(output_args)
+            .arg(&path)
+            .status()
+            .expect("ffmpeg must be installed to run lavfi tests");
+        assert!(status.success(), "ffmpeg failed to generate {}", name);
+        path
+    }
+
+    async fn convert_generated(input: &Path) -> MediaInfo {
+        let input = input.to_str().unwrap();
+        let info = probe_media(input).unwrap();
+        let job = JobSettings {
+            rotation: info.video_rotation(),
+            ..settings(profile(), OutputKind::Video)
+        };
+        let options = ConvertOptions {
+            max_output_bytes: 49_000_000,
+            timeout: Duration::from_secs(60),
+        };
+        let converted = convert_video(
+            input,
+            Some(&info),
+            &job,
+            &options,
+            &CancellationToken::new(),
+            &mut |_| {},
+        )
+        .await
+        .unwrap();
+        let output = probe_media(&converted.path).unwrap();
+        assert_eq!(
+            (converted.width, converted.height),
+            (
+                output.display_dimensions().map(|(width, _)| width),
+                output.display_dimensions().map(|(_, height)| height)
+            )
+        );
+        for path in std::iter::once(converted.path).chain(converted.thumbnail) {
+            std::fs::remove_file(path).unwrap();
+        }
+        std::fs::remove_file(input).unwrap();
+        output
+    }
+
+    #[tokio::test]
+    #[ignore = "needs ffmpeg in PATH, run with `cargo test -- --ignored`"]
+    async fn encodes_odd_sized_lavfi_input_with_even_frame() {
+        let input = lavfi_input(
+            "odd.mkv",
+            &[],
+            "testsrc=size=321x241:rate=10:duration=1",
+            &["-c:v", "ffv1"],
+        );
+        let output = convert_generated(&input).await;
+        assert_eq!(output.dimensions(), Some((320, 240)));
+        assert_eq!(output.pixel_format(), Some("yuv420p"));
+    }
+
+    #[tokio::test]
+    #[ignore = "needs ffmpeg in PATH, run with `cargo test -- --ignored`"]
+    async fn applies_lavfi_input_rotation_once() {
+        // `-display_rotation 90` — поворот против часовой стрелки: портрет 240×320.
+        let input = lavfi_input(
+            "rotated.mp4",
+            &["-display_rotation:v:0", "90", "-noautorotate"],
+            "testsrc=size=320x240:rate=10:duration=1",
+            &["-c:v", "libx264", "-pix_fmt", "yuv420p"],
+        );
+        let output = convert_generated(&input).await;
+        assert_eq!(output.dimensions(), Some((240, 320)));
+        assert_eq!(output.video_rotation(), 0);
+    }
+
+    #[test]
+    fn thumbnail_args_pick_one_small_frame() {
+        let args = build_thumbnail_args("out.mp4", 1.25, "out.thumb.jpg");
+        assert!(args.windows(2).any(|pair| pair == ["-ss", "1.250"]));
+        assert!(args.windows(2).any(|pair| pair
+            == [
+                "-vf",
+                "thumbnail,scale=320:320:force_original_aspect_ratio=decrease"
+            ]));
+        assert!(args.windows(2).any(|pair| pair == ["-frames:v", "1"]));
+        assert_eq!(args.last().map(String::as_str), Some("out.thumb.jpg"));
+    }
+
+    #[test]
+    fn remux_args_copy_streams() {
+        let args = build_ffmpeg_args(
+            ConversionPlan::Remux,
+            &settings(profile(), OutputKind::Video),
+            "in.mkv",
+            "out.mp4",
+        );
+        assert!(args.windows(2).any(|pair| pair == ["-c", "copy"]));
+        assert!(!args.iter().any(|arg| arg == "libx264"));
+        assert_eq!(args.last().map(String::as_str), Some("out.mp4"));
+    }
+
+    #[test]
+    fn measures_then_normalises_loudness() {
+        let trimmed = JobSettings {
+            audio_track: 1,
+            trim: Some(TrimRange {
+                start_secs: 5.0,
+                end_secs: Some(15.0),
+            }),
+            ..settings(profile(), OutputKind::Video)
+        };
+        let args = build_loudness_measure_args(&trimmed, -16.0, "in.mkv");
+        assert!(args.windows(2).any(|pair| pair == ["-map", "0:a:1"]));
+        assert!(args.windows(2).any(|pair| pair == ["-t", "10.000"]));
+        assert!(args.iter().any(|arg| arg.ends_with("print_format=json")));
+        assert_eq!(args[args.len() - 3..], ["-f", "null", "-"]);
+
+        let normalised = JobSettings {
+            loudness: Some(LoudnessCorrection {
+                target: -16.0,
+                measured: LoudnessMeasurement {
+                    input_i: -30.0,
+                    input_tp: -20.0,
+                    input_lra: 7.0,
+                    input_thresh: -40.0,
+                    target_offset: 0.5,
+                },
+            }),
+            frame_rate: FrameRatePlan {
+                target_fps: Some(30.0),
+                capped: false,
+                constant: true,
+            },
+            ..settings(profile(), OutputKind::Video)
+        };
+        let args = build_ffmpeg_args(ConversionPlan::Remux, &normalised, "in.mp4", "out.mp4");
+        assert!(args.windows(2).any(|pair| pair == ["-c", "copy"]));
+        assert!(args.windows(2).any(|pair| pair == ["-c:a", "aac"]));
+        let filters = &args[args.iter().position(|arg| arg == "-af").unwrap() + 1];
+        assert!(filters.starts_with("aresample=async=1,loudnorm=I=-16.0:"));
+        assert!(filters.contains("measured_I=-30.00"));
+    }
+
+    #[test]
+    fn computes_target_bitrate_from_duration_and_budget() {
+        // 50 MB на 100 секунд: 4 Мбит/с всего, минус 128 кбит/с на звук.
+        let bitrate = target_video_bitrate(100.0, 50_000_000, 128_000, 1.0).unwrap();
+        assert_eq!(bitrate, 3_872_000);
+
+        let with_margin = target_video_bitrate(100.0, 50_000_000, 128_000, 0.9).unwrap();
+        assert!(with_margin < bitrate);
+    }
+
+    #[test]
+    fn refuses_budget_below_minimum_bitrate() {
+        assert_eq!(
+            target_video_bitrate(3_600.0, 10_000_000, 128_000, 0.95),
+            None
+        );
+        assert_eq!(target_video_bitrate(0.0, 50_000_000, 128_000, 0.95), None);
+    }
+
+    #[test]
+    fn height_ladder_only_goes_below_source() {
+        assert_eq!(
+            budget_height_ladder(Some(1080)),
+            vec![None, Some(720), Some(480), Some(360)]
+        );
+        assert_eq!(budget_height_ladder(Some(480)), vec![None, Some(360)]);
+        assert_eq!(budget_height_ladder(Some(240)), vec![None]);
+        assert_eq!(budget_height_ladder(None).len(), 4);
+    }
+
+    #[test]
+    fn two_pass_args_discard_first_pass_output() {
+        let first = build_two_pass_args(
+            1,
+            &settings(profile(), OutputKind::Video),
+            "in.mkv",
+            "out.mp4",
+            "out.mp4.2pass",
+            1_000_000,
+            None,
+        );
+        assert!(first.windows(2).any(|pair| pair == ["-pass", "1"]));
+        assert!(first.iter().any(|arg| arg == "-an"));
+        assert_eq!(first.last().map(String::as_str), Some("/dev/null"));
+
+        let second = build_two_pass_args(
+            2,
+            &settings(profile(), OutputKind::Video),
+            "in.mkv",
+            "out.mp4",
+            "out.mp4.2pass",
+            1_000_000,
+            Some(480),
+        );
+        assert!(second.windows(2).any(|pair| pair == ["-pass", "2"]));
+        assert!(second
+            .windows(2)
+            .any(|pair| pair == ["-vf", "scale=-2:480"]));
+        assert!(second.windows(2).any(|pair| pair == ["-b:v", "1000000"]));
+        assert_eq!(second.last().map(String::as_str), Some("out.mp4"));
+    }
+
+    #[test]
+    fn transcode_args_come_from_profile() {
+        let mut small = profile();
+        small.preset = "slow".to_string();
+        small.crf = 28;
+        small.audio_bitrate = "96k".to_string();
+
+        let args = build_ffmpeg_args(
+            ConversionPlan::Transcode,
+            &settings(small, OutputKind::Video),
+            "in.mkv",
+            "out.mp4",
+        );
+        assert!(args.windows(2).any(|pair| pair == ["-c:v", "libx264"]));
+        assert!(args.windows(2).any(|pair| pair == ["-preset", "slow"]));
+        assert!(args.windows(2).any(|pair| pair == ["-crf", "28"]));
+        assert!(args.windows(2).any(|pair| pair == ["-b:a", "96k"]));
+        assert!(!args.iter().any(|arg| arg == "-tag:v"));
+    }
+
+    #[test]
+    fn hevc_profile_uses_x265_two_pass_and_hvc1_tag() {
+        let mut hevc = profile();
+        hevc.video_codec = "libx265".to_string();
+        // H.264 из исходника нельзя выдать за HEVC.
+        assert!(!settings(hevc.clone(), OutputKind::Video).allows_remux());
+
+        let args = build_two_pass_args(
+            2,
+            &settings(hevc, OutputKind::Video),
+            "in.mkv",
+            "out.mp4",
+            "log",
+            500_000,
+            None,
+        );
+        assert!(args.windows(2).any(|pair| pair == ["-tag:v", "hvc1"]));
+        assert!(args
+            .windows(2)
+            .any(|pair| pair == ["-x265-params", "pass=2:stats=log.log"]));
+        assert!(!args.iter().any(|arg| arg == "-passlogfile"));
+    }
+
+    #[test]
+    fn webm_target_uses_vp9_opus_and_webvtt() {
+        let webm = JobSettings {
+            target: OutputTarget::WebmVp9,
+            subtitles: SubtitlePlan::Embed(vec![0]),
+            ..settings(profile(), OutputKind::Video)
+        };
+        assert!(!webm.allows_remux());
+        let args = build_ffmpeg_args(ConversionPlan::Transcode, &webm, "in.mkv", "out.webm");
+        for pair in [
+            ["-c:v", "libvpx-vp9"],
+            ["-cpu-used", "5"],
+            ["-crf", "31"],
+            ["-b:v", "0"],
+            ["-c:a", "libopus"],
+            ["-c:s", "webvtt"],
+        ] {
+            assert!(args.windows(2).any(|window| window == pair), "{:?}", pair);
+        }
+        assert!(!args.iter().any(|arg| arg == "-movflags"));
+        assert_eq!(args.last().map(String::as_str), Some("out.webm"));
+
+        let first = build_two_pass_args(1, &webm, "in.mkv", "out.webm", "log", 500_000, None);
+        assert!(first.windows(2).any(|pair| pair == ["-passlogfile", "log"]));
+        assert_eq!(first[first.len() - 2..], ["webm", "/dev/null"]);
+    }
+
+    #[test]
+    fn av1_target_maps_preset_and_encodes_budget_in_one_pass() {
+        let av1 = JobSettings {
+            target: OutputTarget::Mp4Av1,
+            ..settings(profile(), OutputKind::Video)
+        };
+        let args = build_ffmpeg_args(ConversionPlan::Transcode, &av1, "in.mkv", "out.mp4");
+        assert!(args.windows(2).any(|pair| pair == ["-c:v", "libsvtav1"]));
+        assert!(args.windows(2).any(|pair| pair == ["-preset", "10"]));
+        assert!(args.windows(2).any(|pair| pair == ["-crf", "33"]));
+        assert!(args.windows(2).any(|pair| pair == ["-c:a", "aac"]));
+        assert!(args
+            .windows(2)
+            .any(|pair| pair == ["-movflags", "+faststart"]));
+
+        assert_eq!(budget_passes(OutputTarget::Mp4Av1), [2]);
+        let second = build_two_pass_args(2, &av1, "in.mkv", "out.mp4", "log", 500_000, None);
+        assert!(!second
+            .iter()
+            .any(|arg| arg == "-pass" || arg == "-x265-params"));
+    }
+
+    #[test]
+    fn parses_filter_list() {
+        let output = "Filters:
+  T.. = Timeline support
+  A = Audio input/output
+  | = Source or sink filter
+ ... loudnorm          A->A       EBU R128 loudness normalization
+ ..C zscale            V->V       Apply resizing, colorspace and bit depth conversion.
+ TSC tonemap           V->V       Conversion to/from different dynamic ranges.
+";
+        let filters = parse_filter_list(output);
+        assert_eq!(filters.len(), 3);
+        assert!(super::TONEMAP_FILTERS
+            .iter()
+            .all(|filter| filters.contains(*filter)));
+        assert!(!filters.contains("Timeline"));
+    }
+
+    #[test]
+    fn parses_encoder_list_and_checks_targets() {
+        let output = "Encoders:
+ V..... = Video
+ A..... = Audio
+ ------
+ V....D libx264              libx264 H.264 / AVC / MPEG-4 AVC (codec h264)
+ V....D libvpx-vp9           libvpx VP9 (codec vp9)
+ A....D aac                  AAC (Advanced Audio Coding)
+";
+        let encoders = parse_encoder_list(output);
+        assert_eq!(encoders.len(), 3);
+        assert!(OutputTarget::Mp4H264.missing_encoders(&encoders).is_empty());
+        assert_eq!(
+            OutputTarget::WebmVp9.missing_encoders(&encoders),
+            ["libopus"]
+        );
+        assert_eq!(
+            OutputTarget::Mp4Av1.missing_encoders(&encoders),
+            ["libsvtav1"]
+        );
+        assert_eq!(OutputTarget::WebmVp9.extension(), "webm");
+    }
+
+    #[test]
+    fn animation_args_drop_audio() {
+        let animation = settings(profile(), OutputKind::Animation);
+
+        let args = build_ffmpeg_args(ConversionPlan::Transcode, &animation, "in.webm", "out.mp4");
+        assert!(args.iter().any(|arg| arg == "-an"));
+        assert!(!args.iter().any(|arg| arg == "0:a:0?"));
+        assert!(!args.iter().any(|arg| arg == "-c:a"));
+
+        let second = build_two_pass_args(2, &animation, "in.webm", "out.mp4", "log", 500_000, None);
+        assert!(second.iter().any(|arg| arg == "-an"));
+        assert!(!second.iter().any(|arg| arg == "0:a:0?"));
+    }
+
+    #[test]
+    fn video_note_args_crop_square_and_cap_duration() {
+        let note = settings(profile(), OutputKind::VideoNote);
+
+        let args = build_ffmpeg_args(ConversionPlan::Transcode, &note, "in.mp4", "out.mp4");
+        assert!(args.windows(2).any(|pair| pair
+            == [
+                "-vf",
+                "crop='min(iw,ih)':'min(iw,ih)',scale=384:384,setsar=1"
+            ]));
+        assert!(args.windows(2).any(|pair| pair == ["-t", "60.000"]));
+        assert!(args.iter().any(|arg| arg == "0:a:0?"));
+
+        let second = build_two_pass_args(2, &note, "in.mp4", "out.mp4", "log", 500_000, Some(360));
+        assert!(second.windows(2).any(|pair| pair
+            == [
+                "-vf",
+                "crop='min(iw,ih)':'min(iw,ih)',scale=384:384,setsar=1,scale=-2:360"
+            ]));
+        assert!(second.windows(2).any(|pair| pair == ["-t", "60.000"]));
+    }
+
+    #[test]
+    fn audio_extract_args_pick_first_track_and_codec() {
+        let mp3 = build_audio_extract_args(AudioFormat::Mp3, None, "in.mkv", "in.mp3");
+        assert!(mp3.windows(2).any(|pair| pair == ["-map", "0:a:0"]));
+        assert!(mp3.windows(2).any(|pair| pair == ["-c:a", "libmp3lame"]));
+        assert!(mp3.iter().any(|arg| arg == "-vn"));
+        assert!(!mp3.iter().any(|arg| arg == "-af"));
+        assert_eq!(mp3.last().map(String::as_str), Some("in.mp3"));
+
+        let voice = build_audio_extract_args(AudioFormat::Voice, None, "in.mkv", "in.ogg");
+        assert!(voice.windows(2).any(|pair| pair == ["-c:a", "libopus"]));
+        assert!(voice.windows(2).any(|pair| pair == ["-ac", "1"]));
+    }
+
+    #[test]
+    fn audio_extract_args_measure_then_normalise_loudness() {
+        let measure = build_audio_loudness_measure_args(-16.0, "in.ogg");
+        assert!(measure.windows(2).any(|pair| pair == ["-map", "0:a:0"]));
+        assert!(measure
+            .windows(2)
+            .any(|pair| pair[0] == "-af" && pair[1].ends_with("print_format=json")));
+        assert!(measure.ends_with(&["-f", "null", "-"].map(str::to_string)));
+
+        let correction = LoudnessCorrection {
+            target: -16.0,
+            measured: LoudnessMeasurement {
+                input_i: -30.0,
+                input_tp: -10.0,
+                input_lra: 7.0,
+                input_thresh: -40.0,
+                target_offset: 0.5,
+            },
+        };
+        let voice = build_audio_extract_args(
+            AudioFormat::Voice,
+            Some(&correction),
+            "in.ogg",
+            "in.voice.ogg",
+        );
+        let filters = &voice[voice.iter().position(|arg| arg == "-af").unwrap() + 1];
+        assert_eq!(filters, &correction.filter());
+        assert!(voice.windows(2).any(|pair| pair == ["-c:a", "libopus"]));
+    }
+
+    #[test]
+    fn image_args_apply_exif_orientation_once() {
+        let rotated = build_image_args(ImageFormat::Jpeg, Some(6), "in.heic", "in.jpg");
+        assert!(rotated.iter().any(|arg| arg == "-noautorotate"));
+        assert!(rotated
+            .iter()
+            .any(|arg| arg.starts_with("transpose=1,scale=")));
+        assert!(rotated.windows(2).any(|pair| pair == ["-frames:v", "1"]));
+        assert_eq!(rotated.last().map(String::as_str), Some("in.jpg"));
+
+        let upright = build_image_args(ImageFormat::Png, Some(1), "in.webp", "in.png");
+        assert!(!upright.iter().any(|arg| arg == "-noautorotate"));
+        assert!(upright.windows(2).any(|pair| pair == ["-pix_fmt", "rgba"]));
+    }
+
+    #[test]
+    fn video_sticker_args_decode_alpha_over_background() {
+        let settings = UnstickerSettings {
+            profile: profile(),
+            format: ClipFormat::Mp4,
+            background: [255, 128, 0],
+        };
+        let args = build_video_sticker_args(&settings, (512, 384), "in.webm", "in.mp4");
+        assert!(args.windows(2).any(|pair| pair == ["-c:v", "libvpx-vp9"]));
+        assert!(args.iter().any(|arg| arg == "color=c=0xff8000:s=512x384"));
+        assert!(args.windows(2).any(|pair| pair
+            == [
+                "-filter_complex",
+                "[1:v][0:v]overlay=shortest=1,scale=trunc(iw/2)*2:trunc(ih/2)*2[out]"
+            ]));
+        assert!(args.iter().any(|arg| arg == "-an"));
+        assert_eq!(args.last().map(String::as_str), Some("in.mp4"));
+    }
+
+    #[test]
+    fn animation_args_read_raw_frames_and_cap_gif_fps() {
+        let settings = UnstickerSettings {
+            profile: profile(),
+            format: ClipFormat::Gif,
+            background: [255, 255, 255],
+        };
+        let args = build_animation_args(&settings, (512, 512), 60.0, "in.gif");
+        assert!(args.windows(2).any(|pair| pair == ["-i", "pipe:0"]));
+        assert!(args.windows(2).any(|pair| pair == ["-s", "512x512"]));
+        assert!(!args.iter().any(|arg| arg == "-nostdin"));
+        assert!(args
+            .iter()
+            .any(|arg| arg.starts_with("[0:v]fps=30,split[a][b];[a]palettegen")));
+        assert!(!args.iter().any(|arg| arg == "-c:v"));
+
+        let slow = build_animation_args(&settings, (512, 512), 24.0, "in.gif");
+        assert!(slow.iter().any(|arg| arg.starts_with("[0:v]split")));
+    }
+
+    #[test]
+    fn sticker_args_fit_telegram_rules() {
+        let capped = FrameRatePlan {
+            target_fps: Some(STICKER_MAX_FPS),
+            capped: true,
+            constant: false,
+        };
+        let args = build_sticker_args(40, &capped, "in.mp4", "in.webm");
+        assert!(args.windows(2).any(|pair| pair == ["-c:v", "libvpx-vp9"]));
+        assert!(args.windows(2).any(|pair| pair == ["-crf", "40"]));
+        assert!(args.windows(2).any(|pair| pair == ["-t", "3"]));
+        assert!(args.iter().any(|arg| arg
+            == "scale='if(gte(iw,ih),512,-2)':'if(gte(iw,ih),-2,512)',fps=30.000,format=yuv420p"));
+        assert!(args.iter().any(|arg| arg == "-an"));
+        assert_eq!(args.last().map(String::as_str), Some("in.webm"));
+
+        // Медленный исходник сохраняет свою частоту.
+        let args = build_sticker_args(40, &FrameRatePlan::default(), "in.mp4", "in.webm");
+        assert!(args.iter().any(
+            |arg| arg == "scale='if(gte(iw,ih),512,-2)':'if(gte(iw,ih),-2,512)',format=yuv420p"
+        ));
+    }
+
+    #[test]
+    fn burns_text_subtitles_before_scaling() {
+        let mut burn = settings(profile(), OutputKind::Video);
+        burn.subtitles = SubtitlePlan::BurnText(1);
+
+        let args = build_ffmpeg_args(ConversionPlan::Transcode, &burn, "/tmp/a.mkv", "out.mp4");
+        assert!(args.windows(2).any(|pair| pair == ["-map", "0:v:0"]));
+        assert!(args.windows(2).any(|pair| pair
+            == [
+                "-vf",
+                "subtitles=filename=/tmp/a.mkv:si=1,scale=trunc(iw/2)*2:trunc(ih/2)*2"
+            ]));
+
+        let second =
+            build_two_pass_args(2, &burn, "/tmp/a.mkv", "out.mp4", "log", 500_000, Some(480));
+        assert!(second
+            .windows(2)
+            .any(|pair| pair == ["-vf", "subtitles=filename=/tmp/a.mkv:si=1,scale=-2:480"]));
+        assert!(!second.iter().any(|arg| arg == "-c:s"));
+    }
+
+    #[test]
+    fn overlays_bitmap_subtitles_through_filter_complex() {
+        let mut burn = settings(profile(), OutputKind::Video);
+        burn.subtitles = SubtitlePlan::BurnBitmap(0);
+
+        let args = build_two_pass_args(2, &burn, "in.mkv", "out.mp4", "log", 500_000, Some(720));
+        assert!(args
+            .windows(2)
+            .any(|pair| pair == ["-filter_complex", "[0:v:0][0:s:0]overlay,scale=-2:720[v]"]));
+        assert!(args.windows(2).any(|pair| pair == ["-map", "[v]"]));
+        assert!(!args.iter().any(|arg| arg == "0:v:0"));
+        assert!(args.iter().any(|arg| arg == "0:a:0?"));
+    }
+
+    #[test]
+    fn embeds_text_subtitles_as_mov_text_even_when_remuxing() {
+        let mut embed = settings(profile(), OutputKind::Video);
+        embed.subtitles = SubtitlePlan::Embed(vec![0, 2]);
+
+        let args = build_ffmpeg_args(ConversionPlan::Remux, &embed, "in.mkv", "out.mp4");
+        let copy = args.iter().position(|arg| arg == "copy").unwrap();
+        let codec = args.iter().position(|arg| arg == "mov_text").unwrap();
+        assert!(copy < codec);
+        assert!(args.windows(2).any(|pair| pair == ["-map", "0:s:0"]));
+        assert!(args.windows(2).any(|pair| pair == ["-map", "0:s:2"]));
+    }
+
+    #[test]
+    fn escapes_paths_inside_filter_options() {
+        assert_eq!(escape_filter_value("/tmp/a.mkv"), "/tmp/a.mkv");
+        assert_eq!(escape_filter_value("C:/it's"), "C\\\\:/it\\\\\\'s");
     }
 }
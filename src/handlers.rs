@@ -1,22 +1,65 @@
-use anyhow::{Context, Result as AnyResult};
+use anyhow::Result as AnyResult;
+use std::time::{Duration, Instant};
 use teloxide::{
     prelude::*,
-    types::{InputFile, MediaKind, MessageKind, ParseMode},
+    types::{MediaKind, MessageId, MessageKind, UserId},
 };
-use tokio::{fs, sync::Mutex, task};
+use tokio::{
+    fs,
+    sync::{watch, Mutex},
+};
+
+use crate::caption::parse_caption;
+use crate::config::Config;
+use crate::converter::{
+    AudioFormat, ClipFormat, ConversionError, ConvertedMedia, JobSettings, OutputKind,
+    StickerSource, SubtitlePlan, UnstickerSettings, STICKER_MAX_FPS,
+};
+use crate::jobs::{Admission, CancelOutcome, WorkerSlot};
+use crate::limits::{utc_day_index, QuotaDecision, QuotaKind, RateLimiter};
+use crate::lottie::UnsupportedFeatures;
+use crate::media::MediaInfo;
+use crate::policy::{
+    choose_audio_format, choose_audio_track, choose_frame_rate_plan, choose_hdr_plan,
+    choose_image_format, choose_output_kind, choose_output_target, choose_subtitle_plan,
+    has_audio_language, skip_reason, SkipReason,
+};
+use crate::progress::{format_progress, ProgressUpdate};
+use crate::store::NewJob;
+use crate::telegram::{job_file_name, Preview, SendOptions, Telegram, Upload, UploadKind};
+use crate::transcoder::{JobKey, Transcoder};
 
-use crate::converter::convert_video_to_mp4;
-use crate::limits::{utc_day_index, QuotaDecision, RateLimiter};
-use crate::telegram::download_file;
+/// Как часто можно редактировать статусное сообщение с прогрессом.
+const PROGRESS_EDIT_INTERVAL: Duration = Duration::from_secs(3);
+/// Эмодзи стикера, если в `/sticker add` его не указали.
+const DEFAULT_STICKER_EMOJI: &str = "🎬";
 
 const VIDEO_FILE_EXTENSIONS: &[&str] = &[
     "3gp", "avi", "flv", "m2ts", "m4v", "mkv", "mov", "mp4", "mpeg", "mpg", "mts", "webm", "wmv",
 ];
 
-fn has_video_extension(file_name: &str) -> bool {
+/// Аудиоформаты, которые Telegram не проигрывает из документа. MP3 сюда не
+/// входит: его клиенты и так показывают плеером.
+const AUDIO_FILE_EXTENSIONS: &[&str] = &[
+    "aac", "aif", "aiff", "amr", "ape", "awb", "flac", "m4a", "oga", "ogg", "opus", "wav", "wma",
+];
+const NATIVE_AUDIO_MIME_TYPES: &[&str] = &["audio/mpeg", "audio/mp3"];
+/// Картинки, для которых Telegram не показывает превью документа.
+const IMAGE_FILE_EXTENSIONS: &[&str] = &["avif", "bmp", "heic", "heif", "tif", "tiff", "webp"];
+const IMAGE_MIME_TYPES: &[&str] = &[
+    "image/avif",
+    "image/bmp",
+    "image/heic",
+    "image/heif",
+    "image/tiff",
+    "image/webp",
+    "image/x-ms-bmp",
+];
+
+fn has_extension(file_name: &str, extensions: &[&str]) -> bool {
     file_name
         .rsplit_once('.')
-        .map(|(_, ext)| VIDEO_FILE_EXTENSIONS.contains(&ext.to_ascii_lowercase().as_str()))
+        .map(|(_, ext)| extensions.contains(&ext.to_ascii_lowercase().as_str()))
         .unwrap_or(false)
 }
 
@@ -28,7 +71,34 @@ fn is_video_document(mime_type: Option<&str>, file_name: Option<&str>) -> bool {
         return true;
     }
 
-    file_name.map(has_video_extension).unwrap_or(false)
+    file_name
+        .map(|name| has_extension(name, VIDEO_FILE_EXTENSIONS))
+        .unwrap_or(false)
+}
+
+fn is_image_document(mime_type: Option<&str>, file_name: Option<&str>) -> bool {
+    if mime_type.is_some_and(|mime| IMAGE_MIME_TYPES.contains(&mime)) {
+        return true;
+    }
+
+    file_name
+        .map(|name| has_extension(name, IMAGE_FILE_EXTENSIONS))
+        .unwrap_or(false)
+}
+
+fn is_audio_document(mime_type: Option<&str>, file_name: Option<&str>) -> bool {
+    if let Some(mime) = mime_type {
+        if NATIVE_AUDIO_MIME_TYPES.contains(&mime) || mime.starts_with("video/") {
+            return false;
+        }
+        if mime.starts_with("audio/") {
+            return true;
+        }
+    }
+
+    file_name
+        .map(|name| has_extension(name, AUDIO_FILE_EXTENSIONS))
+        .unwrap_or(false)
 }
 
 fn sanitize_user_name(name: Option<&str>) -> String {
@@ -62,28 +132,456 @@ fn synthetic_quota_key(chat_id: i64, message_id: i64) -> i64 {
     chat_id.wrapping_mul(1_000_003).wrapping_add(message_id) ^ i64::MIN
 }
 
-pub async fn process_video(
-    bot: &Bot,
+/// Перепост тихо уходит в ветку оригинала ответом на то же сообщение, что
+/// и у него; подпись автора размечена MarkdownV2.
+fn repost_options(msg: &Message) -> SendOptions {
+    SendOptions {
+        reply_to: msg.reply_to_message().map(|reply_msg| reply_msg.id),
+        thread_id: msg.thread_id,
+        silent: true,
+        markdown: true,
+    }
+}
+
+/// Ответ на `target` в ветке сообщения `msg`.
+fn reply_options(msg: &Message, target: &Message) -> SendOptions {
+    SendOptions {
+        reply_to: Some(target.id),
+        thread_id: msg.thread_id,
+        ..SendOptions::default()
+    }
+}
+
+/// Превью, размеры и длительность готового видео для клиента.
+fn video_preview(media: &ConvertedMedia) -> Preview {
+    Preview {
+        thumbnail: media.thumbnail.clone(),
+        width: media.width,
+        height: media.height,
+        duration_secs: media.duration_secs.map(|duration| duration.round() as u32),
+    }
+}
+
+/// Подпись перепоста: исходная подпись без команд бота и ссылка на автора.
+fn repost_caption(msg: &Message, caption: Option<&str>) -> Option<String> {
+    let user = msg.from()?;
+    let signature = format!("send by [{}](tg://user?id={})", user.full_name(), user.id);
+    Some(caption.map_or_else(
+        || signature.clone(),
+        |existing_caption| format!("{}\n\n{}", existing_caption, signature),
+    ))
+}
+
+/// Разбирает команду вида `/name@bot args`: имя в нижнем регистре и аргументы.
+pub fn parse_command(text: &str) -> Option<(String, &str)> {
+    let rest = text.strip_prefix('/')?;
+    let (head, args) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+    let name = head.split('@').next().unwrap_or_default();
+    if name.is_empty() {
+        return None;
+    }
+    Some((name.to_ascii_lowercase(), args.trim()))
+}
+
+/// Отвечает текстом на сообщение пользователя в той же ветке.
+async fn reply_text(bot: &dyn Telegram, msg: &Message, text: &str) -> AnyResult<()> {
+    bot.send_text(msg.chat.id, text, &reply_options(msg, msg))
+        .await?;
+    Ok(())
+}
+
+/// Тихо отвечает на исходное сообщение служебным текстом: статусом
+/// конвертации или местом в очереди.
+async fn send_notice(bot: &dyn Telegram, msg: &Message, text: &str) -> AnyResult<MessageId> {
+    let options = SendOptions {
+        silent: true,
+        ..reply_options(msg, msg)
+    };
+    bot.send_text(msg.chat.id, text, &options).await
+}
+
+/// Задача для очереди обработчиков. Сообщение сохраняется целиком: после
+/// перезапуска по нему задача проходит тот же обработчик заново.
+fn new_job(
+    msg: &Message,
+    file_id: &str,
+    user_id: i64,
+    kind: QuotaKind,
+    consumed_day_index: u64,
+) -> NewJob {
+    NewJob {
+        chat_id: msg.chat.id.0,
+        message_id: msg.id.0,
+        user_id,
+        file_id: file_id.to_string(),
+        kind,
+        day_index: consumed_day_index,
+        message: serde_json::to_string(msg).unwrap_or_default(),
+    }
+}
+
+/// Скачивает файл задачи, запущенной сообщением `msg`.
+async fn download_job_file(bot: &dyn Telegram, msg: &Message, file_id: &str) -> AnyResult<String> {
+    let local_name = job_file_name(file_id, msg.chat.id.0, msg.id.0);
+    bot.download_file(file_id, &local_name).await
+}
+
+/// Занимает обработчика для конвертации. Если все заняты, сообщает место
+/// в очереди и ждёт; если очередь полна, отказывает, возвращает квоту и
+/// отдаёт `None`.
+async fn wait_for_worker(
+    bot: &dyn Telegram,
     msg: &Message,
     limiter: &Mutex<RateLimiter>,
-) -> AnyResult<()> {
-    let user = msg.from();
-    let user_id = quota_subject_key(msg);
-    let user_name = sanitize_user_name(user.map(|u| u.full_name()).as_deref());
+    transcoder: &dyn Transcoder,
+    job: NewJob,
+) -> AnyResult<Option<WorkerSlot>> {
+    let NewJob {
+        user_id,
+        kind,
+        day_index: consumed_day_index,
+        ..
+    } = job;
+    // С этого момента задачу можно отменить ответом `/cancel` на исходное
+    // сообщение или на сообщение «в очереди».
+    let track = |notice: Option<MessageId>| {
+        transcoder.track(&JobKey {
+            chat_id: msg.chat.id.0,
+            message_ids: std::iter::once(msg.id.0)
+                .chain(notice.map(|notice| notice.0))
+                .collect(),
+            owner_id: user_id,
+        })
+    };
+    let mut queued = match transcoder.workers().admit(&job) {
+        Admission::Started(mut slot) => {
+            slot.track(track(None));
+            return Ok(Some(slot));
+        }
+        Admission::Queued(queued) => queued,
+        Admission::Full => {
+            log::warn!(
+                "Conversion queue is full: chat_id={}, message_id={}, user_id={}, max_queued={}",
+                msg.chat.id,
+                msg.id,
+                user_id,
+                transcoder.workers().max_queued(),
+            );
+            limiter
+                .lock()
+                .await
+                .refund(user_id, kind, consumed_day_index);
+            reply_text(
+                bot,
+                msg,
+                "Too many files are waiting for conversion right now. Please send it again in a few minutes.",
+            )
+            .await?;
+            return Ok(None);
+        }
+    };
 
     log::info!(
-        "Incoming message: chat_id={}, message_id={}, user_id={}, user_name='{}'",
+        "Conversion queued: chat_id={}, message_id={}, user_id={}, position={}",
         msg.chat.id,
         msg.id,
         user_id,
-        user_name,
+        queued.position(),
+    );
+    let text = format!(
+        "Queued, position {}. Conversion starts when a worker is free.",
+        queued.position()
+    );
+    let notice = match send_notice(bot, msg, &text).await {
+        Ok(notice) => Some(notice),
+        Err(e) => {
+            log::warn!("Failed to send queue message: {:?}", e);
+            None
+        }
+    };
+    // Если бот перезапустится, пока задача ждёт, сообщение удалят при запуске.
+    queued.set_notice(notice.map(|notice| notice.0));
+    queued.track(track(notice));
+
+    let slot = queued.wait().await;
+    if let Some(notice) = notice {
+        if let Err(e) = bot.delete_message(msg.chat.id, notice).await {
+            log::warn!("Failed to delete queue message {}: {:?}", notice, e);
+        }
+    }
+    let Some(slot) = slot else {
+        log::info!(
+            "Queued conversion cancelled: chat_id={}, message_id={}, user_id={}",
+            msg.chat.id,
+            msg.id,
+            user_id,
+        );
+        reply_text(bot, msg, "Conversion cancelled.").await?;
+        return Ok(None);
+    };
+    if notice.is_some() {
+        slot.set_notice(None);
+    }
+    Ok(Some(slot))
+}
+
+/// Редактирует статусное сообщение не чаще `PROGRESS_EDIT_INTERVAL`, пока
+/// конвертер не закроет канал прогресса. Снимки внутри интервала не
+/// теряются: показывается последний из них, в том числе финальный. Без
+/// статусного сообщения просто дожидается конца конвертации.
+async fn update_status_message(
+    bot: &dyn Telegram,
+    chat_id: ChatId,
+    message_id: Option<MessageId>,
+    mut progress: watch::Receiver<Option<ProgressUpdate>>,
+    duration_secs: Option<f64>,
+) {
+    let Some(message_id) = message_id else {
+        while progress.changed().await.is_ok() {}
+        return;
+    };
+    let mut last_text = format_progress(None, None);
+    let mut last_edit = Instant::now();
+    let mut open = true;
+
+    while open && progress.changed().await.is_ok() {
+        // До конца интервала только копим снимки; закрытый канал означает,
+        // что пора показать финальный.
+        let next_edit = tokio::time::sleep_until((last_edit + PROGRESS_EDIT_INTERVAL).into());
+        tokio::pin!(next_edit);
+        loop {
+            tokio::select! {
+                _ = &mut next_edit => break,
+                changed = progress.changed() => if changed.is_err() {
+                    open = false;
+                    break;
+                },
+            }
+        }
+        let text = format_progress(progress.borrow_and_update().as_ref(), duration_secs);
+        if text == last_text {
+            continue;
+        }
+        if let Err(e) = bot.edit_text(chat_id, message_id, &text).await {
+            log::warn!("Failed to update progress message {}: {:?}", message_id, e);
+        }
+        last_text = text;
+        last_edit = Instant::now();
+    }
+}
+
+/// Анализирует файл; `None`, если анализ не удался и файл придётся
+/// перекодировать вслепую.
+async fn probe_source(transcoder: &dyn Transcoder, file_path: &str) -> Option<MediaInfo> {
+    match transcoder.probe(file_path).await {
+        Ok(info) => Some(info),
+        Err(e) => {
+            log::warn!("ffprobe failed for {}, transcoding: {:?}", file_path, e);
+            None
+        }
+    }
+}
+
+/// Применяет политику пропуска, если она разрешена: кружок, например, нужен
+/// даже из видео, которое Telegram и так проигрывает. Такое видео всё равно
+/// конвертируется, если ему нужны фильтры из `settings`.
+fn conversion_skip(
+    info: Option<&MediaInfo>,
+    allow_skip: bool,
+    settings: &JobSettings,
+) -> Option<SkipReason> {
+    let info = info.filter(|_| allow_skip)?;
+    match skip_reason(info)? {
+        SkipReason::NativelyPlayable if settings.requires_filters(info.has_audio()) => None,
+        reason => Some(reason),
+    }
+}
+
+/// Запускает конвертацию, публикуя прогресс в канал. Канал закрывается по
+/// завершении, чтобы задача обновления статуса могла закончиться.
+async fn convert_with_progress(
+    transcoder: &dyn Transcoder,
+    job: &JobKey,
+    file_path: &str,
+    info: Option<&MediaInfo>,
+    settings: &JobSettings,
+    progress_tx: watch::Sender<Option<ProgressUpdate>>,
+) -> Result<ConvertedMedia, ConversionError> {
+    transcoder
+        .convert(job, file_path, info, settings, &mut |update| {
+            progress_tx.send_replace(Some(update));
+        })
+        .await
+}
+
+/// Ответ пользователю на таймаут или отмену конвертации.
+fn stopped_conversion_text(error: &ConversionError) -> String {
+    match error {
+        ConversionError::TimedOut(timeout) => format!(
+            "Conversion took longer than {}s and was stopped.",
+            timeout.as_secs()
+        ),
+        _ => "Conversion cancelled.".to_string(),
+    }
+}
+
+/// Ответ на анимированный стикер, который рендерер нарисовал бы неправильно.
+fn unsupported_sticker_text(error: &anyhow::Error) -> String {
+    match error.downcast_ref::<UnsupportedFeatures>() {
+        Some(UnsupportedFeatures(features)) => format!(
+            "This animated sticker uses effects the bot cannot draw yet ({}), so it was not converted.",
+            features.join(", ")
+        ),
+        None => "This animated sticker cannot be converted.".to_string(),
+    }
+}
+
+/// Ответ на `lang=xxx`, для которого в файле нет дорожки.
+fn audio_language_missing_text(info: &MediaInfo, language: &str) -> String {
+    let tagged: Vec<&str> = info
+        .audio_streams()
+        .filter_map(|stream| stream.language.as_deref())
+        .collect();
+    let available = if tagged.is_empty() {
+        "the tracks have no language tags".to_string()
+    } else {
+        format!("available: {}", tagged.join(", "))
+    };
+    format!(
+        "This video has no \"{}\" audio track ({}), so the default track is kept.",
+        language, available
+    )
+}
+
+/// Разбирает аргументы `/audio`: без аргументов MP3, `voice` — голосовое.
+fn parse_audio_format(args: &str) -> Option<AudioFormat> {
+    match args.to_ascii_lowercase().as_str() {
+        "" | "mp3" => Some(AudioFormat::Mp3),
+        "voice" => Some(AudioFormat::Voice),
+        _ => None,
+    }
+}
+
+/// Разбирает аргументы `/unsticker [gif|mp4] [цвет]`: по умолчанию MP4 на
+/// белом фоне. Цвет — `white`, `black` или `#RRGGBB`.
+fn parse_unsticker_args(args: &str) -> Option<(ClipFormat, [u8; 3])> {
+    let mut format = ClipFormat::Mp4;
+    let mut background = [255, 255, 255];
+    for word in args.split_whitespace() {
+        match word.to_ascii_lowercase().as_str() {
+            "mp4" => format = ClipFormat::Mp4,
+            "gif" => format = ClipFormat::Gif,
+            "white" => background = [255, 255, 255],
+            "black" => background = [0, 0, 0],
+            other => background = parse_hex_color(other)?,
+        }
+    }
+    Some((format, background))
+}
+
+fn parse_hex_color(value: &str) -> Option<[u8; 3]> {
+    let hex = value.strip_prefix('#').unwrap_or(value);
+    if hex.len() != 6 || !hex.chars().all(|ch| ch.is_ascii_hexdigit()) {
+        return None;
+    }
+    let channel = |index: usize| u8::from_str_radix(&hex[index..index + 2], 16).ok();
+    Some([channel(0)?, channel(2)?, channel(4)?])
+}
+
+/// Куда отправить стикер, сделанный `/sticker`.
+#[derive(Debug, PartialEq, Eq)]
+enum StickerTarget {
+    /// Только в чат.
+    Chat,
+    /// В чат и в набор стикеров чата с этим эмодзи.
+    Set { emoji: String },
+}
+
+/// Разбирает аргументы `/sticker [add [эмодзи]]`.
+fn parse_sticker_args(args: &str) -> Option<StickerTarget> {
+    let mut words = args.split_whitespace();
+    match words.next() {
+        None => Some(StickerTarget::Chat),
+        Some(word) if word.eq_ignore_ascii_case("add") => {
+            let emoji = words.collect::<Vec<_>>().join("");
+            Some(StickerTarget::Set {
+                emoji: if emoji.is_empty() {
+                    DEFAULT_STICKER_EMOJI.to_string()
+                } else {
+                    emoji
+                },
+            })
+        }
+        Some(_) => None,
+    }
+}
+
+/// Имя набора стикеров чата. Bot API требует суффикс `_by_<имя бота>`.
+fn chat_sticker_set_name(chat_id: i64, bot_username: &str) -> String {
+    format!("chat{}_by_{}", chat_id.unsigned_abs(), bot_username)
+}
+
+/// Есть ли в файле звук. Если ffprobe не справился, решает сам ffmpeg.
+async fn has_audio_stream(transcoder: &dyn Transcoder, file_path: &str) -> bool {
+    match transcoder.probe(file_path).await {
+        Ok(info) => info.has_audio(),
+        Err(e) => {
+            log::warn!(
+                "ffprobe failed for {}, extracting anyway: {:?}",
+                file_path,
+                e
+            );
+            true
+        }
+    }
+}
+
+/// Обрабатывает `/cancel`, отправленный в ответ на видео или статус конвертации.
+pub async fn process_cancel(
+    bot: &dyn Telegram,
+    msg: &Message,
+    transcoder: &dyn Transcoder,
+) -> AnyResult<()> {
+    let Some(target) = msg.reply_to_message() else {
+        return reply_text(
+            bot,
+            msg,
+            "Reply /cancel to the video being converted or to its progress message.",
+        )
+        .await;
+    };
+
+    let outcome = transcoder.cancel(msg.chat.id.0, target.id.0, quota_subject_key(msg));
+    log::info!(
+        "Cancel requested: chat_id={}, target_message_id={}, outcome={:?}",
+        msg.chat.id,
+        target.id,
+        outcome,
     );
 
+    match outcome {
+        CancelOutcome::Cancelled => Ok(()),
+        CancelOutcome::NotOwner => {
+            reply_text(
+                bot,
+                msg,
+                "Only the sender of the video can cancel its conversion.",
+            )
+            .await
+        }
+        CancelOutcome::NotFound => {
+            reply_text(bot, msg, "There is no running conversion for that message.").await
+        }
+    }
+}
+
+/// Идентификатор файла, если сообщение содержит видео или видеодокумент.
+fn video_file_id(msg: &Message) -> Option<String> {
     let MessageKind::Common(common) = &msg.kind else {
-        return Ok(());
+        return None;
     };
 
-    let file_id = match &common.media_kind {
+    match &common.media_kind {
         MediaKind::Video(video) => {
             log::info!(
                 "Incoming video message: chat_id={}, message_id={}, mime={:?}, file_name={:?}, file_id={}",
@@ -93,7 +591,7 @@ pub async fn process_video(
                 video.video.file_name,
                 video.video.file.id
             );
-            video.video.file.id.to_string()
+            Some(video.video.file.id.to_string())
         }
         MediaKind::Document(document) => {
             let mime_type = document
@@ -112,21 +610,62 @@ pub async fn process_video(
                 document.document.file.id
             );
 
-            if !is_video_document(mime_type, file_name) {
-                return Ok(());
-            }
-
-            document.document.file.id.to_string()
+            is_video_document(mime_type, file_name).then(|| document.document.file.id.to_string())
         }
-        _ => return Ok(()),
+        _ => None,
+    }
+}
+
+/// Идентификатор документа, если `accepts` признаёт его MIME-тип или имя.
+fn document_file_id(
+    msg: &Message,
+    kind: &str,
+    accepts: fn(Option<&str>, Option<&str>) -> bool,
+) -> Option<String> {
+    let MessageKind::Common(common) = &msg.kind else {
+        return None;
     };
+    let MediaKind::Document(document) = &common.media_kind else {
+        return None;
+    };
+
+    let mime_type = document
+        .document
+        .mime_type
+        .as_ref()
+        .map(|mime| mime.essence_str());
+    let file_name = document.document.file_name.as_deref();
+    if !accepts(mime_type, file_name) {
+        return None;
+    }
+
+    log::info!(
+        "Incoming {} document: chat_id={}, message_id={}, mime={:?}, file_name={:?}, file_id={}",
+        kind,
+        msg.chat.id,
+        msg.id,
+        mime_type,
+        file_name,
+        document.document.file.id
+    );
+    Some(document.document.file.id.to_string())
+}
 
+/// Списывает квоту пользователя. При превышении сообщает об этом в чат и
+/// возвращает `None`, иначе возвращает день, за который списана квота.
+async fn consume_quota(
+    bot: &dyn Telegram,
+    msg: &Message,
+    limiter: &Mutex<RateLimiter>,
+    user_id: i64,
+    kind: QuotaKind,
+) -> AnyResult<Option<u64>> {
     let quota_decision = {
         let mut limiter = limiter.lock().await;
-        limiter.check_and_consume(user_id, utc_day_index(std::time::SystemTime::now()))
+        limiter.check_and_consume(user_id, kind, utc_day_index(std::time::SystemTime::now()))
     };
 
-    match quota_decision {
+    let consumed_day_index = match quota_decision {
         QuotaDecision::Allowed {
             user_count,
             user_limit,
@@ -135,7 +674,8 @@ pub async fn process_video(
             day_index,
         } => {
             log::info!(
-                "Quota allowed: day={}, user_id={}, user_count={}/{}, global_count={}/{}",
+                "Quota allowed: kind={:?}, day={}, user_id={}, user_count={}/{}, global_count={}/{}",
+                kind,
                 day_index,
                 user_id,
                 user_count,
@@ -143,6 +683,7 @@ pub async fn process_video(
                 global_count,
                 global_limit,
             );
+            day_index
         }
         QuotaDecision::UserLimitExceeded {
             user_count,
@@ -152,7 +693,8 @@ pub async fn process_video(
             day_index,
         } => {
             log::warn!(
-                "User daily limit exceeded: day={}, user_id={}, user_count={}/{}, global_count={}/{}",
+                "User daily limit exceeded: kind={:?}, day={}, user_id={}, user_count={}/{}, global_count={}/{}",
+                kind,
                 day_index,
                 user_id,
                 user_count,
@@ -160,15 +702,15 @@ pub async fn process_video(
                 global_count,
                 global_limit,
             );
-            bot.send_message(
-                msg.chat.id,
-                format!(
-                    "Daily limit exceeded: {}/{} videos for today. Try again tomorrow (UTC).",
-                    user_count, user_limit
-                ),
-            )
-            .await?;
-            return Ok(());
+            let text = format!(
+                "Daily limit exceeded: {}/{} {} for today. Try again tomorrow (UTC).",
+                user_count,
+                user_limit,
+                kind.noun()
+            );
+            bot.send_text(msg.chat.id, &text, &SendOptions::default())
+                .await?;
+            return Ok(None);
         }
         QuotaDecision::GlobalLimitExceeded {
             global_count,
@@ -176,102 +718,1798 @@ pub async fn process_video(
             day_index,
         } => {
             log::warn!(
-                "Global daily limit exceeded: day={}, user_id={}, global_count={}/{}",
+                "Global daily limit exceeded: kind={:?}, day={}, user_id={}, global_count={}/{}",
+                kind,
                 day_index,
                 user_id,
                 global_count,
                 global_limit,
             );
-            bot.send_message(
+            bot.send_text(
                 msg.chat.id,
                 "Service daily conversion limit is exhausted. Please try again tomorrow (UTC).",
+                &SendOptions::default(),
             )
             .await?;
-            return Ok(());
+            return Ok(None);
         }
-    }
+    };
 
-    // Скачиваем файл.
-    let file_path = download_file(bot, &file_id).await?;
+    Ok(Some(consumed_day_index))
+}
 
-    let mut converted_file_path: Option<String> = None;
+/// Обрабатывает `/audio [voice]`, отправленный в ответ на видео: извлекает
+/// звуковую дорожку и присылает её ответом на это видео.
+pub async fn process_audio(
+    bot: &dyn Telegram,
+    msg: &Message,
+    limiter: &Mutex<RateLimiter>,
+    transcoder: &dyn Transcoder,
+    config: &Config,
+    args: &str,
+) -> AnyResult<()> {
+    let Some(format) = parse_audio_format(args) else {
+        return reply_text(bot, msg, "Usage: reply /audio or /audio voice to a video.").await;
+    };
+    let Some((target, file_id)) = msg
+        .reply_to_message()
+        .and_then(|target| Some((target, video_file_id(target)?)))
+    else {
+        return reply_text(
+            bot,
+            msg,
+            "Reply /audio to a video to extract its soundtrack.",
+        )
+        .await;
+    };
+
+    let user_id = quota_subject_key(msg);
+    let Some(consumed_day_index) =
+        consume_quota(bot, msg, limiter, user_id, QuotaKind::Media).await?
+    else {
+        return Ok(());
+    };
+    let Some(_worker) = wait_for_worker(
+        bot,
+        msg,
+        limiter,
+        transcoder,
+        new_job(msg, &file_id, user_id, QuotaKind::Media, consumed_day_index),
+    )
+    .await?
+    else {
+        return Ok(());
+    };
+
+    let file_path = download_job_file(bot, msg, &file_id).await?;
+    let mut audio_file_path: Option<String> = None;
 
     let processing_result: AnyResult<()> = async {
-        // Клонируем file_path для передачи в замыкание, чтобы оригинал оставался доступен
-        let file_path_clone = file_path.clone();
+        if !has_audio_stream(transcoder, &file_path).await {
+            limiter
+                .lock()
+                .await
+                .refund(user_id, QuotaKind::Media, consumed_day_index);
+            return reply_text(bot, msg, "This video has no audio track.").await;
+        }
 
-        // Конвертация файла выполняется в отдельном блокирующем потоке.
-        let join_result = task::spawn_blocking(move || convert_video_to_mp4(&file_path_clone))
+        log::info!(
+            "Extracting audio: chat_id={}, message_id={}, target_message_id={}, format={:?}",
+            msg.chat.id,
+            msg.id,
+            target.id,
+            format,
+        );
+        let job = JobKey {
+            chat_id: msg.chat.id.0,
+            message_ids: vec![msg.id.0],
+            owner_id: user_id,
+        };
+        let loudness_target = config.profile_for_chat(msg.chat.id.0).1.loudness_target;
+        let audio_path = match transcoder
+            .extract_audio(&job, &file_path, format, loudness_target)
             .await
-            .context("Failed to join blocking task")?;
-        let converted_path = join_result.context("FFmpeg conversion failed")?;
-        converted_file_path = Some(converted_path.clone());
+        {
+            Ok(audio_path) => audio_path,
+            Err(error @ (ConversionError::TimedOut(_) | ConversionError::Cancelled)) => {
+                return reply_text(bot, msg, &stopped_conversion_text(&error)).await;
+            }
+            Err(error) => {
+                return Err(anyhow::Error::new(error).context("FFmpeg audio extraction failed"))
+            }
+        };
+        audio_file_path = Some(audio_path.clone());
 
-        // Формируем запрос на отправку видео.
-        let mut send_video_request = bot
-            .send_video(msg.chat.id, InputFile::file(&converted_path))
-            .disable_notification(true);
+        let kind = match format {
+            AudioFormat::Mp3 => UploadKind::Audio {
+                title: None,
+                performer: None,
+            },
+            AudioFormat::Voice => UploadKind::Voice,
+        };
+        let upload = Upload {
+            path: audio_path,
+            kind,
+            caption: None,
+        };
+        bot.send_upload(msg.chat.id, &upload, &reply_options(msg, target))
+            .await?;
+        Ok(())
+    }
+    .await;
 
-        if let Some(thread_id) = msg.thread_id {
-            send_video_request = send_video_request.message_thread_id(thread_id);
+    for path in std::iter::once(file_path).chain(audio_file_path) {
+        if let Err(e) = fs::remove_file(&path).await {
+            log::error!("Error deleting file {}: {:?}", path, e);
         }
+    }
 
-        if let Some(user) = msg.from() {
-            let full_name = user.full_name();
-            let signature = format!("send by [{}](tg://user?id={})", full_name, user.id);
-            let caption = msg.caption().map_or_else(
-                || signature.clone(),
-                |existing_caption| format!("{}\n\n{}", existing_caption, signature),
-            );
-            send_video_request = send_video_request
-                .caption(caption)
-                .allow_sending_without_reply(true);
-        }
+    processing_result
+}
 
-        if let Some(reply_msg) = msg.reply_to_message() {
-            send_video_request = send_video_request.reply_to_message_id(reply_msg.id);
-        }
+/// Обрабатывает `/unsticker`, отправленный в ответ на стикер: статичный
+/// стикер присылает PNG-документом, видео- и анимированный — роликом MP4
+/// или GIF на сплошном фоне.
+pub async fn process_unsticker(
+    bot: &dyn Telegram,
+    msg: &Message,
+    limiter: &Mutex<RateLimiter>,
+    transcoder: &dyn Transcoder,
+    config: &Config,
+    args: &str,
+) -> AnyResult<()> {
+    let Some((format, background)) = parse_unsticker_args(args) else {
+        return reply_text(
+            bot,
+            msg,
+            "Usage: reply /unsticker [gif|mp4] [white|black|#RRGGBB] to a sticker.",
+        )
+        .await;
+    };
+    let sticker = msg
+        .reply_to_message()
+        .and_then(|target| match &target.kind {
+            MessageKind::Common(common) => match &common.media_kind {
+                MediaKind::Sticker(sticker) => Some((target, &sticker.sticker)),
+                _ => None,
+            },
+            _ => None,
+        });
+    let Some((target, sticker)) = sticker else {
+        return reply_text(bot, msg, "Reply /unsticker to a sticker to convert it.").await;
+    };
 
-        send_video_request = send_video_request.parse_mode(ParseMode::MarkdownV2);
-        send_video_request.await?;
+    let source = if sticker.is_video() {
+        StickerSource::Video
+    } else if sticker.is_animated() {
+        StickerSource::Animated
+    } else {
+        StickerSource::Static
+    };
+    let quota_kind = match source {
+        StickerSource::Static => QuotaKind::Image,
+        StickerSource::Video | StickerSource::Animated => QuotaKind::Media,
+    };
+    let user_id = quota_subject_key(msg);
+    let Some(consumed_day_index) = consume_quota(bot, msg, limiter, user_id, quota_kind).await?
+    else {
+        return Ok(());
+    };
+    let Some(_worker) = wait_for_worker(
+        bot,
+        msg,
+        limiter,
+        transcoder,
+        new_job(
+            msg,
+            &sticker.file.id,
+            user_id,
+            quota_kind,
+            consumed_day_index,
+        ),
+    )
+    .await?
+    else {
+        return Ok(());
+    };
 
-        // Удаляем оригинальное сообщение.
-        bot.delete_message(msg.chat.id, msg.id).await?;
+    log::info!(
+        "Converting sticker: chat_id={}, message_id={}, target_message_id={}, source={:?}, format={:?}, background={:?}, set_name={:?}",
+        msg.chat.id,
+        msg.id,
+        target.id,
+        source,
+        format,
+        background,
+        sticker.set_name,
+    );
+    let file_path = download_job_file(bot, msg, &sticker.file.id).await?;
+    let mut converted_file_path: Option<String> = None;
+
+    let processing_result: AnyResult<()> = async {
+        let settings = UnstickerSettings {
+            profile: config.profile_for_chat(msg.chat.id.0).1.clone(),
+            format,
+            background,
+        };
+        let dimensions = (sticker.width > 0 && sticker.height > 0)
+            .then(|| (u32::from(sticker.width), u32::from(sticker.height)));
+        let job = JobKey {
+            chat_id: msg.chat.id.0,
+            message_ids: vec![msg.id.0],
+            owner_id: user_id,
+        };
+        let converted_path = match transcoder
+            .convert_sticker(&job, &file_path, source, dimensions, &settings)
+            .await
+        {
+            Ok(converted_path) => converted_path,
+            Err(error @ (ConversionError::TimedOut(_) | ConversionError::Cancelled)) => {
+                return reply_text(bot, msg, &stopped_conversion_text(&error)).await;
+            }
+            Err(ConversionError::Failed(error)) if error.is::<UnsupportedFeatures>() => {
+                limiter
+                    .lock()
+                    .await
+                    .refund(user_id, quota_kind, consumed_day_index);
+                return reply_text(bot, msg, &unsupported_sticker_text(&error)).await;
+            }
+            Err(error) => {
+                return Err(anyhow::Error::new(error).context("Sticker conversion failed"))
+            }
+        };
+        converted_file_path = Some(converted_path.clone());
 
+        // Документом, чтобы Telegram не перекодировал GIF и не сжимал PNG.
+        let upload = Upload {
+            path: converted_path,
+            kind: UploadKind::Document { thumbnail: None },
+            caption: None,
+        };
+        bot.send_upload(msg.chat.id, &upload, &reply_options(msg, target))
+            .await?;
         Ok(())
     }
     .await;
 
-    if let Err(e) = fs::remove_file(&file_path).await {
-        log::error!("Error deleting file {}: {:?}", file_path, e);
-    }
-    if let Some(converted_path) = converted_file_path {
-        if let Err(e) = fs::remove_file(&converted_path).await {
-            log::error!("Error deleting file {}: {:?}", converted_path, e);
+    for path in std::iter::once(file_path).chain(converted_file_path) {
+        if let Err(e) = fs::remove_file(&path).await {
+            log::error!("Error deleting file {}: {:?}", path, e);
         }
     }
 
     processing_result
 }
 
-#[cfg(test)]
-mod tests {
-    use super::{is_video_document, sanitize_user_name, synthetic_quota_key};
-
-    #[test]
-    fn detects_video_mime_type() {
-        assert!(is_video_document(
-            Some("video/x-matroska"),
-            Some("source.bin")
-        ));
+/// Обрабатывает `/sticker [add [эмодзи]]`, отправленный в ответ на видео:
+/// присылает видеостикер и по просьбе добавляет его в набор чата.
+pub async fn process_sticker(
+    bot: &dyn Telegram,
+    msg: &Message,
+    limiter: &Mutex<RateLimiter>,
+    transcoder: &dyn Transcoder,
+    config: &Config,
+    args: &str,
+) -> AnyResult<()> {
+    let Some(sticker_target) = parse_sticker_args(args) else {
+        return reply_text(
+            bot,
+            msg,
+            "Usage: reply /sticker or /sticker add [emoji] to a video.",
+        )
+        .await;
+    };
+    let set_owner = config.sticker_set_owner_for_chat(msg.chat.id.0);
+    if sticker_target != StickerTarget::Chat && set_owner.is_none() {
+        return reply_text(bot, msg, "This chat has no sticker set configured.").await;
     }
+    let Some((target, file_id)) = msg
+        .reply_to_message()
+        .and_then(|target| Some((target, video_file_id(target)?)))
+    else {
+        return reply_text(
+            bot,
+            msg,
+            "Reply /sticker to a video to turn it into a sticker.",
+        )
+        .await;
+    };
 
-    #[test]
-    fn detects_video_extension_without_video_mime() {
-        assert!(is_video_document(
-            Some("application/octet-stream"),
-            Some("source.MKV"),
-        ));
+    let user_id = quota_subject_key(msg);
+    let Some(consumed_day_index) =
+        consume_quota(bot, msg, limiter, user_id, QuotaKind::Media).await?
+    else {
+        return Ok(());
+    };
+    let Some(_worker) = wait_for_worker(
+        bot,
+        msg,
+        limiter,
+        transcoder,
+        new_job(msg, &file_id, user_id, QuotaKind::Media, consumed_day_index),
+    )
+    .await?
+    else {
+        return Ok(());
+    };
+
+    log::info!(
+        "Making sticker: chat_id={}, message_id={}, target_message_id={}, target={:?}",
+        msg.chat.id,
+        msg.id,
+        target.id,
+        sticker_target,
+    );
+    let file_path = download_job_file(bot, msg, &file_id).await?;
+    let mut sticker_file_path: Option<String> = None;
+
+    let processing_result: AnyResult<()> = async {
+        let job = JobKey {
+            chat_id: msg.chat.id.0,
+            message_ids: vec![msg.id.0],
+            owner_id: user_id,
+        };
+        // Частоту снижаем только у исходников быстрее лимита Telegram.
+        let media_info = probe_source(transcoder, &file_path).await;
+        let frame_rate = choose_frame_rate_plan(media_info.as_ref(), STICKER_MAX_FPS);
+        let sticker_path = match transcoder.make_sticker(&job, &file_path, &frame_rate).await {
+            Ok(sticker_path) => sticker_path,
+            Err(error @ (ConversionError::TimedOut(_) | ConversionError::Cancelled)) => {
+                return reply_text(bot, msg, &stopped_conversion_text(&error)).await;
+            }
+            Err(error) => return Err(anyhow::Error::new(error).context("Sticker encoding failed")),
+        };
+        sticker_file_path = Some(sticker_path.clone());
+
+        let upload = Upload {
+            path: sticker_path.clone(),
+            kind: UploadKind::Sticker,
+            caption: None,
+        };
+        bot.send_upload(msg.chat.id, &upload, &reply_options(msg, target))
+            .await?;
+
+        if let (StickerTarget::Set { emoji }, Some(owner)) = (&sticker_target, set_owner) {
+            match add_to_chat_sticker_set(bot, msg, UserId(owner), &sticker_path, emoji).await {
+                Ok(set_name) => {
+                    reply_text(
+                        bot,
+                        msg,
+                        &format!("Added to https://t.me/addstickers/{}", set_name),
+                    )
+                    .await?;
+                }
+                Err(e) => {
+                    log::warn!(
+                        "Failed to add sticker to set: chat_id={}, owner={}, error={:?}",
+                        msg.chat.id,
+                        owner,
+                        e
+                    );
+                    reply_text(
+                        bot,
+                        msg,
+                        "The sticker could not be added to the chat's sticker set.",
+                    )
+                    .await?;
+                }
+            }
+        }
+        Ok(())
+    }
+    .await;
+
+    for path in std::iter::once(file_path).chain(sticker_file_path) {
+        if let Err(e) = fs::remove_file(&path).await {
+            log::error!("Error deleting file {}: {:?}", path, e);
+        }
+    }
+
+    processing_result
+}
+
+/// Добавляет стикер в набор чата, создавая набор при первом добавлении.
+/// Возвращает имя набора.
+async fn add_to_chat_sticker_set(
+    bot: &dyn Telegram,
+    msg: &Message,
+    owner: UserId,
+    sticker_path: &str,
+    emoji: &str,
+) -> AnyResult<String> {
+    let set_name = chat_sticker_set_name(msg.chat.id.0, &bot.bot_username().await?);
+
+    if bot.sticker_set_exists(&set_name).await {
+        bot.add_sticker(owner, &set_name, None, sticker_path, emoji)
+            .await?;
+    } else {
+        let title: String = msg
+            .chat
+            .title()
+            .map_or_else(
+                || "Chat stickers".to_string(),
+                |title| format!("{} stickers", title),
+            )
+            .chars()
+            .take(64)
+            .collect();
+        log::info!(
+            "Creating sticker set: name={}, title={}, owner={}",
+            set_name,
+            title,
+            owner
+        );
+        bot.add_sticker(owner, &set_name, Some(&title), sticker_path, emoji)
+            .await?;
+    }
+    Ok(set_name)
+}
+
+/// Перекодирует аудиодокумент в MP3 с тегами или, для коротких моно-записей,
+/// в голосовое сообщение, и удаляет оригинал.
+async fn process_audio_document(
+    bot: &dyn Telegram,
+    msg: &Message,
+    limiter: &Mutex<RateLimiter>,
+    transcoder: &dyn Transcoder,
+    config: &Config,
+    file_id: &str,
+) -> AnyResult<()> {
+    let user_id = quota_subject_key(msg);
+    let Some(consumed_day_index) =
+        consume_quota(bot, msg, limiter, user_id, QuotaKind::Media).await?
+    else {
+        return Ok(());
+    };
+    let Some(_worker) = wait_for_worker(
+        bot,
+        msg,
+        limiter,
+        transcoder,
+        new_job(msg, file_id, user_id, QuotaKind::Media, consumed_day_index),
+    )
+    .await?
+    else {
+        return Ok(());
+    };
+
+    let file_path = download_job_file(bot, msg, file_id).await?;
+    let mut audio_file_path: Option<String> = None;
+
+    let processing_result: AnyResult<()> = async {
+        let media_info = match transcoder.probe(&file_path).await {
+            Ok(info) if !info.has_audio() => {
+                log::info!(
+                    "Skipping audio document without audio stream: chat_id={}, message_id={}",
+                    msg.chat.id,
+                    msg.id,
+                );
+                limiter.lock().await.refund(user_id, QuotaKind::Media, consumed_day_index);
+                return Ok(());
+            }
+            Ok(info) => Some(info),
+            Err(e) => {
+                log::warn!("ffprobe failed for {}, converting: {:?}", file_path, e);
+                None
+            }
+        };
+
+        let format = choose_audio_format(media_info.as_ref(), config.voice_max_duration_secs());
+        log::info!(
+            "Converting audio document: chat_id={}, message_id={}, format={:?}, channels={:?}, duration={:?}",
+            msg.chat.id,
+            msg.id,
+            format,
+            media_info.as_ref().and_then(|info| info.audio_channels()),
+            media_info.as_ref().and_then(|info| info.duration_secs),
+        );
+        let job = JobKey {
+            chat_id: msg.chat.id.0,
+            message_ids: vec![msg.id.0],
+            owner_id: user_id,
+        };
+        let loudness_target = config.profile_for_chat(msg.chat.id.0).1.loudness_target;
+        let audio_path = match transcoder
+            .extract_audio(&job, &file_path, format, loudness_target)
+            .await
+        {
+            Ok(audio_path) => audio_path,
+            Err(error @ (ConversionError::TimedOut(_) | ConversionError::Cancelled)) => {
+                return reply_text(bot, msg, &stopped_conversion_text(&error)).await;
+            }
+            Err(error) => {
+                return Err(anyhow::Error::new(error).context("FFmpeg audio conversion failed"))
+            }
+        };
+        audio_file_path = Some(audio_path.clone());
+
+        let kind = match format {
+            AudioFormat::Mp3 => {
+                let tags = media_info.map(|info| info.tags).unwrap_or_default();
+                UploadKind::Audio {
+                    title: tags.title,
+                    performer: tags.artist,
+                }
+            }
+            AudioFormat::Voice => UploadKind::Voice,
+        };
+        let upload = Upload {
+            path: audio_path,
+            kind,
+            caption: repost_caption(msg, msg.caption()),
+        };
+        bot.send_upload(msg.chat.id, &upload, &repost_options(msg))
+            .await?;
+
+        bot.delete_message(msg.chat.id, msg.id).await?;
+        Ok(())
+    }
+    .await;
+
+    for path in std::iter::once(file_path).chain(audio_file_path) {
+        if let Err(e) = fs::remove_file(&path).await {
+            log::error!("Error deleting file {}: {:?}", path, e);
+        }
+    }
+
+    processing_result
+}
+
+/// Конвертирует картинку-документ в фото (JPEG или PNG с прозрачностью) и
+/// удаляет оригинал. Картинки списывают свою квоту, отдельную от видео.
+async fn process_image_document(
+    bot: &dyn Telegram,
+    msg: &Message,
+    limiter: &Mutex<RateLimiter>,
+    transcoder: &dyn Transcoder,
+    file_id: &str,
+) -> AnyResult<()> {
+    let user_id = quota_subject_key(msg);
+    let Some(consumed_day_index) =
+        consume_quota(bot, msg, limiter, user_id, QuotaKind::Image).await?
+    else {
+        return Ok(());
+    };
+    let Some(_worker) = wait_for_worker(
+        bot,
+        msg,
+        limiter,
+        transcoder,
+        new_job(msg, file_id, user_id, QuotaKind::Image, consumed_day_index),
+    )
+    .await?
+    else {
+        return Ok(());
+    };
+
+    let file_path = download_job_file(bot, msg, file_id).await?;
+    let mut image_file_path: Option<String> = None;
+
+    let processing_result: AnyResult<()> = async {
+        let media_info = match transcoder.probe_image(&file_path).await {
+            Ok(info) => Some(info),
+            Err(e) => {
+                log::warn!("ffprobe failed for {}, converting: {:?}", file_path, e);
+                None
+            }
+        };
+
+        let format = choose_image_format(media_info.as_ref());
+        log::info!(
+            "Converting image document: chat_id={}, message_id={}, format={:?}, pix_fmt={:?}, orientation={:?}",
+            msg.chat.id,
+            msg.id,
+            format,
+            media_info.as_ref().and_then(|info| info.pixel_format()),
+            media_info.as_ref().and_then(|info| info.exif_orientation),
+        );
+        let job = JobKey {
+            chat_id: msg.chat.id.0,
+            message_ids: vec![msg.id.0],
+            owner_id: user_id,
+        };
+        let image_path = match transcoder
+            .convert_image(&job, &file_path, media_info.as_ref(), format)
+            .await
+        {
+            Ok(image_path) => image_path,
+            Err(error @ (ConversionError::TimedOut(_) | ConversionError::Cancelled)) => {
+                return reply_text(bot, msg, &stopped_conversion_text(&error)).await;
+            }
+            Err(error) => {
+                return Err(anyhow::Error::new(error).context("FFmpeg image conversion failed"))
+            }
+        };
+        image_file_path = Some(image_path.clone());
+
+        let upload = Upload {
+            path: image_path,
+            kind: UploadKind::Photo,
+            caption: repost_caption(msg, msg.caption()),
+        };
+        bot.send_upload(msg.chat.id, &upload, &repost_options(msg))
+            .await?;
+
+        bot.delete_message(msg.chat.id, msg.id).await?;
+        Ok(())
+    }
+    .await;
+
+    for path in std::iter::once(file_path).chain(image_file_path) {
+        if let Err(e) = fs::remove_file(&path).await {
+            log::error!("Error deleting file {}: {:?}", path, e);
+        }
+    }
+
+    processing_result
+}
+
+/// Обрабатывает обычное сообщение: аудио- и графические документы идут в
+/// свои конвейеры, видео и видеодокументы — в `process_video`.
+pub async fn process_message(
+    bot: &dyn Telegram,
+    msg: &Message,
+    limiter: &Mutex<RateLimiter>,
+    transcoder: &dyn Transcoder,
+    config: &Config,
+) -> AnyResult<()> {
+    if let Some(file_id) = document_file_id(msg, "audio", is_audio_document) {
+        return process_audio_document(bot, msg, limiter, transcoder, config, &file_id).await;
+    }
+    if let Some(file_id) = document_file_id(msg, "image", is_image_document) {
+        return process_image_document(bot, msg, limiter, transcoder, &file_id).await;
+    }
+    process_video(bot, msg, limiter, transcoder, config).await
+}
+
+async fn process_video(
+    bot: &dyn Telegram,
+    msg: &Message,
+    limiter: &Mutex<RateLimiter>,
+    transcoder: &dyn Transcoder,
+    config: &Config,
+) -> AnyResult<()> {
+    let user = msg.from();
+    let user_id = quota_subject_key(msg);
+    let user_name = sanitize_user_name(user.map(|u| u.full_name()).as_deref());
+
+    log::info!(
+        "Incoming message: chat_id={}, message_id={}, user_id={}, user_name='{}'",
+        msg.chat.id,
+        msg.id,
+        user_id,
+        user_name,
+    );
+
+    let Some(file_id) = video_file_id(msg) else {
+        return Ok(());
+    };
+    // Ошибку в диапазоне обрезки показываем до списания квоты и загрузки.
+    let (caption_options, caption_text) = match parse_caption(msg.caption()) {
+        Ok(parsed) => parsed,
+        Err(error) => return reply_text(bot, msg, &error.to_string()).await,
+    };
+    let video_note = caption_options.video_note || config.video_note_for_chat(msg.chat.id.0);
+
+    let Some(consumed_day_index) =
+        consume_quota(bot, msg, limiter, user_id, QuotaKind::Media).await?
+    else {
+        return Ok(());
+    };
+    let Some(worker) = wait_for_worker(
+        bot,
+        msg,
+        limiter,
+        transcoder,
+        new_job(msg, &file_id, user_id, QuotaKind::Media, consumed_day_index),
+    )
+    .await?
+    else {
+        return Ok(());
+    };
+
+    // Скачиваем файл.
+    let file_path = download_job_file(bot, msg, &file_id).await?;
+
+    let mut converted_media: Option<ConvertedMedia> = None;
+    let mut status_message_id: Option<MessageId> = None;
+
+    let processing_result: AnyResult<()> = async {
+        // Анализируем потоки файла, чтобы решить, нужна ли конвертация.
+        // Видео, которое Telegram и так проигрывает, оставляем в чате как есть,
+        // если его не нужно превращать в кружок или обрезать.
+        // Ради WebM и AV1 перекодируем и то, что Telegram проигрывает сам.
+        let preferred_target = config.output_target_for_chat(msg.chat.id.0);
+        let allow_skip =
+            !video_note && caption_options.trim.is_none() && preferred_target.allows_remux();
+        let media_info = probe_source(transcoder, &file_path).await;
+        // Отмену во время скачивания или анализа конвертация заметила бы
+        // сама, но видео, которое не нужно конвертировать, до неё не дойдёт.
+        if worker.is_cancelled() {
+            return reply_text(bot, msg, "Conversion cancelled.").await;
+        }
+        let source_duration = media_info.as_ref().and_then(|info| info.duration_secs);
+        if let (Some(trim), Some(duration)) = (caption_options.trim, source_duration) {
+            if trim.start_secs >= duration {
+                limiter
+                    .lock()
+                    .await
+                    .refund(user_id, QuotaKind::Media, consumed_day_index);
+                let text = format!(
+                    "The video is only {:.1}s long, so there is nothing to keep after {:.1}s.",
+                    duration, trim.start_secs
+                );
+                return reply_text(bot, msg, &text).await;
+            }
+        }
+
+        let (profile_name, profile) = config.profile_for_chat(msg.chat.id.0);
+        let output = if video_note {
+            OutputKind::VideoNote
+        } else {
+            choose_output_kind(media_info.as_ref(), config.animation_max_duration_secs())
+        };
+        let target = choose_output_target(output, preferred_target);
+        // Субтитры переносим только в обычное видео: у кружков и анимаций их нет.
+        let subtitles = match output {
+            OutputKind::Video => {
+                let (mode, language) = config.subtitles_for_chat(msg.chat.id.0);
+                choose_subtitle_plan(media_info.as_ref(), mode, language)
+            }
+            OutputKind::Animation | OutputKind::VideoNote => SubtitlePlan::None,
+        };
+        // `lang=` из подписи важнее языков, заданных для чата.
+        let audio_languages: Vec<&str> = caption_options
+            .audio_language
+            .as_deref()
+            .into_iter()
+            .chain(
+                config
+                    .audio_languages_for_chat(msg.chat.id.0)
+                    .iter()
+                    .map(String::as_str),
+            )
+            .collect();
+        let audio_track = choose_audio_track(media_info.as_ref(), &audio_languages);
+        if let (Some(language), Some(info)) =
+            (caption_options.audio_language.as_deref(), media_info.as_ref())
+        {
+            if info.has_audio() && !has_audio_language(info, language) {
+                let text = audio_language_missing_text(info, language);
+                if let Err(e) = reply_text(bot, msg, &text).await {
+                    log::warn!("Failed to report missing audio language: {:?}", e);
+                }
+            }
+        }
+        // Анимации и кружки всегда H.264, поэтому для них HDR тонмаппится.
+        let hdr = choose_hdr_plan(
+            media_info.as_ref(),
+            config.keep_10bit_for_chat(msg.chat.id.0) && target.supports_10bit(),
+        );
+        let frame_rate = choose_frame_rate_plan(media_info.as_ref(), config.max_frame_rate());
+        let settings = JobSettings {
+            profile: profile.clone(),
+            output,
+            target,
+            subtitles,
+            audio_track,
+            trim: caption_options.trim,
+            rotation: media_info.as_ref().map_or(0, MediaInfo::video_rotation),
+            hdr,
+            frame_rate,
+            loudness: None,
+        };
+        if let Some(reason) = conversion_skip(media_info.as_ref(), allow_skip, &settings) {
+            log::info!(
+                "Skipping conversion: chat_id={}, message_id={}, user_id={}, reason={}",
+                msg.chat.id,
+                msg.id,
+                user_id,
+                reason,
+            );
+            limiter
+                .lock()
+                .await
+                .refund(user_id, QuotaKind::Media, consumed_day_index);
+            return Ok(());
+        }
+        log::info!(
+            "Converting: chat_id={}, message_id={}, profile={}, output={:?}, target={:?}, subtitles={:?}, audio_track={}, trim={:?}, hdr={:?}, frame_rate={:?}",
+            msg.chat.id,
+            msg.id,
+            profile_name,
+            settings.output,
+            settings.target,
+            settings.subtitles,
+            settings.audio_track,
+            settings.trim,
+            settings.hdr,
+            settings.frame_rate,
+        );
+
+        match send_notice(bot, msg, &format_progress(None, None)).await {
+            Ok(message_id) => {
+                status_message_id = Some(message_id);
+                worker.set_notice(Some(message_id.0));
+            }
+            Err(e) => log::warn!("Failed to send progress message: {:?}", e),
+        }
+
+        // Задачу можно отменить ответом `/cancel` на видео или на статус.
+        let job = JobKey {
+            chat_id: msg.chat.id.0,
+            message_ids: std::iter::once(msg.id.0)
+                .chain(status_message_id.map(|message_id| message_id.0))
+                .collect(),
+            owner_id: user_id,
+        };
+        // Статус правится, пока конвертер не закроет канал прогресса.
+        let (progress_tx, progress_rx) = watch::channel(None);
+        let (conversion, ()) = tokio::join!(
+            convert_with_progress(
+                transcoder,
+                &job,
+                &file_path,
+                media_info.as_ref(),
+                &settings,
+                progress_tx,
+            ),
+            update_status_message(
+                bot,
+                msg.chat.id,
+                status_message_id,
+                progress_rx,
+                settings.output_duration_secs(source_duration),
+            ),
+        );
+
+        let converted = match conversion {
+            Ok(converted) => converted,
+            Err(error @ (ConversionError::TimedOut(_) | ConversionError::Cancelled)) => {
+                log::warn!(
+                    "Conversion stopped: chat_id={}, message_id={}, user_id={}, reason={}",
+                    msg.chat.id,
+                    msg.id,
+                    user_id,
+                    error,
+                );
+                return reply_text(bot, msg, &stopped_conversion_text(&error)).await;
+            }
+            Err(error) => {
+                // Файл не удалось сконвертировать не по вине пользователя.
+                limiter
+                    .lock()
+                    .await
+                    .refund(user_id, QuotaKind::Media, consumed_day_index);
+                return Err(anyhow::Error::new(error).context("FFmpeg conversion failed"));
+            }
+        };
+        log::info!(
+            "Converted: chat_id={}, message_id={}, width={:?}, height={:?}, duration={:?}, frame_rate={:?}",
+            msg.chat.id,
+            msg.id,
+            converted.width,
+            converted.height,
+            converted.duration_secs,
+            converted.frame_rate,
+        );
+        converted_media = Some(converted.clone());
+
+        // Формируем запрос на отправку результата.
+        let preview = video_preview(&converted);
+        let caption = repost_caption(msg, caption_text.as_deref());
+        let options = repost_options(msg);
+        match settings.output {
+            OutputKind::Video | OutputKind::Animation => {
+                let kind = if settings.output == OutputKind::Animation {
+                    UploadKind::Animation(preview)
+                } else if settings.target.is_mp4() {
+                    UploadKind::Video(preview)
+                } else {
+                    UploadKind::Document {
+                        thumbnail: preview.thumbnail,
+                    }
+                };
+                let upload = Upload {
+                    path: converted.path.clone(),
+                    kind,
+                    caption,
+                };
+                bot.send_upload(msg.chat.id, &upload, &options).await?;
+            }
+            OutputKind::VideoNote => {
+                // У кружков нет подписи, поэтому автора указываем ответом на кружок.
+                let upload = Upload {
+                    path: converted.path.clone(),
+                    kind: UploadKind::VideoNote(preview),
+                    caption: None,
+                };
+                let note = bot.send_upload(msg.chat.id, &upload, &options).await?;
+                if let Some(caption) = caption {
+                    let options = SendOptions {
+                        reply_to: Some(note),
+                        ..options
+                    };
+                    bot.send_text(msg.chat.id, &caption, &options).await?;
+                }
+            }
+        }
+
+        // Удаляем оригинальное сообщение.
+        bot.delete_message(msg.chat.id, msg.id).await?;
+
+        Ok(())
+    }
+    .await;
+
+    if let Some(message_id) = status_message_id {
+        if let Err(e) = bot.delete_message(msg.chat.id, message_id).await {
+            log::warn!("Failed to delete progress message {}: {:?}", message_id, e);
+        }
+    }
+
+    if let Err(e) = fs::remove_file(&file_path).await {
+        log::error!("Error deleting file {}: {:?}", file_path, e);
+    }
+    if let Some(converted) = converted_media {
+        for path in std::iter::once(converted.path).chain(converted.thumbnail) {
+            if let Err(e) = fs::remove_file(&path).await {
+                log::error!("Error deleting file {}: {:?}", path, e);
+            }
+        }
+    }
+
+    processing_result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        audio_language_missing_text, chat_sticker_set_name, conversion_skip, convert_with_progress,
+        has_audio_stream, is_audio_document, is_image_document, is_video_document,
+        parse_audio_format, parse_command, parse_sticker_args, parse_unsticker_args, probe_source,
+        process_cancel, process_video, sanitize_user_name, synthetic_quota_key,
+        unsupported_sticker_text, update_status_message, StickerTarget,
+    };
+    use crate::config::Config;
+    use crate::converter::{
+        AudioFormat, ClipFormat, ConversionError, FrameRatePlan, HdrPlan, JobSettings, OutputKind,
+        OutputTarget, SubtitlePlan,
+    };
+    use crate::jobs::CancelOutcome;
+    use crate::limits::{utc_day_index, QuotaDecision, QuotaKind, RateLimiter};
+    use crate::lottie::UnsupportedFeatures;
+    use crate::media::{Container, MediaInfo, MediaTags, StreamInfo, StreamKind};
+    use crate::policy::SkipReason;
+    use crate::progress::{ProgressStage, ProgressUpdate};
+    use crate::telegram::{FakeCall, FakeTelegram, Preview, Upload, UploadKind};
+    use crate::transcoder::{FakeOutcome, FakeTranscoder, JobKey, Transcoder};
+    use std::sync::Arc;
+    use std::time::{Duration, SystemTime};
+    use teloxide::types::{ChatId, Message, MessageId};
+    use tokio::sync::{watch, Mutex};
+
+    fn media(container: Container, video_codec: &str) -> MediaInfo {
+        MediaInfo {
+            container,
+            duration_secs: Some(10.0),
+            bit_rate: None,
+            streams: vec![StreamInfo {
+                index: 0,
+                kind: StreamKind::Video,
+                codec: Some(video_codec.to_string()),
+                pixel_format: Some("yuv420p".to_string()),
+                width: Some(1280),
+                height: Some(720),
+                ..Default::default()
+            }],
+            tags: MediaTags::default(),
+            exif_orientation: None,
+        }
+    }
+
+    fn settings() -> JobSettings {
+        JobSettings {
+            profile: Config::default().profile_for_chat(-100).1.clone(),
+            output: OutputKind::Video,
+            target: OutputTarget::Mp4H264,
+            subtitles: SubtitlePlan::None,
+            audio_track: 0,
+            trim: None,
+            rotation: 0,
+            hdr: HdrPlan::Sdr,
+            frame_rate: FrameRatePlan::default(),
+            loudness: None,
+        }
+    }
+
+    fn job() -> JobKey {
+        JobKey {
+            chat_id: -100,
+            message_ids: vec![10, 11],
+            owner_id: 7,
+        }
+    }
+
+    #[test]
+    fn skips_native_video() {
+        let info = media(Container::Mp4, "h264");
+
+        assert_eq!(
+            conversion_skip(Some(&info), true, &settings()),
+            Some(SkipReason::NativelyPlayable)
+        );
+    }
+
+    #[test]
+    fn converts_native_video_into_video_note() {
+        let info = media(Container::Mp4, "h264");
+
+        assert_eq!(conversion_skip(Some(&info), false, &settings()), None);
+    }
+
+    #[test]
+    fn converts_native_video_that_needs_filters() {
+        let info = media(Container::Mp4, "h264");
+        let capped = JobSettings {
+            frame_rate: FrameRatePlan {
+                target_fps: Some(60.0),
+                capped: true,
+                constant: false,
+            },
+            ..settings()
+        };
+        let tone_mapped = JobSettings {
+            hdr: HdrPlan::ToneMap,
+            ..settings()
+        };
+        let mut normalized = settings();
+        normalized.profile.loudness_target = Some(-16.0);
+        let mut with_audio = info.clone();
+        with_audio.streams.push(StreamInfo {
+            index: 1,
+            kind: StreamKind::Audio,
+            codec: Some("aac".to_string()),
+            ..Default::default()
+        });
+
+        assert_eq!(conversion_skip(Some(&info), true, &capped), None);
+        assert_eq!(conversion_skip(Some(&info), true, &tone_mapped), None);
+        assert_eq!(conversion_skip(Some(&with_audio), true, &normalized), None);
+        // Без звука выравнивать нечего.
+        assert_eq!(
+            conversion_skip(Some(&info), true, &normalized),
+            Some(SkipReason::NativelyPlayable)
+        );
+    }
+
+    #[tokio::test]
+    async fn pipeline_converts_when_probe_fails() {
+        let transcoder = FakeTranscoder::new(None, FakeOutcome::Succeed);
+
+        let media_info = probe_source(&transcoder, "/tmp/in.mkv").await;
+        assert_eq!(media_info, None);
+        assert_eq!(conversion_skip(None, true, &settings()), None);
+    }
+
+    #[tokio::test]
+    async fn pipeline_converts_and_reports_progress() {
+        let info = media(Container::Matroska, "vp9");
+        let transcoder = FakeTranscoder::new(Some(info.clone()), FakeOutcome::Succeed)
+            .with_progress(vec![ProgressUpdate {
+                out_time_secs: 5.0,
+                speed: Some(2.0),
+                finished: false,
+                stage: ProgressStage::Converting,
+            }]);
+
+        let media_info = probe_source(&transcoder, "/tmp/in.mkv").await;
+        assert_eq!(media_info.as_ref(), Some(&info));
+
+        let (progress_tx, mut progress_rx) = watch::channel(None);
+        let converted = convert_with_progress(
+            &transcoder,
+            &job(),
+            "/tmp/in.mkv",
+            media_info.as_ref(),
+            &settings(),
+            progress_tx,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(converted.path, "/tmp/in.mkv.fake.mp4");
+        assert_eq!(converted.thumbnail.as_deref(), Some("/tmp/in.mkv.fake.jpg"));
+        assert_eq!((converted.width, converted.height), (Some(1280), Some(720)));
+        assert_eq!(converted.duration_secs, Some(10.0));
+        assert_eq!(
+            progress_rx
+                .borrow_and_update()
+                .map(|update| update.out_time_secs),
+            Some(5.0)
+        );
+        // Канал закрыт, задача обновления статуса завершится.
+        assert!(progress_rx.changed().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn pipeline_surfaces_conversion_failure() {
+        let transcoder = FakeTranscoder::new(None, FakeOutcome::Fail("broken input".to_string()));
+        let (progress_tx, _progress_rx) = watch::channel(None);
+
+        let result = convert_with_progress(
+            &transcoder,
+            &job(),
+            "/tmp/in.mkv",
+            None,
+            &settings(),
+            progress_tx,
+        )
+        .await;
+
+        assert!(matches!(result, Err(ConversionError::Failed(_))));
+    }
+
+    #[tokio::test]
+    async fn pipeline_cancels_hanging_conversion() {
+        let transcoder = Arc::new(FakeTranscoder::new(
+            None,
+            FakeOutcome::Hang {
+                timeout: Duration::from_secs(60),
+            },
+        ));
+        let (progress_tx, _progress_rx) = watch::channel(None);
+
+        let running = {
+            let transcoder = Arc::clone(&transcoder);
+            tokio::spawn(async move {
+                convert_with_progress(
+                    &*transcoder,
+                    &job(),
+                    "/tmp/in.mkv",
+                    None,
+                    &settings(),
+                    progress_tx,
+                )
+                .await
+            })
+        };
+        // Ждём, пока задача зарегистрируется, и отменяем её по статусному сообщению.
+        let mut outcome = CancelOutcome::NotFound;
+        for _ in 0..100 {
+            outcome = transcoder.cancel(-100, 11, 7);
+            if outcome != CancelOutcome::NotFound {
+                break;
+            }
+            tokio::task::yield_now().await;
+        }
+
+        assert_eq!(outcome, CancelOutcome::Cancelled);
+        assert!(matches!(
+            running.await.unwrap(),
+            Err(ConversionError::Cancelled)
+        ));
+    }
+
+    #[tokio::test]
+    async fn pipeline_times_out_hanging_conversion() {
+        let transcoder = FakeTranscoder::new(
+            None,
+            FakeOutcome::Hang {
+                timeout: Duration::from_millis(10),
+            },
+        );
+        let (progress_tx, _progress_rx) = watch::channel(None);
+
+        let result = convert_with_progress(
+            &transcoder,
+            &job(),
+            "/tmp/in.mkv",
+            None,
+            &settings(),
+            progress_tx,
+        )
+        .await;
+
+        assert!(matches!(result, Err(ConversionError::TimedOut(_))));
+        assert_eq!(transcoder.cancel(-100, 10, 7), CancelOutcome::NotFound);
+    }
+
+    #[tokio::test]
+    async fn audio_extraction_requires_audio_stream() {
+        let silent = FakeTranscoder::new(Some(media(Container::Mp4, "h264")), FakeOutcome::Succeed);
+        assert!(!has_audio_stream(&silent, "/tmp/in.mp4").await);
+
+        let mut with_audio = media(Container::Matroska, "vp9");
+        with_audio.streams.push(StreamInfo {
+            index: 1,
+            kind: StreamKind::Audio,
+            codec: Some("opus".to_string()),
+            pixel_format: None,
+            width: None,
+            height: None,
+            ..Default::default()
+        });
+        let transcoder = FakeTranscoder::new(Some(with_audio), FakeOutcome::Succeed);
+        assert!(has_audio_stream(&transcoder, "/tmp/in.mkv").await);
+        assert_eq!(
+            transcoder
+                .extract_audio(&job(), "/tmp/in.mkv", AudioFormat::Voice, None)
+                .await
+                .unwrap(),
+            "/tmp/in.mkv.fake.voice"
+        );
+
+        // Без ffprobe решение остаётся за ffmpeg.
+        let unprobed = FakeTranscoder::new(None, FakeOutcome::Succeed);
+        assert!(has_audio_stream(&unprobed, "/tmp/in.mkv").await);
+    }
+
+    /// Видео `file_id` с подписью от пользователя 7, сообщение 10 в чате -100.
+    fn video_message(file_id: &str) -> Message {
+        serde_json::from_value(serde_json::json!({
+            "message_id": 10,
+            "date": 1_700_000_000,
+            "chat": {"id": -100, "type": "supergroup", "title": "Test"},
+            "from": {"id": 7, "is_bot": false, "first_name": "Ann"},
+            "caption": "cat",
+            "video": {
+                "file_id": file_id,
+                "file_unique_id": file_id,
+                "width": 1280,
+                "height": 720,
+                "duration": 10,
+                "mime_type": "video/mp4"
+            }
+        }))
+        .unwrap()
+    }
+
+    /// Сколько видео пользователь 7 уже потратил за сегодня.
+    async fn used_quota(limiter: &Mutex<RateLimiter>) -> u32 {
+        let decision = limiter.lock().await.check_and_consume(
+            7,
+            QuotaKind::Media,
+            utc_day_index(SystemTime::now()),
+        );
+        match decision {
+            QuotaDecision::Allowed { user_count, .. } => user_count - 1,
+            other => panic!("unexpected quota decision: {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn status_message_shows_final_progress() {
+        let bot = FakeTelegram::new();
+        let (progress_tx, progress_rx) = watch::channel(None);
+        let update = ProgressUpdate {
+            out_time_secs: 2.0,
+            speed: Some(1.0),
+            finished: false,
+            stage: ProgressStage::MeasuringLoudness,
+        };
+        let sender = async move {
+            progress_tx.send_replace(Some(update));
+            progress_tx.send_replace(Some(ProgressUpdate {
+                out_time_secs: 8.0,
+                stage: ProgressStage::Converting,
+                ..update
+            }));
+        };
+
+        tokio::join!(
+            sender,
+            update_status_message(
+                &bot,
+                ChatId(-100),
+                Some(MessageId(5)),
+                progress_rx,
+                Some(10.0)
+            ),
+        );
+
+        // Оба снимка пришли в одном интервале: виден последний, а не первый.
+        assert_eq!(
+            bot.calls(),
+            [FakeCall::Edit {
+                message_id: MessageId(5),
+                text: "converting… 80% (1.0x)".to_string(),
+            }]
+        );
+    }
+
+    #[tokio::test]
+    async fn process_video_skips_native_video_and_refunds_quota() {
+        let bot = FakeTelegram::new();
+        let limiter = Mutex::new(RateLimiter::new(10, 50));
+        let transcoder =
+            FakeTranscoder::new(Some(media(Container::Mp4, "h264")), FakeOutcome::Succeed);
+
+        process_video(
+            &bot,
+            &video_message("skip-native"),
+            &limiter,
+            &transcoder,
+            &Config::default(),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(bot.calls(), [FakeCall::Download("skip-native".to_string())]);
+        assert_eq!(used_quota(&limiter).await, 0);
+    }
+
+    #[tokio::test]
+    async fn process_video_converts_high_frame_rate_native_video() {
+        let bot = FakeTelegram::new();
+        let limiter = Mutex::new(RateLimiter::new(10, 50));
+        let mut info = media(Container::Mp4, "h264");
+        info.streams[0].frame_rate = Some(120.0);
+        info.streams[0].avg_frame_rate = Some(120.0);
+        let transcoder = FakeTranscoder::new(Some(info), FakeOutcome::Succeed);
+
+        process_video(
+            &bot,
+            &video_message("convert-120fps"),
+            &limiter,
+            &transcoder,
+            &Config::default(),
+        )
+        .await
+        .unwrap();
+
+        assert!(bot
+            .calls()
+            .iter()
+            .any(|call| matches!(call, FakeCall::Upload { .. })));
+        assert_eq!(used_quota(&limiter).await, 1);
+    }
+
+    #[tokio::test]
+    async fn process_video_converts_and_reposts_with_preview() {
+        let bot = FakeTelegram::new();
+        let limiter = Mutex::new(RateLimiter::new(10, 50));
+        let mut info = media(Container::Matroska, "vp9");
+        info.streams.push(StreamInfo {
+            index: 1,
+            kind: StreamKind::Audio,
+            codec: Some("opus".to_string()),
+            ..Default::default()
+        });
+        let transcoder = FakeTranscoder::new(Some(info), FakeOutcome::Succeed);
+
+        process_video(
+            &bot,
+            &video_message("convert-vp9"),
+            &limiter,
+            &transcoder,
+            &Config::default(),
+        )
+        .await
+        .unwrap();
+
+        let calls = bot.calls();
+        let [FakeCall::Download(_), FakeCall::Text {
+            options: status_options,
+            ..
+        }, FakeCall::Upload {
+            upload, options, ..
+        }, FakeCall::Delete(original), FakeCall::Delete(status)] = calls.as_slice()
+        else {
+            panic!("unexpected calls: {:?}", calls);
+        };
+        assert_eq!(status_options.reply_to, Some(MessageId(10)));
+        assert!(status_options.silent);
+        let downloaded = std::env::temp_dir().join("convert-vp9.-100_10.bin");
+        let downloaded = downloaded.to_str().unwrap();
+        assert_eq!(
+            upload,
+            &Upload {
+                path: format!("{}.fake.mp4", downloaded),
+                kind: UploadKind::Video(Preview {
+                    thumbnail: Some(format!("{}.fake.jpg", downloaded)),
+                    width: Some(1280),
+                    height: Some(720),
+                    duration_secs: Some(10),
+                }),
+                caption: Some("cat\n\nsend by [Ann](tg://user?id=7)".to_string()),
+            }
+        );
+        assert!(options.silent && options.markdown);
+        assert_eq!(*original, MessageId(10));
+        assert_eq!(*status, MessageId(1001));
+        assert_eq!(used_quota(&limiter).await, 1);
+    }
+
+    #[tokio::test]
+    async fn process_video_sends_without_thumbnail_when_preview_fails() {
+        let bot = FakeTelegram::new();
+        let limiter = Mutex::new(RateLimiter::new(10, 50));
+        let transcoder = FakeTranscoder::new(
+            Some(media(Container::Matroska, "vp9")),
+            FakeOutcome::Succeed,
+        )
+        .without_thumbnail();
+
+        process_video(
+            &bot,
+            &video_message("convert-no-thumb"),
+            &limiter,
+            &transcoder,
+            &Config::default(),
+        )
+        .await
+        .unwrap();
+
+        let upload = bot
+            .calls()
+            .into_iter()
+            .find_map(|call| match call {
+                FakeCall::Upload { upload, .. } => Some(upload),
+                _ => None,
+            })
+            .expect("converted video must be sent");
+        assert_eq!(
+            upload.kind,
+            UploadKind::Animation(Preview {
+                thumbnail: None,
+                width: Some(1280),
+                height: Some(720),
+                duration_secs: Some(10),
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn process_video_sends_webm_as_document() {
+        let bot = FakeTelegram::new();
+        let limiter = Mutex::new(RateLimiter::new(10, 50));
+        let config = Config::parse(
+            r#"[chats."-100"]
+               output_target = "webm-vp9""#,
+        )
+        .unwrap();
+        let mut info = media(Container::Mp4, "h264");
+        info.streams.push(StreamInfo {
+            index: 1,
+            kind: StreamKind::Audio,
+            codec: Some("aac".to_string()),
+            ..Default::default()
+        });
+        let transcoder = FakeTranscoder::new(Some(info), FakeOutcome::Succeed);
+
+        process_video(
+            &bot,
+            &video_message("convert-webm"),
+            &limiter,
+            &transcoder,
+            &config,
+        )
+        .await
+        .unwrap();
+
+        let upload = bot
+            .calls()
+            .into_iter()
+            .find_map(|call| match call {
+                FakeCall::Upload { upload, .. } => Some(upload),
+                _ => None,
+            })
+            .expect("converted video must be sent");
+        let downloaded = std::env::temp_dir().join("convert-webm.-100_10.bin");
+        assert_eq!(
+            upload.kind,
+            UploadKind::Document {
+                thumbnail: Some(format!("{}.fake.jpg", downloaded.to_str().unwrap())),
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn process_video_tone_maps_hdr_video_note_for_10bit_chat() {
+        let bot = FakeTelegram::new();
+        let limiter = Mutex::new(RateLimiter::new(10, 50));
+        let config = Config::parse(
+            r#"[chats."-100"]
+               output_target = "mp4-hevc"
+               video_note = true
+               keep_10bit = true"#,
+        )
+        .unwrap();
+        let mut info = media(Container::Mp4, "hevc");
+        info.streams[0].pixel_format = Some("yuv420p10le".to_string());
+        info.streams[0].color_transfer = Some("smpte2084".to_string());
+        let transcoder = FakeTranscoder::new(Some(info), FakeOutcome::Succeed);
+
+        process_video(
+            &bot,
+            &video_message("convert-hdr-note"),
+            &limiter,
+            &transcoder,
+            &config,
+        )
+        .await
+        .unwrap();
+
+        let converted = transcoder.converted_settings();
+        assert_eq!(converted.len(), 1);
+        assert_eq!(converted[0].output, OutputKind::VideoNote);
+        assert_eq!(converted[0].target, OutputTarget::Mp4H264);
+        assert_eq!(converted[0].hdr, HdrPlan::ToneMap);
+    }
+
+    #[tokio::test]
+    async fn second_video_from_chat_waits_in_worker_queue() {
+        let bot = FakeTelegram::new();
+        let limiter = Mutex::new(RateLimiter::new(10, 50));
+        let transcoder = FakeTranscoder::new(
+            Some(media(Container::Matroska, "vp9")),
+            FakeOutcome::Hang {
+                timeout: Duration::from_millis(50),
+            },
+        );
+        let config = Config::default();
+        let first = video_message("queue-first");
+        let mut second = video_message("queue-second");
+        second.id = MessageId(11);
+
+        let (first_result, second_result) = tokio::join!(
+            process_video(&bot, &first, &limiter, &transcoder, &config),
+            process_video(&bot, &second, &limiter, &transcoder, &config),
+        );
+
+        first_result.unwrap();
+        second_result.unwrap();
+        assert!(bot.calls().iter().any(|call| matches!(
+            call,
+            FakeCall::Text { text, options, .. }
+                if text.starts_with("Queued, position 1.")
+                    && options.reply_to == Some(MessageId(11))
+        )));
+    }
+
+    /// `/cancel` от автора видео в ответ на `target`.
+    fn cancel_message(message_id: i32, target: &Message) -> Message {
+        serde_json::from_value(serde_json::json!({
+            "message_id": message_id,
+            "date": 1_700_000_010,
+            "chat": {"id": -100, "type": "supergroup", "title": "Test"},
+            "from": {"id": 7, "is_bot": false, "first_name": "Ann"},
+            "text": "/cancel",
+            "reply_to_message": serde_json::to_value(target).unwrap()
+        }))
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn cancel_stops_blocked_conversion() {
+        let bot = FakeTelegram::new();
+        let limiter = Mutex::new(RateLimiter::new(10, 50));
+        let transcoder = FakeTranscoder::new(
+            Some(media(Container::Matroska, "vp9")),
+            FakeOutcome::Hang {
+                timeout: Duration::from_secs(30),
+            },
+        );
+        let config = Config::default();
+        let video = video_message("cancel-blocked");
+        let cancel = cancel_message(12, &video);
+        let cancel_when_running = async {
+            // Статус отправляется прямо перед конвертацией, а та висит.
+            while bot.calls().len() < 2 {
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+            process_cancel(&bot, &cancel, &transcoder).await
+        };
+
+        let (converted, cancelled) = tokio::time::timeout(Duration::from_secs(5), async {
+            tokio::join!(
+                process_video(&bot, &video, &limiter, &transcoder, &config),
+                cancel_when_running,
+            )
+        })
+        .await
+        .expect("/cancel must not wait for the conversion");
+
+        converted.unwrap();
+        cancelled.unwrap();
+        assert!(bot.calls().iter().any(|call| matches!(
+            call,
+            FakeCall::Text { text, .. } if text == "Conversion cancelled."
+        )));
+    }
+
+    #[tokio::test]
+    async fn cancel_removes_queued_video_from_queue() {
+        let bot = FakeTelegram::new();
+        let limiter = Mutex::new(RateLimiter::new(10, 50));
+        let transcoder = FakeTranscoder::new(
+            Some(media(Container::Matroska, "vp9")),
+            FakeOutcome::Hang {
+                timeout: Duration::from_secs(30),
+            },
+        );
+        let config = Config::default();
+        let running = video_message("cancel-running");
+        let mut queued = video_message("cancel-queued");
+        queued.id = MessageId(11);
+        let queue_notice = || {
+            bot.calls().iter().position(
+                |call| matches!(call, FakeCall::Text { text, .. } if text.starts_with("Queued")),
+            )
+        };
+        let cancel_both = async {
+            while queue_notice().is_none() {
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+            process_cancel(&bot, &cancel_message(12, &queued), &transcoder).await?;
+            process_cancel(&bot, &cancel_message(13, &running), &transcoder).await
+        };
+
+        let (running_result, queued_result, cancelled) =
+            tokio::time::timeout(Duration::from_secs(5), async {
+                tokio::join!(
+                    process_video(&bot, &running, &limiter, &transcoder, &config),
+                    process_video(&bot, &queued, &limiter, &transcoder, &config),
+                    cancel_both,
+                )
+            })
+            .await
+            .expect("/cancel must reach a job waiting in the queue");
+
+        running_result.unwrap();
+        queued_result.unwrap();
+        cancelled.unwrap();
+        let calls = bot.calls();
+        // Номера сообщений фейк выдаёт по порядку, начиная с 1000.
+        let notice = MessageId(1000 + queue_notice().unwrap() as i32);
+        assert!(calls.contains(&FakeCall::Delete(notice)));
+        assert!(calls.iter().any(|call| matches!(
+            call,
+            FakeCall::Text { text, options, .. }
+                if text == "Conversion cancelled." && options.reply_to == Some(MessageId(11))
+        )));
+        assert!(!calls.contains(&FakeCall::Download("cancel-queued".to_string())));
+        assert!(!calls.iter().any(|call| matches!(
+            call,
+            FakeCall::Text { text, .. } if text.starts_with("There is no running conversion")
+        )));
+    }
+
+    #[tokio::test]
+    async fn process_video_refunds_quota_when_conversion_fails() {
+        let bot = FakeTelegram::new();
+        let limiter = Mutex::new(RateLimiter::new(10, 50));
+        let transcoder = FakeTranscoder::new(
+            Some(media(Container::Matroska, "vp9")),
+            FakeOutcome::Fail("broken input".to_string()),
+        );
+
+        let result = process_video(
+            &bot,
+            &video_message("convert-fails"),
+            &limiter,
+            &transcoder,
+            &Config::default(),
+        )
+        .await;
+
+        assert!(result.is_err());
+        let calls = bot.calls();
+        assert!(
+            !calls.iter().any(|call| matches!(
+                call,
+                FakeCall::Upload { .. } | FakeCall::Delete(MessageId(10))
+            )),
+            "{:?}",
+            calls
+        );
+        assert_eq!(calls.last(), Some(&FakeCall::Delete(MessageId(1001))));
+        assert_eq!(used_quota(&limiter).await, 0);
+    }
+
+    #[test]
+    fn explains_missing_audio_language() {
+        let mut info = media(Container::Matroska, "h264");
+        for language in [Some("eng"), None, Some("rus")] {
+            info.streams.push(StreamInfo {
+                index: info.streams.len() as u32,
+                kind: StreamKind::Audio,
+                language: language.map(str::to_string),
+                ..Default::default()
+            });
+        }
+        assert_eq!(
+            audio_language_missing_text(&info, "jpn"),
+            "This video has no \"jpn\" audio track (available: eng, rus), so the default track is kept."
+        );
+    }
+
+    #[test]
+    fn explains_unsupported_sticker_features() {
+        let error = anyhow::Error::new(UnsupportedFeatures(vec![
+            "mask".to_string(),
+            "trim paths".to_string(),
+        ]));
+        assert_eq!(
+            unsupported_sticker_text(&error),
+            "This animated sticker uses effects the bot cannot draw yet (mask, trim paths), so it was not converted."
+        );
+    }
+
+    #[test]
+    fn parses_audio_command_args() {
+        assert_eq!(parse_audio_format(""), Some(AudioFormat::Mp3));
+        assert_eq!(parse_audio_format("MP3"), Some(AudioFormat::Mp3));
+        assert_eq!(parse_audio_format("voice"), Some(AudioFormat::Voice));
+        assert_eq!(parse_audio_format("flac"), None);
+    }
+
+    #[test]
+    fn parses_unsticker_args() {
+        assert_eq!(
+            parse_unsticker_args(""),
+            Some((ClipFormat::Mp4, [255, 255, 255]))
+        );
+        assert_eq!(
+            parse_unsticker_args("GIF black"),
+            Some((ClipFormat::Gif, [0, 0, 0]))
+        );
+        assert_eq!(
+            parse_unsticker_args("#ff8000 mp4"),
+            Some((ClipFormat::Mp4, [255, 128, 0]))
+        );
+        assert_eq!(parse_unsticker_args("webp"), None);
+        assert_eq!(parse_unsticker_args("#ff80"), None);
+    }
+
+    #[test]
+    fn parses_sticker_args() {
+        assert_eq!(parse_sticker_args(""), Some(StickerTarget::Chat));
+        assert_eq!(
+            parse_sticker_args("ADD"),
+            Some(StickerTarget::Set {
+                emoji: "🎬".to_string()
+            })
+        );
+        assert_eq!(
+            parse_sticker_args("add 😂"),
+            Some(StickerTarget::Set {
+                emoji: "😂".to_string()
+            })
+        );
+        assert_eq!(parse_sticker_args("remove"), None);
+        assert_eq!(
+            chat_sticker_set_name(-1001234567890, "shitverter_bot"),
+            "chat1001234567890_by_shitverter_bot"
+        );
+    }
+
+    #[test]
+    fn parses_commands_with_bot_mention_and_args() {
+        assert_eq!(parse_command("/cancel"), Some(("cancel".to_string(), "")));
+        assert_eq!(
+            parse_command("/Cancel@shitverter_bot  now "),
+            Some(("cancel".to_string(), "now"))
+        );
+        assert_eq!(parse_command("cancel"), None);
+        assert_eq!(parse_command("/ text"), None);
+    }
+
+    #[test]
+    fn detects_video_mime_type() {
+        assert!(is_video_document(
+            Some("video/x-matroska"),
+            Some("source.bin")
+        ));
+    }
+
+    #[test]
+    fn detects_video_extension_without_video_mime() {
+        assert!(is_video_document(
+            Some("application/octet-stream"),
+            Some("source.MKV"),
+        ));
     }
 
     #[test]
@@ -282,6 +2520,28 @@ mod tests {
         ));
     }
 
+    #[test]
+    fn detects_audio_documents_that_need_conversion() {
+        assert!(is_audio_document(Some("audio/amr"), Some("rec.amr")));
+        assert!(is_audio_document(
+            Some("application/octet-stream"),
+            Some("Track 01.FLAC")
+        ));
+        assert!(is_audio_document(None, Some("memo.m4a")));
+        assert!(!is_audio_document(Some("audio/mpeg"), Some("song.mp3")));
+        assert!(!is_audio_document(Some("video/ogg"), Some("clip.ogg")));
+    }
+
+    #[test]
+    fn detects_image_documents() {
+        assert!(is_image_document(Some("image/heic"), Some("IMG_0001.HEIC")));
+        assert!(is_image_document(
+            Some("application/octet-stream"),
+            Some("scan.tiff")
+        ));
+        assert!(!is_image_document(Some("image/jpeg"), Some("photo.jpg")));
+    }
+
     #[test]
     fn keeps_safe_name() {
         assert_eq!(sanitize_user_name(Some("Иван Ivan_01")), "Иван Ivan_01");
@@ -0,0 +1,463 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio_util::sync::CancellationToken;
+
+use crate::store::{JobStore, NewJob, PersistedJob};
+
+#[derive(Debug, Clone)]
+struct ActiveJob {
+    owner_id: i64,
+    token: CancellationToken,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CancelOutcome {
+    Cancelled,
+    NotOwner,
+    NotFound,
+}
+
+/// Задачи и идущие конвертации, которые можно отменить командой `/cancel`.
+///
+/// Задача доступна по идентификаторам нескольких сообщений (исходного видео,
+/// сообщения «в очереди» и статусного сообщения), чтобы `/cancel` работал в
+/// ответ на любое из них. Конвертация, зарегистрированная внутри принятой
+/// задачи, получает дочерний токен и отменяется вместе с задачей.
+#[derive(Debug, Default, Clone)]
+pub struct ActiveJobs {
+    jobs: Arc<Mutex<HashMap<(i64, i32), JobStack>>>,
+}
+
+/// Регистрации по одному сообщению: внешняя задача, затем вложенные.
+type JobStack = Vec<ActiveJob>;
+
+/// Задача, принятая к обработке: её можно отменить, пока значение живо.
+#[derive(Debug)]
+pub struct TrackedJob {
+    jobs: ActiveJobs,
+    chat_id: i64,
+    message_ids: Vec<i32>,
+    token: CancellationToken,
+}
+
+impl ActiveJobs {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn register(&self, chat_id: i64, message_ids: &[i32], owner_id: i64) -> CancellationToken {
+        let mut jobs = self.jobs.lock().unwrap_or_else(|e| e.into_inner());
+        let token = message_ids
+            .iter()
+            .find_map(|message_id| jobs.get(&(chat_id, *message_id))?.last())
+            .map_or_else(CancellationToken::new, |outer| outer.token.child_token());
+        let job = ActiveJob {
+            owner_id,
+            token: token.clone(),
+        };
+        for message_id in message_ids {
+            jobs.entry((chat_id, *message_id))
+                .or_default()
+                .push(job.clone());
+        }
+        token
+    }
+
+    /// Снимает с учёта последнюю регистрацию с этими сообщениями.
+    pub fn finish(&self, chat_id: i64, message_ids: &[i32]) {
+        let mut jobs = self.jobs.lock().unwrap_or_else(|e| e.into_inner());
+        for message_id in message_ids {
+            let key = (chat_id, *message_id);
+            if let Some(stack) = jobs.get_mut(&key) {
+                stack.pop();
+                if stack.is_empty() {
+                    jobs.remove(&key);
+                }
+            }
+        }
+    }
+
+    /// Регистрирует задачу до того, как она дойдёт до конвертации.
+    pub fn track(&self, chat_id: i64, message_ids: &[i32], owner_id: i64) -> TrackedJob {
+        TrackedJob {
+            jobs: self.clone(),
+            chat_id,
+            message_ids: message_ids.to_vec(),
+            token: self.register(chat_id, message_ids, owner_id),
+        }
+    }
+
+    /// Отменяет задачу, если её запросил тот же пользователь, что прислал видео.
+    pub fn cancel(&self, chat_id: i64, message_id: i32, requester_id: i64) -> CancelOutcome {
+        let jobs = self.jobs.lock().unwrap_or_else(|e| e.into_inner());
+        // Внешняя регистрация отменяет и вложенную конвертацию.
+        let Some(job) = jobs
+            .get(&(chat_id, message_id))
+            .and_then(|stack| stack.first())
+        else {
+            return CancelOutcome::NotFound;
+        };
+        if job.owner_id != requester_id {
+            return CancelOutcome::NotOwner;
+        }
+        job.token.cancel();
+        CancelOutcome::Cancelled
+    }
+}
+
+impl TrackedJob {
+    pub fn is_cancelled(&self) -> bool {
+        self.token.is_cancelled()
+    }
+}
+
+impl Drop for TrackedJob {
+    fn drop(&mut self) {
+        self.jobs.finish(self.chat_id, &self.message_ids);
+    }
+}
+
+/// Ограничивает число одновременных конвертаций. Задачи сверх `workers`
+/// ждут в очереди по порядку прихода; очередь длиннее `max_queued` не растёт.
+/// С хранилищем принятые задачи сохраняются и переживают перезапуск.
+#[derive(Debug)]
+pub struct WorkerPool {
+    workers: Arc<Semaphore>,
+    max_queued: usize,
+    queued: Arc<AtomicUsize>,
+    store: Option<Arc<JobStore>>,
+}
+
+/// Занятый обработчик; освобождается, когда значение удаляется. Вместе с
+/// ним из хранилища удаляется и задача.
+#[derive(Debug)]
+pub struct WorkerSlot {
+    _permit: OwnedSemaphorePermit,
+    record: Option<PersistedJob>,
+    tracked: Option<TrackedJob>,
+}
+
+/// Место в очереди к обработчикам.
+#[derive(Debug)]
+pub struct QueuedJob {
+    position: usize,
+    workers: Arc<Semaphore>,
+    queued: Arc<AtomicUsize>,
+    record: Option<PersistedJob>,
+    tracked: Option<TrackedJob>,
+}
+
+#[derive(Debug)]
+pub enum Admission {
+    /// Свободный обработчик нашёлся сразу.
+    Started(WorkerSlot),
+    Queued(QueuedJob),
+    /// Очередь заполнена, задачу нужно отклонить.
+    Full,
+}
+
+impl WorkerPool {
+    pub fn new(workers: usize, max_queued: usize) -> Self {
+        Self {
+            workers: Arc::new(Semaphore::new(workers.max(1))),
+            max_queued,
+            queued: Arc::new(AtomicUsize::new(0)),
+            store: None,
+        }
+    }
+
+    pub fn with_store(mut self, store: Arc<JobStore>) -> Self {
+        self.store = Some(store);
+        self
+    }
+
+    fn persist(&self, job: &NewJob) -> Option<PersistedJob> {
+        self.store
+            .as_ref()
+            .map(|store| PersistedJob::insert(store, job))
+    }
+
+    /// Занимает обработчика или место в очереди.
+    pub fn admit(&self, job: &NewJob) -> Admission {
+        if let Ok(permit) = Arc::clone(&self.workers).try_acquire_owned() {
+            let record = self.persist(job);
+            if let Some(record) = &record {
+                record.mark_running();
+            }
+            return Admission::Started(WorkerSlot {
+                _permit: permit,
+                record,
+                tracked: None,
+            });
+        }
+        let ahead = self
+            .queued
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |queued| {
+                (queued < self.max_queued).then_some(queued + 1)
+            });
+        match ahead {
+            Ok(ahead) => Admission::Queued(QueuedJob {
+                position: ahead + 1,
+                workers: Arc::clone(&self.workers),
+                queued: Arc::clone(&self.queued),
+                record: self.persist(job),
+                tracked: None,
+            }),
+            Err(_) => Admission::Full,
+        }
+    }
+
+    pub fn max_queued(&self) -> usize {
+        self.max_queued
+    }
+}
+
+impl WorkerSlot {
+    /// Запоминает статусное сообщение задачи, чтобы после перезапуска его
+    /// можно было удалить.
+    pub fn set_notice(&self, notice_message_id: Option<i32>) {
+        if let Some(record) = &self.record {
+            record.set_notice(notice_message_id);
+        }
+    }
+
+    /// Оставляет задачу доступной для `/cancel`, пока занят обработчик.
+    pub fn track(&mut self, tracked: TrackedJob) {
+        self.tracked = Some(tracked);
+    }
+
+    /// Отменил ли пользователь задачу до начала конвертации.
+    pub fn is_cancelled(&self) -> bool {
+        self.tracked.as_ref().is_some_and(TrackedJob::is_cancelled)
+    }
+}
+
+impl QueuedJob {
+    /// Место в очереди на момент постановки, начиная с 1.
+    pub fn position(&self) -> usize {
+        self.position
+    }
+
+    /// Запоминает сообщение «в очереди», как `WorkerSlot::set_notice`.
+    pub fn set_notice(&self, notice_message_id: Option<i32>) {
+        if let Some(record) = &self.record {
+            record.set_notice(notice_message_id);
+        }
+    }
+
+    /// Делает задачу доступной для `/cancel`, пока она ждёт в очереди.
+    pub fn track(&mut self, tracked: TrackedJob) {
+        self.tracked = Some(tracked);
+    }
+
+    /// Ждёт свободного обработчика. Семафор честный, поэтому задачи
+    /// стартуют в порядке очереди. Отменённая задача уходит из очереди и
+    /// отдаёт `None`.
+    pub async fn wait(mut self) -> Option<WorkerSlot> {
+        let cancelled = self
+            .tracked
+            .as_ref()
+            .map(|tracked| tracked.token.clone())
+            .unwrap_or_default();
+        let permit = tokio::select! {
+            permit = Arc::clone(&self.workers).acquire_owned() => {
+                permit.expect("worker semaphore is never closed")
+            }
+            _ = cancelled.cancelled() => return None,
+        };
+        let record = self.record.take();
+        if let Some(record) = &record {
+            record.mark_running();
+        }
+        Some(WorkerSlot {
+            _permit: permit,
+            record,
+            tracked: self.tracked.take(),
+        })
+    }
+}
+
+impl Drop for QueuedJob {
+    /// Освобождает место в очереди и когда задача дождалась обработчика, и
+    /// когда ожидание прервано.
+    fn drop(&mut self) {
+        self.queued.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ActiveJobs, Admission, CancelOutcome, WorkerPool};
+    use crate::limits::QuotaKind;
+    use crate::store::{JobState, JobStore, NewJob};
+    use std::path::Path;
+    use std::sync::Arc;
+
+    fn job(message_id: i32) -> NewJob {
+        NewJob {
+            chat_id: -100,
+            message_id,
+            user_id: 7,
+            file_id: format!("file-{message_id}"),
+            kind: QuotaKind::Media,
+            day_index: 20_000,
+            message: "{}".to_string(),
+        }
+    }
+
+    #[test]
+    fn cancels_job_by_any_registered_message() {
+        let jobs = ActiveJobs::new();
+        let token = jobs.register(-100, &[10, 11], 7);
+
+        assert_eq!(jobs.cancel(-100, 11, 7), CancelOutcome::Cancelled);
+        assert!(token.is_cancelled());
+    }
+
+    #[test]
+    fn only_owner_can_cancel() {
+        let jobs = ActiveJobs::new();
+        let token = jobs.register(-100, &[10], 7);
+
+        assert_eq!(jobs.cancel(-100, 10, 8), CancelOutcome::NotOwner);
+        assert!(!token.is_cancelled());
+    }
+
+    #[test]
+    fn cancels_conversion_registered_inside_tracked_job() {
+        let jobs = ActiveJobs::new();
+        let tracked = jobs.track(-100, &[10, 11], 7);
+        let conversion = jobs.register(-100, &[10, 12], 7);
+
+        assert_eq!(jobs.cancel(-100, 11, 7), CancelOutcome::Cancelled);
+        assert!(tracked.is_cancelled());
+        assert!(conversion.is_cancelled());
+
+        jobs.finish(-100, &[10, 12]);
+        assert_eq!(jobs.cancel(-100, 10, 7), CancelOutcome::Cancelled);
+        drop(tracked);
+        assert_eq!(jobs.cancel(-100, 10, 7), CancelOutcome::NotFound);
+    }
+
+    #[tokio::test]
+    async fn cancelled_job_leaves_queue() {
+        let jobs = ActiveJobs::new();
+        let pool = WorkerPool::new(1, 1);
+        let Admission::Started(_running) = pool.admit(&job(1)) else {
+            panic!("first job should start at once");
+        };
+        let Admission::Queued(mut queued) = pool.admit(&job(2)) else {
+            panic!("second job should be queued");
+        };
+        queued.track(jobs.track(-100, &[2], 7));
+
+        let waiting = tokio::spawn(queued.wait());
+        tokio::task::yield_now().await;
+        assert_eq!(jobs.cancel(-100, 2, 7), CancelOutcome::Cancelled);
+
+        assert!(waiting.await.unwrap().is_none());
+        assert!(matches!(pool.admit(&job(3)), Admission::Queued(_)));
+        assert_eq!(jobs.cancel(-100, 2, 7), CancelOutcome::NotFound);
+    }
+
+    #[test]
+    fn forgets_finished_jobs() {
+        let jobs = ActiveJobs::new();
+        jobs.register(-100, &[10, 11], 7);
+        jobs.finish(-100, &[10, 11]);
+
+        assert_eq!(jobs.cancel(-100, 10, 7), CancelOutcome::NotFound);
+        assert_eq!(jobs.cancel(-200, 10, 7), CancelOutcome::NotFound);
+    }
+
+    #[tokio::test]
+    async fn queues_jobs_beyond_workers_and_rejects_over_cap() {
+        let pool = WorkerPool::new(1, 2);
+        let Admission::Started(running) = pool.admit(&job(1)) else {
+            panic!("first job should start at once");
+        };
+        let Admission::Queued(first) = pool.admit(&job(1)) else {
+            panic!("second job should be queued");
+        };
+        let Admission::Queued(second) = pool.admit(&job(1)) else {
+            panic!("third job should be queued");
+        };
+        assert_eq!((first.position(), second.position()), (1, 2));
+        assert!(matches!(pool.admit(&job(1)), Admission::Full));
+
+        drop(running);
+        let slot = first.wait().await.expect("queued job was not cancelled");
+        // Дождавшаяся задача освободила место в очереди.
+        let Admission::Queued(third) = pool.admit(&job(1)) else {
+            panic!("a queue place should be free again");
+        };
+        assert_eq!(third.position(), 2);
+
+        drop(second);
+        drop(slot);
+        assert!(matches!(pool.admit(&job(1)), Admission::Started(_)));
+    }
+
+    #[tokio::test]
+    async fn persists_admitted_jobs_until_their_slot_is_released() {
+        let store = Arc::new(JobStore::open(Path::new(":memory:")).unwrap());
+        let pool = WorkerPool::new(1, 1).with_store(Arc::clone(&store));
+        let Admission::Started(running) = pool.admit(&job(1)) else {
+            panic!("first job should start at once");
+        };
+        let Admission::Queued(queued) = pool.admit(&job(2)) else {
+            panic!("second job should be queued");
+        };
+        assert!(matches!(pool.admit(&job(3)), Admission::Full));
+
+        let states = |store: &JobStore| -> Vec<(i32, JobState)> {
+            store
+                .pending_jobs()
+                .unwrap()
+                .into_iter()
+                .map(|stored| (stored.job.message_id, stored.state))
+                .collect()
+        };
+        assert_eq!(
+            states(&store),
+            vec![(1, JobState::Running), (2, JobState::Queued)]
+        );
+
+        drop(running);
+        let slot = queued.wait().await.expect("queued job was not cancelled");
+        assert_eq!(states(&store), vec![(2, JobState::Running)]);
+        drop(slot);
+        assert!(states(&store).is_empty());
+    }
+
+    #[tokio::test]
+    async fn persists_queue_and_status_messages() {
+        let store = Arc::new(JobStore::open(Path::new(":memory:")).unwrap());
+        let pool = WorkerPool::new(1, 1).with_store(Arc::clone(&store));
+        let Admission::Started(running) = pool.admit(&job(1)) else {
+            panic!("first job should start at once");
+        };
+        let Admission::Queued(queued) = pool.admit(&job(2)) else {
+            panic!("second job should be queued");
+        };
+        running.set_notice(Some(100));
+        queued.set_notice(Some(101));
+
+        let notices = |store: &JobStore| -> Vec<(i32, Option<i32>)> {
+            store
+                .pending_jobs()
+                .unwrap()
+                .into_iter()
+                .map(|stored| (stored.job.message_id, stored.notice_message_id))
+                .collect()
+        };
+        assert_eq!(notices(&store), vec![(1, Some(100)), (2, Some(101))]);
+
+        drop(running);
+        let slot = queued.wait().await.expect("queued job was not cancelled");
+        slot.set_notice(None);
+        assert_eq!(notices(&store), vec![(2, None)]);
+    }
+}
@@ -0,0 +1,114 @@
+/// Двухбуквенные коды ISO 639-1 и соответствующие им трёхбуквенные ISO 639-2,
+/// которыми ffprobe подписывает дорожки.
+const ISO_639_1: &[(&str, &str)] = &[
+    ("ar", "ara"),
+    ("be", "bel"),
+    ("bg", "bul"),
+    ("cs", "ces"),
+    ("da", "dan"),
+    ("de", "deu"),
+    ("el", "ell"),
+    ("en", "eng"),
+    ("es", "spa"),
+    ("et", "est"),
+    ("fa", "fas"),
+    ("fi", "fin"),
+    ("fr", "fra"),
+    ("he", "heb"),
+    ("hi", "hin"),
+    ("hu", "hun"),
+    ("hy", "hye"),
+    ("id", "ind"),
+    ("it", "ita"),
+    ("ja", "jpn"),
+    ("ka", "kat"),
+    ("kk", "kaz"),
+    ("ko", "kor"),
+    ("lt", "lit"),
+    ("lv", "lav"),
+    ("nl", "nld"),
+    ("no", "nor"),
+    ("pl", "pol"),
+    ("pt", "por"),
+    ("ro", "ron"),
+    ("ru", "rus"),
+    ("sk", "slk"),
+    ("sr", "srp"),
+    ("sv", "swe"),
+    ("th", "tha"),
+    ("tr", "tur"),
+    ("uk", "ukr"),
+    ("uz", "uzb"),
+    ("vi", "vie"),
+    ("zh", "zho"),
+];
+
+/// Библиографические коды ISO 639-2/B, которые встречаются в файлах наравне
+/// с терминологическими (`ger` и `deu`).
+const BIBLIOGRAPHIC: &[(&str, &str)] = &[
+    ("arm", "hye"),
+    ("chi", "zho"),
+    ("cze", "ces"),
+    ("dut", "nld"),
+    ("fre", "fra"),
+    ("geo", "kat"),
+    ("ger", "deu"),
+    ("gre", "ell"),
+    ("per", "fas"),
+    ("rum", "ron"),
+    ("slo", "slk"),
+];
+
+/// Приводит код языка к ISO 639-2/T в нижнем регистре: `ja` и `JPN` — `jpn`,
+/// `ger` — `deu`. Неизвестный двухбуквенный код и не код вовсе — `None`.
+pub fn normalize_language(code: &str) -> Option<String> {
+    if !code.chars().all(|c| c.is_ascii_alphabetic()) {
+        return None;
+    }
+    let code = code.to_ascii_lowercase();
+    match code.len() {
+        2 => ISO_639_1
+            .iter()
+            .find(|(short, _)| *short == code)
+            .map(|(_, long)| long.to_string()),
+        3 => Some(
+            BIBLIOGRAPHIC
+                .iter()
+                .find(|(bibliographic, _)| *bibliographic == code)
+                .map_or(code, |(_, terminological)| terminological.to_string()),
+        ),
+        _ => None,
+    }
+}
+
+/// Один ли это язык, с учётом регистра, двухбуквенных и /B-кодов.
+pub fn same_language(a: &str, b: &str) -> bool {
+    match (normalize_language(a), normalize_language(b)) {
+        (Some(a), Some(b)) => a == b,
+        _ => a.eq_ignore_ascii_case(b),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{normalize_language, same_language};
+
+    #[test]
+    fn normalizes_two_letter_and_bibliographic_codes() {
+        assert_eq!(normalize_language("ja").as_deref(), Some("jpn"));
+        assert_eq!(normalize_language("EN").as_deref(), Some("eng"));
+        assert_eq!(normalize_language("ger").as_deref(), Some("deu"));
+        assert_eq!(normalize_language("Rus").as_deref(), Some("rus"));
+        assert_eq!(normalize_language("xx"), None);
+        assert_eq!(normalize_language("klingon"), None);
+        assert_eq!(normalize_language("ру"), None);
+    }
+
+    #[test]
+    fn matches_track_tags_in_any_code_form() {
+        assert!(same_language("jpn", "ja"));
+        assert!(same_language("fre", "fra"));
+        assert!(same_language("ENG", "eng"));
+        assert!(!same_language("eng", "rus"));
+    }
+}
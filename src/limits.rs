@@ -1,6 +1,10 @@
+use anyhow::Result as AnyResult;
 use std::collections::HashMap;
+use std::sync::Arc;
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
+use crate::store::{JobStore, QuotaUsage};
+
 #[derive(Debug, Clone)]
 pub enum QuotaDecision {
     Allowed {
@@ -24,15 +28,58 @@ pub enum QuotaDecision {
     },
 }
 
+/// Вид работы со своим дневным лимитом: картинки конвертируются быстро и
+/// не должны съедать квоту на видео и звук.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QuotaKind {
+    /// Видео и аудио.
+    Media,
+    Image,
+}
+
+impl QuotaKind {
+    /// Во множественном числе, для сообщений пользователю.
+    pub fn noun(self) -> &'static str {
+        match self {
+            QuotaKind::Media => "videos",
+            QuotaKind::Image => "images",
+        }
+    }
+}
+
+/// Счётчики одного вида квоты за текущие сутки.
 #[derive(Debug)]
-pub struct RateLimiter {
-    day_index: u64,
+struct QuotaCounter {
     user_daily_limit: u32,
     global_daily_limit: u32,
     global_count: u32,
     user_counts: HashMap<i64, u32>,
 }
 
+impl QuotaCounter {
+    fn new(user_daily_limit: u32, global_daily_limit: u32) -> Self {
+        Self {
+            user_daily_limit,
+            global_daily_limit,
+            global_count: 0,
+            user_counts: HashMap::new(),
+        }
+    }
+
+    fn reset(&mut self) {
+        self.global_count = 0;
+        self.user_counts.clear();
+    }
+}
+
+#[derive(Debug)]
+pub struct RateLimiter {
+    day_index: u64,
+    media: QuotaCounter,
+    images: QuotaCounter,
+    store: Option<Arc<JobStore>>,
+}
+
 pub fn utc_day_index(now: SystemTime) -> u64 {
     now.duration_since(UNIX_EPOCH)
         .unwrap_or(Duration::from_secs(0))
@@ -41,71 +88,144 @@ pub fn utc_day_index(now: SystemTime) -> u64 {
 }
 
 impl RateLimiter {
+    /// Лимиты на видео и звук; картинки по умолчанию ограничены так же.
     pub fn new(user_daily_limit: u32, global_daily_limit: u32) -> Self {
         Self {
             day_index: utc_day_index(SystemTime::now()),
-            user_daily_limit,
-            global_daily_limit,
-            global_count: 0,
-            user_counts: HashMap::new(),
+            media: QuotaCounter::new(user_daily_limit, global_daily_limit),
+            images: QuotaCounter::new(user_daily_limit, global_daily_limit),
+            store: None,
+        }
+    }
+
+    pub fn with_image_limits(mut self, user_daily_limit: u32, global_daily_limit: u32) -> Self {
+        self.images = QuotaCounter::new(user_daily_limit, global_daily_limit);
+        self
+    }
+
+    /// Подключает хранилище: счётчики текущих суток читаются из него и
+    /// сохраняются при каждом изменении, так что перезапуск их не обнуляет.
+    pub fn with_store(mut self, store: Arc<JobStore>) -> AnyResult<Self> {
+        store.clear_quota_before(self.day_index);
+        for usage in store.quota_usage(self.day_index)? {
+            let counter = self.counter(usage.kind);
+            counter.user_counts.insert(usage.user_id, usage.count);
+            counter.global_count += usage.count;
         }
+        self.store = Some(store);
+        Ok(self)
     }
 
     pub fn current_day_index(&self) -> u64 {
         self.day_index
     }
 
+    fn counter(&mut self, kind: QuotaKind) -> &mut QuotaCounter {
+        match kind {
+            QuotaKind::Media => &mut self.media,
+            QuotaKind::Image => &mut self.images,
+        }
+    }
+
+    fn persist(&self, kind: QuotaKind, user_id: i64, count: u32) {
+        let Some(store) = &self.store else {
+            return;
+        };
+        let usage = QuotaUsage {
+            kind,
+            user_id,
+            count,
+        };
+        store.save_quota_usage(self.day_index, usage);
+    }
+
     pub fn reset_if_new_day(&mut self, now_day_index: u64) -> bool {
         if now_day_index != self.day_index {
             self.day_index = now_day_index;
-            self.global_count = 0;
-            self.user_counts.clear();
+            self.media.reset();
+            self.images.reset();
+            if let Some(store) = &self.store {
+                store.clear_quota_before(now_day_index);
+            }
             return true;
         }
         false
     }
 
-    pub fn check_and_consume(&mut self, user_id: i64, now_day_index: u64) -> QuotaDecision {
+    pub fn check_and_consume(
+        &mut self,
+        user_id: i64,
+        kind: QuotaKind,
+        now_day_index: u64,
+    ) -> QuotaDecision {
         self.reset_if_new_day(now_day_index);
+        let day_index = self.day_index;
+        let counter = self.counter(kind);
 
-        if self.global_count >= self.global_daily_limit {
+        if counter.global_count >= counter.global_daily_limit {
             return QuotaDecision::GlobalLimitExceeded {
-                global_count: self.global_count,
-                global_limit: self.global_daily_limit,
-                day_index: self.day_index,
+                global_count: counter.global_count,
+                global_limit: counter.global_daily_limit,
+                day_index,
             };
         }
 
-        let user_count = *self.user_counts.get(&user_id).unwrap_or(&0);
-        if user_count >= self.user_daily_limit {
+        let user_count = *counter.user_counts.get(&user_id).unwrap_or(&0);
+        if user_count >= counter.user_daily_limit {
             return QuotaDecision::UserLimitExceeded {
                 user_count,
-                user_limit: self.user_daily_limit,
-                global_count: self.global_count,
-                global_limit: self.global_daily_limit,
-                day_index: self.day_index,
+                user_limit: counter.user_daily_limit,
+                global_count: counter.global_count,
+                global_limit: counter.global_daily_limit,
+                day_index,
             };
         }
 
         let new_user_count = user_count + 1;
-        let new_global_count = self.global_count + 1;
+        let new_global_count = counter.global_count + 1;
 
-        self.user_counts.insert(user_id, new_user_count);
-        self.global_count = new_global_count;
-
-        QuotaDecision::Allowed {
+        counter.user_counts.insert(user_id, new_user_count);
+        counter.global_count = new_global_count;
+        let decision = QuotaDecision::Allowed {
             user_count: new_user_count,
-            user_limit: self.user_daily_limit,
+            user_limit: counter.user_daily_limit,
             global_count: new_global_count,
-            global_limit: self.global_daily_limit,
-            day_index: self.day_index,
+            global_limit: counter.global_daily_limit,
+            day_index,
+        };
+
+        self.persist(kind, user_id, new_user_count);
+        decision
+    }
+
+    /// Возвращает ранее списанную единицу квоты, если день ещё не сменился.
+    pub fn refund(&mut self, user_id: i64, kind: QuotaKind, consumed_day_index: u64) -> bool {
+        if consumed_day_index != self.day_index {
+            return false;
+        }
+
+        let counter = self.counter(kind);
+        let Some(user_count) = counter.user_counts.get_mut(&user_id) else {
+            return false;
+        };
+        if *user_count == 0 {
+            return false;
         }
+
+        *user_count -= 1;
+        let user_count = *user_count;
+        counter.global_count = counter.global_count.saturating_sub(1);
+        self.persist(kind, user_id, user_count);
+        true
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::{utc_day_index, QuotaDecision, RateLimiter};
+    use super::{utc_day_index, QuotaDecision, QuotaKind, RateLimiter};
+    use crate::store::JobStore;
+    use std::path::Path;
+    use std::sync::Arc;
     use std::time::{Duration, UNIX_EPOCH};
 
     #[test]
@@ -114,15 +234,15 @@ mod tests {
         let day = 20_000;
 
         assert!(matches!(
-            limiter.check_and_consume(1, day),
+            limiter.check_and_consume(1, QuotaKind::Media, day),
             QuotaDecision::Allowed { user_count: 1, .. }
         ));
         assert!(matches!(
-            limiter.check_and_consume(1, day),
+            limiter.check_and_consume(1, QuotaKind::Media, day),
             QuotaDecision::Allowed { user_count: 2, .. }
         ));
         assert!(matches!(
-            limiter.check_and_consume(1, day),
+            limiter.check_and_consume(1, QuotaKind::Media, day),
             QuotaDecision::UserLimitExceeded {
                 user_count: 2,
                 user_limit: 2,
@@ -137,21 +257,21 @@ mod tests {
         let day = 20_000;
 
         assert!(matches!(
-            limiter.check_and_consume(1, day),
+            limiter.check_and_consume(1, QuotaKind::Media, day),
             QuotaDecision::Allowed {
                 global_count: 1,
                 ..
             }
         ));
         assert!(matches!(
-            limiter.check_and_consume(2, day),
+            limiter.check_and_consume(2, QuotaKind::Media, day),
             QuotaDecision::Allowed {
                 global_count: 2,
                 ..
             }
         ));
         assert!(matches!(
-            limiter.check_and_consume(3, day),
+            limiter.check_and_consume(3, QuotaKind::Media, day),
             QuotaDecision::GlobalLimitExceeded {
                 global_count: 2,
                 global_limit: 2,
@@ -167,19 +287,108 @@ mod tests {
         let day2 = day1 + 1;
 
         assert!(matches!(
-            limiter.check_and_consume(1, day1),
+            limiter.check_and_consume(1, QuotaKind::Media, day1),
             QuotaDecision::Allowed { .. }
         ));
         assert!(matches!(
-            limiter.check_and_consume(1, day1),
+            limiter.check_and_consume(1, QuotaKind::Media, day1),
             QuotaDecision::GlobalLimitExceeded { .. }
         ));
         assert!(matches!(
-            limiter.check_and_consume(1, day2),
+            limiter.check_and_consume(1, QuotaKind::Media, day2),
             QuotaDecision::Allowed { .. }
         ));
     }
 
+    #[test]
+    fn refunds_consumed_quota_on_same_day() {
+        let mut limiter = RateLimiter::new(1, 10);
+        let day = 20_000;
+
+        assert!(matches!(
+            limiter.check_and_consume(1, QuotaKind::Media, day),
+            QuotaDecision::Allowed { .. }
+        ));
+        assert!(limiter.refund(1, QuotaKind::Media, day));
+        assert!(matches!(
+            limiter.check_and_consume(1, QuotaKind::Media, day),
+            QuotaDecision::Allowed {
+                user_count: 1,
+                global_count: 1,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn ignores_refund_from_previous_day_or_unknown_user() {
+        let mut limiter = RateLimiter::new(5, 10);
+        let day = 20_000;
+
+        limiter.check_and_consume(1, QuotaKind::Media, day);
+        assert!(!limiter.refund(2, QuotaKind::Media, day));
+        limiter.reset_if_new_day(day + 1);
+        assert!(!limiter.refund(1, QuotaKind::Media, day));
+    }
+
+    #[test]
+    fn counts_images_separately_from_media() {
+        let mut limiter = RateLimiter::new(1, 10).with_image_limits(2, 10);
+        let day = 20_000;
+
+        assert!(matches!(
+            limiter.check_and_consume(1, QuotaKind::Media, day),
+            QuotaDecision::Allowed { .. }
+        ));
+        assert!(matches!(
+            limiter.check_and_consume(1, QuotaKind::Media, day),
+            QuotaDecision::UserLimitExceeded { .. }
+        ));
+        assert!(matches!(
+            limiter.check_and_consume(1, QuotaKind::Image, day),
+            QuotaDecision::Allowed {
+                user_count: 1,
+                user_limit: 2,
+                ..
+            }
+        ));
+        assert!(limiter.refund(1, QuotaKind::Image, day));
+        assert!(!limiter.refund(1, QuotaKind::Image, day));
+
+        limiter.reset_if_new_day(day + 1);
+        assert!(matches!(
+            limiter.check_and_consume(1, QuotaKind::Media, day + 1),
+            QuotaDecision::Allowed { user_count: 1, .. }
+        ));
+    }
+
+    #[test]
+    fn restores_quota_from_store_after_restart() {
+        let store = Arc::new(JobStore::open(Path::new(":memory:")).unwrap());
+        let mut limiter = RateLimiter::new(2, 10)
+            .with_store(Arc::clone(&store))
+            .unwrap();
+        let day = limiter.current_day_index();
+        limiter.check_and_consume(1, QuotaKind::Media, day);
+        limiter.check_and_consume(1, QuotaKind::Media, day);
+        limiter.check_and_consume(2, QuotaKind::Media, day);
+        assert!(limiter.refund(2, QuotaKind::Media, day));
+
+        let mut restarted = RateLimiter::new(2, 10).with_store(store).unwrap();
+        assert!(matches!(
+            restarted.check_and_consume(1, QuotaKind::Media, day),
+            QuotaDecision::UserLimitExceeded { user_count: 2, .. }
+        ));
+        assert!(matches!(
+            restarted.check_and_consume(2, QuotaKind::Media, day),
+            QuotaDecision::Allowed {
+                user_count: 1,
+                global_count: 3,
+                ..
+            }
+        ));
+    }
+
     #[test]
     fn computes_utc_day_index() {
         let start = UNIX_EPOCH + Duration::from_secs(0);
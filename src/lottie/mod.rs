@@ -0,0 +1,270 @@
+//! Минимальный рендерер анимированных стикеров Telegram (TGS — это Lottie
+//! JSON, сжатый gzip). Кадры отдаются в RGBA и дальше кодируются ffmpeg.
+
+mod model;
+mod render;
+
+use anyhow::{bail, Context, Result as AnyResult};
+use flate2::read::GzDecoder;
+use std::collections::BTreeSet;
+use std::fmt;
+use std::io::Read;
+use tiny_skia::{Color, Pixmap, Transform};
+
+use model::{Composition, Layer, Shape, LAYER_IMAGE, LAYER_TEXT};
+use render::Renderer;
+
+/// Распакованный TGS больше мегабайта Telegram не принимает; берём с запасом.
+const MAX_JSON_BYTES: u64 = 16 * 1024 * 1024;
+/// Сторона кадра, больше которой не рисуем даже по просьбе композиции.
+const MAX_SIDE: u32 = 2048;
+
+pub struct Animation {
+    composition: Composition,
+}
+
+/// Стикер использует возможности Lottie, без которых рендерер нарисует его
+/// неправильно.
+#[derive(Debug)]
+pub struct UnsupportedFeatures(pub Vec<String>);
+
+impl fmt::Display for UnsupportedFeatures {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "unsupported Lottie features: {}", self.0.join(", "))
+    }
+}
+
+impl std::error::Error for UnsupportedFeatures {}
+
+impl Animation {
+    /// Разбирает стикер `.tgs`.
+    pub fn from_tgs(bytes: &[u8]) -> AnyResult<Self> {
+        let mut json = Vec::new();
+        GzDecoder::new(bytes)
+            .take(MAX_JSON_BYTES)
+            .read_to_end(&mut json)
+            .context("Failed to decompress TGS sticker")?;
+        Self::from_json(&json)
+    }
+
+    pub fn from_json(json: &[u8]) -> AnyResult<Self> {
+        let composition: Composition =
+            serde_json::from_slice(json).context("Failed to parse Lottie animation")?;
+        if composition.w == 0 || composition.h == 0 {
+            bail!("Lottie animation has an empty canvas");
+        }
+        if composition.w > MAX_SIDE || composition.h > MAX_SIDE {
+            bail!(
+                "Lottie canvas {}x{} is larger than {}px",
+                composition.w,
+                composition.h,
+                MAX_SIDE
+            );
+        }
+        if composition.fr <= 0.0 || composition.op <= composition.ip {
+            bail!("Lottie animation has no frames");
+        }
+        Ok(Self { composition })
+    }
+
+    pub fn width(&self) -> u32 {
+        self.composition.w
+    }
+
+    pub fn height(&self) -> u32 {
+        self.composition.h
+    }
+
+    pub fn frame_rate(&self) -> f64 {
+        self.composition.fr
+    }
+
+    pub fn frame_count(&self) -> u32 {
+        (self.composition.op - self.composition.ip).ceil() as u32
+    }
+
+    /// Видимые элементы, которые рендерер пропускает: trim paths, маски,
+    /// повторители, текстовые слои и т. п. Пусто, если рисуется всё.
+    pub fn unsupported_features(&self) -> Vec<String> {
+        let mut features = BTreeSet::new();
+        let layers = self
+            .composition
+            .assets
+            .iter()
+            .flat_map(|asset| &asset.layers)
+            .chain(&self.composition.layers);
+        for layer in layers.filter(|layer| !layer.hd) {
+            collect_layer_features(layer, &mut features);
+        }
+        features.into_iter().collect()
+    }
+
+    /// Кадр `index` (от нуля) поверх непрозрачного `background` в RGBA.
+    pub fn render_frame(&self, index: u32, background: [u8; 3]) -> Vec<u8> {
+        let mut pixmap = Pixmap::new(self.width(), self.height())
+            .expect("canvas size is checked when the animation is parsed");
+        pixmap.fill(Color::from_rgba8(
+            background[0],
+            background[1],
+            background[2],
+            255,
+        ));
+        let renderer = Renderer {
+            frame_rate: self.composition.fr,
+            assets: &self.composition.assets,
+        };
+        renderer.render_layers(
+            &mut pixmap,
+            &self.composition.layers,
+            self.composition.ip + f64::from(index),
+            Transform::identity(),
+            0,
+        );
+        // Фон непрозрачный, поэтому premultiplied-данные совпадают с обычным RGBA.
+        pixmap.take()
+    }
+}
+
+fn collect_layer_features(layer: &Layer, features: &mut BTreeSet<String>) {
+    match layer.ty {
+        LAYER_IMAGE => {
+            features.insert("image layer".to_string());
+        }
+        LAYER_TEXT => {
+            features.insert("text layer".to_string());
+        }
+        _ => {}
+    }
+    if !layer.masks.is_empty() {
+        features.insert("mask".to_string());
+    }
+    if layer.tt.is_some() {
+        features.insert("track matte".to_string());
+    }
+    collect_shape_features(&layer.shapes, features);
+}
+
+fn collect_shape_features(shapes: &[Shape], features: &mut BTreeSet<String>) {
+    for shape in shapes {
+        match shape {
+            Shape::Group { it, hd: false } => collect_shape_features(it, features),
+            Shape::Unsupported { ty, hd: false } => {
+                features.insert(shape_feature_name(ty));
+            }
+            _ => {}
+        }
+    }
+}
+
+fn shape_feature_name(ty: &str) -> String {
+    let name = match ty {
+        "tm" => "trim paths",
+        "rp" => "repeater",
+        "mm" => "merge paths",
+        "rd" => "rounded corners",
+        "sr" => "star",
+        "gs" => "gradient stroke",
+        "op" => "offset path",
+        "pb" => "pucker and bloat",
+        "tw" => "twist",
+        "zz" => "zig zag",
+        other => return format!("shape '{}'", other),
+    };
+    name.to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Animation;
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use std::io::Write;
+
+    /// Красный квадрат 20×20 по центру холста 40×40, уезжающий вправо на 10 px.
+    const SQUARE: &str = r#"{
+        "v": "5.5.2", "fr": 30, "ip": 0, "op": 10, "w": 40, "h": 40,
+        "layers": [{
+            "ty": 4, "ind": 1, "ip": 0, "op": 10, "st": 0,
+            "ks": {
+                "p": {"a": 1, "k": [
+                    {"t": 0, "s": [20, 20], "o": {"x": 0, "y": 0}, "i": {"x": 1, "y": 1}},
+                    {"t": 10, "s": [30, 20]}
+                ]},
+                "a": {"a": 0, "k": [0, 0]},
+                "s": {"a": 0, "k": [100, 100]},
+                "r": {"a": 0, "k": 0},
+                "o": {"a": 0, "k": 100}
+            },
+            "shapes": [{
+                "ty": "gr",
+                "it": [
+                    {"ty": "rc", "p": {"a": 0, "k": [0, 0]}, "s": {"a": 0, "k": [20, 20]}, "r": {"a": 0, "k": 0}},
+                    {"ty": "fl", "c": {"a": 0, "k": [1, 0, 0, 1]}, "o": {"a": 0, "k": 100}},
+                    {"ty": "tr", "p": {"a": 0, "k": [0, 0]}}
+                ]
+            }]
+        }]
+    }"#;
+
+    fn pixel(frame: &[u8], width: u32, x: u32, y: u32) -> [u8; 4] {
+        let offset = ((y * width + x) * 4) as usize;
+        frame[offset..offset + 4].try_into().unwrap()
+    }
+
+    #[test]
+    fn renders_filled_rect_over_background() {
+        let animation = Animation::from_json(SQUARE.as_bytes()).unwrap();
+        assert_eq!((animation.width(), animation.height()), (40, 40));
+        assert_eq!(animation.frame_count(), 10);
+
+        let frame = animation.render_frame(0, [255, 255, 255]);
+        assert_eq!(frame.len(), 40 * 40 * 4);
+        assert_eq!(pixel(&frame, 40, 20, 20), [255, 0, 0, 255]);
+        assert_eq!(pixel(&frame, 40, 2, 2), [255, 255, 255, 255]);
+    }
+
+    #[test]
+    fn interpolates_keyframes() {
+        let animation = Animation::from_json(SQUARE.as_bytes()).unwrap();
+        // На пятом кадре квадрат сдвинут на 5 px: левая граница на x = 15.
+        let frame = animation.render_frame(5, [0, 0, 0]);
+        assert_eq!(pixel(&frame, 40, 12, 20), [0, 0, 0, 255]);
+        assert_eq!(pixel(&frame, 40, 17, 20), [255, 0, 0, 255]);
+        assert_eq!(pixel(&frame, 40, 33, 20), [255, 0, 0, 255]);
+    }
+
+    #[test]
+    fn reports_visible_unsupported_features() {
+        let animation = Animation::from_json(SQUARE.as_bytes()).unwrap();
+        assert!(animation.unsupported_features().is_empty());
+
+        let json = SQUARE
+            .replace(
+                r#"{"ty": "tr", "p": {"a": 0, "k": [0, 0]}}"#,
+                r#"{"ty": "tr", "p": {"a": 0, "k": [0, 0]}},
+                   {"ty": "tm", "s": {"a": 0, "k": 0}, "e": {"a": 0, "k": 50}},
+                   {"ty": "rp", "hd": true},
+                   {"ty": "xx"}"#,
+            )
+            .replace(
+                r#""ty": 4,"#,
+                r#""ty": 4, "masksProperties": [{"mode": "a"}],"#,
+            );
+        let animation = Animation::from_json(json.as_bytes()).unwrap();
+        assert_eq!(
+            animation.unsupported_features(),
+            ["mask", "shape 'xx'", "trim paths"]
+        );
+    }
+
+    #[test]
+    fn reads_gzipped_tgs() {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(SQUARE.as_bytes()).unwrap();
+        let tgs = encoder.finish().unwrap();
+
+        let animation = Animation::from_tgs(&tgs).unwrap();
+        assert_eq!(animation.frame_rate(), 30.0);
+        assert!(Animation::from_tgs(SQUARE.as_bytes()).is_err());
+    }
+}
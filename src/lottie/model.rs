@@ -0,0 +1,364 @@
+//! Подмножество формата Lottie, которое встречается в стикерах Telegram.
+//!
+//! Анимируемые свойства разбираются через `serde_json::Value`: одно и то же
+//! поле бывает числом, массивом или списком ключевых кадров.
+
+use serde::de::Error as _;
+use serde::{Deserialize, Deserializer};
+use serde_json::Value;
+
+#[derive(Debug, Deserialize)]
+pub struct Composition {
+    /// Кадров в секунду.
+    pub fr: f64,
+    pub ip: f64,
+    pub op: f64,
+    pub w: u32,
+    pub h: u32,
+    #[serde(default)]
+    pub layers: Vec<Layer>,
+    #[serde(default)]
+    pub assets: Vec<Asset>,
+}
+
+/// Прекомпозиция, на которую ссылаются слои с `ty = 0`.
+#[derive(Debug, Deserialize)]
+pub struct Asset {
+    pub id: String,
+    #[serde(default)]
+    pub layers: Vec<Layer>,
+}
+
+pub const LAYER_PRECOMP: u8 = 0;
+pub const LAYER_SOLID: u8 = 1;
+pub const LAYER_IMAGE: u8 = 2;
+pub const LAYER_SHAPE: u8 = 4;
+pub const LAYER_TEXT: u8 = 5;
+
+#[derive(Debug, Deserialize)]
+pub struct Layer {
+    pub ty: u8,
+    #[serde(default)]
+    pub ind: Option<i64>,
+    #[serde(default)]
+    pub parent: Option<i64>,
+    #[serde(default)]
+    pub ks: Transform,
+    #[serde(default)]
+    pub ip: f64,
+    #[serde(default = "default_out_point")]
+    pub op: f64,
+    #[serde(default)]
+    pub st: f64,
+    #[serde(default = "default_stretch")]
+    pub sr: f64,
+    #[serde(default)]
+    pub hd: bool,
+    #[serde(default)]
+    pub shapes: Vec<Shape>,
+    #[serde(rename = "refId", default)]
+    pub ref_id: Option<String>,
+    /// Перераспределение времени прекомпозиции, в секундах.
+    #[serde(default)]
+    pub tm: Option<Property>,
+    /// Цвет, ширина и высота сплошного слоя.
+    #[serde(default)]
+    pub sc: Option<String>,
+    #[serde(default)]
+    pub sw: Option<f64>,
+    #[serde(default)]
+    pub sh: Option<f64>,
+    /// Маски и track matte не рисуем; нужны, чтобы о них сообщить.
+    #[serde(rename = "masksProperties", default)]
+    pub masks: Vec<Value>,
+    #[serde(default)]
+    pub tt: Option<u8>,
+}
+
+fn default_out_point() -> f64 {
+    f64::INFINITY
+}
+
+fn default_stretch() -> f64 {
+    1.0
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct Transform {
+    /// Точка привязки.
+    #[serde(default)]
+    pub a: Option<Property>,
+    #[serde(default)]
+    pub p: Option<Position>,
+    /// Масштаб в процентах.
+    #[serde(default)]
+    pub s: Option<Property>,
+    /// Поворот в градусах; у 3D-слоёв он называется `rz`.
+    #[serde(default, alias = "rz")]
+    pub r: Option<Property>,
+    /// Непрозрачность в процентах.
+    #[serde(default)]
+    pub o: Option<Property>,
+}
+
+/// Позиция задаётся либо вектором, либо отдельными `x` и `y`.
+#[derive(Debug)]
+pub enum Position {
+    Combined(Property),
+    Split { x: Property, y: Property },
+}
+
+impl<'de> Deserialize<'de> for Position {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value = Value::deserialize(deserializer)?;
+        if value.get("s").and_then(Value::as_bool) == Some(true) {
+            let x = value.get("x").ok_or_else(|| D::Error::missing_field("x"))?;
+            let y = value.get("y").ok_or_else(|| D::Error::missing_field("y"))?;
+            return Ok(Position::Split {
+                x: Property::from_value(x).map_err(D::Error::custom)?,
+                y: Property::from_value(y).map_err(D::Error::custom)?,
+            });
+        }
+        Property::from_value(&value)
+            .map(Position::Combined)
+            .map_err(D::Error::custom)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "ty")]
+pub enum Shape {
+    #[serde(rename = "gr")]
+    Group {
+        #[serde(default)]
+        it: Vec<Shape>,
+        #[serde(default)]
+        hd: bool,
+    },
+    #[serde(rename = "sh")]
+    Path {
+        ks: PathProperty,
+        #[serde(default)]
+        hd: bool,
+    },
+    #[serde(rename = "rc")]
+    Rect {
+        p: Property,
+        s: Property,
+        #[serde(default)]
+        r: Option<Property>,
+        #[serde(default)]
+        hd: bool,
+    },
+    #[serde(rename = "el")]
+    Ellipse {
+        p: Property,
+        s: Property,
+        #[serde(default)]
+        hd: bool,
+    },
+    #[serde(rename = "fl")]
+    Fill {
+        c: Property,
+        o: Property,
+        /// 1 — nonzero, 2 — evenodd.
+        #[serde(default)]
+        r: Option<u8>,
+        #[serde(default)]
+        hd: bool,
+    },
+    #[serde(rename = "st")]
+    Stroke {
+        c: Property,
+        o: Property,
+        w: Property,
+        #[serde(default)]
+        lc: Option<u8>,
+        #[serde(default)]
+        lj: Option<u8>,
+        #[serde(default)]
+        ml: Option<f64>,
+        #[serde(default)]
+        hd: bool,
+    },
+    #[serde(rename = "gf")]
+    GradientFill {
+        o: Property,
+        s: Property,
+        e: Property,
+        /// 1 — линейный, 2 — радиальный.
+        t: u8,
+        g: GradientColors,
+        #[serde(default)]
+        r: Option<u8>,
+        #[serde(default)]
+        hd: bool,
+    },
+    #[serde(rename = "tr")]
+    Transform(Transform),
+    /// Trim paths, звёзды, повторители и прочее пока не рисуем.
+    #[serde(untagged)]
+    Unsupported {
+        ty: String,
+        #[serde(default)]
+        hd: bool,
+    },
+}
+
+#[derive(Debug, Deserialize)]
+pub struct GradientColors {
+    /// Число цветовых остановок; за ними в `k` могут идти остановки прозрачности.
+    pub p: usize,
+    pub k: Property,
+}
+
+/// Кубическая кривая Безье: вершины и касательные относительно вершин.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BezierPath {
+    pub closed: bool,
+    pub vertices: Vec<[f64; 2]>,
+    pub in_tangents: Vec<[f64; 2]>,
+    pub out_tangents: Vec<[f64; 2]>,
+}
+
+/// Значение, которое может меняться по ключевым кадрам.
+#[derive(Debug, Clone)]
+pub enum Animated<T> {
+    Static(T),
+    Keyframes(Vec<Keyframe<T>>),
+}
+
+#[derive(Debug, Clone)]
+pub struct Keyframe<T> {
+    pub time: f64,
+    pub start: T,
+    /// Конечное значение в старом формате; в новом берётся `start` следующего кадра.
+    pub end: Option<T>,
+    pub hold: bool,
+    /// Касательные кривой смягчения: `o` текущего и `i` следующего кадра.
+    pub ease_out: [f64; 2],
+    pub ease_in: [f64; 2],
+}
+
+pub type Property = Animated<Vec<f64>>;
+pub type PathProperty = Animated<BezierPath>;
+
+impl<T: Clone> Animated<T> {
+    fn parse(value: &Value, parse_value: fn(&Value) -> Option<T>) -> Result<Self, String> {
+        let k = value.get("k").ok_or("animated property has no 'k'")?;
+        let keyframes = k
+            .as_array()
+            .filter(|items| items.first().is_some_and(|item| item.get("t").is_some()));
+        let Some(keyframes) = keyframes else {
+            return parse_value(k)
+                .map(Animated::Static)
+                .ok_or_else(|| format!("unsupported property value: {}", k));
+        };
+
+        let mut parsed = Vec::with_capacity(keyframes.len());
+        let mut previous: Option<T> = None;
+        for keyframe in keyframes {
+            let time = keyframe.get("t").and_then(Value::as_f64).unwrap_or(0.0);
+            let start = keyframe.get("s").and_then(parse_value);
+            let end = keyframe.get("e").and_then(parse_value);
+            // Последний кадр старого формата содержит только `t`.
+            let Some(start) = start.or_else(|| previous.take()) else {
+                continue;
+            };
+            parsed.push(Keyframe {
+                time,
+                start,
+                end: end.clone(),
+                hold: keyframe.get("h").and_then(Value::as_f64) == Some(1.0),
+                ease_out: easing_point(keyframe.get("o"), [0.0, 0.0]),
+                ease_in: easing_point(keyframe.get("i"), [1.0, 1.0]),
+            });
+            previous = end.clone();
+        }
+        if parsed.is_empty() {
+            return Err("animated property has no usable keyframes".to_string());
+        }
+        Ok(Animated::Keyframes(parsed))
+    }
+}
+
+impl Property {
+    pub fn from_value(value: &Value) -> Result<Self, String> {
+        Self::parse(value, parse_numbers)
+    }
+}
+
+impl PathProperty {
+    pub fn from_value(value: &Value) -> Result<Self, String> {
+        Self::parse(value, parse_bezier)
+    }
+}
+
+impl<'de> Deserialize<'de> for Property {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value = Value::deserialize(deserializer)?;
+        Property::from_value(&value).map_err(D::Error::custom)
+    }
+}
+
+impl<'de> Deserialize<'de> for PathProperty {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value = Value::deserialize(deserializer)?;
+        PathProperty::from_value(&value).map_err(D::Error::custom)
+    }
+}
+
+/// Касательная смягчения: `x` и `y` бывают числами или массивами по осям.
+fn easing_point(value: Option<&Value>, default: [f64; 2]) -> [f64; 2] {
+    let component = |key: &str, default: f64| {
+        value
+            .and_then(|value| value.get(key))
+            .and_then(|value| match value {
+                Value::Array(items) => items.first().and_then(Value::as_f64),
+                other => other.as_f64(),
+            })
+            .unwrap_or(default)
+    };
+    [component("x", default[0]), component("y", default[1])]
+}
+
+fn parse_numbers(value: &Value) -> Option<Vec<f64>> {
+    match value {
+        Value::Number(number) => number.as_f64().map(|number| vec![number]),
+        Value::Array(items) => items.iter().map(Value::as_f64).collect(),
+        _ => None,
+    }
+}
+
+fn parse_points(value: Option<&Value>) -> Option<Vec<[f64; 2]>> {
+    value?
+        .as_array()?
+        .iter()
+        .map(|point| {
+            let point = point.as_array()?;
+            Some([point.first()?.as_f64()?, point.get(1)?.as_f64()?])
+        })
+        .collect()
+}
+
+fn parse_bezier(value: &Value) -> Option<BezierPath> {
+    // В ключевых кадрах путь завёрнут в массив из одного элемента.
+    let value = match value {
+        Value::Array(items) => items.first()?,
+        other => other,
+    };
+    let vertices = parse_points(value.get("v"))?;
+    let in_tangents = parse_points(value.get("i")).unwrap_or_default();
+    let out_tangents = parse_points(value.get("o")).unwrap_or_default();
+    Some(BezierPath {
+        closed: value.get("c").and_then(Value::as_bool).unwrap_or(false),
+        in_tangents: pad_tangents(in_tangents, vertices.len()),
+        out_tangents: pad_tangents(out_tangents, vertices.len()),
+        vertices,
+    })
+}
+
+fn pad_tangents(mut tangents: Vec<[f64; 2]>, len: usize) -> Vec<[f64; 2]> {
+    tangents.resize(len, [0.0, 0.0]);
+    tangents
+}
@@ -0,0 +1,668 @@
+//! Растеризация композиции Lottie через tiny-skia.
+//!
+//! Поддерживается то, что реально встречается в стикерах: слои фигур,
+//! сплошные слои, прекомпозиции, заливки, обводки и градиенты. Маски,
+//! матты и trim paths пропускаются.
+
+use tiny_skia::{
+    Color, FillRule, GradientStop, LineCap, LineJoin, LinearGradient, Paint, Path, PathBuilder,
+    Pixmap, Point, RadialGradient, Rect, Shader, SpreadMode, Stroke, Transform as Matrix,
+};
+
+use super::model::{
+    Animated, Asset, BezierPath, GradientColors, Layer, Position, Property, Shape, Transform,
+    LAYER_PRECOMP, LAYER_SHAPE, LAYER_SOLID,
+};
+
+/// Глубже прекомпозиции в стикерах не вкладываются; защита от циклов.
+const MAX_PRECOMP_DEPTH: usize = 8;
+/// Коэффициент для приближения четверти окружности кубической кривой.
+const KAPPA: f64 = 0.552_284_75;
+
+/// Линейная интерполяция между значениями ключевых кадров.
+pub(super) trait Lerp: Clone {
+    fn lerp(&self, to: &Self, t: f64) -> Self;
+}
+
+impl Lerp for Vec<f64> {
+    fn lerp(&self, to: &Self, t: f64) -> Self {
+        self.iter()
+            .zip(to.iter())
+            .map(|(from, to)| from + (to - from) * t)
+            .collect()
+    }
+}
+
+impl Lerp for BezierPath {
+    fn lerp(&self, to: &Self, t: f64) -> Self {
+        // Пути с разным числом вершин не интерполируются, держим исходный.
+        if self.vertices.len() != to.vertices.len() {
+            return self.clone();
+        }
+        let mix = |from: &[[f64; 2]], to: &[[f64; 2]]| {
+            from.iter()
+                .zip(to.iter())
+                .map(|(a, b)| [a[0] + (b[0] - a[0]) * t, a[1] + (b[1] - a[1]) * t])
+                .collect()
+        };
+        BezierPath {
+            closed: self.closed,
+            vertices: mix(&self.vertices, &to.vertices),
+            in_tangents: mix(&self.in_tangents, &to.in_tangents),
+            out_tangents: mix(&self.out_tangents, &to.out_tangents),
+        }
+    }
+}
+
+/// Значение `y` кривой смягчения `cubic-bezier(p1, p2)` в точке `x`.
+fn ease(p1: [f64; 2], p2: [f64; 2], x: f64) -> f64 {
+    let bezier = |a: f64, b: f64, s: f64| {
+        3.0 * (1.0 - s) * (1.0 - s) * s * a + 3.0 * (1.0 - s) * s * s * b + s * s * s
+    };
+    // x(s) монотонна при x1, x2 в [0, 1], поэтому хватает бисекции.
+    let (mut low, mut high) = (0.0, 1.0);
+    let mut s = x;
+    for _ in 0..32 {
+        let current = bezier(p1[0], p2[0], s);
+        if (current - x).abs() < 1e-6 {
+            break;
+        }
+        if current < x {
+            low = s;
+        } else {
+            high = s;
+        }
+        s = (low + high) / 2.0;
+    }
+    bezier(p1[1], p2[1], s)
+}
+
+impl<T: Lerp> Animated<T> {
+    pub(super) fn value_at(&self, frame: f64) -> T {
+        let keyframes = match self {
+            Animated::Static(value) => return value.clone(),
+            Animated::Keyframes(keyframes) => keyframes,
+        };
+        let next_index = keyframes
+            .iter()
+            .position(|keyframe| keyframe.time > frame)
+            .unwrap_or(keyframes.len());
+        if next_index == 0 {
+            return keyframes[0].start.clone();
+        }
+        let current = &keyframes[next_index - 1];
+        let Some(next) = keyframes.get(next_index) else {
+            // После последнего кадра держим его значение.
+            return current.start.clone();
+        };
+        if current.hold {
+            return current.start.clone();
+        }
+        let target = current.end.as_ref().unwrap_or(&next.start);
+        let span = next.time - current.time;
+        if span <= 0.0 {
+            return target.clone();
+        }
+        let progress = ease(
+            current.ease_out,
+            current.ease_in,
+            (frame - current.time) / span,
+        );
+        current.start.lerp(target, progress)
+    }
+}
+
+impl Property {
+    fn component(&self, frame: f64, index: usize, default: f64) -> f64 {
+        self.value_at(frame).get(index).copied().unwrap_or(default)
+    }
+}
+
+fn component(property: &Option<Property>, frame: f64, index: usize, default: f64) -> f64 {
+    property.as_ref().map_or(default, |property| {
+        property.component(frame, index, default)
+    })
+}
+
+/// Матрица слоя или группы: `translate(p)·rotate(r)·scale(s)·translate(-a)`.
+fn transform_matrix(transform: &Transform, frame: f64) -> Matrix {
+    let (x, y) = match &transform.p {
+        Some(Position::Combined(position)) => (
+            position.component(frame, 0, 0.0),
+            position.component(frame, 1, 0.0),
+        ),
+        Some(Position::Split { x, y }) => (x.component(frame, 0, 0.0), y.component(frame, 0, 0.0)),
+        None => (0.0, 0.0),
+    };
+    Matrix::from_translate(x as f32, y as f32)
+        .pre_rotate(component(&transform.r, frame, 0, 0.0) as f32)
+        .pre_scale(
+            (component(&transform.s, frame, 0, 100.0) / 100.0) as f32,
+            (component(&transform.s, frame, 1, 100.0) / 100.0) as f32,
+        )
+        .pre_translate(
+            -component(&transform.a, frame, 0, 0.0) as f32,
+            -component(&transform.a, frame, 1, 0.0) as f32,
+        )
+}
+
+fn transform_opacity(transform: &Transform, frame: f64) -> f32 {
+    (component(&transform.o, frame, 0, 100.0) / 100.0).clamp(0.0, 1.0) as f32
+}
+
+/// Время слоя с учётом его сдвига `st` и растяжения `sr`.
+fn layer_time(layer: &Layer, frame: f64) -> f64 {
+    let stretch = if layer.sr == 0.0 { 1.0 } else { layer.sr };
+    (frame - layer.st) / stretch
+}
+
+pub(super) struct Renderer<'a> {
+    pub frame_rate: f64,
+    pub assets: &'a [Asset],
+}
+
+impl Renderer<'_> {
+    pub fn render_layers(
+        &self,
+        pixmap: &mut Pixmap,
+        layers: &[Layer],
+        frame: f64,
+        parent: Matrix,
+        depth: usize,
+    ) {
+        // Первый слой в списке лежит сверху, поэтому рисуем с конца.
+        for layer in layers.iter().rev() {
+            if layer.hd || frame < layer.ip || frame >= layer.op {
+                continue;
+            }
+            let time = layer_time(layer, frame);
+            let matrix = parent.pre_concat(self.layer_matrix(layers, layer, frame, 0));
+            let opacity = transform_opacity(&layer.ks, time);
+            if opacity <= 0.0 {
+                continue;
+            }
+            match layer.ty {
+                LAYER_SHAPE => render_shapes(pixmap, &layer.shapes, time, matrix, opacity),
+                LAYER_SOLID => render_solid(pixmap, layer, matrix, opacity),
+                LAYER_PRECOMP if depth < MAX_PRECOMP_DEPTH => {
+                    let Some(asset) = layer
+                        .ref_id
+                        .as_deref()
+                        .and_then(|id| self.assets.iter().find(|asset| asset.id == id))
+                    else {
+                        continue;
+                    };
+                    let inner_frame = match &layer.tm {
+                        Some(remap) => remap.component(time, 0, 0.0) * self.frame_rate,
+                        None => time,
+                    };
+                    // Прозрачность прекомпозиции применяется к её слою целиком.
+                    let mut inner = match Pixmap::new(pixmap.width(), pixmap.height()) {
+                        Some(inner) => inner,
+                        None => continue,
+                    };
+                    self.render_layers(&mut inner, &asset.layers, inner_frame, matrix, depth + 1);
+                    pixmap.draw_pixmap(
+                        0,
+                        0,
+                        inner.as_ref(),
+                        &tiny_skia::PixmapPaint {
+                            opacity,
+                            ..Default::default()
+                        },
+                        Matrix::identity(),
+                        None,
+                    );
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Матрица слоя вместе с цепочкой родителей. Прозрачность родителей не
+    /// наследуется, только их трансформация.
+    fn layer_matrix(&self, layers: &[Layer], layer: &Layer, frame: f64, depth: usize) -> Matrix {
+        let own = transform_matrix(&layer.ks, layer_time(layer, frame));
+        let parent = layer
+            .parent
+            .filter(|_| depth < layers.len())
+            .and_then(|index| layers.iter().find(|other| other.ind == Some(index)));
+        match parent {
+            Some(parent) => self
+                .layer_matrix(layers, parent, frame, depth + 1)
+                .pre_concat(own),
+            None => own,
+        }
+    }
+}
+
+fn render_solid(pixmap: &mut Pixmap, layer: &Layer, matrix: Matrix, opacity: f32) {
+    let (Some(width), Some(height)) = (layer.sw, layer.sh) else {
+        return;
+    };
+    let Some(color) = layer.sc.as_deref().and_then(parse_hex_color) else {
+        return;
+    };
+    let Some(rect) = Rect::from_xywh(0.0, 0.0, width as f32, height as f32) else {
+        return;
+    };
+    let path = PathBuilder::from_rect(rect);
+    let paint = solid_paint(with_opacity(color, opacity));
+    pixmap.fill_path(&path, &paint, FillRule::Winding, matrix, None);
+}
+
+fn parse_hex_color(value: &str) -> Option<Color> {
+    let hex = value.strip_prefix('#').unwrap_or(value);
+    if hex.len() != 6 {
+        return None;
+    }
+    let channel = |range| u8::from_str_radix(hex.get(range)?, 16).ok();
+    Some(Color::from_rgba8(
+        channel(0..2)?,
+        channel(2..4)?,
+        channel(4..6)?,
+        255,
+    ))
+}
+
+fn solid_paint(color: Color) -> Paint<'static> {
+    let mut paint = Paint {
+        anti_alias: true,
+        ..Default::default()
+    };
+    paint.set_color(color);
+    paint
+}
+
+fn with_opacity(mut color: Color, opacity: f32) -> Color {
+    color.apply_opacity(opacity);
+    color
+}
+
+/// Цвет Lottie: компоненты от 0 до 1, альфа необязательна.
+fn property_color(property: &Property, frame: f64) -> Color {
+    let value = property.value_at(frame);
+    let channel = |index: usize, default: f64| {
+        value.get(index).copied().unwrap_or(default).clamp(0.0, 1.0) as f32
+    };
+    Color::from_rgba(
+        channel(0, 0.0),
+        channel(1, 0.0),
+        channel(2, 0.0),
+        channel(3, 1.0),
+    )
+    .unwrap_or(Color::BLACK)
+}
+
+fn group_transform(items: &[Shape]) -> Option<&Transform> {
+    items.iter().find_map(|item| match item {
+        Shape::Transform(transform) => Some(transform),
+        _ => None,
+    })
+}
+
+/// Рисует список элементов группы. Стиль (заливка, обводка, градиент)
+/// применяется ко всей геометрии, стоящей в списке перед ним; первые
+/// элементы лежат сверху, поэтому обходим список с конца.
+fn render_shapes(pixmap: &mut Pixmap, items: &[Shape], frame: f64, matrix: Matrix, opacity: f32) {
+    for (index, item) in items.iter().enumerate().rev() {
+        match item {
+            Shape::Group { it, hd: false } => {
+                let (matrix, opacity) = match group_transform(it) {
+                    Some(transform) => (
+                        matrix.pre_concat(transform_matrix(transform, frame)),
+                        opacity * transform_opacity(transform, frame),
+                    ),
+                    None => (matrix, opacity),
+                };
+                if opacity > 0.0 {
+                    render_shapes(pixmap, it, frame, matrix, opacity);
+                }
+            }
+            Shape::Fill { c, o, r, hd: false } => {
+                let Some(path) = collect_geometry(&items[..index], frame, matrix) else {
+                    continue;
+                };
+                let paint = solid_paint(with_opacity(
+                    property_color(c, frame),
+                    opacity * style_opacity(o, frame),
+                ));
+                pixmap.fill_path(&path, &paint, fill_rule(*r), Matrix::identity(), None);
+            }
+            Shape::Stroke {
+                c,
+                o,
+                w,
+                lc,
+                lj,
+                ml,
+                hd: false,
+            } => {
+                let Some(path) = collect_geometry(&items[..index], frame, matrix) else {
+                    continue;
+                };
+                let paint = solid_paint(with_opacity(
+                    property_color(c, frame),
+                    opacity * style_opacity(o, frame),
+                ));
+                // Геометрия уже в координатах холста, толщину масштабируем сами.
+                let scale = (matrix.sx * matrix.sy - matrix.kx * matrix.ky).abs().sqrt();
+                let stroke = Stroke {
+                    width: (w.component(frame, 0, 1.0) as f32 * scale).max(0.0),
+                    miter_limit: ml.unwrap_or(4.0) as f32,
+                    line_cap: match lc {
+                        Some(2) => LineCap::Round,
+                        Some(3) => LineCap::Square,
+                        _ => LineCap::Butt,
+                    },
+                    line_join: match lj {
+                        Some(2) => LineJoin::Round,
+                        Some(3) => LineJoin::Bevel,
+                        _ => LineJoin::Miter,
+                    },
+                    ..Default::default()
+                };
+                pixmap.stroke_path(&path, &paint, &stroke, Matrix::identity(), None);
+            }
+            Shape::GradientFill {
+                o,
+                s,
+                e,
+                t,
+                g,
+                r,
+                hd: false,
+            } => {
+                let Some(path) = collect_geometry(&items[..index], frame, matrix) else {
+                    continue;
+                };
+                let Some(shader) = gradient_shader(*t, g, s, e, frame, matrix) else {
+                    continue;
+                };
+                let paint = Paint {
+                    shader,
+                    anti_alias: true,
+                    ..Default::default()
+                };
+                let mut layer = match Pixmap::new(pixmap.width(), pixmap.height()) {
+                    Some(layer) => layer,
+                    None => continue,
+                };
+                layer.fill_path(&path, &paint, fill_rule(*r), Matrix::identity(), None);
+                pixmap.draw_pixmap(
+                    0,
+                    0,
+                    layer.as_ref(),
+                    &tiny_skia::PixmapPaint {
+                        opacity: opacity * style_opacity(o, frame),
+                        ..Default::default()
+                    },
+                    Matrix::identity(),
+                    None,
+                );
+            }
+            _ => {}
+        }
+    }
+}
+
+fn style_opacity(property: &Property, frame: f64) -> f32 {
+    (property.component(frame, 0, 100.0) / 100.0).clamp(0.0, 1.0) as f32
+}
+
+fn fill_rule(rule: Option<u8>) -> FillRule {
+    match rule {
+        Some(2) => FillRule::EvenOdd,
+        _ => FillRule::Winding,
+    }
+}
+
+fn gradient_shader(
+    kind: u8,
+    colors: &GradientColors,
+    start: &Property,
+    end: &Property,
+    frame: f64,
+    matrix: Matrix,
+) -> Option<Shader<'static>> {
+    let start = start.value_at(frame);
+    let end = end.value_at(frame);
+    let point = |value: &[f64]| {
+        Point::from_xy(
+            value.first().copied().unwrap_or(0.0) as f32,
+            value.get(1).copied().unwrap_or(0.0) as f32,
+        )
+    };
+    let (start, end) = (point(&start), point(&end));
+    let stops = gradient_stops(colors, frame);
+    match kind {
+        2 => RadialGradient::new(
+            start,
+            start,
+            start.distance(end),
+            stops,
+            SpreadMode::Pad,
+            matrix,
+        ),
+        _ => LinearGradient::new(start, end, stops, SpreadMode::Pad, matrix),
+    }
+}
+
+/// Остановки градиента: `p` цветов `[offset, r, g, b]`, затем пары
+/// `[offset, alpha]`, если у градиента есть прозрачность.
+fn gradient_stops(colors: &GradientColors, frame: f64) -> Vec<GradientStop> {
+    let values = colors.k.value_at(frame);
+    let color_len = colors.p * 4;
+    let alphas: Vec<(f64, f64)> = values
+        .get(color_len..)
+        .unwrap_or_default()
+        .chunks_exact(2)
+        .map(|pair| (pair[0], pair[1]))
+        .collect();
+    values
+        .get(..color_len)
+        .unwrap_or(&values)
+        .chunks_exact(4)
+        .map(|stop| {
+            let alpha = interpolate_alpha(&alphas, stop[0]);
+            let color = Color::from_rgba(
+                stop[1].clamp(0.0, 1.0) as f32,
+                stop[2].clamp(0.0, 1.0) as f32,
+                stop[3].clamp(0.0, 1.0) as f32,
+                alpha.clamp(0.0, 1.0) as f32,
+            )
+            .unwrap_or(Color::BLACK);
+            GradientStop::new(stop[0] as f32, color)
+        })
+        .collect()
+}
+
+fn interpolate_alpha(alphas: &[(f64, f64)], offset: f64) -> f64 {
+    let Some(&(first_offset, first_alpha)) = alphas.first() else {
+        return 1.0;
+    };
+    if offset <= first_offset {
+        return first_alpha;
+    }
+    for pair in alphas.windows(2) {
+        let ((from_offset, from_alpha), (to_offset, to_alpha)) = (pair[0], pair[1]);
+        if offset <= to_offset {
+            let span = to_offset - from_offset;
+            if span <= 0.0 {
+                return to_alpha;
+            }
+            return from_alpha + (to_alpha - from_alpha) * (offset - from_offset) / span;
+        }
+    }
+    alphas.last().map_or(1.0, |&(_, alpha)| alpha)
+}
+
+/// Собирает геометрию элементов (включая вложенные группы) в координатах холста.
+fn collect_geometry(items: &[Shape], frame: f64, matrix: Matrix) -> Option<Path> {
+    let mut builder = PathBuilder::new();
+    push_geometry(&mut builder, items, frame, matrix);
+    builder.finish()
+}
+
+fn push_geometry(builder: &mut PathBuilder, items: &[Shape], frame: f64, matrix: Matrix) {
+    for item in items {
+        let path = match item {
+            Shape::Path { ks, hd: false } => bezier_path(&ks.value_at(frame)),
+            Shape::Rect { p, s, r, hd: false } => rect_path(
+                p.value_at(frame),
+                s.value_at(frame),
+                r.as_ref().map_or(0.0, |r| r.component(frame, 0, 0.0)),
+            ),
+            Shape::Ellipse { p, s, hd: false } => {
+                ellipse_path(p.value_at(frame), s.value_at(frame))
+            }
+            Shape::Group { it, hd: false } => {
+                let matrix = match group_transform(it) {
+                    Some(transform) => matrix.pre_concat(transform_matrix(transform, frame)),
+                    None => matrix,
+                };
+                push_geometry(builder, it, frame, matrix);
+                None
+            }
+            _ => None,
+        };
+        if let Some(path) = path.and_then(|path| path.transform(matrix)) {
+            builder.push_path(&path);
+        }
+    }
+}
+
+fn bezier_path(bezier: &BezierPath) -> Option<Path> {
+    let vertices = &bezier.vertices;
+    let first = vertices.first()?;
+    let mut builder = PathBuilder::new();
+    builder.move_to(first[0] as f32, first[1] as f32);
+    let segment = |builder: &mut PathBuilder, from: usize, to: usize| {
+        let (start, end) = (vertices[from], vertices[to]);
+        let (out, into) = (bezier.out_tangents[from], bezier.in_tangents[to]);
+        builder.cubic_to(
+            (start[0] + out[0]) as f32,
+            (start[1] + out[1]) as f32,
+            (end[0] + into[0]) as f32,
+            (end[1] + into[1]) as f32,
+            end[0] as f32,
+            end[1] as f32,
+        );
+    };
+    for index in 1..vertices.len() {
+        segment(&mut builder, index - 1, index);
+    }
+    if bezier.closed && vertices.len() > 1 {
+        segment(&mut builder, vertices.len() - 1, 0);
+        builder.close();
+    }
+    builder.finish()
+}
+
+fn rect_path(position: Vec<f64>, size: Vec<f64>, roundness: f64) -> Option<Path> {
+    let (cx, cy) = (*position.first()?, *position.get(1)?);
+    let (width, height) = (*size.first()?, *size.get(1)?);
+    let (left, top, right, bottom) = (
+        cx - width / 2.0,
+        cy - height / 2.0,
+        cx + width / 2.0,
+        cy + height / 2.0,
+    );
+    let radius = roundness.min(width / 2.0).min(height / 2.0).max(0.0);
+    if radius == 0.0 {
+        let rect = Rect::from_ltrb(left as f32, top as f32, right as f32, bottom as f32)?;
+        return Some(PathBuilder::from_rect(rect));
+    }
+    let handle = radius * (1.0 - KAPPA);
+    let mut builder = PathBuilder::new();
+    let point = |x: f64, y: f64| (x as f32, y as f32);
+    let (x, y) = point(left + radius, top);
+    builder.move_to(x, y);
+    let corners = [
+        (
+            right - radius,
+            top,
+            right - handle,
+            top,
+            right,
+            top + handle,
+            right,
+            top + radius,
+        ),
+        (
+            right,
+            bottom - radius,
+            right,
+            bottom - handle,
+            right - handle,
+            bottom,
+            right - radius,
+            bottom,
+        ),
+        (
+            left + radius,
+            bottom,
+            left + handle,
+            bottom,
+            left,
+            bottom - handle,
+            left,
+            bottom - radius,
+        ),
+        (
+            left,
+            top + radius,
+            left,
+            top + handle,
+            left + handle,
+            top,
+            left + radius,
+            top,
+        ),
+    ];
+    for (line_x, line_y, c1x, c1y, c2x, c2y, end_x, end_y) in corners {
+        builder.line_to(line_x as f32, line_y as f32);
+        builder.cubic_to(
+            c1x as f32,
+            c1y as f32,
+            c2x as f32,
+            c2y as f32,
+            end_x as f32,
+            end_y as f32,
+        );
+    }
+    builder.close();
+    builder.finish()
+}
+
+fn ellipse_path(position: Vec<f64>, size: Vec<f64>) -> Option<Path> {
+    let (cx, cy) = (*position.first()?, *position.get(1)?);
+    let (width, height) = (*size.first()?, *size.get(1)?);
+    let rect = Rect::from_xywh(
+        (cx - width / 2.0) as f32,
+        (cy - height / 2.0) as f32,
+        width as f32,
+        height as f32,
+    )?;
+    PathBuilder::from_oval(rect)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ease;
+
+    #[test]
+    fn linear_easing_is_identity() {
+        for x in [0.0, 0.25, 0.5, 0.9, 1.0] {
+            assert!((ease([0.0, 0.0], [1.0, 1.0], x) - x).abs() < 1e-4);
+        }
+    }
+
+    #[test]
+    fn ease_in_out_is_symmetric() {
+        let (p1, p2) = ([0.42, 0.0], [0.58, 1.0]);
+        assert!((ease(p1, p2, 0.5) - 0.5).abs() < 1e-4);
+        assert!(ease(p1, p2, 0.2) < 0.2);
+        assert!((ease(p1, p2, 0.2) + ease(p1, p2, 0.8) - 1.0).abs() < 1e-4);
+    }
+}
@@ -0,0 +1,226 @@
+use serde::Deserialize;
+
+/// Предел истинного пика после выравнивания, dBTP.
+const TRUE_PEAK_LIMIT: f64 = -1.5;
+/// Целевой диапазон громкости, LU.
+const LOUDNESS_RANGE: f64 = 11.0;
+/// Наибольший диапазон громкости, который принимает `loudnorm`, LU.
+const MAX_LOUDNESS_RANGE: f64 = 50.0;
+/// Отклонение от цели, при котором звук оставляем как есть, LU.
+const LOUDNESS_TOLERANCE: f64 = 1.0;
+/// `loudnorm` пересчитывает звук в 192 кГц; результат возвращаем к 48 кГц.
+const OUTPUT_SAMPLE_RATE: u32 = 48_000;
+
+/// Замер первого прохода `loudnorm` (`print_format=json`).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LoudnessMeasurement {
+    /// Интегральная громкость, LUFS.
+    pub input_i: f64,
+    /// Истинный пик, dBTP.
+    pub input_tp: f64,
+    /// Диапазон громкости, LU.
+    pub input_lra: f64,
+    pub input_thresh: f64,
+    pub target_offset: f64,
+}
+
+/// Числа в JSON от `loudnorm` записаны строками, у тишины — `-inf`.
+#[derive(Deserialize)]
+struct RawMeasurement {
+    input_i: String,
+    input_tp: String,
+    input_lra: String,
+    input_thresh: String,
+    target_offset: String,
+}
+
+impl LoudnessMeasurement {
+    /// Достаёт последний JSON-блок из stderr ffmpeg.
+    pub fn parse(stderr: &str) -> Option<Self> {
+        let start = stderr.rfind('{')?;
+        let end = stderr[start..].find('}')? + start;
+        let raw: RawMeasurement = serde_json::from_str(&stderr[start..=end]).ok()?;
+        Some(Self {
+            input_i: raw.input_i.trim().parse().ok()?,
+            input_tp: raw.input_tp.trim().parse().ok()?,
+            input_lra: raw.input_lra.trim().parse().ok()?,
+            input_thresh: raw.input_thresh.trim().parse().ok()?,
+            target_offset: raw.target_offset.trim().parse().ok()?,
+        })
+    }
+
+    /// Громкость, до которой звук можно поднять одним усилением. Если пик
+    /// после усиления до `target` превысил бы `TRUE_PEAK_LIMIT`, `loudnorm`
+    /// перешёл бы в динамический режим, поэтому цель снижается до предела
+    /// пика, с округлением вниз до знака, с которым она попадёт в фильтр.
+    pub fn linear_target(&self, target: f64) -> f64 {
+        let peak_limited = self.input_i + TRUE_PEAK_LIMIT - self.input_tp;
+        if peak_limited < target {
+            (peak_limited * 10.0).floor() / 10.0
+        } else {
+            target
+        }
+    }
+
+    /// Целевой диапазон не уже замеренного: иначе `loudnorm` тоже сожмёт
+    /// динамику.
+    fn linear_range(&self) -> f64 {
+        ((self.input_lra * 10.0).ceil() / 10.0).clamp(LOUDNESS_RANGE, MAX_LOUDNESS_RANGE)
+    }
+
+    /// Останется ли второй проход линейным. Диапазон шире, чем принимает
+    /// `loudnorm`, и нулевой диапазон очень коротких клипов фильтр всё равно
+    /// обрабатывает динамически.
+    pub fn is_linear(&self) -> bool {
+        self.input_lra >= 0.005 && self.input_lra <= MAX_LOUDNESS_RANGE
+    }
+
+    /// Стоит ли выравнивать звук: тишину и дорожки, которые линейно не
+    /// сдвинуть дальше допуска от их громкости, не трогаем.
+    pub fn needs_correction(&self, target: f64) -> bool {
+        self.input_i.is_finite()
+            && self.input_thresh.is_finite()
+            && (self.linear_target(target) - self.input_i).abs() > LOUDNESS_TOLERANCE
+    }
+}
+
+/// Фильтр первого прохода: только замер, звук никуда не пишется.
+pub fn measure_filter(target: f64) -> String {
+    format!(
+        "loudnorm=I={:.1}:TP={:.1}:LRA={:.1}:print_format=json",
+        target, TRUE_PEAK_LIMIT, LOUDNESS_RANGE
+    )
+}
+
+/// Выравнивание громкости по замеру первого прохода.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LoudnessCorrection {
+    /// Целевая громкость, LUFS.
+    pub target: f64,
+    pub measured: LoudnessMeasurement,
+}
+
+impl LoudnessCorrection {
+    /// Фильтр второго прохода. Цель и диапазон подобраны по замеру так,
+    /// чтобы `loudnorm` работал линейно, то есть менял только общее усиление
+    /// и не сжимал динамику; исключения описаны в `is_linear`.
+    pub fn filter(&self) -> String {
+        let measured = &self.measured;
+        format!(
+            "loudnorm=I={:.1}:TP={:.1}:LRA={:.1}:measured_I={:.2}:measured_TP={:.2}:measured_LRA={:.2}:measured_thresh={:.2}:offset={:.2}:linear=true,aresample={}",
+            measured.linear_target(self.target),
+            TRUE_PEAK_LIMIT,
+            measured.linear_range(),
+            measured.input_i,
+            measured.input_tp,
+            measured.input_lra,
+            measured.input_thresh,
+            measured.target_offset,
+            OUTPUT_SAMPLE_RATE,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{measure_filter, LoudnessCorrection, LoudnessMeasurement};
+
+    const STDERR: &str = r#"Input #0, mov,mp4,m4a,3gp,3g2,mj2, from 'in.mp4':
+[Parsed_loudnorm_0 @ 0x55d0c8a3c2c0]
+{
+	"input_i" : "-27.61",
+	"input_tp" : "-4.47",
+	"input_lra" : "18.06",
+	"input_thresh" : "-39.20",
+	"output_i" : "-16.58",
+	"output_tp" : "-1.50",
+	"output_lra" : "14.78",
+	"output_thresh" : "-27.71",
+	"normalization_type" : "dynamic",
+	"target_offset" : "0.58"
+}
+"#;
+
+    #[test]
+    fn parses_loudnorm_json_from_stderr() {
+        let measured = LoudnessMeasurement::parse(STDERR).unwrap();
+        assert_eq!(
+            measured,
+            LoudnessMeasurement {
+                input_i: -27.61,
+                input_tp: -4.47,
+                input_lra: 18.06,
+                input_thresh: -39.2,
+                target_offset: 0.58,
+            }
+        );
+        assert_eq!(LoudnessMeasurement::parse("no json here"), None);
+        assert_eq!(LoudnessMeasurement::parse("{\"input_i\" : \"-1\"}"), None);
+    }
+
+    #[test]
+    fn skips_silence_and_clips_within_tolerance() {
+        let measured = LoudnessMeasurement::parse(STDERR).unwrap();
+        assert!(measured.needs_correction(-16.0));
+        assert!(!measured.needs_correction(-27.0));
+        assert!(!measured.needs_correction(-28.5));
+
+        let silence =
+            LoudnessMeasurement::parse(&STDERR.replace("-27.61", "-inf").replace("-39.20", "-inf"))
+                .unwrap();
+        assert!(!silence.needs_correction(-16.0));
+
+        // Пик -1.0 dBTP не даёт поднять громкость дальше чем на 0.5 LU.
+        let peaky = LoudnessMeasurement {
+            input_tp: -1.0,
+            ..measured
+        };
+        assert!(!peaky.needs_correction(-16.0));
+    }
+
+    #[test]
+    fn keeps_second_pass_linear() {
+        let measured = LoudnessMeasurement::parse(STDERR).unwrap();
+        // Подъём на 11.61 LU вывел бы пик -4.47 выше -1.5 dBTP.
+        let target = measured.linear_target(-16.0);
+        assert!((target - -24.7).abs() < 1e-9);
+        assert!(measured.input_tp + (target - measured.input_i) <= -1.5);
+        assert_eq!(measured.linear_target(-30.0), -30.0);
+        assert!(measured.is_linear());
+
+        for input_lra in [0.0, 50.5] {
+            assert!(!LoudnessMeasurement {
+                input_lra,
+                ..measured
+            }
+            .is_linear());
+        }
+    }
+
+    #[test]
+    fn builds_two_pass_filters() {
+        assert_eq!(
+            measure_filter(-16.0),
+            "loudnorm=I=-16.0:TP=-1.5:LRA=11.0:print_format=json"
+        );
+        let correction = LoudnessCorrection {
+            target: -16.0,
+            measured: LoudnessMeasurement::parse(STDERR).unwrap(),
+        };
+        assert_eq!(
+            correction.filter(),
+            "loudnorm=I=-24.7:TP=-1.5:LRA=18.1:measured_I=-27.61:measured_TP=-4.47:measured_LRA=18.06:measured_thresh=-39.20:offset=0.58:linear=true,aresample=48000"
+        );
+
+        let quiet_range = LoudnessCorrection {
+            target: -30.0,
+            measured: LoudnessMeasurement {
+                input_lra: 4.2,
+                ..correction.measured
+            },
+        };
+        assert!(quiet_range
+            .filter()
+            .starts_with("loudnorm=I=-30.0:TP=-1.5:LRA=11.0:"));
+    }
+}
@@ -1,24 +1,59 @@
-use anyhow::{Context, Result as AnyResult};
+use anyhow::{bail, Context, Result as AnyResult};
 use dotenv::dotenv;
 use std::sync::Arc;
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
-use teloxide::prelude::*;
+use teloxide::{prelude::*, types::MessageId};
 use tokio::{
     sync::Mutex,
     time::{sleep, Duration as TokioDuration},
 };
 
 // Модульная структура
+mod caption;
+mod config;
 mod converter;
 mod handlers;
+mod jobs;
+mod language;
 mod limits;
+mod lottie;
+mod loudness;
+mod media;
+mod policy;
+mod progress;
+mod store;
 mod telegram;
+mod transcoder;
 
-use handlers::process_video;
+use config::Config;
+use converter::{
+    available_encoders, available_filters, ConvertOptions, OutputTarget, TONEMAP_FILTERS,
+};
+use handlers::{
+    parse_command, process_audio, process_cancel, process_message, process_sticker,
+    process_unsticker,
+};
+use jobs::WorkerPool;
 use limits::{utc_day_index, RateLimiter};
+use store::JobStore;
+use telegram::{job_file_name, remove_downloaded_files};
+use transcoder::{FfmpegTranscoder, Transcoder};
 
 const DEFAULT_USER_DAILY_LIMIT: u32 = 10;
 const DEFAULT_GLOBAL_DAILY_LIMIT: u32 = 50;
+const DEFAULT_IMAGE_USER_DAILY_LIMIT: u32 = 30;
+const DEFAULT_IMAGE_GLOBAL_DAILY_LIMIT: u32 = 200;
+/// Лимит Bot API на загрузку файлов ботом — 50 МБ в десятичных байтах, а не
+/// 50 МиБ. Мегабайт запаса покрывает служебные данные multipart-запроса.
+const DEFAULT_MAX_OUTPUT_BYTES: u64 = 50_000_000 - 1_000_000;
+const DEFAULT_CONVERSION_TIMEOUT_SECS: u64 = 900;
+const DEFAULT_CONVERSION_WORKERS: usize = 2;
+const DEFAULT_MAX_QUEUED_JOBS: usize = 20;
+const DEFAULT_CONFIG_PATH: &str = "config.toml";
+const DEFAULT_JOB_STORE_PATH: &str = "jobs.sqlite3";
+/// Сколько раз задачу запускают заново после перезапусков, прежде чем
+/// сдаться: файл, на котором бот падает, не должен ронять его снова и снова.
+const MAX_RESUME_ATTEMPTS: u32 = 3;
 
 async fn ensure_bot_credentials(bot: &Bot) -> AnyResult<()> {
     bot.get_me()
@@ -28,6 +63,44 @@ async fn ensure_bot_credentials(bot: &Bot) -> AnyResult<()> {
     Ok(())
 }
 
+/// Проверяет, что сборка ffmpeg умеет кодировать во все цели из конфига.
+fn ensure_output_encoders(config: &Config) -> AnyResult<()> {
+    let encoders = available_encoders().context("Failed to list FFmpeg encoders")?;
+    let supported: Vec<OutputTarget> = OutputTarget::ALL
+        .into_iter()
+        .filter(|target| target.missing_encoders(&encoders).is_empty())
+        .collect();
+    log::info!("Output targets supported by FFmpeg: {:?}", supported);
+    for target in config.output_targets() {
+        let missing = target.missing_encoders(&encoders);
+        if !missing.is_empty() {
+            bail!(
+                "Output target {:?} needs FFmpeg encoders {:?}, which this build lacks",
+                target,
+                missing
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Проверяет, что в сборке ffmpeg есть фильтры тонмаппинга HDR: без
+/// libzimg нет `zscale`, и каждое HDR-видео падало бы при конвертации.
+fn ensure_tonemap_filters() -> AnyResult<()> {
+    let filters = available_filters().context("Failed to list FFmpeg filters")?;
+    let missing: Vec<&str> = TONEMAP_FILTERS
+        .into_iter()
+        .filter(|filter| !filters.contains(*filter))
+        .collect();
+    if !missing.is_empty() {
+        bail!(
+            "HDR tone-mapping needs FFmpeg filters {:?}, which this build lacks (zscale comes with libzimg)",
+            missing
+        );
+    }
+    Ok(())
+}
+
 fn next_midnight_utc_seconds() -> u64 {
     let now_secs = SystemTime::now()
         .duration_since(UNIX_EPOCH)
@@ -41,10 +114,10 @@ fn next_midnight_utc_seconds() -> u64 {
     }
 }
 
-fn parse_env_limit(name: &str, default: u32) -> u32 {
+fn parse_env_limit<T: std::str::FromStr>(name: &str, default: T) -> T {
     std::env::var(name)
         .ok()
-        .and_then(|value| value.parse::<u32>().ok())
+        .and_then(|value| value.parse::<T>().ok())
         .unwrap_or(default)
 }
 
@@ -63,6 +136,136 @@ async fn start_quota_monitor(limiter: Arc<Mutex<RateLimiter>>) {
     }
 }
 
+/// Передаёт сообщение обработчику команды или медиафайла.
+async fn handle_message(
+    bot: Bot,
+    msg: Message,
+    limiter: Arc<Mutex<RateLimiter>>,
+    transcoder: Arc<dyn Transcoder>,
+    config: Arc<Config>,
+) {
+    let command = msg.text().and_then(parse_command);
+    let result = match command {
+        Some((name, _)) if name == "cancel" => {
+            process_cancel(&bot, &msg, transcoder.as_ref()).await
+        }
+        Some((name, args)) if name == "audio" => {
+            process_audio(&bot, &msg, &limiter, transcoder.as_ref(), &config, args).await
+        }
+        Some((name, args)) if name == "sticker" => {
+            process_sticker(&bot, &msg, &limiter, transcoder.as_ref(), &config, args).await
+        }
+        Some((name, args)) if name == "unsticker" => {
+            process_unsticker(&bot, &msg, &limiter, transcoder.as_ref(), &config, args).await
+        }
+        _ => process_message(&bot, &msg, &limiter, transcoder.as_ref(), &config).await,
+    };
+    if let Err(e) = result {
+        log::error!("Error processing message: {:?}", e);
+    }
+}
+
+/// Разбирает задачи, прерванные прошлой остановкой бота: удаляет их
+/// временные файлы и возвращает квоту. Задачу запускают заново с исходного
+/// сообщения, и обработчик списывает квоту ещё раз; если это уже нельзя,
+/// пользователю предлагают прислать файл снова.
+async fn resume_pending_jobs(
+    bot: &Bot,
+    store: &Arc<JobStore>,
+    limiter: &Arc<Mutex<RateLimiter>>,
+    transcoder: &Arc<dyn Transcoder>,
+    config: &Arc<Config>,
+) -> AnyResult<()> {
+    let pending = store.pending_jobs()?;
+    if !pending.is_empty() {
+        log::info!("Unfinished jobs from the previous run: {}", pending.len());
+    }
+
+    for stored in pending {
+        let job = &stored.job;
+        let job_name = job_file_name(&job.file_id, job.chat_id, job.message_id);
+        match remove_downloaded_files(&job_name).await {
+            Ok(0) => {}
+            Ok(removed) => log::info!("Removed {} leftover files of job {}", removed, stored.id),
+            Err(e) => log::warn!(
+                "Failed to remove leftover files of job {}: {:?}",
+                stored.id,
+                e
+            ),
+        }
+        let refunded = limiter
+            .lock()
+            .await
+            .refund(job.user_id, job.kind, job.day_index);
+        // «В очереди» или статус прошлого запуска больше не обновится.
+        if let Some(notice) = stored.notice_message_id {
+            if let Err(e) = bot
+                .delete_message(ChatId(job.chat_id), MessageId(notice))
+                .await
+            {
+                log::warn!(
+                    "Failed to delete stale message {} of job {}: {:?}",
+                    notice,
+                    stored.id,
+                    e
+                );
+            }
+        }
+
+        let message = serde_json::from_str::<Message>(&job.message).ok();
+        match message {
+            Some(msg) if stored.attempts < MAX_RESUME_ATTEMPTS => {
+                log::info!(
+                    "Resuming job: id={}, chat_id={}, message_id={}, state={:?}, attempt={}",
+                    stored.id,
+                    job.chat_id,
+                    job.message_id,
+                    stored.state,
+                    stored.attempts + 1,
+                );
+                store.record_attempt(job.chat_id, job.message_id);
+                let (bot, store, limiter, transcoder, config) = (
+                    bot.clone(),
+                    Arc::clone(store),
+                    Arc::clone(limiter),
+                    Arc::clone(transcoder),
+                    Arc::clone(config),
+                );
+                let (chat_id, message_id) = (job.chat_id, job.message_id);
+                tokio::spawn(async move {
+                    handle_message(bot, msg, limiter, transcoder, config).await;
+                    // Обработчик мог отказать ещё до очереди, например по
+                    // квоте; тогда запись остаётся и убирать её нужно здесь.
+                    store.remove_job(chat_id, message_id);
+                });
+            }
+            _ => {
+                log::warn!(
+                    "Dropping job that cannot be resumed: id={}, chat_id={}, message_id={}, attempts={}, quota_refunded={}",
+                    stored.id,
+                    job.chat_id,
+                    job.message_id,
+                    stored.attempts,
+                    refunded,
+                );
+                store.remove_job(job.chat_id, job.message_id);
+                let notice = bot
+                    .send_message(
+                        ChatId(job.chat_id),
+                        "The bot restarted and could not finish converting this file. Please send it again.",
+                    )
+                    .reply_to_message_id(MessageId(job.message_id))
+                    .allow_sending_without_reply(true)
+                    .await;
+                if let Err(e) = notice {
+                    log::warn!("Failed to notify about dropped job {}: {:?}", stored.id, e);
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
 #[tokio::main]
 async fn main() -> AnyResult<()> {
     dotenv().ok();
@@ -74,19 +277,86 @@ async fn main() -> AnyResult<()> {
     let user_daily_limit = parse_env_limit("USER_DAILY_LIMIT", DEFAULT_USER_DAILY_LIMIT);
     let global_daily_limit = parse_env_limit("GLOBAL_DAILY_LIMIT", DEFAULT_GLOBAL_DAILY_LIMIT);
 
-    let limiter = Arc::new(Mutex::new(RateLimiter::new(
-        user_daily_limit,
-        global_daily_limit,
-    )));
+    let config_path =
+        std::env::var("CONFIG_PATH").unwrap_or_else(|_| DEFAULT_CONFIG_PATH.to_string());
+    let config = match Config::load(std::path::Path::new(&config_path)) {
+        Ok(config) => Arc::new(config),
+        Err(error) => {
+            log::error!("{error:?}");
+            return Err(error);
+        }
+    };
+    log::info!(
+        "Encoding profiles loaded: profiles={:?}, default_profile={}",
+        config.profile_names(),
+        config.default_profile(),
+    );
+    if let Err(error) = ensure_output_encoders(&config).and_then(|()| ensure_tonemap_filters()) {
+        log::error!("{error:?}");
+        return Err(error);
+    }
+
+    let convert_options = ConvertOptions {
+        max_output_bytes: parse_env_limit("MAX_OUTPUT_BYTES", DEFAULT_MAX_OUTPUT_BYTES),
+        timeout: Duration::from_secs(parse_env_limit(
+            "CONVERSION_TIMEOUT_SECS",
+            DEFAULT_CONVERSION_TIMEOUT_SECS,
+        )),
+    };
+    log::info!(
+        "Conversion options: max_output_bytes={}, timeout_secs={}",
+        convert_options.max_output_bytes,
+        convert_options.timeout.as_secs(),
+    );
+    let job_store_path =
+        std::env::var("JOB_STORE_PATH").unwrap_or_else(|_| DEFAULT_JOB_STORE_PATH.to_string());
+    let store = match JobStore::open(std::path::Path::new(&job_store_path)) {
+        Ok(store) => Arc::new(store),
+        Err(error) => {
+            log::error!("{error:?}");
+            return Err(error);
+        }
+    };
+    log::info!("Job store opened: path={}", job_store_path);
+
+    let conversion_workers = parse_env_limit("CONVERSION_WORKERS", DEFAULT_CONVERSION_WORKERS);
+    let max_queued_jobs = parse_env_limit("MAX_QUEUED_JOBS", DEFAULT_MAX_QUEUED_JOBS);
+    log::info!(
+        "Worker pool: workers={}, max_queued_jobs={}",
+        conversion_workers,
+        max_queued_jobs,
+    );
+    let transcoder: Arc<dyn Transcoder> = Arc::new(FfmpegTranscoder::new(
+        convert_options,
+        WorkerPool::new(conversion_workers, max_queued_jobs).with_store(Arc::clone(&store)),
+    ));
+
+    let image_user_daily_limit =
+        parse_env_limit("IMAGE_USER_DAILY_LIMIT", DEFAULT_IMAGE_USER_DAILY_LIMIT);
+    let image_global_daily_limit =
+        parse_env_limit("IMAGE_GLOBAL_DAILY_LIMIT", DEFAULT_IMAGE_GLOBAL_DAILY_LIMIT);
+    let limiter = RateLimiter::new(user_daily_limit, global_daily_limit)
+        .with_image_limits(image_user_daily_limit, image_global_daily_limit)
+        .with_store(Arc::clone(&store))
+        .context("Failed to restore quota usage from the job store");
+    let limiter = match limiter {
+        Ok(limiter) => Arc::new(Mutex::new(limiter)),
+        Err(error) => {
+            log::error!("{error:?}");
+            return Err(error);
+        }
+    };
     let monitor_limiter = Arc::clone(&limiter);
 
     {
         let limiter = limiter.lock().await;
         log::info!(
-            "Rate limits initialized: day_index={}, user_daily_limit={}, global_daily_limit={}, next_reset_in_seconds={}",
+            "Rate limits initialized: day_index={}, user_daily_limit={}, global_daily_limit={}, image_user_daily_limit={}, image_global_daily_limit={}, next_reset_in_seconds={}",
             limiter.current_day_index(),
             user_daily_limit,
             global_daily_limit,
+            image_user_daily_limit,
+            image_global_daily_limit,
             next_midnight_utc_seconds(),
         );
     }
@@ -102,14 +372,19 @@ async fn main() -> AnyResult<()> {
         return Err(error);
     }
 
+    if let Err(error) = resume_pending_jobs(&bot, &store, &limiter, &transcoder, &config).await {
+        log::error!("Failed to resume unfinished jobs: {error:?}");
+    }
+
+    // `repl` обрабатывает сообщения одного чата по очереди, поэтому каждое
+    // уходит в свою задачу: иначе второй файл из чата ждал бы конца первого,
+    // не попадая в очередь воркеров, а `/cancel` — конца конвертации.
     teloxide::repl(bot, move |bot: Bot, msg: Message| {
         let limiter = Arc::clone(&limiter);
-        async move {
-            if let Err(e) = process_video(&bot, &msg, &limiter).await {
-                log::error!("Error processing video file: {:?}", e);
-            }
-            respond(())
-        }
+        let transcoder = Arc::clone(&transcoder);
+        let config = Arc::clone(&config);
+        tokio::spawn(handle_message(bot, msg, limiter, transcoder, config));
+        async { respond(()) }
     })
     .await;
     Ok(())
@@ -0,0 +1,572 @@
+use anyhow::{anyhow, Context, Result as AnyResult};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::process::Command;
+
+/// Контейнер входного файла по данным ffprobe.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Container {
+    Mp4,
+    QuickTime,
+    Matroska,
+    Other(String),
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum StreamKind {
+    Video,
+    Audio,
+    Subtitle,
+    #[default]
+    Other,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct StreamInfo {
+    pub index: u32,
+    pub kind: StreamKind,
+    pub codec: Option<String>,
+    pub pixel_format: Option<String>,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    /// Число каналов звуковой дорожки.
+    pub channels: Option<u32>,
+    /// Язык из тега `language` (обычно ISO 639-2: `eng`, `rus`, `jpn`).
+    pub language: Option<String>,
+    /// Флаг `default` из disposition: дорожка, которую плеер выберет сам.
+    pub default: bool,
+    /// На сколько градусов по часовой стрелке повернуть кадр при показе
+    /// (0, 90, 180 или 270): матрица отображения или старый тег `rotate`.
+    pub rotation: u16,
+    /// Передаточная характеристика (`color_transfer`): `smpte2084`, `bt709`...
+    pub color_transfer: Option<String>,
+    /// Базовая частота кадров (`r_frame_rate`): у VFR-видео обычно максимум.
+    pub frame_rate: Option<f64>,
+    /// Средняя частота кадров (`avg_frame_rate`).
+    pub avg_frame_rate: Option<f64>,
+}
+
+/// Передаточные характеристики HDR: PQ (HDR10, Dolby Vision) и HLG.
+const HDR_TRANSFERS: &[&str] = &["smpte2084", "arib-std-b67"];
+
+/// Субтитры-картинки: их нельзя перевести в текст, только наложить на кадр.
+const BITMAP_SUBTITLE_CODECS: &[&str] =
+    &["dvb_subtitle", "dvd_subtitle", "hdmv_pgs_subtitle", "xsub"];
+
+impl StreamInfo {
+    pub fn is_hdr(&self) -> bool {
+        self.color_transfer
+            .as_deref()
+            .is_some_and(|transfer| HDR_TRANSFERS.contains(&transfer))
+    }
+
+    pub fn is_bitmap_subtitle(&self) -> bool {
+        self.kind == StreamKind::Subtitle
+            && self
+                .codec
+                .as_deref()
+                .is_some_and(|codec| BITMAP_SUBTITLE_CODECS.contains(&codec))
+    }
+}
+
+/// Теги контейнера, которые мы показываем в Telegram.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MediaTags {
+    pub title: Option<String>,
+    pub artist: Option<String>,
+}
+
+/// Результат анализа медиафайла через ffprobe.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MediaInfo {
+    pub container: Container,
+    pub duration_secs: Option<f64>,
+    pub bit_rate: Option<u64>,
+    pub streams: Vec<StreamInfo>,
+    pub tags: MediaTags,
+    /// EXIF-ориентация первого кадра (1–8), есть только у `probe_image`.
+    pub exif_orientation: Option<u8>,
+}
+
+impl MediaInfo {
+    pub fn video_stream(&self) -> Option<&StreamInfo> {
+        self.streams
+            .iter()
+            .find(|stream| stream.kind == StreamKind::Video)
+    }
+
+    pub fn audio_streams(&self) -> impl Iterator<Item = &StreamInfo> {
+        self.streams
+            .iter()
+            .filter(|stream| stream.kind == StreamKind::Audio)
+    }
+
+    /// Дорожки субтитров в порядке ffmpeg: позиция в итераторе — это `N`
+    /// в `0:s:N`.
+    pub fn subtitle_streams(&self) -> impl Iterator<Item = &StreamInfo> {
+        self.streams
+            .iter()
+            .filter(|stream| stream.kind == StreamKind::Subtitle)
+    }
+
+    pub fn has_audio(&self) -> bool {
+        self.audio_streams().next().is_some()
+    }
+
+    /// Число каналов первой звуковой дорожки.
+    pub fn audio_channels(&self) -> Option<u32> {
+        self.audio_streams().next()?.channels
+    }
+
+    pub fn video_codec(&self) -> Option<&str> {
+        self.video_stream()?.codec.as_deref()
+    }
+
+    pub fn pixel_format(&self) -> Option<&str> {
+        self.video_stream()?.pixel_format.as_deref()
+    }
+
+    /// Есть ли в кадре альфа-канал, по формату пикселей видеопотока.
+    pub fn has_alpha(&self) -> bool {
+        self.pixel_format().is_some_and(pixel_format_has_alpha)
+    }
+
+    pub fn dimensions(&self) -> Option<(u32, u32)> {
+        let video = self.video_stream()?;
+        Some((video.width?, video.height?))
+    }
+
+    /// Видео снято в HDR (PQ или HLG).
+    pub fn is_hdr(&self) -> bool {
+        self.video_stream().is_some_and(StreamInfo::is_hdr)
+    }
+
+    /// Базовая и средняя частоты кадров видеопотока.
+    pub fn frame_rates(&self) -> (Option<f64>, Option<f64>) {
+        self.video_stream().map_or((None, None), |video| {
+            (video.frame_rate, video.avg_frame_rate)
+        })
+    }
+
+    pub fn video_rotation(&self) -> u16 {
+        self.video_stream().map_or(0, |video| video.rotation)
+    }
+
+    /// Размеры кадра в том виде, в каком его показывает плеер: с учётом
+    /// поворота на 90 или 270 градусов.
+    pub fn display_dimensions(&self) -> Option<(u32, u32)> {
+        let (width, height) = self.dimensions()?;
+        match self.video_rotation() {
+            90 | 270 => Some((height, width)),
+            _ => Some((width, height)),
+        }
+    }
+}
+
+fn pixel_format_has_alpha(pixel_format: &str) -> bool {
+    const ALPHA_PREFIXES: &[&str] = &["yuva", "gbrap", "ya", "rgba", "bgra", "argb", "abgr"];
+    // В палитре pal8 тоже бывает прозрачность (GIF, PNG-8, BMP).
+    pixel_format == "pal8"
+        || ALPHA_PREFIXES
+            .iter()
+            .any(|prefix| pixel_format.starts_with(prefix))
+}
+
+#[derive(Debug, Deserialize)]
+struct ProbeOutput {
+    #[serde(default)]
+    streams: Vec<ProbeStream>,
+    format: Option<ProbeFormat>,
+    #[serde(default)]
+    frames: Vec<ProbeFrame>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ProbeFrame {
+    #[serde(default)]
+    tags: HashMap<String, String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ProbeStream {
+    index: u32,
+    codec_type: Option<String>,
+    codec_name: Option<String>,
+    pix_fmt: Option<String>,
+    width: Option<u32>,
+    height: Option<u32>,
+    channels: Option<u32>,
+    color_transfer: Option<String>,
+    r_frame_rate: Option<String>,
+    avg_frame_rate: Option<String>,
+    #[serde(default)]
+    tags: HashMap<String, String>,
+    #[serde(default)]
+    disposition: ProbeDisposition,
+    #[serde(default)]
+    side_data_list: Vec<ProbeSideData>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ProbeSideData {
+    side_data_type: Option<String>,
+    /// Поворот матрицы отображения против часовой стрелки, как его печатает
+    /// ffprobe: у портретного видео с телефона обычно `-90`.
+    rotation: Option<f64>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct ProbeDisposition {
+    #[serde(default)]
+    default: u8,
+}
+
+#[derive(Debug, Deserialize)]
+struct ProbeFormat {
+    format_name: Option<String>,
+    duration: Option<String>,
+    bit_rate: Option<String>,
+    #[serde(default)]
+    tags: HashMap<String, String>,
+}
+
+/// Значение тега без учёта регистра: FLAC и Ogg хранят `TITLE`, MP4 — `title`.
+fn find_tag(tags: &HashMap<String, String>, key: &str) -> Option<String> {
+    tags.iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case(key))
+        .map(|(_, value)| value.trim().to_string())
+        .filter(|value| !value.is_empty())
+}
+
+fn detect_container(format_name: &str, major_brand: Option<&str>) -> Container {
+    let names: Vec<&str> = format_name.split(',').collect();
+    if names.contains(&"mp4") || names.contains(&"mov") {
+        // mov и mp4 делят один демультиплексор, различаем их по major_brand.
+        return match major_brand.map(str::trim) {
+            Some("qt") => Container::QuickTime,
+            _ => Container::Mp4,
+        };
+    }
+    if names.contains(&"matroska") || names.contains(&"webm") {
+        return Container::Matroska;
+    }
+    Container::Other(format_name.to_string())
+}
+
+/// Поворот по часовой стрелке, округлённый до четверти оборота. Матрица
+/// отображения важнее тега `rotate`, который пишут старые муксеры.
+fn parse_rotation(stream: &ProbeStream) -> u16 {
+    let clockwise = stream
+        .side_data_list
+        .iter()
+        .filter(|side_data| side_data.side_data_type.as_deref() == Some("Display Matrix"))
+        .find_map(|side_data| side_data.rotation)
+        .map(|rotation| -rotation)
+        .or_else(|| find_tag(&stream.tags, "rotate").and_then(|value| value.parse().ok()));
+    clockwise.map_or(0, |degrees: f64| {
+        ((degrees / 90.0).round() as i64 * 90).rem_euclid(360) as u16
+    })
+}
+
+/// Частота кадров в виде дроби ffprobe: `30000/1001`. `0/0` у потоков без
+/// частоты (картинки, звук) превращается в `None`.
+fn parse_frame_rate(value: &str) -> Option<f64> {
+    let rate = match value.split_once('/') {
+        Some((numerator, denominator)) => {
+            numerator.parse::<f64>().ok()? / denominator.parse::<f64>().ok()?
+        }
+        None => value.parse().ok()?,
+    };
+    (rate.is_finite() && rate > 0.0).then_some(rate)
+}
+
+fn parse_stream_kind(codec_type: Option<&str>) -> StreamKind {
+    match codec_type {
+        Some("video") => StreamKind::Video,
+        Some("audio") => StreamKind::Audio,
+        Some("subtitle") => StreamKind::Subtitle,
+        _ => StreamKind::Other,
+    }
+}
+
+/// Разбирает JSON, выданный `ffprobe -print_format json -show_format -show_streams`.
+fn parse_probe_output(json: &str) -> AnyResult<MediaInfo> {
+    let output: ProbeOutput =
+        serde_json::from_str(json).context("Failed to parse ffprobe JSON output")?;
+    let format = output
+        .format
+        .ok_or_else(|| anyhow!("ffprobe output has no format section"))?;
+
+    let container = detect_container(
+        format.format_name.as_deref().unwrap_or_default(),
+        find_tag(&format.tags, "major_brand").as_deref(),
+    );
+    // Ogg/Opus и часть WebM хранят теги не в контейнере, а в звуковом потоке.
+    let audio_tags = output
+        .streams
+        .iter()
+        .find(|stream| stream.codec_type.as_deref() == Some("audio"))
+        .map(|stream| &stream.tags);
+    let find_media_tag = |key: &str| {
+        find_tag(&format.tags, key).or_else(|| audio_tags.and_then(|tags| find_tag(tags, key)))
+    };
+    let tags = MediaTags {
+        title: find_media_tag("title"),
+        artist: find_media_tag("artist"),
+    };
+
+    let streams = output
+        .streams
+        .into_iter()
+        .map(|stream| StreamInfo {
+            index: stream.index,
+            kind: parse_stream_kind(stream.codec_type.as_deref()),
+            rotation: parse_rotation(&stream),
+            language: find_tag(&stream.tags, "language").filter(|language| language != "und"),
+            default: stream.disposition.default == 1,
+            codec: stream.codec_name,
+            pixel_format: stream.pix_fmt,
+            width: stream.width,
+            height: stream.height,
+            channels: stream.channels,
+            color_transfer: stream.color_transfer,
+            frame_rate: stream.r_frame_rate.as_deref().and_then(parse_frame_rate),
+            avg_frame_rate: stream.avg_frame_rate.as_deref().and_then(parse_frame_rate),
+        })
+        .collect();
+
+    let exif_orientation = output
+        .frames
+        .first()
+        .and_then(|frame| find_tag(&frame.tags, "Orientation"))
+        .and_then(|value| value.parse::<u8>().ok())
+        .filter(|orientation| (1..=8).contains(orientation));
+
+    Ok(MediaInfo {
+        container,
+        duration_secs: format.duration.and_then(|value| value.parse().ok()),
+        bit_rate: format.bit_rate.and_then(|value| value.parse().ok()),
+        streams,
+        tags,
+        exif_orientation,
+    })
+}
+
+/// Запускает ffprobe и возвращает описание контейнера и потоков файла.
+pub fn probe_media(file_path: &str) -> AnyResult<MediaInfo> {
+    run_ffprobe(file_path, &[])
+}
+
+/// Как `probe_media`, но дополнительно читает метаданные первого кадра:
+/// EXIF-ориентацию декодеры отдают только на уровне кадра.
+pub fn probe_image(file_path: &str) -> AnyResult<MediaInfo> {
+    run_ffprobe(file_path, &["-show_frames", "-read_intervals", "%+#1"])
+}
+
+fn run_ffprobe(file_path: &str, extra_args: &[&str]) -> AnyResult<MediaInfo> {
+    let output = Command::new("ffprobe")
+        .args([
+            "-v",
+            "error",
+            "-print_format",
+            "json",
+            "-show_format",
+            "-show_streams",
+        ])
+        .args(extra_args)
+        .arg(file_path)
+        .output()
+        .context("Failed to run ffprobe")?;
+
+    if !output.status.success() {
+        return Err(anyhow!(
+            "ffprobe failed for {}: {}",
+            file_path,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+
+    parse_probe_output(&String::from_utf8_lossy(&output.stdout))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{detect_container, parse_frame_rate, parse_probe_output, Container, StreamKind};
+
+    const MKV_PROBE: &str = r#"{
+        "streams": [
+            {"index": 0, "codec_name": "h264", "codec_type": "video", "pix_fmt": "yuv420p", "width": 1920, "height": 1080,
+                "r_frame_rate": "60/1", "avg_frame_rate": "30000/1001"},
+            {"index": 1, "codec_name": "aac", "codec_type": "audio", "disposition": {"default": 1}},
+            {"index": 2, "codec_name": "subrip", "codec_type": "subtitle", "tags": {"language": "eng"}},
+            {"index": 3, "codec_name": "hdmv_pgs_subtitle", "codec_type": "subtitle", "tags": {"language": "und"}}
+        ],
+        "format": {"format_name": "matroska,webm", "duration": "12.480000", "bit_rate": "4213000"}
+    }"#;
+
+    #[test]
+    fn parses_streams_and_format() {
+        let info = parse_probe_output(MKV_PROBE).unwrap();
+
+        assert_eq!(info.container, Container::Matroska);
+        assert_eq!(info.duration_secs, Some(12.48));
+        assert_eq!(info.bit_rate, Some(4_213_000));
+        assert_eq!(info.video_codec(), Some("h264"));
+        assert_eq!(info.pixel_format(), Some("yuv420p"));
+        assert_eq!(info.dimensions(), Some((1920, 1080)));
+        assert_eq!(info.streams[2].kind, StreamKind::Subtitle);
+        assert!(info.has_audio());
+        assert!(info.streams[1].default);
+        let (frame_rate, avg_frame_rate) = info.frame_rates();
+        assert_eq!(frame_rate, Some(60.0));
+        assert!((avg_frame_rate.unwrap() - 29.97).abs() < 0.01);
+        assert!(!info.streams[0].default);
+
+        let subtitles: Vec<_> = info.subtitle_streams().collect();
+        assert_eq!(subtitles.len(), 2);
+        assert_eq!(subtitles[0].language.as_deref(), Some("eng"));
+        assert!(!subtitles[0].is_bitmap_subtitle());
+        assert_eq!(subtitles[1].language, None);
+        assert!(subtitles[1].is_bitmap_subtitle());
+    }
+
+    #[test]
+    fn reads_audio_tags_case_insensitively() {
+        let info = parse_probe_output(
+            r#"{
+                "streams": [{"index": 0, "codec_name": "flac", "codec_type": "audio", "channels": 1}],
+                "format": {"format_name": "flac", "duration": "4.2", "tags": {"TITLE": "Memo", "ARTIST": " Ann ", "ALBUM": ""}}
+            }"#,
+        )
+        .unwrap();
+
+        assert_eq!(info.tags.title.as_deref(), Some("Memo"));
+        assert_eq!(info.tags.artist.as_deref(), Some("Ann"));
+        assert_eq!(info.audio_channels(), Some(1));
+    }
+
+    #[test]
+    fn falls_back_to_audio_stream_tags() {
+        let info = parse_probe_output(
+            r#"{
+                "streams": [{"index": 0, "codec_name": "opus", "codec_type": "audio", "channels": 2,
+                    "tags": {"TITLE": "Voice memo", "ARTIST": "Ann", "ENCODER": "Lavf"}}],
+                "format": {"format_name": "ogg", "duration": "4.2", "tags": {"ARTIST": "Format artist"}}
+            }"#,
+        )
+        .unwrap();
+
+        assert_eq!(info.tags.title.as_deref(), Some("Voice memo"));
+        assert_eq!(info.tags.artist.as_deref(), Some("Format artist"));
+    }
+
+    #[test]
+    fn reads_exif_orientation_and_alpha_from_image_probe() {
+        let info = parse_probe_output(
+            r#"{
+                "frames": [{"media_type": "video", "tags": {"Orientation": "6"}}],
+                "streams": [{"index": 0, "codec_name": "png", "codec_type": "video", "pix_fmt": "rgba", "width": 640, "height": 480}],
+                "format": {"format_name": "png_pipe"}
+            }"#,
+        )
+        .unwrap();
+
+        assert_eq!(info.exif_orientation, Some(6));
+        assert!(info.has_alpha());
+
+        let opaque = parse_probe_output(MKV_PROBE).unwrap();
+        assert_eq!(opaque.exif_orientation, None);
+        assert!(!opaque.has_alpha());
+    }
+
+    #[test]
+    fn reads_rotation_from_display_matrix_or_tag() {
+        let info = parse_probe_output(
+            r#"{
+                "streams": [{"index": 0, "codec_name": "hevc", "codec_type": "video", "width": 1920, "height": 1080,
+                    "side_data_list": [{"side_data_type": "Display Matrix", "displaymatrix": "...", "rotation": -90}]}],
+                "format": {"format_name": "mov,mp4,m4a,3gp,3g2,mj2"}
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(info.video_rotation(), 90);
+        assert_eq!(info.dimensions(), Some((1920, 1080)));
+        assert_eq!(info.display_dimensions(), Some((1080, 1920)));
+
+        let tagged = parse_probe_output(
+            r#"{
+                "streams": [{"index": 0, "codec_name": "h264", "codec_type": "video", "width": 640, "height": 480,
+                    "tags": {"rotate": "180"}}],
+                "format": {"format_name": "mov,mp4,m4a,3gp,3g2,mj2"}
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(tagged.video_rotation(), 180);
+        assert_eq!(tagged.display_dimensions(), Some((640, 480)));
+
+        let counter_clockwise = parse_probe_output(
+            r#"{
+                "streams": [{"index": 0, "codec_name": "h264", "codec_type": "video", "width": 640, "height": 480,
+                    "side_data_list": [{"side_data_type": "Display Matrix", "rotation": 90.0}]}],
+                "format": {"format_name": "mov,mp4,m4a,3gp,3g2,mj2"}
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(counter_clockwise.video_rotation(), 270);
+        assert_eq!(parse_probe_output(MKV_PROBE).unwrap().video_rotation(), 0);
+    }
+
+    #[test]
+    fn parses_frame_rate_fractions() {
+        assert_eq!(parse_frame_rate("25/1"), Some(25.0));
+        assert_eq!(parse_frame_rate("120"), Some(120.0));
+        assert_eq!(parse_frame_rate("0/0"), None);
+        assert_eq!(parse_frame_rate("30/0"), None);
+        assert_eq!(parse_frame_rate("fast"), None);
+    }
+
+    #[test]
+    fn detects_hdr_transfer() {
+        let probe = |transfer: &str| {
+            parse_probe_output(&format!(
+                r#"{{
+                    "streams": [{{"index": 0, "codec_name": "hevc", "codec_type": "video",
+                        "pix_fmt": "yuv420p10le", "color_transfer": "{}"}}],
+                    "format": {{"format_name": "mov,mp4,m4a,3gp,3g2,mj2"}}
+                }}"#,
+                transfer
+            ))
+            .unwrap()
+        };
+        assert!(probe("smpte2084").is_hdr());
+        assert!(probe("arib-std-b67").is_hdr());
+        assert!(!probe("bt709").is_hdr());
+        assert!(!parse_probe_output(MKV_PROBE).unwrap().is_hdr());
+    }
+
+    #[test]
+    fn tolerates_missing_optional_fields() {
+        let info = parse_probe_output(r#"{"format": {"format_name": "avi"}}"#).unwrap();
+
+        assert_eq!(info.container, Container::Other("avi".to_string()));
+        assert_eq!(info.duration_secs, None);
+        assert!(info.video_stream().is_none());
+        assert!(!info.has_audio());
+    }
+
+    #[test]
+    fn rejects_output_without_format() {
+        assert!(parse_probe_output(r#"{"streams": []}"#).is_err());
+    }
+
+    #[test]
+    fn distinguishes_quicktime_from_mp4() {
+        let mov_mp4 = "mov,mp4,m4a,3gp,3g2,mj2";
+        assert_eq!(
+            detect_container(mov_mp4, Some("qt  ")),
+            Container::QuickTime
+        );
+        assert_eq!(detect_container(mov_mp4, Some("isom")), Container::Mp4);
+        assert_eq!(detect_container(mov_mp4, None), Container::Mp4);
+    }
+}
@@ -0,0 +1,595 @@
+use std::fmt;
+
+use crate::config::SubtitleMode;
+use crate::converter::{
+    AudioFormat, FrameRatePlan, HdrPlan, ImageFormat, OutputKind, OutputTarget, SubtitlePlan,
+    MP4_COMPATIBLE_AUDIO_CODECS,
+};
+use crate::language::same_language;
+use crate::media::{Container, MediaInfo, StreamInfo};
+
+/// Форматы пикселей, которые клиенты Telegram декодируют без проблем.
+const NATIVE_PIXEL_FORMATS: &[&str] = &["yuv420p", "yuvj420p"];
+
+/// Допустимое относительное расхождение базовой и средней частоты кадров:
+/// у NTSC-файлов `r_frame_rate` и `avg_frame_rate` записаны по-разному.
+const VFR_TOLERANCE: f64 = 0.02;
+
+/// Причина, по которой видео оставляется в чате без конвертации.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SkipReason {
+    /// MP4 с H.264 в yuv420p и совместимым звуком Telegram проигрывает сам.
+    NativelyPlayable,
+    /// В файле нет видеопотока, конвертировать нечего.
+    NoVideoStream,
+}
+
+impl fmt::Display for SkipReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SkipReason::NativelyPlayable => {
+                write!(f, "already playable natively (mp4, h264, yuv420p)")
+            }
+            SkipReason::NoVideoStream => write!(f, "no video stream"),
+        }
+    }
+}
+
+/// Решает, нужно ли вообще трогать видео, по данным ffprobe.
+pub fn skip_reason(info: &MediaInfo) -> Option<SkipReason> {
+    if info.video_stream().is_none() {
+        return Some(SkipReason::NoVideoStream);
+    }
+
+    let native_container = info.container == Container::Mp4;
+    let native_video = info.video_codec() == Some("h264")
+        && info
+            .pixel_format()
+            .map(|pix_fmt| NATIVE_PIXEL_FORMATS.contains(&pix_fmt))
+            .unwrap_or(false);
+    // Из нескольких дорожек плееры Telegram играют только первую, так что
+    // многоязычный файл всё равно переупаковываем с одной нужной.
+    let native_audio = info.audio_streams().count() <= 1
+        && info.audio_streams().all(|stream| {
+            stream
+                .codec
+                .as_deref()
+                .map(|codec| MP4_COMPATIBLE_AUDIO_CODECS.contains(&codec))
+                .unwrap_or(false)
+        });
+
+    if native_container && native_video && native_audio {
+        Some(SkipReason::NativelyPlayable)
+    } else {
+        None
+    }
+}
+
+/// Короткие ролики без звука отправляем анимацией, остальное видео.
+pub fn choose_output_kind(info: Option<&MediaInfo>, max_animation_secs: f64) -> OutputKind {
+    let Some(info) = info else {
+        return OutputKind::Video;
+    };
+    let short = info
+        .duration_secs
+        .map(|duration| duration <= max_animation_secs)
+        .unwrap_or(false);
+
+    if short && !info.has_audio() {
+        OutputKind::Animation
+    } else {
+        OutputKind::Video
+    }
+}
+
+/// Цель чата годится только для обычного видео: анимации и кружки Telegram
+/// надёжно проигрывает лишь в H.264, поэтому для них цель всегда H.264.
+pub fn choose_output_target(output: OutputKind, preferred: OutputTarget) -> OutputTarget {
+    match output {
+        OutputKind::Video => preferred,
+        OutputKind::Animation | OutputKind::VideoNote => OutputTarget::Mp4H264,
+    }
+}
+
+/// Короткие моно-записи (диктофон, AMR) отправляем голосовым, остальное
+/// аудио MP3-файлом.
+pub fn choose_audio_format(info: Option<&MediaInfo>, max_voice_secs: f64) -> AudioFormat {
+    let Some(info) = info else {
+        return AudioFormat::Mp3;
+    };
+    let short = info
+        .duration_secs
+        .map(|duration| duration <= max_voice_secs)
+        .unwrap_or(false);
+
+    if short && info.audio_channels() == Some(1) {
+        AudioFormat::Voice
+    } else {
+        AudioFormat::Mp3
+    }
+}
+
+/// Прозрачность сохраняем в PNG, всё остальное отдаём JPEG.
+pub fn choose_image_format(info: Option<&MediaInfo>) -> ImageFormat {
+    if info.is_some_and(MediaInfo::has_alpha) {
+        ImageFormat::Png
+    } else {
+        ImageFormat::Jpeg
+    }
+}
+
+/// Выбирает, какие дорожки субтитров и как перенести в результат.
+///
+/// `Embed` перекладывает все текстовые дорожки; если их нет, а есть
+/// картинки (PGS, DVD), одна из них вжигается, как в `Burn`. Для вжигания
+/// берётся дорожка на языке `language`, иначе первая.
+pub fn choose_subtitle_plan(
+    info: Option<&MediaInfo>,
+    mode: SubtitleMode,
+    language: Option<&str>,
+) -> SubtitlePlan {
+    let Some(info) = info else {
+        return SubtitlePlan::None;
+    };
+    let tracks: Vec<(usize, &StreamInfo)> = info.subtitle_streams().enumerate().collect();
+
+    let burn_candidates: Vec<(usize, &StreamInfo)> = match mode {
+        SubtitleMode::Drop => return SubtitlePlan::None,
+        SubtitleMode::Burn => tracks,
+        SubtitleMode::Embed => {
+            let text_tracks: Vec<usize> = tracks
+                .iter()
+                .filter(|(_, stream)| !stream.is_bitmap_subtitle())
+                .map(|(track, _)| *track)
+                .collect();
+            if !text_tracks.is_empty() {
+                return SubtitlePlan::Embed(text_tracks);
+            }
+            tracks
+        }
+    };
+
+    let preferred = language.and_then(|language| {
+        burn_candidates.iter().find(|(_, stream)| {
+            stream
+                .language
+                .as_deref()
+                .is_some_and(|track_language| same_language(track_language, language))
+        })
+    });
+    match preferred.or(burn_candidates.first()) {
+        Some((track, stream)) if stream.is_bitmap_subtitle() => SubtitlePlan::BurnBitmap(*track),
+        Some((track, _)) => SubtitlePlan::BurnText(*track),
+        None => SubtitlePlan::None,
+    }
+}
+
+/// Выбирает единственную звуковую дорожку, которая попадёт в результат.
+///
+/// Языки из `preferred` перебираются по порядку; если ни один не нашёлся,
+/// берётся дорожка с флагом `default`, иначе первая. Возвращает `N`
+/// из `0:a:N`.
+pub fn choose_audio_track(info: Option<&MediaInfo>, preferred: &[&str]) -> usize {
+    let Some(info) = info else {
+        return 0;
+    };
+    let tracks: Vec<&StreamInfo> = info.audio_streams().collect();
+
+    preferred
+        .iter()
+        .find_map(|language| {
+            tracks.iter().position(|stream| {
+                stream
+                    .language
+                    .as_deref()
+                    .is_some_and(|track_language| same_language(track_language, language))
+            })
+        })
+        .or_else(|| tracks.iter().position(|stream| stream.default))
+        .unwrap_or(0)
+}
+
+/// Есть ли звуковая дорожка на языке `language`.
+pub fn has_audio_language(info: &MediaInfo, language: &str) -> bool {
+    info.audio_streams().any(|stream| {
+        stream
+            .language
+            .as_deref()
+            .is_some_and(|track_language| same_language(track_language, language))
+    })
+}
+
+/// HDR переводим в SDR, если чат не просил оставить 10 бит.
+pub fn choose_hdr_plan(info: Option<&MediaInfo>, keep_10bit: bool) -> HdrPlan {
+    match info {
+        Some(info) if info.is_hdr() && keep_10bit => HdrPlan::Keep10Bit,
+        Some(info) if info.is_hdr() => HdrPlan::ToneMap,
+        _ => HdrPlan::Sdr,
+    }
+}
+
+/// Частота кадров результата. Если средняя частота заметно отличается от
+/// базовой, метки времени неравномерные: такое видео приводим к средней
+/// частоте. Слишком быструю частоту снижаем до `max_fps`.
+pub fn choose_frame_rate_plan(info: Option<&MediaInfo>, max_fps: f64) -> FrameRatePlan {
+    let (frame_rate, avg_frame_rate) = info.map_or((None, None), MediaInfo::frame_rates);
+    let variable = match (frame_rate, avg_frame_rate) {
+        (Some(frame_rate), Some(avg_frame_rate)) => {
+            (frame_rate - avg_frame_rate).abs() > frame_rate * VFR_TOLERANCE
+        }
+        _ => false,
+    };
+    let source_fps = if variable {
+        avg_frame_rate
+    } else {
+        frame_rate.or(avg_frame_rate)
+    };
+    match source_fps {
+        Some(fps) if fps > max_fps => FrameRatePlan {
+            target_fps: Some(max_fps),
+            capped: true,
+            constant: variable,
+        },
+        Some(fps) if variable => FrameRatePlan {
+            target_fps: Some(fps),
+            capped: false,
+            constant: true,
+        },
+        _ => FrameRatePlan::default(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        choose_audio_format, choose_audio_track, choose_frame_rate_plan, choose_hdr_plan,
+        choose_image_format, choose_output_kind, choose_output_target, choose_subtitle_plan,
+        has_audio_language, skip_reason, SkipReason,
+    };
+    use crate::config::SubtitleMode;
+    use crate::converter::{
+        AudioFormat, FrameRatePlan, HdrPlan, ImageFormat, OutputKind, OutputTarget, SubtitlePlan,
+    };
+    use crate::media::{Container, MediaInfo, MediaTags, StreamInfo, StreamKind};
+
+    fn media(container: Container, video: Option<(&str, &str)>, audio: &[&str]) -> MediaInfo {
+        let mut streams = Vec::new();
+        if let Some((codec, pix_fmt)) = video {
+            streams.push(StreamInfo {
+                index: 0,
+                kind: StreamKind::Video,
+                codec: Some(codec.to_string()),
+                pixel_format: Some(pix_fmt.to_string()),
+                width: Some(1280),
+                height: Some(720),
+                ..Default::default()
+            });
+        }
+        for codec in audio {
+            streams.push(StreamInfo {
+                index: streams.len() as u32,
+                kind: StreamKind::Audio,
+                codec: Some(codec.to_string()),
+                pixel_format: None,
+                width: None,
+                height: None,
+                ..Default::default()
+            });
+        }
+        MediaInfo {
+            container,
+            duration_secs: Some(30.0),
+            bit_rate: None,
+            streams,
+            tags: MediaTags::default(),
+            exif_orientation: None,
+        }
+    }
+
+    #[test]
+    fn applies_skip_policy_to_samples() {
+        let cases = [
+            (
+                "phone mp4",
+                media(Container::Mp4, Some(("h264", "yuv420p")), &["aac"]),
+                Some(SkipReason::NativelyPlayable),
+            ),
+            (
+                "silent mp4",
+                media(Container::Mp4, Some(("h264", "yuv420p")), &[]),
+                Some(SkipReason::NativelyPlayable),
+            ),
+            (
+                "mp4 with full-range yuv",
+                media(Container::Mp4, Some(("h264", "yuvj420p")), &["mp3"]),
+                Some(SkipReason::NativelyPlayable),
+            ),
+            (
+                "hevc mp4",
+                media(Container::Mp4, Some(("hevc", "yuv420p")), &["aac"]),
+                None,
+            ),
+            (
+                "10-bit h264 mp4",
+                media(Container::Mp4, Some(("h264", "yuv420p10le")), &["aac"]),
+                None,
+            ),
+            (
+                "mp4 with opus",
+                media(Container::Mp4, Some(("h264", "yuv420p")), &["opus"]),
+                None,
+            ),
+            (
+                "quicktime mov",
+                media(Container::QuickTime, Some(("h264", "yuv420p")), &["aac"]),
+                None,
+            ),
+            (
+                "matroska",
+                media(Container::Matroska, Some(("h264", "yuv420p")), &["aac"]),
+                None,
+            ),
+            (
+                "webm",
+                media(Container::Matroska, Some(("vp9", "yuv420p")), &["opus"]),
+                None,
+            ),
+            (
+                "audio only",
+                media(Container::Mp4, None, &["aac"]),
+                Some(SkipReason::NoVideoStream),
+            ),
+        ];
+
+        for (name, info, expected) in cases {
+            assert_eq!(skip_reason(&info), expected, "case: {}", name);
+        }
+    }
+
+    #[test]
+    fn sends_short_silent_clips_as_animations() {
+        let mut silent = media(Container::Matroska, Some(("vp9", "yuv420p")), &[]);
+        let with_audio = media(Container::Matroska, Some(("vp9", "yuv420p")), &["opus"]);
+
+        assert_eq!(
+            choose_output_kind(Some(&silent), 30.0),
+            OutputKind::Animation
+        );
+        assert_eq!(
+            choose_output_kind(Some(&with_audio), 30.0),
+            OutputKind::Video
+        );
+        assert_eq!(choose_output_kind(Some(&silent), 10.0), OutputKind::Video);
+        assert_eq!(choose_output_kind(None, 30.0), OutputKind::Video);
+
+        silent.duration_secs = None;
+        assert_eq!(choose_output_kind(Some(&silent), 30.0), OutputKind::Video);
+    }
+
+    #[test]
+    fn sends_short_mono_recordings_as_voice() {
+        let recording = |duration_secs: f64, channels: u32| MediaInfo {
+            duration_secs: Some(duration_secs),
+            streams: vec![StreamInfo {
+                kind: StreamKind::Audio,
+                codec: Some("amr_nb".to_string()),
+                channels: Some(channels),
+                ..Default::default()
+            }],
+            ..media(Container::Other("amr".to_string()), None, &[])
+        };
+
+        assert_eq!(
+            choose_audio_format(Some(&recording(40.0, 1)), 120.0),
+            AudioFormat::Voice
+        );
+        assert_eq!(
+            choose_audio_format(Some(&recording(40.0, 2)), 120.0),
+            AudioFormat::Mp3
+        );
+        assert_eq!(
+            choose_audio_format(Some(&recording(600.0, 1)), 120.0),
+            AudioFormat::Mp3
+        );
+        assert_eq!(choose_audio_format(None, 120.0), AudioFormat::Mp3);
+    }
+
+    #[test]
+    fn keeps_transparency_in_png() {
+        let image = |pix_fmt: &str| {
+            media(
+                Container::Other("webp_pipe".to_string()),
+                Some(("webp", pix_fmt)),
+                &[],
+            )
+        };
+
+        assert_eq!(
+            choose_image_format(Some(&image("yuva420p"))),
+            ImageFormat::Png
+        );
+        assert_eq!(
+            choose_image_format(Some(&image("yuv420p"))),
+            ImageFormat::Jpeg
+        );
+        assert_eq!(choose_image_format(None), ImageFormat::Jpeg);
+    }
+
+    fn with_subtitles(tracks: &[(&str, Option<&str>)]) -> MediaInfo {
+        let mut info = media(Container::Matroska, Some(("h264", "yuv420p")), &["aac"]);
+        for (codec, language) in tracks {
+            info.streams.push(StreamInfo {
+                index: info.streams.len() as u32,
+                kind: StreamKind::Subtitle,
+                codec: Some(codec.to_string()),
+                language: language.map(str::to_string),
+                ..Default::default()
+            });
+        }
+        info
+    }
+
+    #[test]
+    fn burns_language_matched_or_first_subtitle_track() {
+        let info = with_subtitles(&[("ass", Some("jpn")), ("subrip", Some("eng"))]);
+        assert_eq!(
+            choose_subtitle_plan(Some(&info), SubtitleMode::Burn, Some("ENG")),
+            SubtitlePlan::BurnText(1)
+        );
+        assert_eq!(
+            choose_subtitle_plan(Some(&info), SubtitleMode::Burn, Some("rus")),
+            SubtitlePlan::BurnText(0)
+        );
+        assert_eq!(
+            choose_subtitle_plan(Some(&info), SubtitleMode::Drop, Some("eng")),
+            SubtitlePlan::None
+        );
+
+        let bluray = with_subtitles(&[("hdmv_pgs_subtitle", Some("eng"))]);
+        assert_eq!(
+            choose_subtitle_plan(Some(&bluray), SubtitleMode::Burn, None),
+            SubtitlePlan::BurnBitmap(0)
+        );
+    }
+
+    #[test]
+    fn embeds_text_tracks_and_burns_bitmap_only_inputs() {
+        let info = with_subtitles(&[
+            ("hdmv_pgs_subtitle", None),
+            ("subrip", Some("eng")),
+            ("ass", Some("rus")),
+        ]);
+        assert_eq!(
+            choose_subtitle_plan(Some(&info), SubtitleMode::Embed, None),
+            SubtitlePlan::Embed(vec![1, 2])
+        );
+
+        let dvd = with_subtitles(&[("dvd_subtitle", Some("eng"))]);
+        assert_eq!(
+            choose_subtitle_plan(Some(&dvd), SubtitleMode::Embed, None),
+            SubtitlePlan::BurnBitmap(0)
+        );
+        assert_eq!(
+            choose_subtitle_plan(Some(&with_subtitles(&[])), SubtitleMode::Embed, None),
+            SubtitlePlan::None
+        );
+        assert_eq!(
+            choose_subtitle_plan(None, SubtitleMode::Burn, None),
+            SubtitlePlan::None
+        );
+    }
+
+    fn with_audio_tracks(tracks: &[(Option<&str>, bool)]) -> MediaInfo {
+        let mut info = media(Container::Matroska, Some(("h264", "yuv420p")), &[]);
+        for (language, default) in tracks {
+            info.streams.push(StreamInfo {
+                index: info.streams.len() as u32,
+                kind: StreamKind::Audio,
+                codec: Some("aac".to_string()),
+                language: language.map(str::to_string),
+                default: *default,
+                ..Default::default()
+            });
+        }
+        info
+    }
+
+    #[test]
+    fn picks_preferred_language_then_default_audio_track() {
+        let info = with_audio_tracks(&[(Some("eng"), false), (Some("jpn"), true), (None, false)]);
+        assert_eq!(choose_audio_track(Some(&info), &["rus", "JPN", "eng"]), 1);
+        assert_eq!(choose_audio_track(Some(&info), &["eng"]), 0);
+        assert_eq!(choose_audio_track(Some(&info), &["rus"]), 1);
+        assert_eq!(choose_audio_track(Some(&info), &[]), 1);
+        assert_eq!(choose_audio_track(Some(&info), &["en"]), 0);
+        assert!(has_audio_language(&info, "ja"));
+        assert!(!has_audio_language(&info, "rus"));
+
+        let untagged = with_audio_tracks(&[(None, false), (None, false)]);
+        assert_eq!(choose_audio_track(Some(&untagged), &["eng"]), 0);
+        assert_eq!(choose_audio_track(None, &["eng"]), 0);
+    }
+
+    #[test]
+    fn does_not_skip_mp4_with_several_audio_tracks() {
+        let mut info = with_audio_tracks(&[(Some("eng"), true), (Some("jpn"), false)]);
+        info.container = Container::Mp4;
+        assert_eq!(skip_reason(&info), None);
+    }
+
+    #[test]
+    fn tone_maps_hdr_unless_chat_keeps_10bit() {
+        let mut hdr = media(Container::Mp4, Some(("hevc", "yuv420p10le")), &["aac"]);
+        hdr.streams[0].color_transfer = Some("arib-std-b67".to_string());
+        assert_eq!(choose_hdr_plan(Some(&hdr), false), HdrPlan::ToneMap);
+        assert_eq!(choose_hdr_plan(Some(&hdr), true), HdrPlan::Keep10Bit);
+
+        let sdr = media(Container::Mp4, Some(("hevc", "yuv420p10le")), &["aac"]);
+        assert_eq!(choose_hdr_plan(Some(&sdr), true), HdrPlan::Sdr);
+        assert_eq!(choose_hdr_plan(None, false), HdrPlan::Sdr);
+    }
+
+    #[test]
+    fn caps_fast_and_normalises_variable_frame_rates() {
+        let with_rates = |frame_rate: f64, avg_frame_rate: f64| {
+            let mut info = media(Container::Mp4, Some(("h264", "yuv420p")), &["aac"]);
+            info.streams[0].frame_rate = Some(frame_rate);
+            info.streams[0].avg_frame_rate = Some(avg_frame_rate);
+            info
+        };
+
+        assert_eq!(
+            choose_frame_rate_plan(Some(&with_rates(30.0, 29.97)), 60.0),
+            FrameRatePlan::default()
+        );
+        assert_eq!(
+            choose_frame_rate_plan(Some(&with_rates(120.0, 120.0)), 60.0),
+            FrameRatePlan {
+                target_fps: Some(60.0),
+                capped: true,
+                constant: false,
+            }
+        );
+        assert_eq!(
+            choose_frame_rate_plan(Some(&with_rates(90.0, 24.5)), 60.0),
+            FrameRatePlan {
+                target_fps: Some(24.5),
+                capped: false,
+                constant: true,
+            }
+        );
+        assert_eq!(
+            choose_frame_rate_plan(Some(&with_rates(240.0, 100.0)), 60.0),
+            FrameRatePlan {
+                target_fps: Some(60.0),
+                capped: true,
+                constant: true,
+            }
+        );
+        assert_eq!(choose_frame_rate_plan(None, 60.0), FrameRatePlan::default());
+    }
+
+    #[test]
+    fn keeps_mp4_targets_for_animations_and_notes() {
+        assert_eq!(
+            choose_output_target(OutputKind::Video, OutputTarget::WebmVp9),
+            OutputTarget::WebmVp9
+        );
+        assert_eq!(
+            choose_output_target(OutputKind::VideoNote, OutputTarget::Mp4Av1),
+            OutputTarget::Mp4H264
+        );
+        assert_eq!(
+            choose_output_target(OutputKind::Animation, OutputTarget::WebmVp9),
+            OutputTarget::Mp4H264
+        );
+        assert_eq!(
+            choose_output_target(OutputKind::Animation, OutputTarget::Mp4Hevc),
+            OutputTarget::Mp4H264
+        );
+        assert_eq!(
+            choose_output_target(OutputKind::VideoNote, OutputTarget::Mp4Hevc),
+            OutputTarget::Mp4H264
+        );
+    }
+}
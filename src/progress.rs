@@ -0,0 +1,266 @@
+/// Какой запуск ffmpeg сейчас идёт: у каждого свой отсчёт процентов.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ProgressStage {
+    /// Кодирование результата.
+    #[default]
+    Converting,
+    /// Первый проход `loudnorm`: только замер громкости.
+    MeasuringLoudness,
+    /// Перекодирование после неудачного ремукса.
+    Reencoding,
+    /// Кодирование под размер: проход `pass` из `passes`; попытки после
+    /// первой (`attempt` с 1) идут в меньшем разрешении.
+    FittingSize { attempt: u8, pass: u8, passes: u8 },
+}
+
+impl ProgressStage {
+    fn label(self) -> String {
+        match self {
+            ProgressStage::Converting => "converting".to_string(),
+            ProgressStage::MeasuringLoudness => "measuring loudness".to_string(),
+            ProgressStage::Reencoding => "re-encoding".to_string(),
+            ProgressStage::FittingSize {
+                attempt,
+                pass,
+                passes,
+            } => {
+                let resolution = if attempt > 1 {
+                    " at a lower resolution"
+                } else {
+                    ""
+                };
+                format!("shrinking to fit{}, pass {}/{}", resolution, pass, passes)
+            }
+        }
+    }
+}
+
+/// Снимок прогресса ffmpeg, собранный из блока `-progress pipe:1`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ProgressUpdate {
+    /// Позиция в выходном файле, секунды.
+    pub out_time_secs: f64,
+    /// Скорость кодирования относительно реального времени (`1.5x`).
+    pub speed: Option<f64>,
+    /// ffmpeg прислал `progress=end`.
+    pub finished: bool,
+    /// Проставляет конвертер; парсер считает всё кодированием.
+    pub stage: ProgressStage,
+}
+
+impl ProgressUpdate {
+    /// Процент готовности относительно длительности из ffprobe.
+    pub fn percent(&self, duration_secs: f64) -> Option<u8> {
+        if self.finished {
+            return Some(100);
+        }
+        if duration_secs <= 0.0 {
+            return None;
+        }
+        let percent = (self.out_time_secs / duration_secs * 100.0).clamp(0.0, 100.0);
+        Some(percent as u8)
+    }
+}
+
+/// Текст статусного сообщения для чата.
+pub fn format_progress(update: Option<&ProgressUpdate>, duration_secs: Option<f64>) -> String {
+    let label = update
+        .map_or(ProgressStage::Converting, |update| update.stage)
+        .label();
+    let percent = update.and_then(|update| duration_secs.and_then(|d| update.percent(d)));
+    match (percent, update.and_then(|update| update.speed)) {
+        (Some(percent), Some(speed)) => format!("{}… {}% ({:.1}x)", label, percent, speed),
+        (Some(percent), None) => format!("{}… {}%", label, percent),
+        (None, _) => format!("{}…", label),
+    }
+}
+
+/// Построчный разбор вывода `ffmpeg -progress pipe:1`.
+///
+/// ffmpeg пишет блоки `key=value`, каждый из которых завершается строкой
+/// `progress=continue` или `progress=end`.
+#[derive(Debug, Default)]
+pub struct ProgressParser {
+    out_time_secs: Option<f64>,
+    speed: Option<f64>,
+}
+
+impl ProgressParser {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Принимает очередную строку и возвращает снимок, когда блок закончен.
+    pub fn feed_line(&mut self, line: &str) -> Option<ProgressUpdate> {
+        let (key, value) = line.trim().split_once('=')?;
+        let value = value.trim();
+
+        match key {
+            // Несмотря на название, `out_time_ms` у ffmpeg в микросекундах,
+            // как и `out_time_us`. В начале кодирования там бывает мусор
+            // вроде `-9223372036854775807`, такие значения пропускаем.
+            "out_time_us" | "out_time_ms" => {
+                if let Some(micros) = value.parse::<i64>().ok().filter(|micros| *micros >= 0) {
+                    self.out_time_secs = Some(micros as f64 / 1_000_000.0);
+                }
+            }
+            "speed" => {
+                self.speed = value
+                    .trim_end_matches('x')
+                    .trim()
+                    .parse::<f64>()
+                    .ok()
+                    .filter(|speed| speed.is_finite());
+            }
+            "progress" => {
+                return Some(ProgressUpdate {
+                    out_time_secs: self.out_time_secs.unwrap_or(0.0),
+                    speed: self.speed,
+                    finished: value == "end",
+                    stage: ProgressStage::Converting,
+                });
+            }
+            _ => {}
+        }
+
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{format_progress, ProgressParser, ProgressStage, ProgressUpdate};
+
+    /// Фрагмент реального вывода `ffmpeg -progress pipe:1 -nostats`.
+    const RECORDED_PROGRESS: &str = "\
+frame=120
+fps=59.87
+stream_0_0_q=28.0
+bitrate= 812.4kbits/s
+total_size=524336
+out_time_us=5163000
+out_time_ms=5163000
+out_time=00:00:05.163000
+dup_frames=0
+drop_frames=0
+speed=2.57x
+progress=continue
+frame=251
+fps=62.10
+stream_0_0_q=28.0
+bitrate= 790.1kbits/s
+total_size=1048624
+out_time_us=-9223372036854775807
+out_time_ms=10617000
+out_time=00:00:10.617000
+dup_frames=0
+drop_frames=0
+speed=N/A
+progress=continue
+frame=300
+fps=61.00
+stream_0_0_q=-1.0
+bitrate= 801.0kbits/s
+total_size=1228800
+out_time_us=12480000
+out_time_ms=12480000
+out_time=00:00:12.480000
+dup_frames=0
+drop_frames=0
+speed=2.61x
+progress=end
+";
+
+    fn parse_all(input: &str) -> Vec<ProgressUpdate> {
+        let mut parser = ProgressParser::new();
+        input
+            .lines()
+            .filter_map(|line| parser.feed_line(line))
+            .collect()
+    }
+
+    #[test]
+    fn parses_recorded_progress_blocks() {
+        let updates = parse_all(RECORDED_PROGRESS);
+
+        assert_eq!(updates.len(), 3);
+        assert_eq!(updates[0].out_time_secs, 5.163);
+        assert_eq!(updates[0].speed, Some(2.57));
+        assert!(!updates[0].finished);
+        assert_eq!(updates[2].out_time_secs, 12.48);
+        assert!(updates[2].finished);
+    }
+
+    #[test]
+    fn keeps_later_valid_time_and_drops_unknown_speed() {
+        let updates = parse_all(RECORDED_PROGRESS);
+
+        // Отрицательный out_time_us пропускается, позицию даёт out_time_ms.
+        assert_eq!(updates[1].out_time_secs, 10.617);
+        assert_eq!(updates[1].speed, None);
+    }
+
+    #[test]
+    fn computes_percent_against_duration() {
+        let updates = parse_all(RECORDED_PROGRESS);
+
+        assert_eq!(updates[0].percent(12.48), Some(41));
+        assert_eq!(updates[2].percent(12.48), Some(100));
+        assert_eq!(updates[0].percent(0.0), None);
+        assert_eq!(updates[0].percent(2.0), Some(100));
+    }
+
+    #[test]
+    fn ignores_garbage_lines() {
+        let mut parser = ProgressParser::new();
+        assert_eq!(parser.feed_line(""), None);
+        assert_eq!(parser.feed_line("not a key value line"), None);
+        assert_eq!(parser.feed_line("out_time_ms=abc"), None);
+        assert_eq!(
+            parser.feed_line("progress=continue"),
+            Some(ProgressUpdate {
+                out_time_secs: 0.0,
+                speed: None,
+                finished: false,
+                stage: ProgressStage::Converting,
+            })
+        );
+    }
+
+    #[test]
+    fn formats_status_text() {
+        let update = ProgressUpdate {
+            out_time_secs: 5.0,
+            speed: Some(1.5),
+            finished: false,
+            stage: ProgressStage::Converting,
+        };
+        let measuring = ProgressUpdate {
+            stage: ProgressStage::MeasuringLoudness,
+            ..update
+        };
+
+        assert_eq!(
+            format_progress(Some(&update), Some(10.0)),
+            "converting… 50% (1.5x)"
+        );
+        assert_eq!(format_progress(Some(&update), None), "converting…");
+        assert_eq!(format_progress(None, Some(10.0)), "converting…");
+        assert_eq!(
+            format_progress(Some(&measuring), Some(10.0)),
+            "measuring loudness… 50% (1.5x)"
+        );
+        let second_pass = ProgressUpdate {
+            stage: ProgressStage::FittingSize {
+                attempt: 2,
+                pass: 2,
+                passes: 2,
+            },
+            ..update
+        };
+        assert_eq!(
+            format_progress(Some(&second_pass), None),
+            "shrinking to fit at a lower resolution, pass 2/2…"
+        );
+    }
+}
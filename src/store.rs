@@ -0,0 +1,594 @@
+use anyhow::{bail, Context, Result as AnyResult};
+use rusqlite::{params, Connection};
+use std::path::Path;
+use std::sync::{mpsc, Arc, Mutex, MutexGuard};
+
+use crate::limits::QuotaKind;
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS jobs (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    chat_id INTEGER NOT NULL,
+    message_id INTEGER NOT NULL,
+    user_id INTEGER NOT NULL,
+    file_id TEXT NOT NULL,
+    quota_kind TEXT NOT NULL,
+    day_index INTEGER NOT NULL,
+    state TEXT NOT NULL,
+    attempts INTEGER NOT NULL DEFAULT 0,
+    message TEXT NOT NULL,
+    notice_message_id INTEGER,
+    UNIQUE (chat_id, message_id)
+);
+CREATE TABLE IF NOT EXISTS quota_usage (
+    day_index INTEGER NOT NULL,
+    quota_kind TEXT NOT NULL,
+    user_id INTEGER NOT NULL,
+    count INTEGER NOT NULL,
+    PRIMARY KEY (day_index, quota_kind, user_id)
+);
+";
+
+fn kind_name(kind: QuotaKind) -> &'static str {
+    match kind {
+        QuotaKind::Media => "media",
+        QuotaKind::Image => "image",
+    }
+}
+
+fn parse_kind(name: &str) -> AnyResult<QuotaKind> {
+    match name {
+        "media" => Ok(QuotaKind::Media),
+        "image" => Ok(QuotaKind::Image),
+        other => bail!("Unknown quota kind in job store: {other}"),
+    }
+}
+
+/// Стадия сохранённой задачи.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JobState {
+    /// Ждёт свободного обработчика.
+    Queued,
+    /// Файл скачивается или конвертируется.
+    Running,
+}
+
+impl JobState {
+    fn name(self) -> &'static str {
+        match self {
+            JobState::Queued => "queued",
+            JobState::Running => "running",
+        }
+    }
+
+    fn parse(name: &str) -> AnyResult<Self> {
+        match name {
+            "queued" => Ok(JobState::Queued),
+            "running" => Ok(JobState::Running),
+            other => bail!("Unknown job state in job store: {other}"),
+        }
+    }
+}
+
+/// Задача, принятая в очередь: за неё уже списана квота.
+#[derive(Debug, Clone, PartialEq)]
+pub struct NewJob {
+    pub chat_id: i64,
+    pub message_id: i32,
+    pub user_id: i64,
+    pub file_id: String,
+    pub kind: QuotaKind,
+    /// День, за который списана квота.
+    pub day_index: u64,
+    /// Исходное сообщение в JSON: по нему задача запускается заново.
+    pub message: String,
+}
+
+/// Задача, не завершившаяся до остановки бота.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StoredJob {
+    pub id: i64,
+    pub state: JobState,
+    /// Сколько раз задачу уже запускали заново после перезапуска.
+    pub attempts: u32,
+    /// Сообщение «в очереди» или статус конвертации, которое бот не успел
+    /// удалить.
+    pub notice_message_id: Option<i32>,
+    pub job: NewJob,
+}
+
+/// Сколько единиц квоты пользователь потратил за сутки.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QuotaUsage {
+    pub kind: QuotaKind,
+    pub user_id: i64,
+    pub count: u32,
+}
+
+/// Изменение, которое выполняет поток записи хранилища.
+#[derive(Debug)]
+enum Write {
+    /// Сохраняет задачу в состоянии `Queued`. Повторно принятое сообщение
+    /// (запуск после перезапуска) обновляет старую запись и сохраняет
+    /// счётчик попыток.
+    InsertJob(NewJob),
+    SetState {
+        chat_id: i64,
+        message_id: i32,
+        state: JobState,
+    },
+    RecordAttempt {
+        chat_id: i64,
+        message_id: i32,
+    },
+    SetNotice {
+        chat_id: i64,
+        message_id: i32,
+        notice_message_id: Option<i32>,
+    },
+    RemoveJob {
+        chat_id: i64,
+        message_id: i32,
+    },
+    SaveQuotaUsage {
+        day_index: u64,
+        usage: QuotaUsage,
+    },
+    ClearQuotaBefore(u64),
+    /// Отвечает, когда всё, что поставлено раньше, уже записано.
+    Flush(mpsc::Sender<()>),
+}
+
+/// Задачи и счётчики квот в SQLite, чтобы они пережили перезапуск.
+///
+/// Изменения пишет отдельный поток в порядке поступления: обработчики и
+/// ограничитель квот ставят их в очередь и не ждут диска, даже держа
+/// замок. Чтение сначала дожидается записи всего поставленного раньше.
+#[derive(Debug)]
+pub struct JobStore {
+    connection: Arc<Mutex<Connection>>,
+    writes: mpsc::Sender<Write>,
+}
+
+impl JobStore {
+    /// Открывает базу, создавая её при необходимости, и запускает поток
+    /// записи. `:memory:` — база в памяти, для тестов.
+    pub fn open(path: &Path) -> AnyResult<Self> {
+        let connection = Connection::open(path)
+            .with_context(|| format!("Failed to open job store {}", path.display()))?;
+        // WAL не блокирует чтение на время записи и не синхронизирует диск
+        // на каждой транзакции.
+        connection
+            .pragma_update_and_check(None, "journal_mode", "WAL", |row| row.get::<_, String>(0))
+            .context("Failed to switch job store to WAL")?;
+        connection
+            .execute_batch("PRAGMA synchronous = NORMAL;")
+            .context("Failed to configure job store")?;
+        connection
+            .execute_batch(SCHEMA)
+            .context("Failed to create job store tables")?;
+        add_notice_column(&connection).context("Failed to upgrade job store tables")?;
+
+        let connection = Arc::new(Mutex::new(connection));
+        let (writes, queue) = mpsc::channel();
+        let writer_connection = Arc::clone(&connection);
+        std::thread::Builder::new()
+            .name("job-store".to_string())
+            .spawn(move || run_writer(&writer_connection, queue))
+            .context("Failed to start job store writer")?;
+        Ok(Self { connection, writes })
+    }
+
+    fn connection(&self) -> MutexGuard<'_, Connection> {
+        lock(&self.connection)
+    }
+
+    fn queue(&self, write: Write) {
+        if let Err(mpsc::SendError(write)) = self.writes.send(write) {
+            log::warn!("Job store writer has stopped, dropping {:?}", write);
+        }
+    }
+
+    /// Ждёт, пока поток записи выполнит всё, что поставлено раньше.
+    fn flush(&self) {
+        let (done, wait) = mpsc::channel();
+        self.queue(Write::Flush(done));
+        let _ = wait.recv();
+    }
+
+    pub fn insert_job(&self, job: &NewJob) {
+        self.queue(Write::InsertJob(job.clone()));
+    }
+
+    pub fn set_state(&self, chat_id: i64, message_id: i32, state: JobState) {
+        self.queue(Write::SetState {
+            chat_id,
+            message_id,
+            state,
+        });
+    }
+
+    /// Отмечает ещё одну попытку довести задачу до конца.
+    pub fn record_attempt(&self, chat_id: i64, message_id: i32) {
+        self.queue(Write::RecordAttempt {
+            chat_id,
+            message_id,
+        });
+    }
+
+    /// Запоминает служебное сообщение задачи; `None` — оно уже удалено.
+    pub fn set_notice(&self, chat_id: i64, message_id: i32, notice_message_id: Option<i32>) {
+        self.queue(Write::SetNotice {
+            chat_id,
+            message_id,
+            notice_message_id,
+        });
+    }
+
+    pub fn remove_job(&self, chat_id: i64, message_id: i32) {
+        self.queue(Write::RemoveJob {
+            chat_id,
+            message_id,
+        });
+    }
+
+    /// Задачи, оставшиеся от прошлого запуска, в порядке поступления.
+    pub fn pending_jobs(&self) -> AnyResult<Vec<StoredJob>> {
+        self.flush();
+        let connection = self.connection();
+        let mut statement = connection.prepare(
+            "SELECT id, chat_id, message_id, user_id, file_id, quota_kind, day_index, state, attempts, message, notice_message_id
+             FROM jobs ORDER BY id",
+        )?;
+        let mut rows = statement.query([])?;
+
+        let mut jobs = Vec::new();
+        while let Some(row) = rows.next()? {
+            jobs.push(StoredJob {
+                id: row.get(0)?,
+                state: JobState::parse(&row.get::<_, String>(7)?)?,
+                attempts: row.get(8)?,
+                notice_message_id: row.get(10)?,
+                job: NewJob {
+                    chat_id: row.get(1)?,
+                    message_id: row.get(2)?,
+                    user_id: row.get(3)?,
+                    file_id: row.get(4)?,
+                    kind: parse_kind(&row.get::<_, String>(5)?)?,
+                    day_index: row.get::<_, i64>(6)? as u64,
+                    message: row.get(9)?,
+                },
+            });
+        }
+        Ok(jobs)
+    }
+
+    /// Счётчики квот за указанные сутки.
+    pub fn quota_usage(&self, day_index: u64) -> AnyResult<Vec<QuotaUsage>> {
+        self.flush();
+        let connection = self.connection();
+        let mut statement = connection.prepare(
+            "SELECT quota_kind, user_id, count FROM quota_usage WHERE day_index = ?1 AND count > 0",
+        )?;
+        let mut rows = statement.query(params![day_index as i64])?;
+
+        let mut usage = Vec::new();
+        while let Some(row) = rows.next()? {
+            usage.push(QuotaUsage {
+                kind: parse_kind(&row.get::<_, String>(0)?)?,
+                user_id: row.get(1)?,
+                count: row.get(2)?,
+            });
+        }
+        Ok(usage)
+    }
+
+    pub fn save_quota_usage(&self, day_index: u64, usage: QuotaUsage) {
+        self.queue(Write::SaveQuotaUsage { day_index, usage });
+    }
+
+    /// Удаляет счётчики прошедших суток.
+    pub fn clear_quota_before(&self, day_index: u64) {
+        self.queue(Write::ClearQuotaBefore(day_index));
+    }
+}
+
+/// Базы первой версии создавались без `notice_message_id`.
+fn add_notice_column(connection: &Connection) -> rusqlite::Result<()> {
+    let exists: bool = connection.query_row(
+        "SELECT EXISTS (SELECT 1 FROM pragma_table_info('jobs') WHERE name = 'notice_message_id')",
+        [],
+        |row| row.get(0),
+    )?;
+    if !exists {
+        connection.execute_batch("ALTER TABLE jobs ADD COLUMN notice_message_id INTEGER;")?;
+    }
+    Ok(())
+}
+
+fn lock(connection: &Mutex<Connection>) -> MutexGuard<'_, Connection> {
+    connection.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Поток записи: выполняет изменения по одному, пока хранилище не закрыто.
+/// Ошибку базы только логируем: конвертация важнее.
+fn run_writer(connection: &Mutex<Connection>, queue: mpsc::Receiver<Write>) {
+    for write in queue {
+        if let Write::Flush(done) = write {
+            let _ = done.send(());
+            continue;
+        }
+        let description = format!("{:?}", write);
+        if let Err(e) = apply(&lock(connection), write) {
+            log::warn!("Failed to write to job store: {}: {:?}", description, e);
+        }
+    }
+}
+
+fn apply(connection: &Connection, write: Write) -> rusqlite::Result<()> {
+    match write {
+        Write::InsertJob(job) => {
+            connection.execute(
+                "INSERT INTO jobs (chat_id, message_id, user_id, file_id, quota_kind, day_index, state, message)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)
+                 ON CONFLICT (chat_id, message_id) DO UPDATE SET
+                     user_id = excluded.user_id,
+                     file_id = excluded.file_id,
+                     quota_kind = excluded.quota_kind,
+                     day_index = excluded.day_index,
+                     state = excluded.state,
+                     message = excluded.message,
+                     notice_message_id = NULL",
+                params![
+                    job.chat_id,
+                    job.message_id,
+                    job.user_id,
+                    job.file_id,
+                    kind_name(job.kind),
+                    job.day_index as i64,
+                    JobState::Queued.name(),
+                    job.message,
+                ],
+            )?;
+        }
+        Write::SetState {
+            chat_id,
+            message_id,
+            state,
+        } => {
+            connection.execute(
+                "UPDATE jobs SET state = ?3 WHERE chat_id = ?1 AND message_id = ?2",
+                params![chat_id, message_id, state.name()],
+            )?;
+        }
+        Write::RecordAttempt {
+            chat_id,
+            message_id,
+        } => {
+            connection.execute(
+                "UPDATE jobs SET attempts = attempts + 1 WHERE chat_id = ?1 AND message_id = ?2",
+                params![chat_id, message_id],
+            )?;
+        }
+        Write::SetNotice {
+            chat_id,
+            message_id,
+            notice_message_id,
+        } => {
+            connection.execute(
+                "UPDATE jobs SET notice_message_id = ?3 WHERE chat_id = ?1 AND message_id = ?2",
+                params![chat_id, message_id, notice_message_id],
+            )?;
+        }
+        Write::RemoveJob {
+            chat_id,
+            message_id,
+        } => {
+            connection.execute(
+                "DELETE FROM jobs WHERE chat_id = ?1 AND message_id = ?2",
+                params![chat_id, message_id],
+            )?;
+        }
+        Write::SaveQuotaUsage { day_index, usage } => {
+            connection.execute(
+                "INSERT INTO quota_usage (day_index, quota_kind, user_id, count) VALUES (?1, ?2, ?3, ?4)
+                 ON CONFLICT (day_index, quota_kind, user_id) DO UPDATE SET count = excluded.count",
+                params![
+                    day_index as i64,
+                    kind_name(usage.kind),
+                    usage.user_id,
+                    usage.count
+                ],
+            )?;
+        }
+        Write::ClearQuotaBefore(day_index) => {
+            connection.execute(
+                "DELETE FROM quota_usage WHERE day_index < ?1",
+                params![day_index as i64],
+            )?;
+        }
+        Write::Flush(_) => {}
+    }
+    Ok(())
+}
+
+/// Запись задачи в хранилище. Удаляется вместе со значением, то есть когда
+/// обработчик закончил с задачей; после падения бота запись остаётся.
+#[derive(Debug)]
+pub struct PersistedJob {
+    store: Arc<JobStore>,
+    chat_id: i64,
+    message_id: i32,
+}
+
+impl PersistedJob {
+    pub fn insert(store: &Arc<JobStore>, job: &NewJob) -> Self {
+        store.insert_job(job);
+        Self {
+            store: Arc::clone(store),
+            chat_id: job.chat_id,
+            message_id: job.message_id,
+        }
+    }
+
+    pub fn mark_running(&self) {
+        self.store
+            .set_state(self.chat_id, self.message_id, JobState::Running);
+    }
+
+    pub fn set_notice(&self, notice_message_id: Option<i32>) {
+        self.store
+            .set_notice(self.chat_id, self.message_id, notice_message_id);
+    }
+}
+
+impl Drop for PersistedJob {
+    fn drop(&mut self) {
+        self.store.remove_job(self.chat_id, self.message_id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{JobState, JobStore, NewJob, PersistedJob, QuotaUsage};
+    use crate::limits::QuotaKind;
+    use std::path::Path;
+    use std::sync::Arc;
+
+    fn store() -> Arc<JobStore> {
+        Arc::new(JobStore::open(Path::new(":memory:")).unwrap())
+    }
+
+    fn job(message_id: i32) -> NewJob {
+        NewJob {
+            chat_id: -100,
+            message_id,
+            user_id: 7,
+            file_id: format!("file-{message_id}"),
+            kind: QuotaKind::Media,
+            day_index: 20_000,
+            message: "{}".to_string(),
+        }
+    }
+
+    #[test]
+    fn opens_file_store_in_wal_mode() {
+        let path = std::env::temp_dir().join(format!("jobs-wal-{}.sqlite3", std::process::id()));
+        let store = JobStore::open(&path).unwrap();
+        let mode: String = store
+            .connection()
+            .query_row("PRAGMA journal_mode", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(mode, "wal");
+
+        drop(store);
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{}", path.display(), suffix));
+        }
+    }
+
+    #[test]
+    fn adds_notice_column_to_old_databases() {
+        let path = std::env::temp_dir().join(format!("jobs-v1-{}.sqlite3", std::process::id()));
+        rusqlite::Connection::open(&path)
+            .unwrap()
+            .execute_batch(
+                "CREATE TABLE jobs (
+                    id INTEGER PRIMARY KEY AUTOINCREMENT,
+                    chat_id INTEGER NOT NULL,
+                    message_id INTEGER NOT NULL,
+                    user_id INTEGER NOT NULL,
+                    file_id TEXT NOT NULL,
+                    quota_kind TEXT NOT NULL,
+                    day_index INTEGER NOT NULL,
+                    state TEXT NOT NULL,
+                    attempts INTEGER NOT NULL DEFAULT 0,
+                    message TEXT NOT NULL,
+                    UNIQUE (chat_id, message_id)
+                );",
+            )
+            .unwrap();
+
+        let store = JobStore::open(&path).unwrap();
+        store.insert_job(&job(10));
+        store.set_notice(-100, 10, Some(55));
+        assert_eq!(store.pending_jobs().unwrap()[0].notice_message_id, Some(55));
+
+        drop(store);
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{}", path.display(), suffix));
+        }
+    }
+
+    #[test]
+    fn keeps_jobs_until_handler_finishes() {
+        let store = store();
+        let first = PersistedJob::insert(&store, &job(10));
+        let second = PersistedJob::insert(&store, &job(11));
+        second.mark_running();
+
+        let pending = store.pending_jobs().unwrap();
+        assert_eq!(pending.len(), 2);
+        assert_eq!(pending[0].job, job(10));
+        assert_eq!(pending[0].state, JobState::Queued);
+        assert_eq!(pending[1].state, JobState::Running);
+
+        drop(first);
+        let pending = store.pending_jobs().unwrap();
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].job.message_id, 11);
+
+        // Задача, прерванная падением, остаётся в базе.
+        std::mem::forget(second);
+        assert_eq!(store.pending_jobs().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn resumed_message_keeps_attempt_count() {
+        let store = store();
+        store.insert_job(&job(10));
+        store.set_state(-100, 10, JobState::Running);
+        store.record_attempt(-100, 10);
+        store.set_notice(-100, 10, Some(55));
+        let stored = &store.pending_jobs().unwrap()[0];
+        assert_eq!(stored.notice_message_id, Some(55));
+        let id = stored.id;
+
+        let mut resumed = job(10);
+        resumed.day_index = 20_001;
+        store.insert_job(&resumed);
+
+        let pending = store.pending_jobs().unwrap();
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].id, id);
+        assert_eq!(pending[0].attempts, 1);
+        assert_eq!(pending[0].notice_message_id, None);
+        assert_eq!(pending[0].state, JobState::Queued);
+        assert_eq!(pending[0].job.day_index, 20_001);
+    }
+
+    #[test]
+    fn stores_quota_usage_per_day() {
+        let store = store();
+        let usage = |kind, user_id, count| QuotaUsage {
+            kind,
+            user_id,
+            count,
+        };
+        store.save_quota_usage(20_000, usage(QuotaKind::Media, 7, 1));
+        store.save_quota_usage(20_000, usage(QuotaKind::Media, 7, 2));
+        store.save_quota_usage(20_000, usage(QuotaKind::Image, 7, 0));
+        store.save_quota_usage(20_001, usage(QuotaKind::Image, 8, 3));
+
+        assert_eq!(
+            store.quota_usage(20_000).unwrap(),
+            vec![usage(QuotaKind::Media, 7, 2)]
+        );
+        store.clear_quota_before(20_001);
+        assert!(store.quota_usage(20_000).unwrap().is_empty());
+        assert_eq!(
+            store.quota_usage(20_001).unwrap(),
+            vec![usage(QuotaKind::Image, 8, 3)]
+        );
+    }
+}
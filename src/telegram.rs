@@ -1,8 +1,18 @@
 use anyhow::Result as AnyResult;
+use async_trait::async_trait;
 use std::path::Path;
-use teloxide::prelude::*;
+use teloxide::{
+    prelude::*,
+    requests::HasPayload,
+    types::{InputFile, InputSticker, MessageId, ParseMode},
+};
 use tokio::fs;
 
+use crate::converter::VIDEO_NOTE_SIZE;
+
+/// Каталог для скачанных файлов и результатов конвертации.
+const DOWNLOAD_DIR: &str = "/tmp";
+
 fn extract_extension(file_path: &str) -> &str {
     Path::new(file_path)
         .extension()
@@ -10,25 +20,468 @@ fn extract_extension(file_path: &str) -> &str {
         .unwrap_or("bin")
 }
 
-/// Скачивает файл с серверов Telegram по его идентификатору.
-pub async fn download_file(bot: &Bot, file_id: &str) -> AnyResult<String> {
-    let file = bot.get_file(file_id).send().await?;
-    let download_url = format!(
-        "https://api.telegram.org/file/bot{}/{}",
-        bot.token(),
-        file.path
-    );
-    let response = reqwest::get(&download_url).await?;
-    let extension = extract_extension(&file.path);
-    let file_path = format!("/tmp/{}.{}", file_id, extension);
-    let content = response.bytes().await?;
-    fs::write(&file_path, &content).await?;
-    Ok(file_path)
+/// Куда и как отправить сообщение: ответом в ветке форума, без звука
+/// уведомления, с текстом или подписью в MarkdownV2.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SendOptions {
+    pub reply_to: Option<MessageId>,
+    pub thread_id: Option<i32>,
+    pub silent: bool,
+    pub markdown: bool,
+}
+
+impl SendOptions {
+    fn parse_mode(&self) -> Option<ParseMode> {
+        self.markdown.then_some(ParseMode::MarkdownV2)
+    }
+}
+
+/// Превью, размеры и длительность видео: клиент показывает их до загрузки.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Preview {
+    pub thumbnail: Option<String>,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub duration_secs: Option<u32>,
+}
+
+/// Как Telegram покажет загруженный файл.
+#[derive(Debug, Clone, PartialEq)]
+pub enum UploadKind {
+    /// Видео с потоковым воспроизведением.
+    Video(Preview),
+    Animation(Preview),
+    /// Кружок; ширина и высота у него задаются одной стороной.
+    VideoNote(Preview),
+    Document {
+        thumbnail: Option<String>,
+    },
+    Audio {
+        title: Option<String>,
+        performer: Option<String>,
+    },
+    Voice,
+    Photo,
+    Sticker,
+}
+
+/// Файл для отправки и подпись к нему.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Upload {
+    pub path: String,
+    pub kind: UploadKind,
+    pub caption: Option<String>,
+}
+
+/// Всё, что обработчики сообщений делают через Bot API.
+#[async_trait]
+pub trait Telegram: Send + Sync {
+    /// Скачивает файл по идентификатору под именем `local_name` (см.
+    /// `job_file_name`) и возвращает путь к нему.
+    async fn download_file(&self, file_id: &str, local_name: &str) -> AnyResult<String>;
+
+    async fn send_text(
+        &self,
+        chat_id: ChatId,
+        text: &str,
+        options: &SendOptions,
+    ) -> AnyResult<MessageId>;
+
+    async fn edit_text(&self, chat_id: ChatId, message_id: MessageId, text: &str) -> AnyResult<()>;
+
+    async fn delete_message(&self, chat_id: ChatId, message_id: MessageId) -> AnyResult<()>;
+
+    /// Загружает файл и возвращает идентификатор отправленного сообщения.
+    async fn send_upload(
+        &self,
+        chat_id: ChatId,
+        upload: &Upload,
+        options: &SendOptions,
+    ) -> AnyResult<MessageId>;
+
+    /// Имя пользователя бота, из него складываются имена наборов стикеров.
+    async fn bot_username(&self) -> AnyResult<String>;
+
+    async fn sticker_set_exists(&self, name: &str) -> bool;
+
+    /// Добавляет WebM-стикер в набор, создавая набор, если передан `title`.
+    async fn add_sticker(
+        &self,
+        owner: UserId,
+        set_name: &str,
+        new_set_title: Option<&str>,
+        sticker_path: &str,
+        emoji: &str,
+    ) -> AnyResult<()>;
+}
+
+#[async_trait]
+impl Telegram for Bot {
+    async fn download_file(&self, file_id: &str, local_name: &str) -> AnyResult<String> {
+        let file = self.get_file(file_id).send().await?;
+        let download_url = format!(
+            "https://api.telegram.org/file/bot{}/{}",
+            self.token(),
+            file.path
+        );
+        let response = reqwest::get(&download_url).await?;
+        let extension = extract_extension(&file.path);
+        let file_path = format!("{}/{}.{}", DOWNLOAD_DIR, local_name, extension);
+        let content = response.bytes().await?;
+        fs::write(&file_path, &content).await?;
+        Ok(file_path)
+    }
+
+    async fn send_text(
+        &self,
+        chat_id: ChatId,
+        text: &str,
+        options: &SendOptions,
+    ) -> AnyResult<MessageId> {
+        let mut request = self.send_message(chat_id, text);
+        let payload = request.payload_mut();
+        payload.parse_mode = options.parse_mode();
+        payload.reply_to_message_id = options.reply_to;
+        payload.allow_sending_without_reply = Some(true);
+        payload.message_thread_id = options.thread_id;
+        payload.disable_notification = Some(options.silent);
+        Ok(request.await?.id)
+    }
+
+    async fn edit_text(&self, chat_id: ChatId, message_id: MessageId, text: &str) -> AnyResult<()> {
+        self.edit_message_text(chat_id, message_id, text).await?;
+        Ok(())
+    }
+
+    async fn delete_message(&self, chat_id: ChatId, message_id: MessageId) -> AnyResult<()> {
+        Requester::delete_message(self, chat_id, message_id).await?;
+        Ok(())
+    }
+
+    async fn send_upload(
+        &self,
+        chat_id: ChatId,
+        upload: &Upload,
+        options: &SendOptions,
+    ) -> AnyResult<MessageId> {
+        let file = InputFile::file(&upload.path);
+        let thumbnail = |path: &Option<String>| path.as_deref().map(InputFile::file);
+        let caption = upload.caption.clone();
+        let parse_mode = caption.as_ref().and(options.parse_mode());
+        let sent = match &upload.kind {
+            UploadKind::Video(preview) => {
+                let mut request = self.send_video(chat_id, file);
+                let payload = request.payload_mut();
+                payload.thumb = thumbnail(&preview.thumbnail);
+                payload.duration = preview.duration_secs;
+                payload.width = preview.width;
+                payload.height = preview.height;
+                payload.supports_streaming = Some(true);
+                payload.caption = caption;
+                payload.parse_mode = parse_mode;
+                payload.reply_to_message_id = options.reply_to;
+                payload.allow_sending_without_reply = Some(true);
+                payload.message_thread_id = options.thread_id;
+                payload.disable_notification = Some(options.silent);
+                request.await?
+            }
+            UploadKind::Animation(preview) => {
+                let mut request = self.send_animation(chat_id, file);
+                let payload = request.payload_mut();
+                payload.thumb = thumbnail(&preview.thumbnail);
+                payload.duration = preview.duration_secs;
+                payload.width = preview.width;
+                payload.height = preview.height;
+                payload.caption = caption;
+                payload.parse_mode = parse_mode;
+                payload.reply_to_message_id = options.reply_to;
+                payload.allow_sending_without_reply = Some(true);
+                payload.message_thread_id = options.thread_id;
+                payload.disable_notification = Some(options.silent);
+                request.await?
+            }
+            UploadKind::VideoNote(preview) => {
+                let mut request = self.send_video_note(chat_id, file);
+                let payload = request.payload_mut();
+                payload.thumb = thumbnail(&preview.thumbnail);
+                payload.duration = preview.duration_secs;
+                payload.length = Some(VIDEO_NOTE_SIZE);
+                payload.reply_to_message_id = options.reply_to;
+                payload.allow_sending_without_reply = Some(true);
+                payload.message_thread_id = options.thread_id;
+                payload.disable_notification = Some(options.silent);
+                request.await?
+            }
+            UploadKind::Document { thumbnail: thumb } => {
+                let mut request = self.send_document(chat_id, file);
+                let payload = request.payload_mut();
+                payload.thumb = thumbnail(thumb);
+                payload.caption = caption;
+                payload.parse_mode = parse_mode;
+                payload.reply_to_message_id = options.reply_to;
+                payload.allow_sending_without_reply = Some(true);
+                payload.message_thread_id = options.thread_id;
+                payload.disable_notification = Some(options.silent);
+                request.await?
+            }
+            UploadKind::Audio { title, performer } => {
+                let mut request = self.send_audio(chat_id, file);
+                let payload = request.payload_mut();
+                payload.title = title.clone();
+                payload.performer = performer.clone();
+                payload.caption = caption;
+                payload.parse_mode = parse_mode;
+                payload.reply_to_message_id = options.reply_to;
+                payload.allow_sending_without_reply = Some(true);
+                payload.message_thread_id = options.thread_id;
+                payload.disable_notification = Some(options.silent);
+                request.await?
+            }
+            UploadKind::Voice => {
+                let mut request = self.send_voice(chat_id, file);
+                let payload = request.payload_mut();
+                payload.caption = caption;
+                payload.parse_mode = parse_mode;
+                payload.reply_to_message_id = options.reply_to;
+                payload.allow_sending_without_reply = Some(true);
+                payload.message_thread_id = options.thread_id;
+                payload.disable_notification = Some(options.silent);
+                request.await?
+            }
+            UploadKind::Photo => {
+                let mut request = self.send_photo(chat_id, file);
+                let payload = request.payload_mut();
+                payload.caption = caption;
+                payload.parse_mode = parse_mode;
+                payload.reply_to_message_id = options.reply_to;
+                payload.allow_sending_without_reply = Some(true);
+                payload.message_thread_id = options.thread_id;
+                payload.disable_notification = Some(options.silent);
+                request.await?
+            }
+            UploadKind::Sticker => {
+                // У `send_sticker` в этой версии Bot API ответ задаётся
+                // числом, а не `MessageId`.
+                let mut request = self.send_sticker(chat_id, file);
+                let payload = request.payload_mut();
+                payload.reply_to_message_id = options.reply_to.map(|message_id| message_id.0);
+                payload.allow_sending_without_reply = Some(true);
+                payload.message_thread_id = options.thread_id;
+                payload.disable_notification = Some(options.silent);
+                request.await?
+            }
+        };
+        Ok(sent.id)
+    }
+
+    async fn bot_username(&self) -> AnyResult<String> {
+        Ok(self.get_me().await?.username().to_string())
+    }
+
+    async fn sticker_set_exists(&self, name: &str) -> bool {
+        self.get_sticker_set(name).await.is_ok()
+    }
+
+    async fn add_sticker(
+        &self,
+        owner: UserId,
+        set_name: &str,
+        new_set_title: Option<&str>,
+        sticker_path: &str,
+        emoji: &str,
+    ) -> AnyResult<()> {
+        let sticker = InputSticker::Webm(InputFile::file(sticker_path));
+        match new_set_title {
+            Some(title) => {
+                self.create_new_sticker_set(owner, set_name, title, sticker, emoji)
+                    .await?
+            }
+            None => {
+                self.add_sticker_to_set(owner, set_name, sticker, emoji)
+                    .await?
+            }
+        };
+        Ok(())
+    }
+}
+
+/// Общая часть имён рабочих файлов задачи. Один и тот же файл могут
+/// одновременно прислать в разные чаты или дважды в один, поэтому к
+/// идентификатору файла добавляется сообщение, за которым стоит задача.
+pub fn job_file_name(file_id: &str, chat_id: i64, message_id: i32) -> String {
+    format!("{}.{}_{}", file_id, chat_id, message_id)
+}
+
+/// Файлы задачи начинаются с `job_file_name`: скачанный оригинал и всё,
+/// что из него получено (`.mp4`, `.thumb.jpg`, логи двухпроходного
+/// кодирования).
+fn belongs_to_job(name: &str, job_name: &str) -> bool {
+    !job_name.is_empty()
+        && name
+            .strip_prefix(job_name)
+            .is_some_and(|rest| rest.starts_with('.'))
+}
+
+/// Удаляет файлы, оставшиеся от прерванной задачи. Возвращает их число.
+pub async fn remove_downloaded_files(job_name: &str) -> AnyResult<usize> {
+    let mut removed = 0;
+    let mut entries = fs::read_dir(DOWNLOAD_DIR).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let name = entry.file_name();
+        if name
+            .to_str()
+            .is_some_and(|name| belongs_to_job(name, job_name))
+        {
+            fs::remove_file(entry.path()).await?;
+            removed += 1;
+        }
+    }
+    Ok(removed)
+}
+
+#[cfg(test)]
+pub use fake::{FakeCall, FakeTelegram};
+
+#[cfg(test)]
+mod fake {
+    use super::{SendOptions, Telegram, Upload};
+    use anyhow::Result as AnyResult;
+    use async_trait::async_trait;
+    use std::sync::atomic::{AtomicI32, Ordering};
+    use std::sync::Mutex;
+    use teloxide::types::{ChatId, MessageId, UserId};
+
+    /// Запрос к Bot API, записанный `FakeTelegram`.
+    #[derive(Debug, Clone, PartialEq)]
+    pub enum FakeCall {
+        Download(String),
+        Text {
+            chat_id: ChatId,
+            text: String,
+            options: SendOptions,
+        },
+        Edit {
+            message_id: MessageId,
+            text: String,
+        },
+        Delete(MessageId),
+        Upload {
+            chat_id: ChatId,
+            upload: Upload,
+            options: SendOptions,
+        },
+        AddSticker {
+            set_name: String,
+            created: bool,
+        },
+    }
+
+    /// Bot API для тестов: записывает запросы и выдаёт сообщениям номера
+    /// по порядку. Скачанные файлы — пустые файлы во временном каталоге.
+    pub struct FakeTelegram {
+        calls: Mutex<Vec<FakeCall>>,
+        next_message_id: AtomicI32,
+    }
+
+    impl FakeTelegram {
+        pub fn new() -> Self {
+            Self {
+                calls: Mutex::new(Vec::new()),
+                next_message_id: AtomicI32::new(1000),
+            }
+        }
+
+        pub fn calls(&self) -> Vec<FakeCall> {
+            self.calls.lock().unwrap().clone()
+        }
+
+        fn record(&self, call: FakeCall) -> MessageId {
+            self.calls.lock().unwrap().push(call);
+            MessageId(self.next_message_id.fetch_add(1, Ordering::Relaxed))
+        }
+    }
+
+    #[async_trait]
+    impl Telegram for FakeTelegram {
+        async fn download_file(&self, file_id: &str, local_name: &str) -> AnyResult<String> {
+            self.record(FakeCall::Download(file_id.to_string()));
+            let path = std::env::temp_dir().join(format!("{}.bin", local_name));
+            tokio::fs::write(&path, b"").await?;
+            Ok(path.to_string_lossy().into_owned())
+        }
+
+        async fn send_text(
+            &self,
+            chat_id: ChatId,
+            text: &str,
+            options: &SendOptions,
+        ) -> AnyResult<MessageId> {
+            Ok(self.record(FakeCall::Text {
+                chat_id,
+                text: text.to_string(),
+                options: options.clone(),
+            }))
+        }
+
+        async fn edit_text(
+            &self,
+            _chat_id: ChatId,
+            message_id: MessageId,
+            text: &str,
+        ) -> AnyResult<()> {
+            self.record(FakeCall::Edit {
+                message_id,
+                text: text.to_string(),
+            });
+            Ok(())
+        }
+
+        async fn delete_message(&self, _chat_id: ChatId, message_id: MessageId) -> AnyResult<()> {
+            self.record(FakeCall::Delete(message_id));
+            Ok(())
+        }
+
+        async fn send_upload(
+            &self,
+            chat_id: ChatId,
+            upload: &Upload,
+            options: &SendOptions,
+        ) -> AnyResult<MessageId> {
+            Ok(self.record(FakeCall::Upload {
+                chat_id,
+                upload: upload.clone(),
+                options: options.clone(),
+            }))
+        }
+
+        async fn bot_username(&self) -> AnyResult<String> {
+            Ok("fake_bot".to_string())
+        }
+
+        async fn sticker_set_exists(&self, _name: &str) -> bool {
+            false
+        }
+
+        async fn add_sticker(
+            &self,
+            _owner: UserId,
+            set_name: &str,
+            new_set_title: Option<&str>,
+            _sticker_path: &str,
+            _emoji: &str,
+        ) -> AnyResult<()> {
+            self.record(FakeCall::AddSticker {
+                set_name: set_name.to_string(),
+                created: new_set_title.is_some(),
+            });
+            Ok(())
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::extract_extension;
+    use super::{belongs_to_job, extract_extension, job_file_name};
 
     #[test]
     fn extracts_extension_from_path() {
@@ -49,4 +502,30 @@ mod tests {
     fn extracts_extension_from_basename_with_dotted_directories() {
         assert_eq!(extract_extension("videos.v1/source.mkv"), "mkv");
     }
+
+    #[test]
+    fn matches_files_derived_from_download() {
+        let job = job_file_name("AgAD1", -100, 10);
+        assert_eq!(job, "AgAD1.-100_10");
+        for name in [
+            "AgAD1.-100_10.mkv",
+            "AgAD1.-100_10.mp4",
+            "AgAD1.-100_10.converted.mp4",
+            "AgAD1.-100_10.thumb.jpg",
+            "AgAD1.-100_10.mp4.2pass-0.log",
+        ] {
+            assert!(belongs_to_job(name, &job), "{name}");
+        }
+        for name in [
+            "AgAD1.-100_100.mkv",
+            "AgAD1.-100_11.mkv",
+            "AgAD1.-200_10.mkv",
+            "AgAD1.mkv",
+            "AgAD1.-100_10",
+            "jobs.sqlite3",
+        ] {
+            assert!(!belongs_to_job(name, &job), "{name}");
+        }
+        assert!(!belongs_to_job(".mkv", ""));
+    }
 }
@@ -0,0 +1,444 @@
+use anyhow::{Context, Result as AnyResult};
+use async_trait::async_trait;
+use tokio::task;
+
+use crate::converter::{
+    convert_image, convert_sticker, convert_video, extract_audio, make_video_sticker, AudioFormat,
+    ConversionError, ConvertOptions, ConvertedMedia, FrameRatePlan, ImageFormat, JobSettings,
+    StickerSource, UnstickerSettings,
+};
+use crate::jobs::{ActiveJobs, CancelOutcome, TrackedJob, WorkerPool};
+use crate::media::{probe_image, probe_media, MediaInfo};
+use crate::progress::ProgressUpdate;
+
+/// Кто и где запустил конвертацию: по этим сообщениям её можно отменить.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct JobKey {
+    pub chat_id: i64,
+    pub message_ids: Vec<i32>,
+    pub owner_id: i64,
+}
+
+/// Всё, что обработчик сообщений знает о перекодировании медиа.
+#[async_trait]
+pub trait Transcoder: Send + Sync {
+    /// Анализирует потоки и контейнер файла.
+    async fn probe(&self, file_path: &str) -> AnyResult<MediaInfo>;
+
+    /// Анализирует картинку, включая EXIF-ориентацию первого кадра.
+    async fn probe_image(&self, file_path: &str) -> AnyResult<MediaInfo>;
+
+    /// Конвертирует файл с настройками чата и возвращает результат с превью.
+    async fn convert(
+        &self,
+        job: &JobKey,
+        file_path: &str,
+        info: Option<&MediaInfo>,
+        settings: &JobSettings,
+        on_progress: &mut (dyn FnMut(ProgressUpdate) + Send),
+    ) -> Result<ConvertedMedia, ConversionError>;
+
+    /// Извлекает первую звуковую дорожку и возвращает путь к аудиофайлу.
+    /// С `loudness_target` громкость выравнивается по замеру.
+    async fn extract_audio(
+        &self,
+        job: &JobKey,
+        file_path: &str,
+        format: AudioFormat,
+        loudness_target: Option<f64>,
+    ) -> Result<String, ConversionError>;
+
+    /// Конвертирует картинку в JPEG или PNG и возвращает путь к результату.
+    async fn convert_image(
+        &self,
+        job: &JobKey,
+        file_path: &str,
+        info: Option<&MediaInfo>,
+        format: ImageFormat,
+    ) -> Result<String, ConversionError>;
+
+    /// Превращает стикер в PNG, MP4 или GIF и возвращает путь к результату.
+    async fn convert_sticker(
+        &self,
+        job: &JobKey,
+        file_path: &str,
+        source: StickerSource,
+        dimensions: Option<(u32, u32)>,
+        settings: &UnstickerSettings,
+    ) -> Result<String, ConversionError>;
+
+    /// Делает из видео WebM-стикер и возвращает путь к нему.
+    async fn make_sticker(
+        &self,
+        job: &JobKey,
+        file_path: &str,
+        frame_rate: &FrameRatePlan,
+    ) -> Result<String, ConversionError>;
+
+    /// Регистрирует принятую задачу, чтобы её можно было отменить ещё в
+    /// очереди, при скачивании или анализе.
+    fn track(&self, job: &JobKey) -> TrackedJob;
+
+    /// Отменяет задачу или идущую конвертацию по любому из сообщений задачи.
+    fn cancel(&self, chat_id: i64, message_id: i32, requester_id: i64) -> CancelOutcome;
+
+    /// Обработчики, которые задача занимает на время конвертации.
+    fn workers(&self) -> &WorkerPool;
+}
+
+/// Настоящий бэкенд: ffprobe и ffmpeg из `PATH`.
+pub struct FfmpegTranscoder {
+    options: ConvertOptions,
+    jobs: ActiveJobs,
+    workers: WorkerPool,
+}
+
+impl FfmpegTranscoder {
+    pub fn new(options: ConvertOptions, workers: WorkerPool) -> Self {
+        Self {
+            options,
+            jobs: ActiveJobs::new(),
+            workers,
+        }
+    }
+}
+
+#[async_trait]
+impl Transcoder for FfmpegTranscoder {
+    async fn probe(&self, file_path: &str) -> AnyResult<MediaInfo> {
+        let file_path = file_path.to_string();
+        task::spawn_blocking(move || probe_media(&file_path))
+            .await
+            .context("Failed to join blocking task")?
+    }
+
+    async fn probe_image(&self, file_path: &str) -> AnyResult<MediaInfo> {
+        let file_path = file_path.to_string();
+        task::spawn_blocking(move || probe_image(&file_path))
+            .await
+            .context("Failed to join blocking task")?
+    }
+
+    async fn convert(
+        &self,
+        job: &JobKey,
+        file_path: &str,
+        info: Option<&MediaInfo>,
+        settings: &JobSettings,
+        on_progress: &mut (dyn FnMut(ProgressUpdate) + Send),
+    ) -> Result<ConvertedMedia, ConversionError> {
+        let cancel = self
+            .jobs
+            .register(job.chat_id, &job.message_ids, job.owner_id);
+        let result = convert_video(
+            file_path,
+            info,
+            settings,
+            &self.options,
+            &cancel,
+            on_progress,
+        )
+        .await;
+        self.jobs.finish(job.chat_id, &job.message_ids);
+        result
+    }
+
+    async fn extract_audio(
+        &self,
+        job: &JobKey,
+        file_path: &str,
+        format: AudioFormat,
+        loudness_target: Option<f64>,
+    ) -> Result<String, ConversionError> {
+        let cancel = self
+            .jobs
+            .register(job.chat_id, &job.message_ids, job.owner_id);
+        let result =
+            extract_audio(file_path, format, loudness_target, &self.options, &cancel).await;
+        self.jobs.finish(job.chat_id, &job.message_ids);
+        result
+    }
+
+    async fn convert_image(
+        &self,
+        job: &JobKey,
+        file_path: &str,
+        info: Option<&MediaInfo>,
+        format: ImageFormat,
+    ) -> Result<String, ConversionError> {
+        let cancel = self
+            .jobs
+            .register(job.chat_id, &job.message_ids, job.owner_id);
+        let result = convert_image(file_path, info, format, &self.options, &cancel).await;
+        self.jobs.finish(job.chat_id, &job.message_ids);
+        result
+    }
+
+    async fn convert_sticker(
+        &self,
+        job: &JobKey,
+        file_path: &str,
+        source: StickerSource,
+        dimensions: Option<(u32, u32)>,
+        settings: &UnstickerSettings,
+    ) -> Result<String, ConversionError> {
+        let cancel = self
+            .jobs
+            .register(job.chat_id, &job.message_ids, job.owner_id);
+        let result = convert_sticker(
+            file_path,
+            source,
+            dimensions,
+            settings,
+            &self.options,
+            &cancel,
+        )
+        .await;
+        self.jobs.finish(job.chat_id, &job.message_ids);
+        result
+    }
+
+    async fn make_sticker(
+        &self,
+        job: &JobKey,
+        file_path: &str,
+        frame_rate: &FrameRatePlan,
+    ) -> Result<String, ConversionError> {
+        let cancel = self
+            .jobs
+            .register(job.chat_id, &job.message_ids, job.owner_id);
+        let result = make_video_sticker(file_path, frame_rate, &self.options, &cancel).await;
+        self.jobs.finish(job.chat_id, &job.message_ids);
+        result
+    }
+
+    fn track(&self, job: &JobKey) -> TrackedJob {
+        self.jobs.track(job.chat_id, &job.message_ids, job.owner_id)
+    }
+
+    fn cancel(&self, chat_id: i64, message_id: i32, requester_id: i64) -> CancelOutcome {
+        self.jobs.cancel(chat_id, message_id, requester_id)
+    }
+
+    fn workers(&self) -> &WorkerPool {
+        &self.workers
+    }
+}
+
+#[cfg(test)]
+pub use fake::{FakeOutcome, FakeTranscoder};
+
+#[cfg(test)]
+mod fake {
+    use super::{JobKey, Transcoder};
+    use crate::converter::{
+        AudioFormat, ConversionError, ConvertedMedia, FrameRatePlan, ImageFormat, JobSettings,
+        StickerSource, UnstickerSettings,
+    };
+    use crate::jobs::{ActiveJobs, CancelOutcome, TrackedJob, WorkerPool};
+    use crate::media::MediaInfo;
+    use crate::progress::ProgressUpdate;
+    use anyhow::{anyhow, Result as AnyResult};
+    use async_trait::async_trait;
+    use std::sync::Mutex;
+    use std::time::Duration;
+    use tokio_util::sync::CancellationToken;
+
+    /// Чем закончится конвертация в `FakeTranscoder`.
+    #[derive(Debug, Clone)]
+    pub enum FakeOutcome {
+        Succeed,
+        Fail(String),
+        /// Висит, пока задачу не отменят или не истечёт `timeout`.
+        Hang {
+            timeout: Duration,
+        },
+    }
+
+    /// Бэкенд для тестов без ffmpeg: отдаёт заранее заданный `MediaInfo`
+    /// и сценарий конвертации.
+    pub struct FakeTranscoder {
+        probe_result: Option<MediaInfo>,
+        outcome: FakeOutcome,
+        progress: Vec<ProgressUpdate>,
+        thumbnail: bool,
+        converted: Mutex<Vec<JobSettings>>,
+        jobs: ActiveJobs,
+        workers: WorkerPool,
+    }
+
+    impl FakeTranscoder {
+        /// `probe_result: None` имитирует сбой ffprobe.
+        pub fn new(probe_result: Option<MediaInfo>, outcome: FakeOutcome) -> Self {
+            Self {
+                probe_result,
+                outcome,
+                progress: Vec::new(),
+                thumbnail: true,
+                converted: Mutex::new(Vec::new()),
+                jobs: ActiveJobs::new(),
+                workers: WorkerPool::new(1, 1),
+            }
+        }
+
+        pub fn with_progress(mut self, progress: Vec<ProgressUpdate>) -> Self {
+            self.progress = progress;
+            self
+        }
+
+        /// Имитирует сбой снятия превью: видео готово, а превью нет.
+        pub fn without_thumbnail(mut self) -> Self {
+            self.thumbnail = false;
+            self
+        }
+
+        /// Параметры, с которыми по порядку вызывался `convert`.
+        pub fn converted_settings(&self) -> Vec<JobSettings> {
+            self.converted.lock().unwrap().clone()
+        }
+
+        /// Разыгрывает `outcome` для зарегистрированной задачи.
+        async fn finish_job(
+            &self,
+            cancel: CancellationToken,
+            output_path: String,
+        ) -> Result<String, ConversionError> {
+            match &self.outcome {
+                FakeOutcome::Succeed => Ok(output_path),
+                FakeOutcome::Fail(message) => Err(ConversionError::Failed(anyhow!("{}", message))),
+                FakeOutcome::Hang { timeout } => tokio::select! {
+                    _ = cancel.cancelled() => Err(ConversionError::Cancelled),
+                    _ = tokio::time::sleep(*timeout) => Err(ConversionError::TimedOut(*timeout)),
+                },
+            }
+        }
+    }
+
+    #[async_trait]
+    impl Transcoder for FakeTranscoder {
+        async fn probe(&self, file_path: &str) -> AnyResult<MediaInfo> {
+            self.probe_result
+                .clone()
+                .ok_or_else(|| anyhow!("fake ffprobe failed for {}", file_path))
+        }
+
+        async fn probe_image(&self, file_path: &str) -> AnyResult<MediaInfo> {
+            self.probe(file_path).await
+        }
+
+        async fn convert(
+            &self,
+            job: &JobKey,
+            file_path: &str,
+            info: Option<&MediaInfo>,
+            settings: &JobSettings,
+            on_progress: &mut (dyn FnMut(ProgressUpdate) + Send),
+        ) -> Result<ConvertedMedia, ConversionError> {
+            self.converted.lock().unwrap().push(settings.clone());
+            let cancel = self
+                .jobs
+                .register(job.chat_id, &job.message_ids, job.owner_id);
+            for update in &self.progress {
+                on_progress(*update);
+            }
+
+            let result = self
+                .finish_job(cancel, format!("{}.fake.mp4", file_path))
+                .await;
+            self.jobs.finish(job.chat_id, &job.message_ids);
+            // Размеры и длительность «результата» берём из исходника.
+            let dimensions = info.and_then(MediaInfo::display_dimensions);
+            result.map(|path| ConvertedMedia {
+                path,
+                thumbnail: self.thumbnail.then(|| format!("{}.fake.jpg", file_path)),
+                width: dimensions.map(|(width, _)| width),
+                height: dimensions.map(|(_, height)| height),
+                duration_secs: info.and_then(|info| info.duration_secs),
+                frame_rate: settings.frame_rate,
+            })
+        }
+
+        async fn extract_audio(
+            &self,
+            job: &JobKey,
+            file_path: &str,
+            format: AudioFormat,
+            _loudness_target: Option<f64>,
+        ) -> Result<String, ConversionError> {
+            let cancel = self
+                .jobs
+                .register(job.chat_id, &job.message_ids, job.owner_id);
+            let output_path = format!("{}.fake.{:?}", file_path, format).to_lowercase();
+            let result = self.finish_job(cancel, output_path).await;
+            self.jobs.finish(job.chat_id, &job.message_ids);
+            result
+        }
+
+        async fn convert_image(
+            &self,
+            job: &JobKey,
+            file_path: &str,
+            _info: Option<&MediaInfo>,
+            format: ImageFormat,
+        ) -> Result<String, ConversionError> {
+            let cancel = self
+                .jobs
+                .register(job.chat_id, &job.message_ids, job.owner_id);
+            let output_path = format!("{}.fake.{:?}", file_path, format).to_lowercase();
+            let result = self.finish_job(cancel, output_path).await;
+            self.jobs.finish(job.chat_id, &job.message_ids);
+            result
+        }
+
+        async fn convert_sticker(
+            &self,
+            job: &JobKey,
+            file_path: &str,
+            source: StickerSource,
+            _dimensions: Option<(u32, u32)>,
+            settings: &UnstickerSettings,
+        ) -> Result<String, ConversionError> {
+            let cancel = self
+                .jobs
+                .register(job.chat_id, &job.message_ids, job.owner_id);
+            let output_path = match source {
+                StickerSource::Static => format!("{}.fake.{:?}", file_path, ImageFormat::Png),
+                StickerSource::Video | StickerSource::Animated => {
+                    format!("{}.fake.{:?}", file_path, settings.format)
+                }
+            }
+            .to_lowercase();
+            let result = self.finish_job(cancel, output_path).await;
+            self.jobs.finish(job.chat_id, &job.message_ids);
+            result
+        }
+
+        async fn make_sticker(
+            &self,
+            job: &JobKey,
+            file_path: &str,
+            _frame_rate: &FrameRatePlan,
+        ) -> Result<String, ConversionError> {
+            let cancel = self
+                .jobs
+                .register(job.chat_id, &job.message_ids, job.owner_id);
+            let result = self
+                .finish_job(cancel, format!("{}.fake.webm", file_path))
+                .await;
+            self.jobs.finish(job.chat_id, &job.message_ids);
+            result
+        }
+
+        fn track(&self, job: &JobKey) -> TrackedJob {
+            self.jobs.track(job.chat_id, &job.message_ids, job.owner_id)
+        }
+
+        fn cancel(&self, chat_id: i64, message_id: i32, requester_id: i64) -> CancelOutcome {
+            self.jobs.cancel(chat_id, message_id, requester_id)
+        }
+
+        fn workers(&self) -> &WorkerPool {
+            &self.workers
+        }
+    }
+}